@@ -1,6 +1,5 @@
 use std::{
-    io::{ErrorKind, Write},
-    net::TcpStream,
+    io::{self, ErrorKind},
     sync::{Arc, Mutex},
     thread,
     time::Duration,
@@ -11,14 +10,14 @@ use crate::{camera::Camera, camera_system::CameraSystem, config::Config};
 use aws_config::BehaviorVersion;
 use common::incident::Incident;
 use incident_recognition::aws_rekognition::is_incident;
-use mqtt::model::{
-    components::{
-        encoded_string::EncodedString, login::Login, qos::QoS, topic_filter::TopicFilter,
-        topic_level::TopicLevel, topic_name::TopicName,
+use mqtt::{
+    client::{client_options::ClientOptions, mqtt_client::MqttClient, tls_options::TlsOptions},
+    model::{
+        components::{
+            qos::QoS, topic_filter::TopicFilter, topic_level::TopicLevel, topic_name::TopicName,
+        },
+        packets::publish::Publish,
     },
-    packet::Packet,
-    packets::{connect::Connect, publish::Publish, subscribe::Subscribe},
-    return_codes::connect_return_code::ConnectReturnCode,
 };
 use thread_pool::thread_pool::ThreadPool;
 use tokio::runtime::Runtime;
@@ -29,17 +28,18 @@ const CLOSE_INCIDENT: &[u8] = b"close-incident";
 const CAMERA_DATA: &[u8] = b"camera-data";
 
 const UPDATE_DATA_INTERVAL: u64 = 2;
-const READ_MESSAGE_INTERVAL: u64 = 1;
 const ANALYSE_IMAGES_INTERVAL: u64 = 3;
 
 const CAMERA_THREADS_NUMBER: usize = 4;
 
+/// Seconds between pings sent to the server to detect a lost connection
+const KEEP_ALIVE: u16 = 10;
+
 /// Runs the client
 pub fn client_run(config: Config) -> std::io::Result<()> {
-    let key = *config.get_key();
     let active_range = config.get_active_range();
 
-    let mut server_stream = connect_to_server(config.clone())?;
+    let client = connect_to_server(config.clone())?;
     let mut camera_system = CameraSystem::new();
 
     for (i, camera) in config.get_cameras().iter().enumerate() {
@@ -54,39 +54,31 @@ pub fn client_run(config: Config) -> std::io::Result<()> {
 
     let images_folder = config.get_images_folder().to_owned();
 
-    make_initial_subscribes(&mut server_stream, &key);
-
-    let server_stream = Arc::new(Mutex::new(server_stream));
     let camera_system = Arc::new(Mutex::new(camera_system));
 
-    let server_stream_clone = server_stream.clone();
-    let camera_system_clone = camera_system.clone();
+    register_callbacks(&client, camera_system.clone())?;
+    make_initial_subscribes(&client);
 
-    let thread_update = thread::spawn(move || {
-        update_camera_system_status(server_stream_clone, camera_system_clone, &key);
-    });
-
-    let server_stream_clone = server_stream.clone();
+    let client_clone = client.clone();
     let camera_system_clone = camera_system.clone();
 
-    let thread_read = thread::spawn(move || {
-        read_incoming_packets(server_stream_clone, camera_system_clone, &key);
+    let thread_update = thread::spawn(move || {
+        update_camera_system_status(client_clone, camera_system_clone);
     });
 
-    let server_stream_clone = server_stream.clone();
+    let client_clone = client.clone();
     let camera_system_clone = camera_system.clone();
 
     let thread_image_recognition = thread::spawn(move || {
         image_recognition(
-            server_stream_clone,
+            client_clone,
             camera_system_clone,
             images_folder,
-            &key,
             config.get_confidence_threshold(),
         );
     });
 
-    let threads = vec![thread_update, thread_read, thread_image_recognition];
+    let threads = vec![thread_update, thread_image_recognition];
 
     for thread in threads {
         match thread.join() {
@@ -100,159 +92,130 @@ pub fn client_run(config: Config) -> std::io::Result<()> {
     Ok(())
 }
 
-/// Read incoming packages in a loop
-fn read_incoming_packets(
-    server_stream: Arc<Mutex<TcpStream>>,
+/// Registers the handlers of the publishes received from the server
+fn register_callbacks(
+    client: &MqttClient,
     camera_system: Arc<Mutex<CameraSystem>>,
-    key: &[u8; 32],
-) {
-    loop {
-        let locked_stream = match server_stream.lock() {
-            Ok(stream) => stream,
-            Err(_) => {
-                println!("Mutex was poisoned");
-                return;
-            }
-        };
-
-        let mut clone_stream = match locked_stream.try_clone() {
-            Ok(stream) => stream,
-            Err(_) => {
-                println!("Error cloning stream");
-                return;
-            }
-        };
-
-        match clone_stream.set_nonblocking(true) {
-            Ok(_) => {}
-            Err(_) => {
-                println!("Error setting non-blocking");
-                return;
-            }
-        };
-
-        let incoming_publish = match Packet::from_bytes(&mut clone_stream, key) {
-            Ok(Packet::Publish(publish)) => publish,
-            _ => {
-                drop(locked_stream);
-                thread::sleep(Duration::from_secs(READ_MESSAGE_INTERVAL));
-                continue;
-            }
-        };
-
-        drop(locked_stream);
-
-        let topic_levels = incoming_publish.topic().levels();
-
-        let cloned_camera_system = camera_system.clone();
+) -> std::io::Result<()> {
+    let new_incident = TopicFilter::new(vec![TopicLevel::Literal(NEW_INCIDENT.to_vec())], false);
+    let close_incident = TopicFilter::new(
+        vec![
+            TopicLevel::Literal(CLOSE_INCIDENT.to_vec()),
+            TopicLevel::SingleLevelWildcard,
+        ],
+        false,
+    );
 
-        if topic_levels.len() == 1 && topic_levels[0] == NEW_INCIDENT {
-            handle_new_incident(incoming_publish, cloned_camera_system);
-        } else if topic_levels.len() == 2 && topic_levels[0] == CLOSE_INCIDENT {
-            handle_close_incident(incoming_publish, cloned_camera_system);
-        }
-    }
+    let cloned_camera_system = camera_system.clone();
+    client
+        .on_publish(new_incident, move |publish| {
+            handle_new_incident(publish, cloned_camera_system.clone());
+        })
+        .map_err(|err| io::Error::other(err.to_string()))?;
+
+    let cloned_camera_system = camera_system.clone();
+    client
+        .on_publish(close_incident, move |publish| {
+            handle_close_incident(publish, cloned_camera_system.clone());
+        })
+        .map_err(|err| io::Error::other(err.to_string()))?;
+
+    // The client subscribes again by itself, the cameras data is republished so it is current
+    client
+        .on_reconnect(move |client| {
+            let _ = publish_camera_system_status(client, &camera_system);
+        })
+        .map_err(|err| io::Error::other(err.to_string()))
 }
 
 /// Periodically updates the camera system status
-fn update_camera_system_status(
-    server_stream: Arc<Mutex<TcpStream>>,
-    camera_system: Arc<Mutex<CameraSystem>>,
-    key: &[u8; 32],
-) {
+fn update_camera_system_status(client: MqttClient, camera_system: Arc<Mutex<CameraSystem>>) {
     loop {
-        let locked_camera_system = match camera_system.lock() {
-            Ok(locked_camera_system) => locked_camera_system,
-            Err(_) => {
-                println!("Mutex was poisoned");
-                return;
-            }
-        };
+        if !publish_camera_system_status(&client, &camera_system) {
+            return;
+        }
 
-        let topic_name = TopicName::new(vec![CAMERA_DATA.to_vec()], false);
-        let cameras_data = locked_camera_system.cameras_data().as_bytes().to_vec();
+        thread::sleep(Duration::from_secs(UPDATE_DATA_INTERVAL));
+    }
+}
 
-        drop(locked_camera_system);
+/// Publishes the data of the cameras. Returns false if the mutex was poisoned
+fn publish_camera_system_status(client: &MqttClient, camera_system: &Mutex<CameraSystem>) -> bool {
+    let locked_camera_system = match camera_system.lock() {
+        Ok(locked_camera_system) => locked_camera_system,
+        Err(_) => {
+            println!("Mutex was poisoned");
+            return false;
+        }
+    };
 
-        let cloned_server_stream = server_stream.clone();
+    let topic_name = TopicName::new(vec![CAMERA_DATA.to_vec()], false);
+    let cameras_data = locked_camera_system.cameras_data().as_bytes().to_vec();
 
-        publish(topic_name, cameras_data, cloned_server_stream, key);
+    drop(locked_camera_system);
 
-        thread::sleep(Duration::from_secs(UPDATE_DATA_INTERVAL));
-    }
+    publish(topic_name, cameras_data, client);
+    true
 }
 
 /// Publishes a message to a topic
-fn publish(
-    topic_name: TopicName,
-    message: Vec<u8>,
-    server_stream: Arc<Mutex<TcpStream>>,
-    key: &[u8; 32],
-) {
-    let dup = false;
+fn publish(topic_name: TopicName, message: Vec<u8>, client: &MqttClient) {
     let qos = QoS::AtMost;
     let retain = false;
-    let package_identifier = None;
-    let message_bytes = message;
-
-    let publish_packet = Publish::new(
-        dup,
-        qos,
-        retain,
-        topic_name,
-        package_identifier,
-        message_bytes,
-    );
 
-    let mut locked_server_stream = match server_stream.lock() {
-        Ok(locked_server_stream) => locked_server_stream,
-        Err(_) => {
-            println!("Mutex was poisoned");
-            return;
-        }
-    };
+    if let Err(err) = client.publish(topic_name, message, qos, retain) {
+        println!("Error publishing: {}", err);
+    }
+}
 
-    let _ = locked_server_stream.write(publish_packet.to_bytes(key).as_slice());
+/// Connects to the server
+fn connect_to_server(config: Config) -> std::io::Result<MqttClient> {
+    let options = ClientOptions::new(config.get_id(), *config.get_key())
+        .with_login(config.get_username(), config.get_password())
+        .with_key_id(config.get_key_id())
+        .with_encryption(config.get_encryption())
+        .with_keep_alive(KEEP_ALIVE)
+        .with_default_reconnect();
+    let options = match config.get_client_key() {
+        Some(client_key) => options.with_client_key(*client_key),
+        None => options,
+    };
+    let options = match config.get_keyring_file() {
+        Some(keyring_file) => options.with_keyring_file(keyring_file),
+        None => options,
+    };
+    let options = match tls_options(&config)? {
+        Some(tls) => options.with_tls(tls),
+        None => options,
+    };
 
-    drop(locked_server_stream);
+    MqttClient::connect(config.get_address(), options)
+        .map_err(|err| io::Error::new(ErrorKind::ConnectionRefused, err.to_string()))
 }
 
-/// Connects to the server
-fn connect_to_server(config: Config) -> std::io::Result<TcpStream> {
-    let address = config.get_address().to_owned();
-    let id = config.get_id().to_owned();
-    let username = config.get_username().to_owned();
-    let password = config.get_password().to_owned();
-    let key = config.get_key().to_owned();
-
-    let mut to_server_stream = TcpStream::connect(address)?;
-
-    let client_id_bytes: Vec<u8> = id.as_bytes().to_vec();
-    let client_id = EncodedString::new(client_id_bytes);
-    let will = None;
-
-    let username = EncodedString::new(username.as_bytes().to_vec());
-    let password = Some(EncodedString::new(password.as_bytes().to_vec()));
-    let login = Some(Login::new(username, password));
-
-    let connect = Connect::new(false, 0, client_id, will, login);
-
-    let _ = to_server_stream.write(connect.to_bytes(&key).as_slice());
-
-    match Packet::from_bytes(&mut to_server_stream, &key) {
-        Ok(Packet::Connack(connack)) => match connack.connect_return_code() {
-            ConnectReturnCode::ConnectionAccepted => Ok(to_server_stream),
-            _ => Err(std::io::Error::new(
-                ErrorKind::Other,
-                format!("Connection refused: {:?}", connack.connect_return_code()),
-            )),
-        },
-        _ => Err(std::io::Error::new(ErrorKind::Other, "No connack recibed")),
+/// Returns the TLS settings of the connection, None if no certificate authorities are configured
+fn tls_options(config: &Config) -> std::io::Result<Option<TlsOptions>> {
+    let ca_file = match config.get_tls_ca_file() {
+        Some(ca_file) => ca_file,
+        None => return Ok(None),
+    };
+
+    let mut tls = TlsOptions::new(ca_file, config.get_tls_server_name())
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err.to_string()))?;
+    if let (Some(certificate_file), Some(private_key_file)) = (
+        config.get_tls_certificate_file(),
+        config.get_tls_private_key_file(),
+    ) {
+        tls = tls
+            .with_client_certificate(certificate_file, private_key_file)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err.to_string()))?;
     }
+
+    Ok(Some(tls))
 }
 
 /// Handles a new incident
-fn handle_new_incident(incoming_publish: Publish, camera_system: Arc<Mutex<CameraSystem>>) {
+fn handle_new_incident(incoming_publish: &Publish, camera_system: Arc<Mutex<CameraSystem>>) {
     let incident_string = String::from_utf8_lossy(incoming_publish.message()).to_string();
     let incident = match Incident::from_string(incident_string) {
         Ok(incident) => incident,
@@ -276,7 +239,7 @@ fn handle_new_incident(incoming_publish: Publish, camera_system: Arc<Mutex<Camer
 }
 
 /// Handles the closing of an incident
-fn handle_close_incident(incoming_publish: Publish, camera_system: Arc<Mutex<CameraSystem>>) {
+fn handle_close_incident(incoming_publish: &Publish, camera_system: Arc<Mutex<CameraSystem>>) {
     let topic_levels = incoming_publish.topic().levels();
     let incident_id = String::from_utf8_lossy(topic_levels[1].as_slice()).to_string();
 
@@ -294,7 +257,7 @@ fn handle_close_incident(incoming_publish: Publish, camera_system: Arc<Mutex<Cam
 }
 
 /// Make initial subscribes
-fn make_initial_subscribes(server_stream: &mut TcpStream, key: &[u8; 32]) {
+fn make_initial_subscribes(client: &MqttClient) {
     let new_incident = TopicFilter::new(vec![TopicLevel::Literal(NEW_INCIDENT.to_vec())], false);
     let close_incident = TopicFilter::new(
         vec![
@@ -304,25 +267,20 @@ fn make_initial_subscribes(server_stream: &mut TcpStream, key: &[u8; 32]) {
         false,
     );
     let topics = vec![new_incident, close_incident];
-    subscribe(topics, server_stream, key);
+    subscribe(topics, client);
 }
 
 /// Handles the subscription to a topic
-fn subscribe(filter: Vec<TopicFilter>, server_stream: &mut TcpStream, key: &[u8; 32]) {
+fn subscribe(filter: Vec<TopicFilter>, client: &MqttClient) {
     let mut topics_filters = vec![];
 
     for topic_filter in filter {
         topics_filters.push((topic_filter, QoS::AtLeast));
     }
 
-    let packet_id = 1;
-
-    let subscribe_packet = Subscribe::new(packet_id, topics_filters);
-    let _ = server_stream.write(subscribe_packet.to_bytes(key).as_slice());
-
-    match Packet::from_bytes(server_stream, key) {
-        Ok(Packet::Suback(_)) => {}
-        _ => {
+    match client.subscribe(topics_filters) {
+        Ok(_) => {}
+        Err(_) => {
             println!("Suback was not recibed");
         }
     }
@@ -330,10 +288,9 @@ fn subscribe(filter: Vec<TopicFilter>, server_stream: &mut TcpStream, key: &[u8;
 
 /// Main loop for image recognition
 fn image_recognition(
-    server_stream: Arc<Mutex<TcpStream>>,
+    client: MqttClient,
     camera_system: Arc<Mutex<CameraSystem>>,
     images_folder: String,
-    key: &[u8; 32],
     confidence_threshold: f32,
 ) {
     let thread_pool = ThreadPool::new(CAMERA_THREADS_NUMBER);
@@ -366,20 +323,12 @@ fn image_recognition(
             if let Some(path) = look_for_new_images(images_folder.clone(), camera.clone()) {
                 locked_camera_system.add_seen_image(camera.id(), path.as_str());
 
-                let server_stream = server_stream.clone();
-                let key = *key;
+                let client = client.clone();
                 let config = config.clone();
 
                 // println!("Image found: {}", path);
                 thread_pool.execute(move || {
-                    analyze_image(
-                        server_stream,
-                        &mut camera,
-                        path,
-                        &key,
-                        &config,
-                        confidence_threshold,
-                    );
+                    analyze_image(&client, &mut camera, path, &config, confidence_threshold);
                 });
                 // println!("Image analyzed");
             }
@@ -438,10 +387,9 @@ fn look_for_new_images(images_folder: String, camera: Camera) -> Option<String>
 
 /// Analyzes an image using AWS Rekognition
 fn analyze_image(
-    server_stream: Arc<Mutex<TcpStream>>,
+    client: &MqttClient,
     camera: &mut Camera,
     path: String,
-    key: &[u8; 32],
     config: &aws_config::SdkConfig,
     confidence_threshold: f32,
 ) {
@@ -457,17 +405,12 @@ fn analyze_image(
     let posible_label = rt.block_on(is_incident(config, path.as_str(), confidence_threshold));
 
     if let Some(label) = posible_label {
-        alert_incident(server_stream, camera, key, label);
+        alert_incident(client, camera, label);
     }
 }
 
 /// Alerts an incident that was recognized by the cameras
-fn alert_incident(
-    server_stream: Arc<Mutex<TcpStream>>,
-    camera: &mut Camera,
-    key: &[u8; 32],
-    label: String,
-) {
+fn alert_incident(client: &MqttClient, camera: &mut Camera, label: String) {
     let topic_name = TopicName::new(
         vec![
             DETECTED_INCIDENT.to_vec(),
@@ -479,5 +422,5 @@ fn alert_incident(
 
     let message = data.join(";").as_bytes().to_vec();
 
-    publish(topic_name, message, server_stream, key);
+    publish(topic_name, message, client);
 }
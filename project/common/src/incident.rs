@@ -16,13 +16,13 @@ pub enum IncidentStatus {
 
 impl IncidentStatus {
     /// Creates a new incident status from a string
-    pub fn from_string(string: String) -> Self {
+    pub fn from_string(string: String) -> Result<Self, Error> {
         match string.as_str() {
-            "0" => IncidentStatus::Pending,
-            "1" => IncidentStatus::InProgress,
-            "2" => IncidentStatus::Resolvable,
-            "3" => IncidentStatus::Resolved,
-            _ => panic!("Invalid incident status"),
+            "0" => Ok(IncidentStatus::Pending),
+            "1" => Ok(IncidentStatus::InProgress),
+            "2" => Ok(IncidentStatus::Resolvable),
+            "3" => Ok(IncidentStatus::Resolved),
+            _ => Err(Error::new("Invalid incident status".to_string())),
         }
     }
 
@@ -100,7 +100,7 @@ impl Incident {
             Ok(value) => value,
             Err(_) => return Err(Error::new("Invalid y coordinate".to_string())),
         };
-        let state = IncidentStatus::from_string(splited_string[5].to_string());
+        let state = IncidentStatus::from_string(splited_string[5].to_string())?;
 
         Ok(Incident {
             uuid: id,
@@ -210,9 +210,11 @@ mod tests {
 
     #[test]
     fn test_incident_status_from_string() {
-        let status = IncidentStatus::from_string("0".to_string());
+        let status = IncidentStatus::from_string("0".to_string()).unwrap();
 
         assert_eq!(status, IncidentStatus::Pending);
+        assert!(IncidentStatus::from_string("4".to_string()).is_err());
+        assert!(Incident::from_string("1;incident;incident;1.0;1.0;x".to_string()).is_err());
     }
 
     #[test]
@@ -1,19 +1,18 @@
 use std::{
-    io::{ErrorKind, Write},
-    net::TcpStream,
-    sync::{Arc, Mutex, MutexGuard},
+    io::{self, ErrorKind},
+    sync::{Arc, Mutex},
     thread,
     time::Duration,
 };
 
-use mqtt::model::{
-    components::{
-        encoded_string::EncodedString, login::Login, qos::QoS, topic_filter::TopicFilter,
-        topic_level::TopicLevel, topic_name::TopicName,
+use mqtt::{
+    client::{client_options::ClientOptions, mqtt_client::MqttClient, tls_options::TlsOptions},
+    model::{
+        components::{
+            qos::QoS, topic_filter::TopicFilter, topic_level::TopicLevel, topic_name::TopicName,
+        },
+        packets::publish::Publish,
     },
-    packet::Packet,
-    packets::{connect::Connect, publish::Publish, subscribe::Subscribe, unsubscribe::Unsubscribe},
-    return_codes::connect_return_code::ConnectReturnCode,
 };
 
 use crate::{config::Config, drone::Drone};
@@ -28,7 +27,6 @@ const CLOSE_INCIDENT: &[u8] = b"close-incident";
 const DRONE_DATA: &[u8] = b"drone-data";
 const READY_INCIDENT: &[u8] = b"ready-incident";
 
-const READ_MESSAGE_INTERVAL: u64 = 100;
 const UPDATE_DATA_INTERVAL: u64 = 1;
 const CHECK_BATTERY_INTERVAL: u64 = 5;
 const PENDING_INCIDENTS_INTERVAL: u64 = 1;
@@ -42,10 +40,12 @@ const DRONE_ATTENDING_DURATION: u64 = 10;
 
 const DRONE_COUNT_PER_INCIDENT: usize = 2;
 
+/// Seconds between pings sent to the server to detect a lost connection
+const KEEP_ALIVE: u16 = 10;
+
 /// Runs the client with the specified configuration
 pub fn client_run(config: Config) -> std::io::Result<()> {
-    let server_stream = connect_to_server(config.clone())?;
-    let server_stream = Arc::new(Mutex::new(server_stream));
+    let client = connect_to_server(config.clone())?;
 
     let drone = Arc::new(Mutex::new(Drone::new(
         config.get_id(),
@@ -57,39 +57,24 @@ pub fn client_run(config: Config) -> std::io::Result<()> {
         config.get_active_range(),
     )));
 
-    let key = config.get_key().to_owned();
+    register_callbacks(&client, drone.clone())?;
 
     let new_incident = TopicFilter::new(vec![TopicLevel::Literal(NEW_INCIDENT.to_vec())], false);
+    subscribe(&client, new_incident)?;
 
-    match server_stream.lock() {
-        Ok(mut server_stream) => {
-            subscribe(new_incident, &mut server_stream, &key)?;
-        }
-        Err(_) => {
-            return Err(std::io::Error::new(ErrorKind::Other, "Mutex was poisoned"));
-        }
-    }
-
-    let server_stream_clone = server_stream.clone();
+    let client_clone = client.clone();
     let drone_clone = drone.clone();
 
     let thread_update = thread::spawn(move || {
-        update_drone_status(server_stream_clone, drone_clone, &key);
-    });
-
-    let server_stream_cloned = server_stream.clone();
-    let drone_cloned = drone.clone();
-
-    let thread_read = thread::spawn(move || {
-        read_incoming_packets(server_stream_cloned, drone_cloned, &key);
+        update_drone_status(client_clone, drone_clone);
     });
 
     // Thread to handle pending incidents
-    let server_stream_cloned = server_stream.clone();
+    let client_cloned = client.clone();
     let drone_cloned = drone.clone();
 
     let thread_pending_incidents = thread::spawn(move || {
-        handle_pending_incidents(drone_cloned, server_stream_cloned, &key);
+        handle_pending_incidents(drone_cloned, client_cloned);
     });
 
     let drone_cloned = drone.clone();
@@ -109,7 +94,7 @@ pub fn client_run(config: Config) -> std::io::Result<()> {
     let mut locked_drone = match drone.lock() {
         Ok(drone) => drone,
         Err(_) => {
-            return Err(std::io::Error::new(ErrorKind::Other, "Mutex was poisoned"));
+            return Err(io::Error::other("Mutex was poisoned"));
         }
     };
 
@@ -121,7 +106,6 @@ pub fn client_run(config: Config) -> std::io::Result<()> {
 
     let threads = vec![
         thread_update,
-        thread_read,
         thread_pending_incidents,
         thread_discharge_battery,
         thread_recharge_battery,
@@ -131,10 +115,7 @@ pub fn client_run(config: Config) -> std::io::Result<()> {
         match thread.join() {
             Ok(_) => {}
             Err(_) => {
-                return Err(std::io::Error::new(
-                    ErrorKind::Other,
-                    "Error joining threads",
-                ));
+                return Err(io::Error::other("Error joining threads"));
             }
         }
     }
@@ -143,99 +124,76 @@ pub fn client_run(config: Config) -> std::io::Result<()> {
 }
 
 /// Connects to the server with the specified address
-fn connect_to_server(config: Config) -> std::io::Result<TcpStream> {
-    let address = config.get_address();
-    let id = config.get_id();
-    let username = config.get_username();
-    let password = config.get_password();
-    let key = config.get_key();
-
-    let mut to_server_stream = TcpStream::connect(address)?;
-
-    let client_id_bytes: Vec<u8> = id.to_string().into_bytes();
-
-    let client_id = EncodedString::new(client_id_bytes);
-    let will = None;
-
-    let username = EncodedString::from_string(&username.to_string());
-    let password = Some(EncodedString::from_string(&password.to_string()));
-
-    let login = Some(Login::new(username, password));
-    let connect = Connect::new(false, 0, client_id, will, login);
-
-    let _ = to_server_stream.write(connect.to_bytes(key).as_slice());
+fn connect_to_server(config: Config) -> std::io::Result<MqttClient> {
+    let options = ClientOptions::new(&config.get_id().to_string(), *config.get_key())
+        .with_login(config.get_username(), config.get_password())
+        .with_key_id(config.get_key_id())
+        .with_encryption(config.get_encryption())
+        .with_keep_alive(KEEP_ALIVE)
+        .with_default_reconnect();
+    let options = match config.get_client_key() {
+        Some(client_key) => options.with_client_key(*client_key),
+        None => options,
+    };
+    let options = match config.get_keyring_file() {
+        Some(keyring_file) => options.with_keyring_file(keyring_file),
+        None => options,
+    };
+    let options = match tls_options(&config)? {
+        Some(tls) => options.with_tls(tls),
+        None => options,
+    };
 
-    match Packet::from_bytes(&mut to_server_stream, key) {
-        Ok(Packet::Connack(connack)) => match connack.connect_return_code() {
-            ConnectReturnCode::ConnectionAccepted => Ok(to_server_stream),
-            _ => Err(std::io::Error::new(
-                ErrorKind::Other,
-                format!("Connection refused: {:?}", connack.connect_return_code()),
-            )),
-        },
-        _ => Err(std::io::Error::new(ErrorKind::Other, "No connack recibed")),
-    }
+    MqttClient::connect(config.get_address(), options)
+        .map_err(|err| io::Error::new(ErrorKind::ConnectionRefused, err.to_string()))
 }
 
-/// Reads incoming packets from the server
-fn read_incoming_packets(stream: Arc<Mutex<TcpStream>>, drone: Arc<Mutex<Drone>>, key: &[u8; 32]) {
-    loop {
-        let locked_stream = match stream.lock() {
-            Ok(stream) => stream,
-            Err(_) => {
-                return;
-            }
-        };
-
-        let mut cloned_stream = match locked_stream.try_clone() {
-            Ok(stream) => stream,
-            Err(_) => {
-                return;
-            }
-        };
-
-        match cloned_stream.set_nonblocking(true) {
-            Ok(_) => {}
-            Err(_) => {
-                return;
-            }
-        }
+/// Returns the TLS settings of the connection, None if no certificate authorities are configured
+fn tls_options(config: &Config) -> std::io::Result<Option<TlsOptions>> {
+    let ca_file = match config.get_tls_ca_file() {
+        Some(ca_file) => ca_file,
+        None => return Ok(None),
+    };
 
-        match Packet::from_bytes(&mut cloned_stream, key) {
-            Ok(Packet::Publish(publish)) => {
-                drop(locked_stream);
-                let cloned_drone = drone.clone();
-                let cloned_stream = stream.clone();
+    let mut tls = TlsOptions::new(ca_file, config.get_tls_server_name())
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err.to_string()))?;
+    if let (Some(certificate_file), Some(private_key_file)) = (
+        config.get_tls_certificate_file(),
+        config.get_tls_private_key_file(),
+    ) {
+        tls = tls
+            .with_client_certificate(certificate_file, private_key_file)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err.to_string()))?;
+    }
 
-                handle_publish(publish, cloned_drone, cloned_stream, key);
-                continue;
-            }
-            Ok(Packet::Puback(_)) => {}
-            Ok(Packet::Pingresp(_)) => {}
-            Ok(Packet::Suback(_)) => {}
-            Ok(Packet::Unsuback(_)) => {}
-            Ok(Packet::Pingreq(_)) => {}
-            Ok(Packet::Disconnect(_)) => {
-                break;
-            }
-            _ => {
-                drop(locked_stream);
-                thread::sleep(Duration::from_millis(READ_MESSAGE_INTERVAL));
-                continue;
-            }
-        }
+    Ok(Some(tls))
+}
 
-        drop(locked_stream);
+/// Registers the handlers of the publishes received from the server
+fn register_callbacks(client: &MqttClient, drone: Arc<Mutex<Drone>>) -> std::io::Result<()> {
+    for action in [NEW_INCIDENT, ATTENDING_INCIDENT, CLOSE_INCIDENT] {
+        let mut levels = vec![TopicLevel::Literal(action.to_vec())];
+        if action != NEW_INCIDENT {
+            levels.push(TopicLevel::SingleLevelWildcard);
+        }
+
+        let drone = drone.clone();
+        let callback_client = client.clone();
+        client
+            .on_publish(TopicFilter::new(levels, false), move |publish| {
+                handle_publish(publish, drone.clone(), &callback_client);
+            })
+            .map_err(|err| io::Error::other(err.to_string()))?;
     }
+
+    // The client subscribes again by itself, the telemetry is republished so it is current
+    client
+        .on_reconnect(move |client| publish_drone_status(client, &drone))
+        .map_err(|err| io::Error::other(err.to_string()))
 }
 
 /// Handles the incoming publish packet
-fn handle_publish(
-    publish: Publish,
-    drone: Arc<Mutex<Drone>>,
-    server_stream: Arc<Mutex<TcpStream>>,
-    key: &[u8; 32],
-) {
+fn handle_publish(publish: &Publish, drone: Arc<Mutex<Drone>>, client: &MqttClient) {
     let message = match String::from_utf8(publish.message().to_vec()) {
         Ok(message) => message,
         Err(_) => {
@@ -269,7 +227,7 @@ fn handle_publish(
 
             match action {
                 ATTENDING_INCIDENT => handle_attending_incident(uuid, drone),
-                CLOSE_INCIDENT => handle_close_incident(uuid, drone, server_stream, key),
+                CLOSE_INCIDENT => handle_close_incident(uuid, drone, client),
                 _ => {}
             }
         }
@@ -335,8 +293,7 @@ fn handle_attending_incident(uuid: String, drone: Arc<Mutex<Drone>>) {
 fn handle_close_incident(
     closing_incident_uuid: String,
     drone: Arc<Mutex<Drone>>,
-    server_stream: Arc<Mutex<TcpStream>>,
-    key: &[u8; 32],
+    client: &MqttClient,
 ) {
     let mut locked_drone = match drone.lock() {
         Ok(drone) => drone,
@@ -372,21 +329,11 @@ fn handle_close_incident(
         false,
     );
 
-    let mut stream = match server_stream.lock() {
-        Ok(stream) => stream,
-        Err(_) => {
-            println!("Mutex was poisoned");
-            return;
-        }
-    };
-
-    match unsubscribe(topic_filter, &mut stream, key) {
+    match client.unsubscribe(vec![topic_filter]) {
         Ok(_) => {}
         Err(e) => eprintln!("Error: {:?}", e),
     }
 
-    drop(stream);
-
     thread::spawn(move || {
         travel(drone.clone(), x, y, TravelLocation::Anchor);
 
@@ -406,123 +353,43 @@ fn handle_close_incident(
 }
 
 /// Updates the drone status
-fn update_drone_status(
-    server_stream: Arc<Mutex<TcpStream>>,
-    drone: Arc<Mutex<Drone>>,
-    key: &[u8; 32],
-) {
+fn update_drone_status(client: MqttClient, drone: Arc<Mutex<Drone>>) {
     loop {
-        let drone = match drone.lock() {
-            Ok(drone) => drone,
-            Err(_) => {
-                return;
-            }
-        };
-
-        let mut levels = vec![DRONE_DATA.to_vec()];
-        levels.push(drone.id().to_string().into_bytes());
-
-        let topic_name = TopicName::new(levels, false);
-        let message = drone.data().into_bytes();
-
-        drop(drone);
-        let mut stream = match server_stream.lock() {
-            Ok(server_stream) => server_stream,
-            Err(_) => {
-                return;
-            }
-        };
-
-        match publish(topic_name, message, &mut stream, QoS::AtMost, key) {
-            Ok(_) => {}
-            Err(e) => eprintln!("Error: {:?}", e),
-        }
-
-        drop(stream);
+        publish_drone_status(&client, &drone);
 
         thread::sleep(Duration::from_secs(UPDATE_DATA_INTERVAL));
     }
 }
 
-/// Subscribes to the specified topic filter
-fn subscribe(
-    filter: TopicFilter,
-    server_stream: &mut MutexGuard<TcpStream>,
-    key: &[u8; 32],
-) -> std::io::Result<()> {
-    let mut server_stream = match server_stream.try_clone() {
-        Ok(stream) => stream,
-        Err(_) => {
-            println!("Mutex was poisoned");
-            return Err(std::io::Error::new(ErrorKind::Other, "Mutex was poisoned"));
-        }
-    };
-
-    let packet_id = 1;
-    let qos = QoS::AtMost;
-    let topics_filters = vec![(filter, qos)];
-
-    let subscribe_packet = Subscribe::new(packet_id, topics_filters);
-    let _ = server_stream.write(subscribe_packet.to_bytes(key).as_slice());
-
-    Ok(())
-}
-
-/// Unsubscribes from the specified topic filter
-fn unsubscribe(
-    filter: TopicFilter,
-    server_stream: &mut MutexGuard<TcpStream>,
-    key: &[u8; 32],
-) -> std::io::Result<()> {
-    let mut server_stream = match server_stream.try_clone() {
-        Ok(stream) => stream,
+/// Publishes the position, status and battery of the drone
+fn publish_drone_status(client: &MqttClient, drone: &Mutex<Drone>) {
+    let drone = match drone.lock() {
+        Ok(drone) => drone,
         Err(_) => {
-            return Err(std::io::Error::new(ErrorKind::Other, "Mutex was poisoned"));
+            return;
         }
     };
 
-    let packet_id = 1;
-    let topics_filters = vec![(filter)];
+    let mut levels = vec![DRONE_DATA.to_vec()];
+    levels.push(drone.id().to_string().into_bytes());
 
-    let unsubscribe_packet = Unsubscribe::new(packet_id, topics_filters);
+    let topic_name = TopicName::new(levels, false);
+    let message = drone.data().into_bytes();
 
-    let _ = server_stream.write(unsubscribe_packet.to_bytes(key).as_slice());
+    drop(drone);
 
-    Ok(())
+    match client.publish(topic_name, message, QoS::AtMost, true) {
+        Ok(_) => {}
+        Err(e) => eprintln!("Error: {:?}", e),
+    }
 }
 
-/// Publishes the specified message to the server
-fn publish(
-    topic_name: TopicName,
-    message: Vec<u8>,
-    server_stream: &mut MutexGuard<TcpStream>,
-    qos: QoS,
-    key: &[u8; 32],
-) -> std::io::Result<()> {
-    let mut server_stream = match server_stream.try_clone() {
-        Ok(stream) => stream,
-        Err(_) => {
-            return Err(std::io::Error::new(ErrorKind::Other, "Mutex was poisoned"));
-        }
-    };
-
-    let dup = false;
-    let retain = true;
-    let package_identifier = None;
-    let message_bytes = message;
-
-    let publish_packet = Publish::new(
-        dup,
-        qos.clone(),
-        retain,
-        topic_name,
-        package_identifier,
-        message_bytes,
-    );
-
-    let _ = server_stream.write(publish_packet.to_bytes(key).as_slice());
-
-    Ok(())
+/// Subscribes to the specified topic filter with QoS 1, so the incidents published with QoS 1 are not lost
+fn subscribe(client: &MqttClient, filter: TopicFilter) -> std::io::Result<()> {
+    client
+        .subscribe(vec![(filter, QoS::AtLeast)])
+        .map(|_| ())
+        .map_err(|err| io::Error::other(err.to_string()))
 }
 
 /// Travels to the specified location
@@ -559,11 +426,7 @@ fn travel(drone: Arc<Mutex<Drone>>, x: f64, y: f64, travel_location: TravelLocat
 }
 
 /// Handles the pending incidents of the drone queue
-fn handle_pending_incidents(
-    drone: Arc<Mutex<Drone>>,
-    server_stream: Arc<Mutex<TcpStream>>,
-    key: &[u8; 32],
-) {
+fn handle_pending_incidents(drone: Arc<Mutex<Drone>>, client: MqttClient) {
     loop {
         let locked_drone = match drone.lock() {
             Ok(drone) => drone,
@@ -589,11 +452,10 @@ fn handle_pending_incidents(
                 drop(locked_drone);
 
                 let drone = drone.clone();
-                let server_stream = server_stream.clone();
-                let key = *key;
+                let client = client.clone();
 
                 thread::spawn(move || {
-                    handle_incident(incident, drone.clone(), server_stream.clone(), &key);
+                    handle_incident(incident, drone, client);
                 });
             }
             None => {
@@ -605,12 +467,7 @@ fn handle_pending_incidents(
 }
 
 /// Handles the last incident
-fn handle_incident(
-    incident: Incident,
-    drone: Arc<Mutex<Drone>>,
-    server_stream: Arc<Mutex<TcpStream>>,
-    key: &[u8; 32],
-) {
+fn handle_incident(incident: Incident, drone: Arc<Mutex<Drone>>, client: MqttClient) {
     let topic_filter = TopicFilter::new(
         vec![
             TopicLevel::Literal(ATTENDING_INCIDENT.to_vec()),
@@ -619,22 +476,13 @@ fn handle_incident(
         false,
     );
 
-    let mut stream_locked = match server_stream.lock() {
-        Ok(stream) => stream,
-        Err(_) => {
-            return;
-        }
-    };
-
-    match subscribe(topic_filter, &mut stream_locked, key) {
+    match subscribe(&client, topic_filter) {
         Ok(_) => {}
         Err(e) => {
             eprintln!("Error: {:?}", e);
         }
     }
 
-    drop(stream_locked);
-
     travel(
         drone.clone(),
         incident.x_coordinate,
@@ -676,13 +524,6 @@ fn handle_incident(
     drone_locked.set_status(DroneStatus::AttendingIncident);
     drop(drone_locked);
 
-    let mut locked_stream = match server_stream.lock() {
-        Ok(stream) => stream,
-        Err(_) => {
-            return;
-        }
-    };
-
     let topic_name = TopicName::new(
         vec![
             TopicLevel::Literal(ATTENDING_INCIDENT.to_vec()).to_bytes(),
@@ -692,13 +533,11 @@ fn handle_incident(
     );
     let message = b"".to_vec();
 
-    match publish(topic_name, message, &mut locked_stream, QoS::AtMost, key) {
+    match client.publish(topic_name, message, QoS::AtMost, true) {
         Ok(_) => {}
         Err(_) => println!("Drone is attending the incident. no le llego el puback"),
     }
 
-    drop(locked_stream);
-
     loop {
         let locked_drone = match drone.lock() {
             Ok(drone) => drone,
@@ -716,13 +555,6 @@ fn handle_incident(
         thread::sleep(Duration::from_secs(WAIT_FOR_DRONE_INTERVAL));
     }
 
-    let mut locked_stream = match server_stream.lock() {
-        Ok(stream) => stream,
-        Err(_) => {
-            return;
-        }
-    };
-
     let topic_filter = TopicFilter::new(
         vec![
             TopicLevel::Literal(CLOSE_INCIDENT.to_vec()),
@@ -731,7 +563,7 @@ fn handle_incident(
         false,
     );
 
-    match subscribe(topic_filter, &mut locked_stream, key) {
+    match subscribe(&client, topic_filter) {
         Ok(_) => {}
         Err(_) => println!("Drone subscribe to close incident topic. no le llego el suback"),
     }
@@ -744,13 +576,11 @@ fn handle_incident(
         false,
     );
 
-    match unsubscribe(topic_filter, &mut locked_stream, key) {
+    match client.unsubscribe(vec![topic_filter]) {
         Ok(_) => {}
         Err(e) => eprintln!("Error: {:?}", e),
     }
 
-    drop(locked_stream);
-
     let duration_incident = Duration::from_secs(DRONE_ATTENDING_DURATION);
 
     thread::sleep(duration_incident);
@@ -764,19 +594,10 @@ fn handle_incident(
     );
     let message = b"".to_vec();
 
-    let mut locked_stream = match server_stream.lock() {
-        Ok(stream) => stream,
-        Err(_) => {
-            return;
-        }
-    };
-
-    match publish(topic_name, message, &mut locked_stream, QoS::AtMost, key) {
+    match client.publish(topic_name, message, QoS::AtMost, true) {
         Ok(_) => {}
         Err(e) => eprintln!("Error: {:?}", e),
     }
-
-    drop(locked_stream);
 }
 
 /// Discharges the battery of the drone
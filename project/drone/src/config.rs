@@ -1,3 +1,4 @@
+use mqtt::keyring::{parse_key, KEY_SIZE};
 use std::collections::HashMap;
 use std::io;
 use std::{fs::File, io::Read, path::Path};
@@ -10,6 +11,14 @@ pub struct Config {
     username: String,
     password: String,
     key: String,
+    key_id: u32,
+    client_key: Option<[u8; KEY_SIZE]>,
+    keyring_file: Option<String>,
+    encryption: bool,
+    tls_ca_file: Option<String>,
+    tls_server_name: Option<String>,
+    tls_certificate_file: Option<String>,
+    tls_private_key_file: Option<String>,
     x_central_position: f64,
     y_central_position: f64,
     x_anchor_position: f64,
@@ -49,6 +58,42 @@ impl Config {
             key: config_map
                 .remove("key")
                 .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Missing key"))?,
+            key_id: match config_map.remove("key_id") {
+                Some(key_id) => key_id
+                    .parse::<u32>()
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid key_id"))?,
+                None => 0,
+            },
+            client_key: match config_map
+                .remove("client_key")
+                .filter(|key| !key.is_empty())
+            {
+                Some(client_key) => Some(parse_key(&client_key).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Invalid client_key")
+                })?),
+                None => None,
+            },
+            keyring_file: config_map
+                .remove("keyring_file")
+                .filter(|file| !file.is_empty()),
+            encryption: match config_map.remove("encryption") {
+                Some(encryption) => encryption.parse::<bool>().map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Invalid encryption")
+                })?,
+                None => true,
+            },
+            tls_ca_file: config_map
+                .remove("tls_ca_file")
+                .filter(|file| !file.is_empty()),
+            tls_server_name: config_map
+                .remove("tls_server_name")
+                .filter(|name| !name.is_empty()),
+            tls_certificate_file: config_map
+                .remove("tls_certificate_file")
+                .filter(|file| !file.is_empty()),
+            tls_private_key_file: config_map
+                .remove("tls_private_key_file")
+                .filter(|file| !file.is_empty()),
             id: config_map
                 .remove("id")
                 .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Missing id"))?
@@ -134,6 +179,53 @@ impl Config {
         self.key.as_bytes().try_into().unwrap_or(&[0; 32])
     }
 
+    /// Returns the id of the key, 0 if it is not configured
+    pub fn get_key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    /// Returns the key of this client alone, None if its sessions are agreed from the shared key
+    pub fn get_client_key(&self) -> Option<&[u8; KEY_SIZE]> {
+        self.client_key.as_ref()
+    }
+
+    /// Returns the file the keys pushed by the server are saved to, None if they are only kept in memory
+    pub fn get_keyring_file(&self) -> Option<&str> {
+        self.keyring_file.as_deref()
+    }
+
+    /// Returns whether the packets are encrypted with the key. Without encryption the client speaks plain MQTT
+    pub fn get_encryption(&self) -> bool {
+        self.encryption
+    }
+
+    /// Returns the PEM file with the certificate authorities that sign the certificate of the server,
+    /// None if the connection does not use TLS
+    pub fn get_tls_ca_file(&self) -> Option<&str> {
+        self.tls_ca_file.as_deref()
+    }
+
+    /// Returns the name the certificate of the server must be issued to, by default the host of its address
+    pub fn get_tls_server_name(&self) -> &str {
+        match &self.tls_server_name {
+            Some(server_name) => server_name,
+            None => self
+                .address
+                .rsplit_once(':')
+                .map_or(self.address.as_str(), |(host, _)| host),
+        }
+    }
+
+    /// Returns the PEM file with the certificate presented to the server, None if it does not ask for one
+    pub fn get_tls_certificate_file(&self) -> Option<&str> {
+        self.tls_certificate_file.as_deref()
+    }
+
+    /// Returns the PEM file with the private key of the certificate presented to the server
+    pub fn get_tls_private_key_file(&self) -> Option<&str> {
+        self.tls_private_key_file.as_deref()
+    }
+
     /// Returns the x central position of the drone
     pub fn get_x_central_position(&self) -> f64 {
         self.x_central_position
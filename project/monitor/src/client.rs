@@ -1,22 +1,23 @@
 use std::{
-    collections::HashMap,
-    io::{ErrorKind, Write},
-    net::TcpStream,
-    sync::mpsc::{channel, Receiver, Sender},
+    io::{self, ErrorKind},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex, MutexGuard,
+    },
 };
 
 use common::{
     drone_status::DroneStatus,
     incident::{Incident, IncidentStatus},
 };
-use mqtt::model::{
-    components::{
-        encoded_string::EncodedString, login::Login, qos::QoS, topic_filter::TopicFilter,
-        topic_level::TopicLevel, topic_name::TopicName,
+use mqtt::{
+    client::{client_options::ClientOptions, mqtt_client::MqttClient, tls_options::TlsOptions},
+    model::{
+        components::{
+            qos::QoS, topic_filter::TopicFilter, topic_level::TopicLevel, topic_name::TopicName,
+        },
+        packets::publish::Publish,
     },
-    packet::Packet,
-    packets::{connect::Connect, publish::Publish, subscribe::Subscribe},
-    return_codes::connect_return_code::ConnectReturnCode,
 };
 
 use crate::{
@@ -37,26 +38,34 @@ pub fn client_run(config: Config) -> Result<(), String> {
     let (ui_sender, ui_receiver) = channel();
 
     // Connect to the server
-    let mut stream = match connect_to_server(config.clone()) {
-        Ok(stream) => stream,
+    let client = match connect_to_server(config.clone()) {
+        Ok(client) => client,
         Err(e) => {
             return Err(format!("Error connecting to server: {:?}", e));
         }
     };
 
+    let monitor = Arc::new(Mutex::new(Monitor::new()));
+
+    // Handle the publishes of the topics before subscribing, so none is lost
+    match register_callbacks(&client, monitor.clone(), monitor_sender.clone()) {
+        Ok(_) => {}
+        Err(e) => {
+            return Err(format!("Error registering callbacks: {:?}", e));
+        }
+    }
+
     // Subscribe to the topics
-    match subscribe_to_topics(&mut stream, config.get_key()) {
+    match subscribe_to_topics(&client) {
         Ok(_) => {}
         Err(e) => {
             return Err(format!("Error subscribing to topics: {:?}", e));
         }
     }
 
-    let cloned_key = *config.get_key();
-
     // monitor start in a thread to avoid blocking the main thread
     std::thread::spawn(move || {
-        start_monitor(stream, monitor_sender, ui_receiver, &cloned_key);
+        start_monitor(client, monitor, monitor_sender, ui_receiver);
     });
 
     // start the ui in the main thread
@@ -75,37 +84,49 @@ pub fn client_run(config: Config) -> Result<(), String> {
 }
 
 /// Connects to the server
-fn connect_to_server(config: Config) -> std::io::Result<TcpStream> {
-    let address = config.get_address();
-    let key = config.get_key();
-    let client_id = config.get_id();
-    let username = config.get_username();
-    let password = config.get_password();
-
-    let mut to_server_stream = TcpStream::connect(address)?;
-
-    let client_id_bytes = client_id.as_bytes().to_vec();
-    let client_id = EncodedString::new(client_id_bytes);
-    let will = None;
-
-    let username = EncodedString::from_string(&username.to_string());
-    let password = Some(EncodedString::from_string(&password.to_string()));
-    let login = Some(Login::new(username, password));
-
-    let connect = Connect::new(false, 0, client_id, will, login);
-
-    let _ = to_server_stream.write(connect.to_bytes(key).as_slice());
-
-    match Packet::from_bytes(&mut to_server_stream, key) {
-        Ok(Packet::Connack(connack)) => match connack.connect_return_code() {
-            ConnectReturnCode::ConnectionAccepted => Ok(to_server_stream),
-            _ => Err(std::io::Error::new(
-                ErrorKind::Other,
-                format!("Connection refused: {:?}", connack.connect_return_code()),
-            )),
-        },
-        _ => Err(std::io::Error::new(ErrorKind::Other, "No connack recibed")),
+fn connect_to_server(config: Config) -> std::io::Result<MqttClient> {
+    let options = ClientOptions::new(config.get_id(), *config.get_key())
+        .with_login(config.get_username(), config.get_password())
+        .with_key_id(config.get_key_id())
+        .with_encryption(config.get_encryption())
+        .with_keep_alive(KEEP_ALIVE)
+        .with_default_reconnect();
+    let options = match config.get_client_key() {
+        Some(client_key) => options.with_client_key(*client_key),
+        None => options,
+    };
+    let options = match config.get_keyring_file() {
+        Some(keyring_file) => options.with_keyring_file(keyring_file),
+        None => options,
+    };
+    let options = match tls_options(&config)? {
+        Some(tls) => options.with_tls(tls),
+        None => options,
+    };
+
+    MqttClient::connect(config.get_address(), options)
+        .map_err(|err| io::Error::new(ErrorKind::ConnectionRefused, err.to_string()))
+}
+
+/// Returns the TLS settings of the connection, None if no certificate authorities are configured
+fn tls_options(config: &Config) -> std::io::Result<Option<TlsOptions>> {
+    let ca_file = match config.get_tls_ca_file() {
+        Some(ca_file) => ca_file,
+        None => return Ok(None),
+    };
+
+    let mut tls = TlsOptions::new(ca_file, config.get_tls_server_name())
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err.to_string()))?;
+    if let (Some(certificate_file), Some(private_key_file)) = (
+        config.get_tls_certificate_file(),
+        config.get_tls_private_key_file(),
+    ) {
+        tls = tls
+            .with_client_certificate(certificate_file, private_key_file)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err.to_string()))?;
     }
+
+    Ok(Some(tls))
 }
 
 /// Starts the UI
@@ -134,120 +155,135 @@ fn start_ui(
     )
 }
 
-const CAMERA_DATA: &[u8] = b"camera-data";
-const DRONE_DATA: &[u8] = b"drone-data";
 const CLIENT_REGISTER: &[u8] = b"$client-register";
 const NEW_INCIDENT: &[u8] = b"new-incident";
-const ATTENDING_INCIDENT: &[u8] = b"attending-incident";
-const READY_INCIDENT: &[u8] = b"ready-incident";
 const CLOSE_INCIDENT: &[u8] = b"close-incident";
-const DETECTED_INCIDENT: &[u8] = b"detected-incident";
+
+/// Seconds between pings sent to the server to keep the connection alive
+const KEEP_ALIVE: u16 = 10;
 
 const SEPARATOR: char = ';';
 const ENUMARATOR: char = '|';
 
-/// Starts the monitor
+/// Starts the monitor, publishing the actions of the UI
 fn start_monitor(
-    stream: TcpStream,
+    client: MqttClient,
+    monitor: Arc<Mutex<Monitor>>,
     monitor_sender: Sender<MonitorAction>,
     ui_reciver: Receiver<UIAction>,
-    key: &[u8; 32],
 ) {
-    let mut monitor = Monitor::new();
-    let mut unacknowledged_publish = HashMap::new();
-    let mut publish_counter = 0;
-
-    let mut stream = stream;
+    for action in ui_reciver {
+        let mut monitor = match lock_monitor(&monitor) {
+            Some(monitor) => monitor,
+            None => return,
+        };
 
-    match stream.set_nonblocking(true) {
-        Ok(_) => {}
-        Err(_) => {
-            println!("Error setting stream to non-blocking");
+        match action {
+            UIAction::RegistrateDrone(drone_registration) => {
+                register_drone(drone_registration, &client)
+            }
+            UIAction::RegistrateIncident(incident_registration) => register_incident(
+                incident_registration,
+                &mut monitor,
+                monitor_sender.clone(),
+                &client,
+            ),
+            UIAction::EditIncident(incident_edit) => {
+                edit_incident(incident_edit, &mut monitor, monitor_sender.clone())
+            }
+            UIAction::ResolveIncident(incident) => {
+                resolve_incident(incident, &mut monitor, &client, monitor_sender.clone())
+            }
         }
     }
+}
 
-    loop {
-        match Packet::from_bytes(&mut stream, key) {
-            Ok(Packet::Puback(puback)) => {
-                let packet_id = puback.packet_identifier();
-
-                if unacknowledged_publish.remove(&packet_id).is_none() {
-                    println!("Publish id does not match the puback id");
-                }
-            }
-            Ok(Packet::Publish(publish)) => {
-                let topic_name = publish.topic();
-                let topic_levels = topic_name.levels();
-
-                match topic_levels[0].as_slice() {
-                    DRONE_DATA => {
-                        drone_data(publish.clone(), monitor_sender.clone());
-                    }
-                    CAMERA_DATA => {
-                        camera_data(publish.clone(), monitor_sender.clone());
-                    }
-                    ATTENDING_INCIDENT => {
-                        attend_incident(publish.clone(), &mut monitor, monitor_sender.clone());
-                    }
-                    READY_INCIDENT => {
-                        ready_incident(publish.clone(), &mut monitor, monitor_sender.clone());
-                    }
-                    DETECTED_INCIDENT => {
-                        detected_incident(publish.clone(), monitor_sender.clone());
-                    }
-                    _ => {
-                        println!("Unknown topic");
-                    }
-                }
-            }
+/// Registers the handlers of the publishes of each topic the monitor subscribes to
+fn register_callbacks(
+    client: &MqttClient,
+    monitor: Arc<Mutex<Monitor>>,
+    monitor_sender: Sender<MonitorAction>,
+) -> std::io::Result<()> {
+    let sender = monitor_sender.clone();
+    on_publish(client, "drone-data/+", move |publish| {
+        drone_data(publish, sender.clone());
+    })?;
+
+    let sender = monitor_sender.clone();
+    on_publish(client, "camera-data", move |publish| {
+        camera_data(publish, sender.clone());
+    })?;
+
+    let cloned_monitor = monitor.clone();
+    let sender = monitor_sender.clone();
+    on_publish(client, "attending-incident/+", move |publish| {
+        if let Some(mut monitor) = lock_monitor(&cloned_monitor) {
+            attend_incident(publish, &mut monitor, sender.clone());
+        }
+    })?;
 
-            Ok(_) => {}
-            Err(_) => {}
+    let cloned_monitor = monitor.clone();
+    let sender = monitor_sender.clone();
+    on_publish(client, "ready-incident/+", move |publish| {
+        if let Some(mut monitor) = lock_monitor(&cloned_monitor) {
+            ready_incident(publish, &mut monitor, sender.clone());
         }
+    })?;
 
-        let publish = match ui_reciver.try_recv() {
-            Ok(UIAction::RegistrateDrone(drone_registration)) => {
-                register_drone(drone_registration, publish_counter)
-            }
+    on_publish(client, "detected-incident/+", move |publish| {
+        detected_incident(publish, monitor_sender.clone());
+    })?;
 
-            Ok(UIAction::RegistrateIncident(incident_registration)) => register_incident(
-                incident_registration,
-                &mut monitor,
-                monitor_sender.clone(),
-                publish_counter,
-            ),
+    // The client subscribes again by itself, the open incidents are republished in case
+    // the server lost them
+    client
+        .on_reconnect(move |client| republish_open_incidents(client, &monitor))
+        .map_err(|err| io::Error::other(err.to_string()))
+}
 
-            Ok(UIAction::EditIncident(incident_edit)) => {
-                edit_incident(incident_edit, &mut monitor, monitor_sender.clone());
-                None
-            }
+/// Publishes again the incidents that are not attended yet
+fn republish_open_incidents(client: &MqttClient, monitor: &Mutex<Monitor>) {
+    let open_incidents = match lock_monitor(monitor) {
+        Some(monitor) => monitor.get_open_incidents(),
+        None => return,
+    };
 
-            Ok(UIAction::ResolveIncident(incident)) => resolve_incident(
-                incident,
-                &mut monitor,
-                publish_counter,
-                monitor_sender.clone(),
-            ),
-            Err(_) => None,
-        };
+    for incident in open_incidents {
+        publish_new_incident(client, &incident);
+    }
+}
 
-        if let Some(publish) = publish {
-            match stream.write(publish.to_bytes(key).as_slice()) {
-                Ok(_) => {
-                    unacknowledged_publish.insert(publish.package_identifier(), publish.clone());
-                }
-                Err(_) => {
-                    println!("Error sending publish packet");
-                }
-            }
+/// Registers the handler of the publishes of a topic filter
+fn on_publish(
+    client: &MqttClient,
+    topic_filter: &str,
+    callback: impl Fn(&Publish) + Send + Sync + 'static,
+) -> std::io::Result<()> {
+    client
+        .on_publish(parse_topic_filter(topic_filter), callback)
+        .map_err(|err| io::Error::other(err.to_string()))
+}
 
-            publish_counter += 1;
+/// Locks the monitor, returning None if the mutex was poisoned
+fn lock_monitor(monitor: &Mutex<Monitor>) -> Option<MutexGuard<'_, Monitor>> {
+    match monitor.lock() {
+        Ok(monitor) => Some(monitor),
+        Err(_) => {
+            println!("Mutex was poisoned");
+            None
         }
     }
 }
 
+/// Publishes a message to a topic, waiting for its acknowledgement
+fn publish(client: &MqttClient, topic_name: TopicName, message: Vec<u8>, qos: QoS, retain: bool) {
+    if let Err(err) = client.publish(topic_name, message, qos, retain) {
+        println!("Error sending publish packet: {}", err);
+    }
+}
+
 /// Handles the drone data
-fn drone_data(publish: Publish, monitor_sender: Sender<MonitorAction>) {
+fn drone_data(publish: &Publish, monitor_sender: Sender<MonitorAction>) {
     let topic_name = publish.topic();
     let topic_levels = topic_name.levels();
 
@@ -257,6 +293,10 @@ fn drone_data(publish: Publish, monitor_sender: Sender<MonitorAction>) {
     let content = publish.message();
     let content_str = String::from_utf8_lossy(content).to_string();
     let splitted_content: Vec<&str> = content_str.split(SEPARATOR).collect();
+    if splitted_content.len() < 4 {
+        println!("Malformed drone data");
+        return;
+    }
 
     let x_coordinate = match splitted_content[0].parse::<f64>() {
         Ok(x) => x,
@@ -292,7 +332,7 @@ fn drone_data(publish: Publish, monitor_sender: Sender<MonitorAction>) {
 }
 
 /// Handles the camera data
-fn camera_data(publish: Publish, monitor_sender: Sender<MonitorAction>) {
+fn camera_data(publish: &Publish, monitor_sender: Sender<MonitorAction>) {
     let content = publish.message();
 
     let content_str = String::from_utf8_lossy(content).to_string();
@@ -301,6 +341,10 @@ fn camera_data(publish: Publish, monitor_sender: Sender<MonitorAction>) {
     // this are camera data
     for camera_data in splitted_content {
         let camera_data = camera_data.split(SEPARATOR).collect::<Vec<&str>>();
+        if camera_data.len() < 4 {
+            println!("Malformed camera data");
+            return;
+        }
         let id = match camera_data[0].parse::<String>() {
             Ok(id) => id,
             Err(_) => {
@@ -342,7 +386,11 @@ fn camera_data(publish: Publish, monitor_sender: Sender<MonitorAction>) {
 }
 
 /// Handles the attending incident
-fn attend_incident(publish: Publish, monitor: &mut Monitor, monitor_sender: Sender<MonitorAction>) {
+fn attend_incident(
+    publish: &Publish,
+    monitor: &mut Monitor,
+    monitor_sender: Sender<MonitorAction>,
+) {
     let topic_name = publish.topic();
     let topic_levels = topic_name.levels();
     let incident_id = topic_levels[1].as_slice();
@@ -361,7 +409,7 @@ fn attend_incident(publish: Publish, monitor: &mut Monitor, monitor_sender: Send
 }
 
 /// Handles the ready incident
-fn ready_incident(publish: Publish, monitor: &mut Monitor, monitor_sender: Sender<MonitorAction>) {
+fn ready_incident(publish: &Publish, monitor: &mut Monitor, monitor_sender: Sender<MonitorAction>) {
     let topic_name = publish.topic();
     let topic_levels = topic_name.levels();
     let incident_id = topic_levels[1].as_slice();
@@ -381,25 +429,13 @@ fn ready_incident(publish: Publish, monitor: &mut Monitor, monitor_sender: Sende
 }
 
 /// Registers a drone
-fn register_drone(
-    drone_registration: DroneRegistration,
-    package_identifier: u16,
-) -> Option<Publish> {
+fn register_drone(drone_registration: DroneRegistration, client: &MqttClient) {
     let topic_name = TopicName::new(vec![CLIENT_REGISTER.to_vec()], true);
     let message = drone_registration.build_drone_message().into_bytes();
-    let dup = false;
     let qos = QoS::AtLeast;
     let retain = false;
-    let package_identifier = Some(package_identifier);
-
-    Some(Publish::new(
-        dup,
-        qos,
-        retain,
-        topic_name,
-        package_identifier,
-        message,
-    ))
+
+    publish(client, topic_name, message, qos, retain);
 }
 
 /// Registers an incident
@@ -407,8 +443,8 @@ fn register_incident(
     incident_registration: IncidentRegistration,
     monitor: &mut Monitor,
     monitor_sender: Sender<MonitorAction>,
-    package_identifier: u16,
-) -> Option<Publish> {
+    client: &MqttClient,
+) {
     let uuid = monitor.get_amount_incidents().to_string();
     let name = incident_registration.name.clone();
     let description = incident_registration.description.clone();
@@ -416,34 +452,37 @@ fn register_incident(
         Ok(x) => x,
         Err(_) => {
             println!("Error parsing x coordinate");
-            return None;
+            return;
         }
     };
     let y_coordinate = match incident_registration.y.clone().parse() {
         Ok(y) => y,
         Err(_) => {
             println!("Error parsing y coordinate");
-            return None;
+            return;
         }
     };
     let status = IncidentStatus::Pending;
     let incident = Incident::new(uuid, name, description, x_coordinate, y_coordinate, status);
 
+    monitor.new_incident(incident.clone());
+
+    if monitor_sender
+        .send(MonitorAction::Incident(incident.clone()))
+        .is_ok()
+    {
+        publish_new_incident(client, &incident);
+    }
+}
+
+/// Publishes an incident for the drones to attend it
+fn publish_new_incident(client: &MqttClient, incident: &Incident) {
     let topic_name = TopicName::new(vec![NEW_INCIDENT.to_vec()], false);
     let message = incident.to_string().into_bytes();
-    let dup = false;
     let qos = QoS::AtLeast;
     let retain = true;
-    let package_identifier = Some(package_identifier);
-
-    let publish = Publish::new(dup, qos, retain, topic_name, package_identifier, message);
 
-    monitor.new_incident(incident.clone());
-
-    match monitor_sender.send(MonitorAction::Incident(incident.clone())) {
-        Ok(_) => Some(publish),
-        Err(_) => None,
-    }
+    publish(client, topic_name, message, qos, retain);
 }
 
 /// Edits an incident
@@ -472,9 +511,9 @@ fn edit_incident(
 fn resolve_incident(
     incident: Incident,
     monitor: &mut Monitor,
-    package_identifier: u16,
+    client: &MqttClient,
     monitor_sender: Sender<MonitorAction>,
-) -> Option<Publish> {
+) {
     let incident_id = incident.id();
     monitor.set_resolved_incident(incident.id());
 
@@ -494,23 +533,14 @@ fn resolve_incident(
         false,
     );
     let message = vec![];
-    let dup = false;
-    let qos = QoS::AtLeast;
+    let qos = QoS::Exactly;
     let retain = false;
-    let package_identifier = Some(package_identifier);
-
-    Some(Publish::new(
-        dup,
-        qos,
-        retain,
-        topic_name,
-        package_identifier,
-        message,
-    ))
+
+    publish(client, topic_name, message, qos, retain);
 }
 
 /// Handles the autodetected incident by the camera system
-fn detected_incident(publish: Publish, monitor_sender: Sender<MonitorAction>) {
+fn detected_incident(publish: &Publish, monitor_sender: Sender<MonitorAction>) {
     let topic_levels = publish.topic().levels();
     let camera_id = String::from_utf8_lossy(topic_levels[1].as_slice()).to_string();
 
@@ -519,6 +549,10 @@ fn detected_incident(publish: Publish, monitor_sender: Sender<MonitorAction>) {
     let data = String::from_utf8_lossy(publish.message()).to_string();
 
     let splitted_data: Vec<&str> = data.split(SEPARATOR).collect();
+    if splitted_data.len() < 3 {
+        println!("Malformed detected incident");
+        return;
+    }
     let x = splitted_data[0].to_string();
     let y = splitted_data[1].to_string();
     let label = splitted_data[2].to_string();
@@ -541,9 +575,7 @@ fn detected_incident(publish: Publish, monitor_sender: Sender<MonitorAction>) {
 }
 
 /// Subscribes to the topics that the monitor need to work properly
-fn subscribe_to_topics(stream: &mut TcpStream, key: &[u8; 32]) -> std::io::Result<()> {
-    let mut topic_filters = vec![];
-
+fn subscribe_to_topics(client: &MqttClient) -> std::io::Result<()> {
     let topics = vec![
         "camera-data",
         "camera-update",
@@ -553,37 +585,25 @@ fn subscribe_to_topics(stream: &mut TcpStream, key: &[u8; 32]) -> std::io::Resul
         "detected-incident/+",
     ];
 
-    for topic in topics {
-        let mut levels = vec![];
-        for level in topic.split('/') {
-            if let Ok(topic_level) = TopicLevel::from_bytes(level.as_bytes().to_vec()) {
-                levels.push(topic_level);
-            }
-        }
-
-        let topic_filter = TopicFilter::new(levels, false);
-        let qos = QoS::AtLeast;
+    let topic_filters = topics
+        .into_iter()
+        .map(|topic| (parse_topic_filter(topic), QoS::AtLeast))
+        .collect();
 
-        topic_filters.push((topic_filter, qos));
+    match client.subscribe(topic_filters) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(io::Error::other("Suback was not received.")),
     }
+}
 
-    let subscribe = Subscribe::new(1, topic_filters);
-
-    match stream.write(subscribe.to_bytes(key).as_slice()) {
-        Ok(_) => {}
-        Err(_) => {
-            return Err(std::io::Error::new(
-                ErrorKind::Other,
-                "Error sending subscribe packet",
-            ));
+/// Builds a topic filter from its levels separated by '/'
+fn parse_topic_filter(topic: &str) -> TopicFilter {
+    let mut levels = vec![];
+    for level in topic.split('/') {
+        if let Ok(topic_level) = TopicLevel::from_bytes(level.as_bytes().to_vec()) {
+            levels.push(topic_level);
         }
     }
 
-    match Packet::from_bytes(stream, key) {
-        Ok(Packet::Suback(_)) => Ok(()),
-        _ => Err(std::io::Error::new(
-            ErrorKind::Other,
-            "Suback was not received.",
-        )),
-    }
+    TopicFilter::new(levels, false)
 }
@@ -90,6 +90,20 @@ impl Monitor {
     pub fn get_amount_incidents(&self) -> usize {
         self.incidents.len()
     }
+
+    /// Gets the incidents that are not attended by enough drones yet, ordered by UUID
+    pub fn get_open_incidents(&self) -> Vec<Incident> {
+        let mut open_incidents: Vec<Incident> = self
+            .open_incidents
+            .keys()
+            .filter_map(|incident_uuid| self.incidents.get(incident_uuid))
+            .filter(|incident| incident.status != IncidentStatus::Resolved)
+            .cloned()
+            .collect();
+
+        open_incidents.sort_by(|a, b| a.uuid.cmp(&b.uuid));
+        open_incidents
+    }
 }
 
 #[cfg(test)]
@@ -172,4 +186,29 @@ mod tests {
         let incident = monitor.get_incident(&incident.uuid).unwrap();
         assert_eq!(incident.status, IncidentStatus::Resolved);
     }
+
+    #[test]
+    fn test_open_incidents() {
+        let mut monitor = Monitor::new();
+        for uuid in ["incident1", "incident2", "incident3"] {
+            let incident = Incident::new(
+                uuid.to_string(),
+                uuid.to_string(),
+                uuid.to_string(),
+                1.0,
+                1.0,
+                IncidentStatus::Pending,
+            );
+            monitor.new_incident(incident);
+        }
+
+        monitor.attend_incident("incident1".to_string());
+        monitor.attend_incident("incident1".to_string());
+        monitor.set_resolved_incident("incident3".to_string());
+
+        let open_incidents = monitor.get_open_incidents();
+
+        assert_eq!(open_incidents.len(), 1);
+        assert_eq!(open_incidents[0].uuid, "incident2");
+    }
 }
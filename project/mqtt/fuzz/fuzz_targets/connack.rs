@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mqtt::model::{components::fixed_header::FixedHeader, packets::connack::Connack};
+
+// The content of the packet follows its fixed header unencrypted
+fuzz_target!(|data: &[u8]| {
+    let stream = &mut &data[..];
+    if let Ok(fixed_header) = FixedHeader::from_bytes(stream) {
+        let _ = Connack::from_bytes(fixed_header, stream);
+    }
+});
@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mqtt::{codec::packet_decoder::PacketDecoder, model::packet::Packet};
+
+const KEY: [u8; 32] = *b"12345678901234567890123456789012";
+
+// Almost every input is refused when it is decrypted, so this target covers the framing and decryption
+fuzz_target!(|data: &[u8]| {
+    let _ = Packet::from_bytes(&mut &data[..], &KEY);
+
+    let mut decoder = PacketDecoder::with_max_packet_size(64 * 1024);
+    decoder.extend(data);
+    while let Ok(Some(_)) = decoder.decode(&KEY) {}
+});
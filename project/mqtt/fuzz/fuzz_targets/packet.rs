@@ -0,0 +1,20 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mqtt::{
+    codec::packet_decoder::PacketDecoder,
+    model::{components::protocol_version::ProtocolVersion, packet::Packet},
+    NO_ENCRYPTION,
+};
+
+// The packets are not encrypted, so the input reaches the parsers of every packet and version
+fuzz_target!(|data: &[u8]| {
+    for version in [ProtocolVersion::V311, ProtocolVersion::V5] {
+        let _ = Packet::from_bytes_with_version(&mut &data[..], NO_ENCRYPTION, version);
+
+        let mut decoder = PacketDecoder::with_max_packet_size(64 * 1024);
+        decoder.set_protocol_version(version);
+        decoder.extend(data);
+        while let Ok(Some(_)) = decoder.decode(NO_ENCRYPTION) {}
+    }
+});
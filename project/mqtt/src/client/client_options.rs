@@ -0,0 +1,277 @@
+use std::{io, time::Duration};
+
+use crate::{
+    client::tls_options::TlsOptions,
+    keyring::Keyring,
+    model::components::{
+        encoded_string::EncodedString,
+        login::Login,
+        properties::{Properties, Property},
+        will::Will,
+    },
+    ProtocolVersion, NO_ENCRYPTION,
+};
+
+const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Delays between reconnection attempts of the clients that do not set their own
+const DEFAULT_RECONNECT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const DEFAULT_RECONNECT_MAXIMUM_DELAY: Duration = Duration::from_secs(60);
+
+/// Represents the options used to connect a client to the server
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    client_id: String,
+    key: [u8; 32],
+    key_id: u32,
+    previous_keys: Vec<(u32, [u8; 32])>,
+    keyring_file: Option<String>,
+    client_key: Option<[u8; 32]>,
+    encryption: bool,
+    username: Option<String>,
+    password: Option<String>,
+    keep_alive: u16,
+    clean_session: bool,
+    protocol_version: ProtocolVersion,
+    session_expiry_interval: Option<u32>,
+    will: Option<Will>,
+    ack_timeout: Duration,
+    reconnect: Option<ReconnectDelays>,
+    tls: Option<TlsOptions>,
+}
+
+/// Represents the delays between reconnection attempts, which double from the initial one up to the maximum
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectDelays {
+    pub initial: Duration,
+    pub maximum: Duration,
+}
+
+impl Default for ReconnectDelays {
+    fn default() -> Self {
+        ReconnectDelays {
+            initial: DEFAULT_RECONNECT_INITIAL_DELAY,
+            maximum: DEFAULT_RECONNECT_MAXIMUM_DELAY,
+        }
+    }
+}
+
+impl ClientOptions {
+    /// Creates the options of a client with the specified id and encryption key, that encrypts its packets,
+    /// without login, keep alive, will, reconnection nor TLS, that resumes its previous session and speaks MQTT 3.1.1
+    pub fn new(client_id: &str, key: [u8; 32]) -> Self {
+        ClientOptions {
+            client_id: client_id.to_string(),
+            key,
+            key_id: 0,
+            previous_keys: Vec::new(),
+            keyring_file: None,
+            client_key: None,
+            encryption: true,
+            username: None,
+            password: None,
+            keep_alive: 0,
+            clean_session: false,
+            protocol_version: ProtocolVersion::V311,
+            session_expiry_interval: None,
+            will: None,
+            ack_timeout: DEFAULT_ACK_TIMEOUT,
+            reconnect: None,
+            tls: None,
+        }
+    }
+
+    /// Sets whether the packets are encrypted with the key. Without encryption the client speaks
+    /// standard MQTT 3.1.1, as expected by the plain listeners of the server and other brokers
+    pub fn with_encryption(mut self, encryption: bool) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    /// Sets the id of the key, with which the server finds it among its current and previous keys
+    pub fn with_key_id(mut self, key_id: u32) -> Self {
+        self.key_id = key_id;
+        self
+    }
+
+    /// Adds a key the client used before the current one, which it still accepts from the server.
+    /// Only the last previous key is kept
+    pub fn with_previous_key(mut self, key_id: u32, key: [u8; 32]) -> Self {
+        self.previous_keys.push((key_id, key));
+        self
+    }
+
+    /// Sets the file the keys the server pushes are saved to. Its keys are newer than the key of the options,
+    /// so the client connects with the last key it received even after it restarts
+    pub fn with_keyring_file(mut self, keyring_file: &str) -> Self {
+        self.keyring_file = Some(keyring_file.to_string());
+        self
+    }
+
+    /// Sets the key of this client alone, from which the keys of its sessions are derived and with which
+    /// its login is sealed in the Connect. Without it they are derived from the key shared by every client,
+    /// and the login is only encrypted with that key
+    pub fn with_client_key(mut self, client_key: [u8; 32]) -> Self {
+        self.client_key = Some(client_key);
+        self
+    }
+
+    /// Sets the username and password sent in the Connect packet
+    pub fn with_login(mut self, username: &str, password: &str) -> Self {
+        self.username = Some(username.to_string());
+        self.password = Some(password.to_string());
+        self
+    }
+
+    /// Sets the seconds between pings. With 0 no pings are sent
+    pub fn with_keep_alive(mut self, keep_alive: u16) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Sets whether the server discards the session of the client when it disconnects
+    pub fn with_clean_session(mut self, clean_session: bool) -> Self {
+        self.clean_session = clean_session;
+        self
+    }
+
+    /// Sets the version of MQTT the client speaks. With MQTT 5.0 the packets carry properties and reason codes
+    pub fn with_protocol_version(mut self, protocol_version: ProtocolVersion) -> Self {
+        self.protocol_version = protocol_version;
+        self
+    }
+
+    /// Sets the seconds the server keeps the session of the client after it disconnects, only sent in MQTT 5.0.
+    /// u32::MAX keeps it forever, and without it the session ends with the connection
+    pub fn with_session_expiry_interval(mut self, session_expiry_interval: u32) -> Self {
+        self.session_expiry_interval = Some(session_expiry_interval);
+        self
+    }
+
+    /// Sets the message the server publishes if the client disconnects unexpectedly
+    pub fn with_will(mut self, will: Will) -> Self {
+        self.will = Some(will);
+        self
+    }
+
+    /// Sets how long requests wait for their acknowledgement
+    pub fn with_ack_timeout(mut self, ack_timeout: Duration) -> Self {
+        self.ack_timeout = ack_timeout;
+        self
+    }
+
+    /// Makes the client reconnect when the connection is lost, waiting between attempts a delay
+    /// that doubles from the initial one up to the maximum
+    pub fn with_reconnect(mut self, initial_delay: Duration, maximum_delay: Duration) -> Self {
+        self.reconnect = Some(ReconnectDelays {
+            initial: initial_delay,
+            maximum: maximum_delay,
+        });
+        self
+    }
+
+    /// Makes the client reconnect when the connection is lost with the default delays,
+    /// which double from one second up to a minute
+    pub fn with_default_reconnect(mut self) -> Self {
+        self.reconnect = Some(ReconnectDelays::default());
+        self
+    }
+
+    /// Makes the client connect over TLS, verifying the certificate of the server.
+    /// The packets inside the TLS session are standard MQTT, so they are not encrypted with the key
+    pub fn with_tls(mut self, tls: TlsOptions) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    /// Returns the key the Connect and Connack are encrypted with, empty if the encryption is disabled
+    /// or the client connects over TLS. The rest of the packets use the key agreed for the session
+    pub fn key(&self) -> &[u8] {
+        if self.encryption && self.tls.is_none() {
+            &self.key
+        } else {
+            NO_ENCRYPTION
+        }
+    }
+
+    /// Returns the keyring of the Connect and Connack, with the key as the current one after the previous keys,
+    /// unless the keyring file has newer ones. It is empty if the encryption is disabled or the client connects over TLS
+    pub fn keyring(&self) -> io::Result<Keyring> {
+        let mut keyring = Keyring::default();
+        if self.key().is_empty() {
+            return Ok(keyring);
+        }
+
+        for (key_id, key) in &self.previous_keys {
+            keyring.rotate(*key_id, key);
+        }
+        keyring.rotate(self.key_id, &self.key);
+        if let Some(keyring_file) = &self.keyring_file {
+            keyring.load(keyring_file)?;
+        }
+        Ok(keyring)
+    }
+
+    /// Returns the file the keys the server pushes are saved to, None if they are only kept in memory
+    pub fn keyring_file(&self) -> Option<&str> {
+        self.keyring_file.as_deref()
+    }
+
+    /// Returns the key of this client alone, None if the session keys are derived from the current key
+    /// of the keyring
+    pub fn client_key(&self) -> Option<&[u8]> {
+        self.client_key
+            .as_ref()
+            .map(|client_key| client_key.as_slice())
+    }
+
+    pub fn keep_alive(&self) -> u16 {
+        self.keep_alive
+    }
+
+    pub fn clean_session(&self) -> bool {
+        self.clean_session
+    }
+
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.protocol_version
+    }
+
+    /// Returns the properties of the Connect packet, empty in MQTT 3.1.1
+    pub fn connect_properties(&self) -> Properties {
+        let mut properties = Properties::new();
+        if self.protocol_version == ProtocolVersion::V5 {
+            if let Some(session_expiry_interval) = self.session_expiry_interval {
+                properties.set(Property::SessionExpiryInterval(session_expiry_interval));
+            }
+        }
+        properties
+    }
+
+    pub fn will(&self) -> Option<&Will> {
+        self.will.as_ref()
+    }
+
+    pub fn ack_timeout(&self) -> Duration {
+        self.ack_timeout
+    }
+
+    pub fn reconnect(&self) -> Option<ReconnectDelays> {
+        self.reconnect
+    }
+
+    pub fn tls(&self) -> Option<&TlsOptions> {
+        self.tls.as_ref()
+    }
+
+    /// Returns the login of the Connect packet, None if no username was set
+    pub fn login(&self) -> Option<Login> {
+        let username = self.username.as_ref()?;
+        let password = self.password.as_ref().map(EncodedString::from_string);
+
+        Some(Login::new(EncodedString::from_string(username), password))
+    }
+}
@@ -0,0 +1,10 @@
+/// options to connect a client to the server
+pub mod client_options;
+/// client that owns the connection and routes the incoming packets
+pub mod mqtt_client;
+/// allocation of packet identifiers
+pub mod packet_identifiers;
+/// certificates used to connect to the server over TLS
+pub mod tls_options;
+/// socket of the client, over TCP or TLS
+mod transport;
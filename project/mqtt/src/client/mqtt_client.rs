@@ -0,0 +1,1490 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+use crate::{
+    client::{
+        client_options::{ClientOptions, ReconnectDelays},
+        packet_identifiers::PacketIdentifiers,
+        transport::Transport,
+    },
+    codec::{packet_decoder::PacketDecoder, packet_encoder::PacketEncoder},
+    encryptation::key_exchange::{seal_login, KeyExchange},
+    errors::error::{MqttError, MqttResult},
+    keyring::{append_to_file, parse_rotation_message, Keyring, KEY_ROTATION_TOPIC},
+    model::{
+        components::{
+            encoded_string::EncodedString, key_share::KeyShare, properties::Properties, qos::QoS,
+            topic_filter::TopicFilter, topic_name::TopicName,
+        },
+        packet::Packet,
+        packets::{
+            connect::Connect, disconnect::Disconnect, pingreq::Pingreq, puback::Puback,
+            pubcomp::Pubcomp, publish::Publish, pubrec::Pubrec, pubrel::Pubrel,
+            subscribe::Subscribe, unsubscribe::Unsubscribe,
+        },
+        return_codes::{
+            connect_return_code::ConnectReturnCode, suback_return_code::SubackReturnCode,
+        },
+    },
+    Direction, FrameKey,
+};
+
+/// How often the ping thread checks whether a ping is due
+const PING_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Function called with the publishes received on the topics matched by its filter
+pub type PublishCallback = Arc<dyn Fn(&Publish) + Send + Sync>;
+
+/// Function called after the client reconnects and its subscriptions were restored
+pub type ReconnectCallback = Arc<dyn Fn(&MqttClient) + Send + Sync>;
+
+/// Represents a client connected to an MQTT server.
+/// It owns the socket, over TCP or TLS, and runs a reader thread that routes the acknowledgements
+/// to the requests waiting for them and the publishes to the callbacks registered for their topics.
+/// Callbacks run on their own thread, so they may publish and wait for the acknowledgement.
+/// If a keep alive was set, a ping is sent whenever nothing was sent for that long, and the
+/// connection is closed if the server does not answer it in time.
+/// If reconnection was enabled, a lost connection is opened again after a backoff with jitter,
+/// the subscriptions are sent again and the reconnect callbacks are called to republish the state.
+/// When the server rotates its key, the new key becomes the current one of the keyring of the client,
+/// which connects with it from then on, and is saved to the keyring file of the options if they have one.
+/// The client can be cloned to share the connection between threads
+#[derive(Clone)]
+pub struct MqttClient {
+    inner: Arc<ClientInner>,
+}
+
+struct ClientInner {
+    addresses: Vec<SocketAddr>,
+    options: ClientOptions,
+    /// Keys of the Connect and Connack, updated when the server pushes a new key
+    keyring: Mutex<Keyring>,
+    stream: Mutex<Stream>,
+    ack_timeout: Duration,
+    keep_alive: Duration,
+    session_present: AtomicBool,
+    /// Packet identifiers of QoS 2 publishes already delivered that were not released yet,
+    /// kept while the server resumes the session
+    received_publishes: Mutex<HashSet<u16>>,
+    connected: AtomicBool,
+    /// Set when the client disconnects on purpose, so it is not reconnected
+    stopped: AtomicBool,
+    /// Number of the current connection, so the threads of a previous one stop
+    connection: AtomicUsize,
+    packet_identifiers: Mutex<PacketIdentifiers>,
+    /// Requests waiting for an acknowledgement, by packet identifier
+    pending: Mutex<HashMap<u16, Sender<Packet>>>,
+    callbacks: Mutex<Vec<(TopicFilter, PublishCallback)>>,
+    /// Topic filters the server accepted, sent again after reconnecting
+    subscriptions: Mutex<Vec<(TopicFilter, QoS)>>,
+    reconnect_callbacks: Mutex<Vec<ReconnectCallback>>,
+    last_sent: Mutex<Instant>,
+    /// Moment the last unanswered ping was sent
+    ping_sent: Mutex<Option<Instant>>,
+}
+
+impl MqttClient {
+    /// Connects to the server at the specified address and waits for its Connack.
+    /// Fails if the server refuses the connection
+    pub fn connect(address: impl ToSocketAddrs, options: ClientOptions) -> MqttResult<Self> {
+        let addresses: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
+        let keyring = options.keyring()?;
+        let (stream, incoming, session_present) = open_connection(&addresses, &options, &keyring)?;
+
+        let client = MqttClient {
+            inner: Arc::new(ClientInner {
+                addresses,
+                keyring: Mutex::new(keyring),
+                stream: Mutex::new(stream),
+                ack_timeout: options.ack_timeout(),
+                keep_alive: Duration::from_secs(options.keep_alive() as u64),
+                options,
+                session_present: AtomicBool::new(session_present),
+                received_publishes: Mutex::new(HashSet::new()),
+                connected: AtomicBool::new(true),
+                stopped: AtomicBool::new(false),
+                connection: AtomicUsize::new(0),
+                packet_identifiers: Mutex::new(PacketIdentifiers::new()),
+                pending: Mutex::new(HashMap::new()),
+                callbacks: Mutex::new(Vec::new()),
+                subscriptions: Mutex::new(Vec::new()),
+                reconnect_callbacks: Mutex::new(Vec::new()),
+                last_sent: Mutex::new(Instant::now()),
+                ping_sent: Mutex::new(None),
+            }),
+        };
+
+        let (publish_sender, publish_receiver) = mpsc::channel();
+
+        let reader = client.clone();
+        thread::spawn(move || reader.run_connections(incoming, publish_sender));
+
+        let dispatcher = client.clone();
+        thread::spawn(move || dispatcher.dispatch_publishes(publish_receiver));
+
+        Ok(client)
+    }
+
+    /// Returns whether the server had a session stored for the client when it last connected
+    pub fn session_present(&self) -> bool {
+        self.inner.session_present.load(Ordering::SeqCst)
+    }
+
+    /// Returns the keys the client connects with, whose current key is the last one the server pushed
+    pub fn keyring(&self) -> MqttResult<Keyring> {
+        Ok(lock(&self.inner.keyring)?.clone())
+    }
+
+    /// Returns whether the connection is still open
+    pub fn is_connected(&self) -> bool {
+        self.inner.connected.load(Ordering::SeqCst)
+    }
+
+    /// Registers a callback for the publishes received on the topics matched by a topic filter.
+    /// A publish is passed to every callback whose filter matches its topic
+    pub fn on_publish(
+        &self,
+        topic_filter: TopicFilter,
+        callback: impl Fn(&Publish) + Send + Sync + 'static,
+    ) -> MqttResult<()> {
+        lock(&self.inner.callbacks)?.push((topic_filter, Arc::new(callback)));
+        Ok(())
+    }
+
+    /// Registers a callback called each time the client reconnects, after its subscriptions were sent again.
+    /// It runs on its own thread, so it may publish the state the server lost
+    pub fn on_reconnect(
+        &self,
+        callback: impl Fn(&MqttClient) + Send + Sync + 'static,
+    ) -> MqttResult<()> {
+        lock(&self.inner.reconnect_callbacks)?.push(Arc::new(callback));
+        Ok(())
+    }
+
+    /// Subscribes to a set of topic filters, returning the return code the server gave to each one.
+    /// The accepted ones are subscribed again when the client reconnects
+    pub fn subscribe(&self, topics: Vec<(TopicFilter, QoS)>) -> MqttResult<Vec<SubackReturnCode>> {
+        let return_codes = self.send_subscribe(topics.clone())?;
+
+        let mut subscriptions = lock(&self.inner.subscriptions)?;
+        for ((topic_filter, qos), return_code) in topics.into_iter().zip(&return_codes) {
+            if *return_code == SubackReturnCode::Failure {
+                continue;
+            }
+            subscriptions.retain(|(subscribed, _)| *subscribed != topic_filter);
+            subscriptions.push((topic_filter, qos));
+        }
+
+        Ok(return_codes)
+    }
+
+    /// Unsubscribes from a set of topic filters, waiting for the Unsuback
+    pub fn unsubscribe(&self, topic_filters: Vec<TopicFilter>) -> MqttResult<()> {
+        let request = self.start_request()?;
+        let unsubscribe = Unsubscribe::new(request.packet_identifier, topic_filters.clone());
+        self.send(Packet::Unsubscribe(unsubscribe))?;
+
+        match request.wait("Unsuback")? {
+            Packet::Unsuback(_) => {}
+            packet => return Err(MqttError::UnexpectedPacket(format!("{:?}", packet))),
+        }
+
+        lock(&self.inner.subscriptions)?
+            .retain(|(subscribed, _)| !topic_filters.contains(subscribed));
+        Ok(())
+    }
+
+    /// Publishes a message. With QoS 1 it waits for the Puback, and with QoS 2 it completes
+    /// the Pubrec, Pubrel and Pubcomp flow before returning
+    pub fn publish(
+        &self,
+        topic_name: TopicName,
+        message: Vec<u8>,
+        qos: QoS,
+        retain: bool,
+    ) -> MqttResult<()> {
+        self.publish_with_properties(topic_name, message, qos, retain, Properties::new())
+    }
+
+    /// Publishes a message with the properties of MQTT 5.0, such as the response topic and correlation data
+    /// of a request, user properties or a message expiry interval. They are left out in MQTT 3.1.1.
+    /// Fails if the server rejects the message with the reason code of the Puback or Pubrec
+    pub fn publish_with_properties(
+        &self,
+        topic_name: TopicName,
+        message: Vec<u8>,
+        qos: QoS,
+        retain: bool,
+        properties: Properties,
+    ) -> MqttResult<()> {
+        if qos == QoS::AtMost {
+            let publish = Publish::new(false, qos, retain, topic_name, None, message)
+                .with_properties(properties);
+            return self.send(Packet::Publish(publish));
+        }
+
+        let request = self.start_request()?;
+        let packet_identifier = request.packet_identifier;
+        let publish = Publish::new(
+            false,
+            qos.clone(),
+            retain,
+            topic_name,
+            Some(packet_identifier),
+            message,
+        )
+        .with_properties(properties);
+        self.send(Packet::Publish(publish))?;
+
+        if qos == QoS::AtLeast {
+            return match request.wait("Puback")? {
+                Packet::Puback(puback) if puback.reason_code().is_error() => Err(
+                    MqttError::PublishRejected(format!("{:?}", puback.reason_code())),
+                ),
+                Packet::Puback(_) => Ok(()),
+                packet => Err(MqttError::UnexpectedPacket(format!("{:?}", packet))),
+            };
+        }
+
+        match request.wait("Pubrec")? {
+            Packet::Pubrec(pubrec) if pubrec.reason_code().is_error() => {
+                return Err(MqttError::PublishRejected(format!(
+                    "{:?}",
+                    pubrec.reason_code()
+                )))
+            }
+            Packet::Pubrec(_) => {}
+            packet => return Err(MqttError::UnexpectedPacket(format!("{:?}", packet))),
+        }
+
+        self.send(Packet::Pubrel(Pubrel::new(packet_identifier)))?;
+
+        match request.wait("Pubcomp")? {
+            Packet::Pubcomp(_) => Ok(()),
+            packet => Err(MqttError::UnexpectedPacket(format!("{:?}", packet))),
+        }
+    }
+
+    /// Sends a Disconnect packet and closes the connection, without reconnecting
+    pub fn disconnect(&self) -> MqttResult<()> {
+        self.inner.stopped.store(true, Ordering::SeqCst);
+        let result = self.send(Packet::Disconnect(Disconnect::new()));
+        self.close();
+        result
+    }
+
+    /// Sends a Subscribe packet and waits for the return codes of the Suback
+    fn send_subscribe(&self, topics: Vec<(TopicFilter, QoS)>) -> MqttResult<Vec<SubackReturnCode>> {
+        let request = self.start_request()?;
+        let subscribe = Subscribe::new(request.packet_identifier, topics);
+        self.send(Packet::Subscribe(subscribe))?;
+
+        match request.wait("Suback")? {
+            Packet::Suback(suback) => Ok(suback.suback_return_codes().clone()),
+            packet => Err(MqttError::UnexpectedPacket(format!("{:?}", packet))),
+        }
+    }
+
+    /// Sends a packet to the server, closing the connection if it fails
+    fn send(&self, packet: Packet) -> MqttResult<()> {
+        if !self.is_connected() {
+            return Err(MqttError::NotConnected);
+        }
+
+        let mut stream = lock(&self.inner.stream)?;
+        let Stream {
+            transport,
+            key,
+            encoder,
+        } = &mut *stream;
+        encoder.encode(&packet, key.as_slice());
+        if let Err(err) = encoder.write_to(transport) {
+            drop(stream);
+            self.close();
+            return Err(err.into());
+        }
+        drop(stream);
+
+        *lock(&self.inner.last_sent)? = Instant::now();
+        Ok(())
+    }
+
+    /// Allocates a packet identifier and registers a request waiting for its acknowledgements
+    fn start_request(&self) -> MqttResult<Request> {
+        if !self.is_connected() {
+            return Err(MqttError::NotConnected);
+        }
+
+        let packet_identifier = lock(&self.inner.packet_identifiers)?.allocate()?;
+        let (sender, receiver) = mpsc::channel();
+        lock(&self.inner.pending)?.insert(packet_identifier, sender);
+
+        Ok(Request {
+            client: self.clone(),
+            packet_identifier,
+            receiver,
+        })
+    }
+
+    /// Marks the client as disconnected and shuts the socket down, which stops the threads of the client.
+    /// The requests waiting for an acknowledgement fail
+    fn close(&self) {
+        if !self.inner.connected.swap(false, Ordering::SeqCst) {
+            return;
+        }
+
+        if let Ok(stream) = self.inner.stream.lock() {
+            stream.transport.shutdown();
+        }
+        if let Ok(mut pending) = self.inner.pending.lock() {
+            pending.clear();
+        }
+    }
+
+    /// Reads the packets of each connection until it is closed, reconnecting if it was enabled
+    fn run_connections(&self, mut incoming: Incoming, publish_sender: Sender<Publish>) {
+        loop {
+            if !self.inner.keep_alive.is_zero() {
+                let pinger = self.clone();
+                let connection = self.inner.connection.load(Ordering::SeqCst);
+                thread::spawn(move || pinger.keep_alive(connection));
+            }
+
+            self.read_packets(incoming, &publish_sender);
+
+            incoming = match self.inner.options.reconnect() {
+                Some(delays) => match self.reconnect(delays) {
+                    Some(incoming) => incoming,
+                    None => return,
+                },
+                None => return,
+            };
+        }
+    }
+
+    /// Opens the connection again, waiting between attempts, until it succeeds or the client is
+    /// disconnected. Returns the side of the new connection to read its packets from
+    fn reconnect(&self, delays: ReconnectDelays) -> Option<Incoming> {
+        let mut attempt = 0;
+
+        while !self.inner.stopped.load(Ordering::SeqCst) {
+            thread::sleep(backoff_delay(attempt, delays));
+            attempt = attempt.saturating_add(1);
+
+            if self.inner.stopped.load(Ordering::SeqCst) {
+                break;
+            }
+
+            let keyring = match lock(&self.inner.keyring) {
+                Ok(keyring) => keyring.clone(),
+                Err(_) => break,
+            };
+            let incoming =
+                match open_connection(&self.inner.addresses, &self.inner.options, &keyring)
+                    .and_then(|(stream, incoming, session_present)| {
+                        self.restore(stream, session_present)?;
+                        Ok(incoming)
+                    }) {
+                    Ok(incoming) => incoming,
+                    Err(_) => continue,
+                };
+
+            let client = self.clone();
+            thread::spawn(move || client.resync());
+
+            return Some(incoming);
+        }
+
+        None
+    }
+
+    /// Replaces the stream of the client with the one of a new connection and marks it as connected.
+    /// Without a session on the server, the QoS 2 publishes it did not release are not sent again
+    fn restore(&self, stream: Stream, session_present: bool) -> MqttResult<()> {
+        *lock(&self.inner.stream)? = stream;
+        if !session_present {
+            lock(&self.inner.received_publishes)?.clear();
+        }
+        *lock(&self.inner.last_sent)? = Instant::now();
+        *lock(&self.inner.ping_sent)? = None;
+        self.inner
+            .session_present
+            .store(session_present, Ordering::SeqCst);
+        self.inner.connection.fetch_add(1, Ordering::SeqCst);
+        self.inner.connected.store(true, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    /// Subscribes again to the topic filters of the client and calls the reconnect callbacks
+    fn resync(&self) {
+        let subscriptions = match self.inner.subscriptions.lock() {
+            Ok(subscriptions) => subscriptions.clone(),
+            Err(_) => return,
+        };
+        // If it fails the connection was lost again, and this runs once more after reconnecting
+        if !subscriptions.is_empty() && self.send_subscribe(subscriptions).is_err() {
+            return;
+        }
+
+        let callbacks: Vec<ReconnectCallback> = match self.inner.reconnect_callbacks.lock() {
+            Ok(callbacks) => callbacks.clone(),
+            Err(_) => return,
+        };
+        for callback in callbacks {
+            callback(self);
+        }
+    }
+
+    /// Reads the packets sent by the server until the connection is closed
+    fn read_packets(&self, mut incoming: Incoming, publish_sender: &Sender<Publish>) {
+        let Incoming {
+            transport,
+            key,
+            decoder,
+        } = &mut incoming;
+
+        loop {
+            let packet = match decoder.decode(key.as_slice()) {
+                Ok(Some(packet)) => packet,
+                Ok(None) => match decoder.read_from(transport) {
+                    Ok(0) => break,
+                    Ok(_) => continue,
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    Err(_) => break,
+                },
+                Err(_) => break,
+            };
+
+            let result = match packet {
+                Packet::Publish(publish) if is_key_rotation(&publish) => self.rotate_key(&publish),
+                Packet::Publish(publish) => self.receive_publish(publish, publish_sender),
+                Packet::Pubrel(pubrel) => self.release_publish(pubrel.packet_identifier()),
+                Packet::Pingresp(_) => lock(&self.inner.ping_sent).map(|mut ping_sent| {
+                    *ping_sent = None;
+                }),
+                Packet::Puback(puback) => match puback.packet_identifier() {
+                    Some(packet_identifier) => {
+                        self.acknowledge(packet_identifier, Packet::Puback(puback))
+                    }
+                    None => Ok(()),
+                },
+                Packet::Pubrec(pubrec) => {
+                    self.acknowledge(pubrec.packet_identifier(), Packet::Pubrec(pubrec))
+                }
+                Packet::Pubcomp(pubcomp) => {
+                    self.acknowledge(pubcomp.packet_identifier(), Packet::Pubcomp(pubcomp))
+                }
+                Packet::Suback(suback) => {
+                    self.acknowledge(suback.packet_identifier(), Packet::Suback(suback))
+                }
+                Packet::Unsuback(unsuback) => {
+                    self.acknowledge(unsuback.packet_identifier(), Packet::Unsuback(unsuback))
+                }
+                _ => Ok(()),
+            };
+
+            if result.is_err() {
+                break;
+            }
+        }
+
+        self.close();
+    }
+
+    /// Acknowledges a publish received from the server and passes it to the callbacks.
+    /// A QoS 2 publish is passed only once until the server releases it
+    fn receive_publish(
+        &self,
+        publish: Publish,
+        publish_sender: &Sender<Publish>,
+    ) -> MqttResult<()> {
+        match (publish.qos(), publish.package_identifier()) {
+            (QoS::AtLeast, packet_identifier) => {
+                let puback = Puback::new(packet_identifier);
+                self.send(Packet::Puback(puback))?;
+            }
+            (QoS::Exactly, Some(packet_identifier)) => {
+                let pubrec = Pubrec::new(packet_identifier);
+                self.send(Packet::Pubrec(pubrec))?;
+
+                if !lock(&self.inner.received_publishes)?.insert(packet_identifier) {
+                    return Ok(());
+                }
+            }
+            _ => {}
+        }
+
+        publish_sender
+            .send(publish)
+            .map_err(|_| MqttError::NotConnected)
+    }
+
+    /// Completes the flow of a QoS 2 publish the server released, so its packet identifier delivers again
+    fn release_publish(&self, packet_identifier: u16) -> MqttResult<()> {
+        lock(&self.inner.received_publishes)?.remove(&packet_identifier);
+        let pubcomp = Pubcomp::new(packet_identifier);
+        self.send(Packet::Pubcomp(pubcomp))
+    }
+
+    /// Makes the key the server pushed the current key of the keyring and saves it to the keyring file.
+    /// The packets of the connection keep the key of the session, so it is used from the next connection on
+    fn rotate_key(&self, publish: &Publish) -> MqttResult<()> {
+        let mut keyring = lock(&self.inner.keyring)?;
+        if keyring.is_empty() {
+            return Ok(());
+        }
+
+        if let Some((key_id, key)) = parse_rotation_message(publish.message()) {
+            keyring.rotate(key_id, &key);
+            if let Some(keyring_file) = self.inner.options.keyring_file() {
+                append_to_file(keyring_file, key_id, &key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Passes an acknowledgement to the request waiting for it
+    fn acknowledge(&self, packet_identifier: u16, packet: Packet) -> MqttResult<()> {
+        if let Some(sender) = lock(&self.inner.pending)?.get(&packet_identifier) {
+            let _ = sender.send(packet);
+        }
+        Ok(())
+    }
+
+    /// Calls the callbacks of the topics of the received publishes until the connection is closed
+    fn dispatch_publishes(&self, publish_receiver: Receiver<Publish>) {
+        for publish in publish_receiver {
+            let callbacks: Vec<PublishCallback> = match self.inner.callbacks.lock() {
+                Ok(callbacks) => callbacks
+                    .iter()
+                    .filter(|(topic_filter, _)| {
+                        topic_filter.match_topic_name(publish.topic().clone())
+                    })
+                    .map(|(_, callback)| callback.clone())
+                    .collect(),
+                Err(_) => return,
+            };
+
+            for callback in callbacks {
+                callback(&publish);
+            }
+        }
+    }
+
+    /// Sends a ping whenever nothing was sent for the keep alive, closing the connection
+    /// if a ping is not answered within the keep alive. It stops when the connection changes
+    fn keep_alive(&self, connection: usize) {
+        let is_current =
+            || self.is_connected() && self.inner.connection.load(Ordering::SeqCst) == connection;
+
+        while is_current() {
+            thread::sleep(PING_CHECK_INTERVAL);
+
+            let ping_sent = match self.inner.ping_sent.lock() {
+                Ok(ping_sent) => *ping_sent,
+                Err(_) => break,
+            };
+            if let Some(ping_sent) = ping_sent {
+                if ping_sent.elapsed() > self.inner.keep_alive {
+                    break;
+                }
+                continue;
+            }
+
+            let idle = match self.inner.last_sent.lock() {
+                Ok(last_sent) => last_sent.elapsed(),
+                Err(_) => break,
+            };
+            if idle < self.inner.keep_alive {
+                continue;
+            }
+
+            if self.send(Packet::Pingreq(Pingreq::new())).is_err() {
+                break;
+            }
+            match self.inner.ping_sent.lock() {
+                Ok(mut ping_sent) => *ping_sent = Some(Instant::now()),
+                Err(_) => break,
+            }
+        }
+
+        if is_current() {
+            self.close();
+        }
+    }
+}
+
+/// Represents a request waiting for its acknowledgements.
+/// Its packet identifier is released when it is dropped
+struct Request {
+    client: MqttClient,
+    packet_identifier: u16,
+    receiver: Receiver<Packet>,
+}
+
+impl Request {
+    /// Waits for the next acknowledgement of the request
+    fn wait(&self, packet_type: &str) -> MqttResult<Packet> {
+        match self.receiver.recv_timeout(self.client.inner.ack_timeout) {
+            Ok(packet) => Ok(packet),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                Err(MqttError::AckTimeout(packet_type.to_string()))
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(MqttError::NotConnected),
+        }
+    }
+}
+
+impl Drop for Request {
+    fn drop(&mut self) {
+        if let Ok(mut pending) = self.client.inner.pending.lock() {
+            pending.remove(&self.packet_identifier);
+        }
+        if let Ok(mut packet_identifiers) = self.client.inner.packet_identifiers.lock() {
+            packet_identifiers.release(self.packet_identifier);
+        }
+    }
+}
+
+/// Represents the side of a connection the client sends on: its socket, the key its packets are
+/// encrypted with and the encoder that gives them their sequence numbers
+struct Stream {
+    transport: Transport,
+    key: Vec<u8>,
+    encoder: PacketEncoder,
+}
+
+/// Represents the side of a connection the client reads from, with the decoder that read the Connack
+struct Incoming {
+    transport: Transport,
+    key: Vec<u8>,
+    decoder: PacketDecoder,
+}
+
+/// Opens a connection to the server and waits for its Connack, returning the sides of the connection and
+/// whether the server had a session stored for the client.
+/// If the packets are encrypted, they are encrypted with the current key of the keyring, and the client
+/// agrees a key for the session in the Connect and Connack
+fn open_connection(
+    addresses: &[SocketAddr],
+    options: &ClientOptions,
+    keyring: &Keyring,
+) -> MqttResult<(Stream, Incoming, bool)> {
+    let mut transport = Transport::connect(addresses, options.tls(), options.ack_timeout())?;
+    let keys = FrameKey::from(keyring);
+    let key = keys.key();
+    let key_exchange = (!key.is_empty()).then(KeyExchange::new);
+
+    // With a key of its own, the login of the client is not exposed to the clients that know the shared key
+    let login = match (&key_exchange, options.client_key(), options.login()) {
+        (Some(key_exchange), Some(client_key), Some(login)) => Some(seal_login(
+            &login,
+            options.client_id().as_bytes(),
+            &key_exchange.public_key(),
+            client_key,
+        )?),
+        (_, _, login) => login,
+    };
+    let mut connect = Connect::new(
+        options.clean_session(),
+        options.keep_alive(),
+        EncodedString::new(options.client_id().as_bytes().to_vec()),
+        options.will().cloned(),
+        login,
+    )
+    .with_protocol_version(options.protocol_version())
+    .with_properties(options.connect_properties());
+    if let Some(key_exchange) = &key_exchange {
+        connect = connect.with_key_share(KeyShare::new(key_exchange.public_key()));
+    }
+    let mut encoder = PacketEncoder::new();
+    encoder.set_protocol_version(options.protocol_version());
+    encoder.encode(&Packet::Connect(connect), keys);
+    encoder.write_to(&mut transport)?;
+
+    let mut decoder = PacketDecoder::new();
+    decoder.set_direction(Direction::ToClient);
+    decoder.set_protocol_version(options.protocol_version());
+    let connack = match read_packet(&mut transport, &mut decoder, keys)? {
+        Packet::Connack(connack) => connack,
+        packet => return Err(MqttError::UnexpectedPacket(format!("{:?}", packet))),
+    };
+    match connack.connect_return_code() {
+        ConnectReturnCode::ConnectionAccepted => {}
+        return_code => return Err(MqttError::ConnectionRefused(format!("{:?}", return_code))),
+    }
+
+    let session_key = match key_exchange {
+        Some(key_exchange) => {
+            let (public_key, confirmation) = match connack.key_share() {
+                Some(key_share) => (key_share.public_key(), key_share.confirmation()),
+                None => return Err(MqttError::KeyExchange("The server sent no key".to_string())),
+            };
+            let confirmation = confirmation.ok_or_else(|| {
+                MqttError::KeyExchange("The server sent no confirmation".to_string())
+            })?;
+            key_exchange.complete(
+                public_key,
+                confirmation,
+                options.client_id().as_bytes(),
+                options.client_key().unwrap_or(key),
+            )?
+        }
+        None => key.to_vec(),
+    };
+    transport.set_read_timeout(None)?;
+
+    let incoming = Incoming {
+        transport: transport.clone(),
+        key: session_key.clone(),
+        decoder,
+    };
+    let stream = Stream {
+        transport,
+        key: session_key,
+        encoder,
+    };
+    Ok((stream, incoming, connack.session_present()))
+}
+
+/// Reads from the socket until the decoder has a whole packet
+fn read_packet(
+    transport: &mut Transport,
+    decoder: &mut PacketDecoder,
+    key: FrameKey,
+) -> MqttResult<Packet> {
+    loop {
+        if let Some(packet) = decoder.decode(key)? {
+            return Ok(packet);
+        }
+
+        match decoder.read_from(transport) {
+            Ok(0) => return Err(MqttError::IoError(ErrorKind::UnexpectedEof.into())),
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Returns whether a publish of the server pushes a new key
+fn is_key_rotation(publish: &Publish) -> bool {
+    let levels = publish.topic().levels();
+    levels.len() == 1 && levels[0] == KEY_ROTATION_TOPIC.as_bytes()
+}
+
+/// Returns the delay before a reconnection attempt. It doubles with each attempt up to the maximum,
+/// and a random part of up to half of it is taken off so clients do not all reconnect at once
+fn backoff_delay(attempt: u32, delays: ReconnectDelays) -> Duration {
+    let factor = 2u32.saturating_pow(attempt);
+    let delay = delays.initial.saturating_mul(factor).min(delays.maximum);
+    let jitter = rand::thread_rng().gen_range(0.0..0.5);
+
+    delay.mul_f64(1.0 - jitter)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MqttResult<MutexGuard<'_, T>> {
+    mutex
+        .lock()
+        .map_err(|_| MqttError::IoError(std::io::Error::other("The client lock was poisoned")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+
+    use rcgen::{CertificateParams, KeyPair};
+    use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+    use super::*;
+    use crate::client::tls_options::TlsOptions;
+    use crate::encryptation::key_exchange::open_login;
+    use crate::keyring::rotation_message;
+    use crate::model::{
+        components::{properties::Property, topic_level::TopicLevel},
+        packets::{connack::Connack, suback::Suback},
+    };
+    use crate::{tls::crypto_provider, ProtocolVersion, ReasonCode, NO_ENCRYPTION};
+
+    const KEY: [u8; 32] = [0; 32];
+
+    /// Represents the server side of an encrypted connection, numbering the frames in each direction
+    /// after the Connect and Connack
+    struct Session {
+        stream: TcpStream,
+        connect: Connect,
+        key: Vec<u8>,
+        received: u64,
+        sent: u64,
+    }
+
+    impl Session {
+        fn read(&mut self) -> MqttResult<Packet> {
+            self.received += 1;
+            Packet::from_bytes(&mut self.stream, FrameKey::new(&self.key, self.received))
+        }
+
+        fn write(&mut self, packet: Packet) {
+            self.sent += 1;
+            let bytes = packet
+                .to_bytes(FrameKey::new(&self.key, self.sent).with_direction(Direction::ToClient));
+            self.stream.write_all(&bytes).unwrap();
+        }
+    }
+
+    /// Starts a server that accepts a client and then runs the specified function with its session
+    fn setup_server(serve: impl FnOnce(Session) + Send + 'static) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let session = accept_connect(stream, &KEY).unwrap();
+
+            serve(session);
+        });
+
+        address
+    }
+
+    /// Reads the Connect of a client and agrees the key of its session from the key of the client,
+    /// answering with a Connack that accepts it
+    fn accept_connect(stream: TcpStream, client_key: &[u8]) -> MqttResult<Session> {
+        accept_connect_with_session(stream, client_key, false)
+    }
+
+    /// Accepts the Connect of a client like `accept_connect`, with a Connack that reports whether
+    /// the session of the client is resumed
+    fn accept_connect_with_session(
+        mut stream: TcpStream,
+        client_key: &[u8],
+        session_present: bool,
+    ) -> MqttResult<Session> {
+        let connect = match Packet::from_bytes(&mut stream, &KEY)? {
+            Packet::Connect(connect) => connect,
+            packet => panic!("Expected a Connect, received {:?}", packet),
+        };
+        let key_exchange = KeyExchange::new();
+        let public_key = key_exchange.public_key();
+        let (session_key, confirmation) = key_exchange.accept(
+            connect.key_share().unwrap().public_key(),
+            connect.client_id().content(),
+            client_key,
+        )?;
+
+        let connack = Connack::new(session_present, ConnectReturnCode::ConnectionAccepted)
+            .with_key_share(KeyShare::with_confirmation(public_key, confirmation));
+        stream.write_all(
+            &connack.to_bytes(FrameKey::from(&KEY).with_direction(Direction::ToClient)),
+        )?;
+
+        Ok(Session {
+            stream,
+            connect,
+            key: session_key,
+            received: 0,
+            sent: 0,
+        })
+    }
+
+    /// Returns the TLS settings of a server with a self signed certificate for localhost, and the ones
+    /// of a client that trusts that certificate and expects it to be issued to the server name
+    fn tls_settings(ca_file: &str, server_name: &str) -> (Arc<ServerConfig>, TlsOptions) {
+        let key_pair = KeyPair::generate().unwrap();
+        let certificate = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .self_signed(&key_pair)
+            .unwrap();
+
+        std::fs::write(ca_file, certificate.pem()).unwrap();
+        let tls = TlsOptions::new(ca_file, server_name);
+        std::fs::remove_file(ca_file).unwrap();
+
+        let config = ServerConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![certificate.der().clone()],
+                key_pair.serialize_der().try_into().unwrap(),
+            )
+            .unwrap();
+
+        (Arc::new(config), tls.unwrap())
+    }
+
+    fn topic_name(topic_name: &str) -> TopicName {
+        let levels = topic_name
+            .split('/')
+            .map(|level| level.as_bytes().to_vec())
+            .collect();
+        TopicName::new(levels, false)
+    }
+
+    fn topic_filter(topic_filter: &str) -> TopicFilter {
+        let levels = topic_filter
+            .split('/')
+            .map(|level| TopicLevel::from_bytes(level.as_bytes().to_vec()).unwrap())
+            .collect();
+        TopicFilter::new(levels, false)
+    }
+
+    #[test]
+    fn test_publish_received_before_the_suback_reaches_the_callback() {
+        let address = setup_server(|mut session| {
+            let packet_identifier = match session.read().unwrap() {
+                Packet::Subscribe(subscribe) => subscribe.packet_identifier(),
+                packet => panic!("Expected a Subscribe, received {:?}", packet),
+            };
+
+            let publish = Publish::new(
+                false,
+                QoS::AtMost,
+                false,
+                topic_name("new-incident"),
+                None,
+                b"incident".to_vec(),
+            );
+            session.write(Packet::Publish(publish));
+
+            let suback = Suback::new(
+                packet_identifier,
+                vec![SubackReturnCode::SuccessMaximumQoS0],
+            );
+            session.write(Packet::Suback(suback));
+
+            thread::sleep(Duration::from_secs(1));
+        });
+
+        let client = MqttClient::connect(address, ClientOptions::new("drone", KEY)).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        client
+            .on_publish(topic_filter("new-incident"), move |publish| {
+                sender.send(publish.message().clone()).unwrap();
+            })
+            .unwrap();
+
+        let return_codes = client
+            .subscribe(vec![(topic_filter("new-incident"), QoS::AtMost)])
+            .unwrap();
+
+        assert_eq!(return_codes, vec![SubackReturnCode::SuccessMaximumQoS0]);
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(1)).unwrap(),
+            b"incident"
+        );
+    }
+
+    #[test]
+    fn test_qos_2_publish_completes_the_flow() {
+        let address = setup_server(|mut session| {
+            let packet_identifier = match session.read().unwrap() {
+                Packet::Publish(publish) => publish.package_identifier().unwrap(),
+                packet => panic!("Expected a Publish, received {:?}", packet),
+            };
+            let pubrec = Pubrec::new(packet_identifier);
+            session.write(Packet::Pubrec(pubrec));
+
+            match session.read().unwrap() {
+                Packet::Pubrel(pubrel) => assert_eq!(pubrel.packet_identifier(), packet_identifier),
+                packet => panic!("Expected a Pubrel, received {:?}", packet),
+            }
+            let pubcomp = Pubcomp::new(packet_identifier);
+            session.write(Packet::Pubcomp(pubcomp));
+
+            thread::sleep(Duration::from_secs(1));
+        });
+
+        let client = MqttClient::connect(address, ClientOptions::new("monitor", KEY)).unwrap();
+
+        client
+            .publish(topic_name("close-incident/1"), vec![], QoS::Exactly, false)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_replayed_frame_closes_the_connection() {
+        let address = setup_server(|mut session| {
+            let publish = Publish::new(
+                false,
+                QoS::AtMost,
+                false,
+                topic_name("new-incident"),
+                None,
+                b"incident".to_vec(),
+            );
+            let bytes = Packet::Publish(publish)
+                .to_bytes(FrameKey::new(&session.key, 1).with_direction(Direction::ToClient));
+            session.stream.write_all(&bytes).unwrap();
+            session.stream.write_all(&bytes).unwrap();
+
+            thread::sleep(Duration::from_secs(1));
+        });
+
+        let client = MqttClient::connect(address, ClientOptions::new("drone", KEY)).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        client
+            .on_publish(topic_filter("new-incident"), move |publish| {
+                sender.send(publish.message().clone()).unwrap();
+            })
+            .unwrap();
+
+        assert!(receiver.recv_timeout(Duration::from_secs(1)).is_ok());
+        assert!(receiver.recv_timeout(Duration::from_millis(200)).is_err());
+        assert!(!client.is_connected());
+    }
+
+    #[test]
+    fn test_requests_fail_when_the_connection_is_closed() {
+        let address = setup_server(drop);
+
+        let options = ClientOptions::new("drone", KEY).with_ack_timeout(Duration::from_secs(5));
+        let client = MqttClient::connect(address, options).unwrap();
+
+        let result = client.publish(topic_name("drone-data/1"), vec![], QoS::AtLeast, false);
+
+        assert!(result.is_err());
+        assert!(!client.is_connected());
+    }
+
+    /// Accepts a connection, answering its Connect and its Subscribe, and returns its session
+    /// and the subscribed topic filter
+    fn accept_subscriber(listener: &TcpListener) -> (Session, TopicFilter) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut session = accept_connect(stream, &KEY).unwrap();
+
+        let subscribe = match session.read().unwrap() {
+            Packet::Subscribe(subscribe) => subscribe,
+            packet => panic!("Expected a Subscribe, received {:?}", packet),
+        };
+        let suback = Suback::new(
+            subscribe.packet_identifier(),
+            vec![SubackReturnCode::SuccessMaximumQoS0],
+        );
+        let topic_filter = subscribe.topics()[0].0.clone();
+        session.write(Packet::Suback(suback));
+
+        (session, topic_filter)
+    }
+
+    #[test]
+    fn test_reconnect_resubscribes_and_calls_the_callbacks() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (session, _) = accept_subscriber(&listener);
+            let first_key = session.key.clone();
+            drop(session);
+
+            let (mut session, topic_filter) = accept_subscriber(&listener);
+            assert_ne!(session.key, first_key);
+            match session.read().unwrap() {
+                // The session is sent too so the connection stays open
+                Packet::Publish(publish) => sender.send((topic_filter, publish, session)).unwrap(),
+                packet => panic!("Expected a Publish, received {:?}", packet),
+            }
+        });
+
+        let options = ClientOptions::new("drone", KEY)
+            .with_reconnect(Duration::from_millis(10), Duration::from_millis(100));
+        let client = MqttClient::connect(address, options).unwrap();
+        client
+            .on_reconnect(|client| {
+                client
+                    .publish(topic_name("drone-data"), vec![], QoS::AtMost, false)
+                    .unwrap();
+            })
+            .unwrap();
+        client
+            .subscribe(vec![(topic_filter("new-incident"), QoS::AtMost)])
+            .unwrap();
+
+        let (resubscribed, publish, _session) =
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        assert_eq!(resubscribed, topic_filter("new-incident"));
+        assert_eq!(*publish.topic(), topic_name("drone-data"));
+        assert!(client.is_connected());
+    }
+
+    #[test]
+    fn test_qos_2_publish_resent_in_a_resumed_session_is_delivered_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            for (session_present, dup) in [(false, false), (true, true)] {
+                let (stream, _) = listener.accept().unwrap();
+                let mut session =
+                    accept_connect_with_session(stream, &KEY, session_present).unwrap();
+                let subscribe = match session.read().unwrap() {
+                    Packet::Subscribe(subscribe) => subscribe,
+                    packet => panic!("Expected a Subscribe, received {:?}", packet),
+                };
+                let suback = Suback::new(
+                    subscribe.packet_identifier(),
+                    vec![SubackReturnCode::SuccessMaximumQoS2],
+                );
+                session.write(Packet::Suback(suback));
+
+                let publish = Publish::new(
+                    dup,
+                    QoS::Exactly,
+                    false,
+                    topic_name("new-incident"),
+                    Some(1),
+                    b"incident".to_vec(),
+                );
+                session.write(Packet::Publish(publish));
+                match session.read().unwrap() {
+                    Packet::Pubrec(pubrec) => assert_eq!(pubrec.packet_identifier(), 1),
+                    packet => panic!("Expected a Pubrec, received {:?}", packet),
+                }
+
+                // The first connection is lost before the server releases the publish
+                if session_present {
+                    session.write(Packet::Pubrel(Pubrel::new(1)));
+                    match session.read().unwrap() {
+                        Packet::Pubcomp(pubcomp) => assert_eq!(pubcomp.packet_identifier(), 1),
+                        packet => panic!("Expected a Pubcomp, received {:?}", packet),
+                    }
+                    // The session is sent too so the connection stays open
+                    sender.send(session).unwrap();
+                }
+            }
+        });
+
+        let options = ClientOptions::new("drone", KEY)
+            .with_reconnect(Duration::from_millis(10), Duration::from_millis(100));
+        let client = MqttClient::connect(address, options).unwrap();
+        let (message_sender, message_receiver) = mpsc::channel();
+        client
+            .on_publish(topic_filter("new-incident"), move |publish| {
+                message_sender.send(publish.message().clone()).unwrap();
+            })
+            .unwrap();
+        client
+            .subscribe(vec![(topic_filter("new-incident"), QoS::Exactly)])
+            .unwrap();
+
+        let _session = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        assert_eq!(
+            message_receiver
+                .recv_timeout(Duration::from_secs(1))
+                .unwrap(),
+            b"incident"
+        );
+        assert!(message_receiver
+            .recv_timeout(Duration::from_millis(200))
+            .is_err());
+    }
+
+    #[test]
+    fn test_pushed_key_is_used_by_the_next_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let new_key = [5; 32];
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut session = accept_connect(stream, &KEY).unwrap();
+            let push = Publish::new(
+                false,
+                QoS::AtMost,
+                false,
+                topic_name(KEY_ROTATION_TOPIC),
+                None,
+                rotation_message(2, &new_key),
+            );
+            session.write(Packet::Publish(push));
+            drop(session);
+
+            let (mut stream, _) = listener.accept().unwrap();
+            let connect = Packet::from_bytes(&mut stream, FrameKey::with_id(2, &new_key, 0));
+            sender.send((connect.is_ok(), stream)).unwrap();
+        });
+
+        let keyring_file =
+            std::env::temp_dir().join(format!("{}_pushed_keys.txt", std::process::id()));
+        let keyring_file = keyring_file.to_str().unwrap();
+        let options = ClientOptions::new("drone", KEY)
+            .with_keyring_file(keyring_file)
+            .with_reconnect(Duration::from_millis(10), Duration::from_millis(100));
+        let client = MqttClient::connect(address, options.clone()).unwrap();
+
+        let (connect_decrypted, _stream) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        // A client started again with the same options connects with the pushed key
+        let restarted_keyring = options.keyring();
+        std::fs::remove_file(keyring_file).unwrap();
+
+        assert!(connect_decrypted);
+        assert_eq!(
+            client.keyring().unwrap().current(),
+            Some((2, new_key.as_slice()))
+        );
+        assert_eq!(
+            restarted_keyring.unwrap().current(),
+            Some((2, new_key.as_slice()))
+        );
+    }
+
+    #[test]
+    fn test_backoff_delay_doubles_up_to_the_maximum() {
+        let delays = ReconnectDelays {
+            initial: Duration::from_millis(100),
+            maximum: Duration::from_secs(1),
+        };
+
+        for (attempt, delay) in [(0, 100), (1, 200), (3, 800), (4, 1000), (40, 1000)] {
+            let delay = Duration::from_millis(delay);
+            let backoff = backoff_delay(attempt, delays);
+
+            assert!(backoff <= delay);
+            assert!(backoff >= delay / 2);
+        }
+    }
+
+    #[test]
+    fn test_refused_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            Packet::from_bytes(&mut stream, &KEY).unwrap();
+            let connack = Connack::new(false, ConnectReturnCode::BadUsernameOrPassword);
+            stream
+                .write_all(
+                    &connack.to_bytes(FrameKey::from(&KEY).with_direction(Direction::ToClient)),
+                )
+                .unwrap();
+        });
+
+        let options = ClientOptions::new("drone", KEY).with_login("drone1", "wrong");
+
+        assert!(matches!(
+            MqttClient::connect(address, options),
+            Err(MqttError::ConnectionRefused(_))
+        ));
+    }
+
+    #[test]
+    fn test_session_key_is_derived_from_the_key_of_the_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client_key = [7; 32];
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut session = accept_connect(stream, &client_key).unwrap();
+            assert_ne!(session.key, KEY);
+            // The login is sealed with the key of the client, not only with the shared key
+            let sealed = session.connect.login().unwrap();
+            assert!(sealed.username().content().is_empty());
+            let login = open_login(
+                sealed,
+                b"drone",
+                session.connect.key_share().unwrap().public_key(),
+                &client_key,
+            )
+            .unwrap();
+            assert_eq!(login.username().content(), b"drone7");
+            assert_eq!(login.password().unwrap().content(), b"sauron");
+
+            match session.read().unwrap() {
+                Packet::Publish(publish) => publish.message().clone(),
+                packet => panic!("Expected a Publish, received {:?}", packet),
+            }
+        });
+
+        let options = ClientOptions::new("drone", KEY)
+            .with_client_key(client_key)
+            .with_login("drone7", "sauron");
+        let client = MqttClient::connect(address, options).unwrap();
+        client
+            .publish(topic_name("drone-data"), b"7".to_vec(), QoS::AtMost, false)
+            .unwrap();
+
+        assert_eq!(server.join().unwrap(), b"7");
+    }
+
+    #[test]
+    fn test_server_without_the_key_of_the_client_is_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let _ = accept_connect(stream, &KEY);
+        });
+
+        let options = ClientOptions::new("drone", KEY).with_client_key([7; 32]);
+
+        assert!(matches!(
+            MqttClient::connect(address, options),
+            Err(MqttError::KeyExchange(_))
+        ));
+    }
+
+    #[test]
+    fn test_client_without_encryption_speaks_plain_mqtt() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert!(matches!(
+                Packet::from_bytes(&mut stream, NO_ENCRYPTION).unwrap(),
+                Packet::Connect(_)
+            ));
+            let connack = Connack::new(false, ConnectReturnCode::ConnectionAccepted);
+            stream.write_all(&connack.to_bytes(NO_ENCRYPTION)).unwrap();
+
+            match Packet::from_bytes(&mut stream, NO_ENCRYPTION).unwrap() {
+                Packet::Publish(publish) => publish.message().clone(),
+                packet => panic!("Expected a Publish, received {:?}", packet),
+            }
+        });
+
+        let options = ClientOptions::new("sensor", KEY).with_encryption(false);
+        let client = MqttClient::connect(address, options).unwrap();
+        client
+            .publish(
+                topic_name("sensor-data"),
+                b"42".to_vec(),
+                QoS::AtMost,
+                false,
+            )
+            .unwrap();
+
+        assert_eq!(server.join().unwrap(), b"42");
+    }
+
+    #[test]
+    fn test_mqtt_5_client_sends_properties_and_reads_reason_codes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let connect = match Packet::from_bytes(&mut stream, NO_ENCRYPTION).unwrap() {
+                Packet::Connect(connect) => connect,
+                packet => panic!("Expected a Connect, received {:?}", packet),
+            };
+            assert_eq!(connect.protocol_version(), ProtocolVersion::V5);
+            assert_eq!(connect.properties().session_expiry_interval(), Some(60));
+            let connack = Connack::new(false, ConnectReturnCode::ConnectionAccepted);
+            stream
+                .write_all(&connack.to_bytes_with_version(NO_ENCRYPTION, ProtocolVersion::V5))
+                .unwrap();
+
+            let publish = match Packet::from_bytes_with_version(
+                &mut stream,
+                NO_ENCRYPTION,
+                ProtocolVersion::V5,
+            )
+            .unwrap()
+            {
+                Packet::Publish(publish) => publish,
+                packet => panic!("Expected a Publish, received {:?}", packet),
+            };
+            let puback = Puback::new(publish.package_identifier())
+                .with_reason_code(ReasonCode::NotAuthorized);
+            stream
+                .write_all(&puback.to_bytes_with_version(NO_ENCRYPTION, ProtocolVersion::V5))
+                .unwrap();
+
+            publish.properties().correlation_data().map(<[u8]>::to_vec)
+        });
+
+        let options = ClientOptions::new("drone-1", KEY)
+            .with_encryption(false)
+            .with_protocol_version(ProtocolVersion::V5)
+            .with_session_expiry_interval(60);
+        let client = MqttClient::connect(address, options).unwrap();
+        let properties = Properties::new().with(Property::CorrelationData(vec![7]));
+        let result = client.publish_with_properties(
+            topic_name("drone/1/command"),
+            b"land".to_vec(),
+            QoS::AtLeast,
+            false,
+            properties,
+        );
+
+        assert!(matches!(result, Err(MqttError::PublishRejected(_))));
+        assert_eq!(server.join().unwrap(), Some(vec![7]));
+    }
+
+    #[test]
+    fn test_client_connects_over_tls_verifying_the_server_certificate() {
+        let (config, tls) = tls_settings("test_mqtt_client_tls_ca.pem", "localhost");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let session = ServerConnection::new(config.clone()).unwrap();
+            let mut stream = StreamOwned::new(session, stream);
+            assert!(matches!(
+                Packet::from_bytes(&mut stream, NO_ENCRYPTION).unwrap(),
+                Packet::Connect(_)
+            ));
+            let connack = Connack::new(false, ConnectReturnCode::ConnectionAccepted);
+            stream.write_all(&connack.to_bytes(NO_ENCRYPTION)).unwrap();
+
+            match Packet::from_bytes(&mut stream, NO_ENCRYPTION).unwrap() {
+                Packet::Publish(publish) => publish.message().clone(),
+                packet => panic!("Expected a Publish, received {:?}", packet),
+            }
+        });
+
+        let options = ClientOptions::new("sensor", KEY).with_tls(tls);
+        let client = MqttClient::connect(address, options).unwrap();
+        client
+            .publish(
+                topic_name("sensor-data"),
+                b"42".to_vec(),
+                QoS::AtMost,
+                false,
+            )
+            .unwrap();
+
+        assert_eq!(server.join().unwrap(), b"42");
+    }
+
+    #[test]
+    fn test_client_rejects_a_server_certificate_of_another_name() {
+        let (config, tls) = tls_settings("test_mqtt_client_tls_name_ca.pem", "broker.example.com");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let session = ServerConnection::new(config.clone()).unwrap();
+            let mut stream = StreamOwned::new(session, stream);
+            let _ = Packet::from_bytes(&mut stream, NO_ENCRYPTION);
+        });
+
+        let options = ClientOptions::new("sensor", KEY).with_tls(tls);
+        assert!(MqttClient::connect(address, options).is_err());
+    }
+}
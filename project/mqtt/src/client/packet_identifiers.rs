@@ -0,0 +1,80 @@
+use std::collections::HashSet;
+
+use crate::errors::error::{MqttError, MqttResult};
+
+/// Keeps track of the packet identifiers in use by the requests of a client.
+/// Identifiers are handed out in order, skipping 0 and the ones still in use
+#[derive(Debug)]
+pub struct PacketIdentifiers {
+    next: u16,
+    in_use: HashSet<u16>,
+}
+
+impl PacketIdentifiers {
+    pub fn new() -> Self {
+        PacketIdentifiers {
+            next: 1,
+            in_use: HashSet::new(),
+        }
+    }
+
+    /// Returns an identifier that is not in use, marking it as used
+    pub fn allocate(&mut self) -> MqttResult<u16> {
+        if self.in_use.len() == u16::MAX as usize {
+            return Err(MqttError::NoPacketIdentifierAvailable);
+        }
+
+        loop {
+            let packet_identifier = self.next;
+            self.next = self.next.checked_add(1).unwrap_or(1);
+
+            if self.in_use.insert(packet_identifier) {
+                return Ok(packet_identifier);
+            }
+        }
+    }
+
+    /// Frees an identifier so it can be used again
+    pub fn release(&mut self, packet_identifier: u16) {
+        self.in_use.remove(&packet_identifier);
+    }
+}
+
+impl Default for PacketIdentifiers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_skips_identifiers_in_use() {
+        let mut packet_identifiers = PacketIdentifiers::new();
+
+        assert_eq!(packet_identifiers.allocate().unwrap(), 1);
+        assert_eq!(packet_identifiers.allocate().unwrap(), 2);
+
+        packet_identifiers.release(1);
+        packet_identifiers.next = u16::MAX;
+
+        assert_eq!(packet_identifiers.allocate().unwrap(), u16::MAX);
+        assert_eq!(packet_identifiers.allocate().unwrap(), 1);
+        assert_eq!(packet_identifiers.allocate().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_allocate_fails_when_every_identifier_is_in_use() {
+        let mut packet_identifiers = PacketIdentifiers::new();
+
+        for _ in 0..u16::MAX {
+            packet_identifiers.allocate().unwrap();
+        }
+
+        assert!(packet_identifiers.allocate().is_err());
+        packet_identifiers.release(42);
+        assert_eq!(packet_identifiers.allocate().unwrap(), 42);
+    }
+}
@@ -0,0 +1,70 @@
+use std::sync::Arc;
+
+use rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+
+use crate::{
+    errors::error::{MqttError, MqttResult},
+    tls::{crypto_provider, load_certificates, load_private_key, load_root_store},
+};
+
+/// Represents the TLS settings of a client: the certificate authorities trusted to sign the certificate
+/// of the server, the name that certificate must be issued to and, if the server verifies the clients,
+/// the certificate the client presents
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    server_name: ServerName<'static>,
+    root_store: Arc<RootCertStore>,
+    config: Arc<ClientConfig>,
+}
+
+impl TlsOptions {
+    /// Creates the settings of a client that trusts the certificate authorities of a PEM file
+    /// and expects the certificate of the server to be issued to the server name
+    pub fn new(ca_file: &str, server_name: &str) -> MqttResult<Self> {
+        let server_name = ServerName::try_from(server_name.to_string())
+            .map_err(|err| MqttError::Tls(err.to_string()))?;
+        let root_store = Arc::new(load_root_store(ca_file)?);
+
+        let config = client_config_builder(root_store.clone())?.with_no_client_auth();
+
+        Ok(TlsOptions {
+            server_name,
+            root_store,
+            config: Arc::new(config),
+        })
+    }
+
+    /// Makes the client present the certificate of a PEM file, signed with the private key of another one
+    pub fn with_client_certificate(
+        mut self,
+        certificate_file: &str,
+        private_key_file: &str,
+    ) -> MqttResult<Self> {
+        let certificates = load_certificates(certificate_file)?;
+        let private_key = load_private_key(private_key_file)?;
+
+        let config = client_config_builder(self.root_store.clone())?
+            .with_client_auth_cert(certificates, private_key)
+            .map_err(|err| MqttError::Tls(err.to_string()))?;
+
+        self.config = Arc::new(config);
+        Ok(self)
+    }
+
+    pub fn server_name(&self) -> &ServerName<'static> {
+        &self.server_name
+    }
+
+    pub fn config(&self) -> Arc<ClientConfig> {
+        self.config.clone()
+    }
+}
+
+fn client_config_builder(
+    root_store: Arc<RootCertStore>,
+) -> MqttResult<rustls::ConfigBuilder<ClientConfig, rustls::client::WantsClientCert>> {
+    Ok(ClientConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|err| MqttError::Tls(err.to_string()))?
+        .with_root_certificates(root_store))
+}
@@ -0,0 +1,136 @@
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use rustls::ClientConnection;
+
+use crate::{
+    client::tls_options::TlsOptions,
+    errors::error::{MqttError, MqttResult},
+};
+
+/// Amount of bytes of TLS records read from the socket at once
+const RECORDS_BUFFER_SIZE: usize = 4096;
+
+/// Represents the socket of a client, over TCP or TLS. Clones share the socket, so a thread
+/// can read while others write. With TLS the reader waits for records without holding the session,
+/// so writes are not blocked meanwhile
+#[derive(Clone)]
+pub(crate) struct Transport {
+    stream: Arc<TcpStream>,
+    session: Option<Arc<Mutex<ClientConnection>>>,
+}
+
+impl Transport {
+    /// Connects to the server, completing the TLS handshake if the options are set.
+    /// Reads time out after the specified duration until the timeout is changed
+    pub fn connect(
+        addresses: &[SocketAddr],
+        tls: Option<&TlsOptions>,
+        timeout: Duration,
+    ) -> MqttResult<Self> {
+        let stream = TcpStream::connect(addresses)?;
+        stream.set_read_timeout(Some(timeout))?;
+
+        let session = match tls {
+            Some(tls) => {
+                let mut session = ClientConnection::new(tls.config(), tls.server_name().clone())
+                    .map_err(|err| MqttError::Tls(err.to_string()))?;
+                while session.is_handshaking() {
+                    session.complete_io(&mut &stream)?;
+                }
+                Some(Arc::new(Mutex::new(session)))
+            }
+            None => None,
+        };
+
+        Ok(Transport {
+            stream: Arc::new(stream),
+            session,
+        })
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    /// Closes the socket, telling the server first if the connection is over TLS
+    pub fn shutdown(&self) {
+        if let Some(session) = &self.session {
+            if let Ok(mut session) = lock_session(session) {
+                session.send_close_notify();
+                let _ = write_records(&mut session, &self.stream);
+            }
+        }
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let session = match &self.session {
+            Some(session) => session,
+            None => return (&*self.stream).read(buf),
+        };
+
+        let mut records = [0; RECORDS_BUFFER_SIZE];
+        loop {
+            match lock_session(session)?.reader().read(buf) {
+                Ok(read) => return Ok(read),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+
+            let read = (&*self.stream).read(&mut records)?;
+            if read == 0 {
+                return Ok(0);
+            }
+
+            let mut session = lock_session(session)?;
+            let mut received = &records[..read];
+            while !received.is_empty() {
+                session.read_tls(&mut received)?;
+                session
+                    .process_new_packets()
+                    .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+            }
+            write_records(&mut session, &self.stream)?;
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let session = match &self.session {
+            Some(session) => session,
+            None => return (&*self.stream).write(buf),
+        };
+
+        let mut session = lock_session(session)?;
+        let written = session.writer().write(buf)?;
+        write_records(&mut session, &self.stream)?;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.stream).flush()
+    }
+}
+
+/// Writes the TLS records waiting to be sent
+fn write_records(session: &mut ClientConnection, stream: &TcpStream) -> io::Result<()> {
+    while session.wants_write() {
+        session.write_tls(&mut &*stream)?;
+    }
+    Ok(())
+}
+
+fn lock_session(session: &Mutex<ClientConnection>) -> io::Result<MutexGuard<'_, ClientConnection>> {
+    session
+        .lock()
+        .map_err(|_| io::Error::other("The TLS session lock was poisoned"))
+}
@@ -0,0 +1,4 @@
+/// buffering of the bytes received until they hold whole packets
+pub mod packet_decoder;
+/// queue of the bytes of the packets not written yet
+pub mod packet_encoder;
@@ -0,0 +1,346 @@
+use std::io::{Cursor, ErrorKind, Read};
+
+use crate::{
+    encryptation::{encryping_tool::key_id, EXTRA_DATA_SIZE},
+    errors::error::{MqttError, MqttResult},
+    model::{components::fixed_header::FixedHeader, packet::Packet},
+    Direction, FrameKey, ProtocolVersion,
+};
+
+/// Amount of bytes requested to the reader on each read
+const READ_CHUNK_SIZE: usize = 4096;
+/// Size of the largest packet the protocol can encode: the fixed header, the maximum
+/// remaining length and the nonce and tag of the encryption
+pub const MAX_PACKET_SIZE: usize = 5 + 268_435_455 + EXTRA_DATA_SIZE;
+
+/// Buffers the bytes received from a socket and takes the packets out of them once they arrived whole.
+/// Unlike `Packet::from_bytes`, a read that would block in the middle of a packet does not lose the bytes
+/// already read, so it can be used with non blocking sockets.
+/// Packets larger than the maximum size are rejected as soon as their fixed header arrives.
+/// It counts the packets of the connection, so an encrypted packet must have the next sequence number
+/// and the direction of the decoder, and keeps the id of the key the last one was encrypted with.
+/// Packets are read in the version of MQTT of the connection, which the first Connect decoded chooses
+#[derive(Debug)]
+pub struct PacketDecoder {
+    buffer: Vec<u8>,
+    /// Bytes at the start of the buffer that belong to packets already decoded
+    consumed: usize,
+    max_packet_size: usize,
+    /// Sequence number of the next packet
+    sequence: u64,
+    direction: Direction,
+    key_id: Option<u32>,
+    version: ProtocolVersion,
+}
+
+impl PacketDecoder {
+    /// Creates a decoder that accepts packets up to the largest size of the protocol
+    pub fn new() -> Self {
+        PacketDecoder::with_max_packet_size(MAX_PACKET_SIZE)
+    }
+
+    /// Creates a decoder that rejects the packets of more than the specified amount of bytes
+    pub fn with_max_packet_size(max_packet_size: usize) -> Self {
+        PacketDecoder {
+            buffer: Vec::new(),
+            consumed: 0,
+            max_packet_size,
+            sequence: 0,
+            direction: Direction::ToServer,
+            key_id: None,
+            version: ProtocolVersion::V311,
+        }
+    }
+
+    /// Appends bytes received from the socket
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.compact();
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Reads once from the reader, appending the bytes read. Returns the amount of bytes read,
+    /// 0 if the reader reached its end. Errors such as WouldBlock are returned without losing any byte
+    pub fn read_from(&mut self, reader: &mut dyn Read) -> std::io::Result<usize> {
+        self.compact();
+
+        let start = self.buffer.len();
+        self.buffer.resize(start + READ_CHUNK_SIZE, 0);
+
+        let result = reader.read(&mut self.buffer[start..]);
+        let read = *result.as_ref().unwrap_or(&0);
+        self.buffer.truncate(start + read);
+
+        result
+    }
+
+    /// Takes the next packet out of the buffered bytes. Returns None while they do not hold a whole packet yet.
+    /// Fails if the packet is malformed or larger than the maximum size, after which the stream can not be trusted
+    pub fn decode<'a>(&mut self, key: impl Into<FrameKey<'a>>) -> MqttResult<Option<Packet>> {
+        let key = key
+            .into()
+            .with_sequence(self.sequence)
+            .with_direction(self.direction);
+        let pending = &self.buffer[self.consumed..];
+        let mut cursor = Cursor::new(pending);
+
+        let fixed_header = match FixedHeader::from_bytes(&mut cursor) {
+            Ok(fixed_header) => fixed_header,
+            Err(MqttError::IoError(err)) if err.kind() == ErrorKind::UnexpectedEof => {
+                return Ok(None)
+            }
+            Err(err) => return Err(err),
+        };
+
+        let header_length = cursor.position() as usize;
+        let packet_length = header_length + fixed_header.content_length(key.key());
+        if packet_length > self.max_packet_size {
+            return Err(MqttError::PacketTooLarge(packet_length));
+        }
+        if pending.len() < packet_length {
+            return Ok(None);
+        }
+
+        let packet = Packet::from_bytes_with_version(
+            &mut Cursor::new(&pending[..packet_length]),
+            key,
+            self.version,
+        )?;
+        if let Packet::Connect(connect) = &packet {
+            self.version = connect.protocol_version();
+        }
+        if !key.key().is_empty() {
+            self.key_id = key_id(&pending[header_length..packet_length]);
+        }
+        self.consumed += packet_length;
+        self.sequence += 1;
+
+        Ok(Some(packet))
+    }
+
+    /// Returns the id of the key the last packet decoded was encrypted with, None if it was not encrypted
+    pub fn key_id(&self) -> Option<u32> {
+        self.key_id
+    }
+
+    /// Sets the direction the packets are received in, to the server unless a client decodes them
+    pub fn set_direction(&mut self, direction: Direction) {
+        self.direction = direction;
+    }
+
+    /// Sets the version of MQTT the next packets are read in
+    pub fn set_protocol_version(&mut self, version: ProtocolVersion) {
+        self.version = version;
+    }
+
+    /// Returns the version of MQTT the packets are read in
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    /// Returns the amount of bytes received that were not decoded yet
+    pub fn buffered(&self) -> usize {
+        self.buffer.len() - self.consumed
+    }
+
+    /// Drops the bytes of the packets already decoded
+    fn compact(&mut self) {
+        if self.consumed > 0 {
+            self.buffer.drain(..self.consumed);
+            self.consumed = 0;
+        }
+    }
+}
+
+impl Default for PacketDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::{
+        keyring::Keyring,
+        model::{
+            components::{qos::QoS, topic_name::TopicName},
+            packets::{connect::Connect, pingreq::Pingreq, puback::Puback, publish::Publish},
+        },
+        EncodedString, ReasonCode, NO_ENCRYPTION,
+    };
+
+    const KEY: [u8; 32] = [0; 32];
+
+    /// Reader that returns its bytes a few at a time, failing with WouldBlock between them
+    struct TrickleReader {
+        bytes: Vec<u8>,
+        position: usize,
+        would_block: bool,
+    }
+
+    impl Read for TrickleReader {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            self.would_block = !self.would_block;
+            if self.would_block {
+                return Err(io::Error::from(ErrorKind::WouldBlock));
+            }
+
+            let end = (self.position + 3).min(self.bytes.len());
+            let read = end - self.position;
+            buffer[..read].copy_from_slice(&self.bytes[self.position..end]);
+            self.position = end;
+
+            Ok(read)
+        }
+    }
+
+    #[test]
+    fn test_decode_waits_for_the_whole_packet() {
+        let bytes = Pingreq::new().to_bytes(&KEY);
+        let mut decoder = PacketDecoder::new();
+
+        for byte in &bytes[..bytes.len() - 1] {
+            decoder.extend(&[*byte]);
+            assert!(decoder.decode(&KEY).unwrap().is_none());
+        }
+
+        decoder.extend(&bytes[bytes.len() - 1..]);
+        decoder.extend(&bytes);
+
+        assert!(matches!(
+            decoder.decode(&KEY).unwrap(),
+            Some(Packet::Pingreq(_))
+        ));
+        assert_eq!(decoder.buffered(), bytes.len());
+    }
+
+    #[test]
+    fn test_packets_larger_than_the_maximum_are_rejected() {
+        let publish = Publish::new(
+            false,
+            QoS::AtMost,
+            false,
+            TopicName::new(vec![b"camera-data".to_vec()], false),
+            None,
+            vec![0; 1024],
+        );
+        let bytes = publish.to_bytes(&KEY);
+        let mut decoder = PacketDecoder::with_max_packet_size(512);
+
+        // Only the fixed header is needed to reject it
+        decoder.extend(&bytes[..4]);
+
+        assert!(matches!(
+            decoder.decode(&KEY),
+            Err(MqttError::PacketTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn test_partial_reads_do_not_lose_bytes() {
+        let publish = Publish::new(
+            false,
+            QoS::AtMost,
+            false,
+            TopicName::new(vec![b"drone-data".to_vec(), b"1".to_vec()], false),
+            None,
+            b"0;0;Free;100".to_vec(),
+        );
+        let mut bytes = publish.to_bytes(&KEY);
+        bytes.extend(Pingreq::new().to_bytes(FrameKey::new(&KEY, 1)));
+
+        let mut reader = TrickleReader {
+            bytes,
+            position: 0,
+            would_block: false,
+        };
+        let mut decoder = PacketDecoder::new();
+        let mut packets = vec![];
+
+        loop {
+            match decoder.read_from(&mut reader) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                Err(err) => panic!("Unexpected error {:?}", err),
+            }
+
+            while let Some(packet) = decoder.decode(&KEY).unwrap() {
+                packets.push(packet);
+            }
+        }
+
+        assert_eq!(packets.len(), 2);
+        match &packets[0] {
+            Packet::Publish(received) => assert_eq!(received.message(), publish.message()),
+            packet => panic!("Expected a Publish, received {:?}", packet),
+        }
+        assert!(matches!(packets[1], Packet::Pingreq(_)));
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn test_replayed_packet_is_rejected() {
+        let bytes = Pingreq::new().to_bytes(&KEY);
+        let mut decoder = PacketDecoder::new();
+        decoder.extend(&bytes);
+        decoder.extend(&bytes);
+
+        assert!(decoder.decode(&KEY).unwrap().is_some());
+        assert!(matches!(
+            decoder.decode(&KEY),
+            Err(MqttError::ErrorDecryption(_))
+        ));
+    }
+
+    #[test]
+    fn test_reordered_packets_are_rejected() {
+        let mut decoder = PacketDecoder::new();
+        decoder.extend(&Pingreq::new().to_bytes(FrameKey::new(&KEY, 1)));
+        decoder.extend(&Pingreq::new().to_bytes(FrameKey::new(&KEY, 0)));
+
+        assert!(decoder.decode(&KEY).is_err());
+    }
+
+    #[test]
+    fn test_keeps_the_id_of_the_key_of_the_last_packet() {
+        let mut keyring = Keyring::new(1, &KEY);
+        keyring.rotate(2, &[7; 32]);
+        let mut decoder = PacketDecoder::new();
+        decoder.extend(&Pingreq::new().to_bytes(keyring.frame_key(1).unwrap()));
+
+        assert!(decoder.decode(&keyring).unwrap().is_some());
+        assert_eq!(decoder.key_id(), Some(1));
+    }
+
+    #[test]
+    fn test_the_connect_chooses_the_version_of_the_next_packets() {
+        let connect = Connect::new(
+            true,
+            10,
+            EncodedString::from_string(&"a".to_string()),
+            None,
+            None,
+        )
+        .with_protocol_version(ProtocolVersion::V5);
+        let puback = Puback::new(Some(1)).with_reason_code(ReasonCode::NoMatchingSubscribers);
+
+        let mut decoder = PacketDecoder::new();
+        decoder.extend(&connect.to_bytes(NO_ENCRYPTION));
+        decoder.extend(&puback.to_bytes_with_version(NO_ENCRYPTION, ProtocolVersion::V5));
+
+        assert!(matches!(
+            decoder.decode(NO_ENCRYPTION),
+            Ok(Some(Packet::Connect(_)))
+        ));
+        assert_eq!(decoder.protocol_version(), ProtocolVersion::V5);
+        match decoder.decode(NO_ENCRYPTION) {
+            Ok(Some(Packet::Puback(puback))) => {
+                assert_eq!(puback.reason_code(), ReasonCode::NoMatchingSubscribers)
+            }
+            packet => panic!("Expected a Puback, received {:?}", packet),
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    collections::VecDeque,
+    io::{ErrorKind, Write},
+};
+
+use crate::{model::packet::Packet, Direction, FrameKey, ProtocolVersion};
+
+/// Queues the bytes of the packets to send and writes them as the socket accepts them.
+/// A short write or a write that would block leaves the rest queued, so packets are never
+/// cut or interleaved on non blocking sockets.
+/// The packets it encodes take the sequence numbers of the connection in the order they are queued,
+/// are encrypted for the direction they are sent in and are written in the version of MQTT of the connection
+#[derive(Debug, Default)]
+pub struct PacketEncoder {
+    pending: VecDeque<u8>,
+    /// Sequence number of the next packet
+    sequence: u64,
+    direction: Direction,
+    version: ProtocolVersion,
+}
+
+impl PacketEncoder {
+    pub fn new() -> Self {
+        PacketEncoder {
+            pending: VecDeque::new(),
+            sequence: 0,
+            direction: Direction::ToServer,
+            version: ProtocolVersion::V311,
+        }
+    }
+
+    /// Encrypts a packet with the key and the next sequence number, and queues it
+    pub fn encode<'a>(&mut self, packet: &Packet, key: impl Into<FrameKey<'a>>) {
+        let key = key
+            .into()
+            .with_sequence(self.sequence)
+            .with_direction(self.direction);
+        self.queue(&packet.to_bytes_with_version(key, self.version));
+        self.sequence += 1;
+    }
+
+    /// Sets the direction the packets are sent in, to the server unless the server encodes them
+    pub fn set_direction(&mut self, direction: Direction) {
+        self.direction = direction;
+    }
+
+    /// Sets the version of MQTT the next packets are written in
+    pub fn set_protocol_version(&mut self, version: ProtocolVersion) {
+        self.version = version;
+    }
+
+    /// Returns the version of MQTT the packets are written in
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    /// Queues the bytes of a packet after the ones already queued
+    pub fn queue(&mut self, bytes: &[u8]) {
+        self.pending.extend(bytes);
+    }
+
+    /// Writes as many queued bytes as the writer accepts without blocking.
+    /// Returns the amount of bytes written
+    pub fn write_to(&mut self, writer: &mut dyn Write) -> std::io::Result<usize> {
+        let mut written = 0;
+
+        while !self.pending.is_empty() {
+            let (bytes, _) = self.pending.as_slices();
+            match writer.write(bytes) {
+                Ok(0) => return Err(std::io::Error::from(ErrorKind::WriteZero)),
+                Ok(amount) => {
+                    self.pending.drain(..amount);
+                    written += amount;
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(written)
+    }
+
+    /// Returns the amount of bytes waiting to be written
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Returns true if every queued byte was written
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Drops the bytes waiting to be written
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::{
+        codec::packet_decoder::PacketDecoder,
+        model::packets::{pingreq::Pingreq, puback::Puback},
+    };
+
+    /// Writer that accepts a limited amount of bytes before blocking
+    struct LimitedWriter {
+        written: Vec<u8>,
+        capacity: usize,
+    }
+
+    impl Write for LimitedWriter {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            if self.capacity == 0 {
+                return Err(io::Error::from(ErrorKind::WouldBlock));
+            }
+
+            // Accepts at most 2 bytes per write, like a short write
+            let amount = bytes.len().min(self.capacity).min(2);
+            self.written.extend_from_slice(&bytes[..amount]);
+            self.capacity -= amount;
+
+            Ok(amount)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_short_writes_keep_the_rest_queued() {
+        let mut encoder = PacketEncoder::new();
+        let mut writer = LimitedWriter {
+            written: vec![],
+            capacity: 5,
+        };
+
+        encoder.queue(&[1, 2, 3, 4]);
+        encoder.queue(&[5, 6, 7]);
+
+        assert_eq!(encoder.write_to(&mut writer).unwrap(), 5);
+        assert_eq!(encoder.pending(), 2);
+
+        writer.capacity = 10;
+        assert_eq!(encoder.write_to(&mut writer).unwrap(), 2);
+        assert!(encoder.is_empty());
+        assert_eq!(writer.written, vec![1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn test_encoded_packets_take_consecutive_sequence_numbers() {
+        let key = [0; 32];
+        let mut encoder = PacketEncoder::new();
+        let mut writer = LimitedWriter {
+            written: vec![],
+            capacity: usize::MAX,
+        };
+
+        encoder.encode(&Packet::Pingreq(Pingreq::new()), &key);
+        encoder.encode(&Packet::Puback(Puback::new(Some(1))), &key);
+        encoder.write_to(&mut writer).unwrap();
+
+        let mut decoder = PacketDecoder::new();
+        decoder.extend(&writer.written);
+        assert!(matches!(
+            decoder.decode(&key).unwrap(),
+            Some(Packet::Pingreq(_))
+        ));
+        assert!(matches!(
+            decoder.decode(&key).unwrap(),
+            Some(Packet::Puback(_))
+        ));
+    }
+}
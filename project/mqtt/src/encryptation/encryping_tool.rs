@@ -1,58 +1,196 @@
-use aes_gcm::aead::{Aead, KeyInit, OsRng};
+use aes_gcm::aead::{Aead, KeyInit, OsRng, Payload};
 use aes_gcm::{Aes256Gcm, Nonce}; // Or `Aes128Gcm`
 use rand::RngCore;
 
-/// To encrypt data, ignore the first 2 bytes corresponding to the fixed header
-pub fn encrypt(data: Vec<u8>, key: &[u8]) -> Result<Vec<u8>, String> {
-    let key = aes_gcm::Key::<Aes256Gcm>::from_slice(key);
-    let cipher = Aes256Gcm::new(key);
+use super::frame_key::FrameKey;
+
+const KEY_ID_SIZE: usize = 4;
+const SEQUENCE_SIZE: usize = 8;
+const NONCE_SIZE: usize = 12;
+/// Bytes of the frame before the nonce: the id of the key and the sequence number
+const HEADER_SIZE: usize = KEY_ID_SIZE + SEQUENCE_SIZE;
+
+/// To encrypt data, ignore the first 2 bytes corresponding to the fixed header.
+/// The id of the key and the sequence number of the frame go before the nonce and are authenticated
+/// as associated data, along with the direction of the frame, which each side knows without sending it.
+/// With an empty key the data is returned as is
+pub fn encrypt(data: Vec<u8>, key: FrameKey) -> Result<Vec<u8>, String> {
+    if key.key().is_empty() {
+        return Ok(data);
+    }
+
+    let cipher = Aes256Gcm::new(aes_gcm::Key::<Aes256Gcm>::from_slice(key.key()));
+    let mut header = key.id().to_be_bytes().to_vec();
+    header.extend_from_slice(&key.sequence().to_be_bytes());
 
     // Generate a random nonce
-    let mut nonce = [0u8; 12];
+    let mut nonce = [0u8; NONCE_SIZE];
     OsRng.fill_bytes(&mut nonce);
 
     let nonce = Nonce::from_slice(&nonce); // 96-bits; unique per message
 
-    let ciphertext = match cipher.encrypt(nonce, data.as_ref()) {
+    let payload = Payload {
+        msg: &data,
+        aad: &associated_data(&header, key),
+    };
+    let ciphertext = match cipher.encrypt(nonce, payload) {
         Ok(ciphertext) => ciphertext,
         Err(_) => {
             return Err("Error encrypting data".to_string());
         }
     };
 
-    let mut encrypted_data = nonce.to_vec();
+    let mut encrypted_data = header;
+    encrypted_data.extend_from_slice(nonce);
     encrypted_data.extend_from_slice(&ciphertext);
 
     Ok(encrypted_data)
 }
 
-/// To decrypt data
-pub fn decrypt(encrypted_data: &[u8], key: &[u8]) -> Result<Vec<u8>, String> {
-    let key = aes_gcm::Key::<Aes256Gcm>::from_slice(key);
-    let cipher = Aes256Gcm::new(key);
+/// To decrypt data with the key of the id the frame carries, failing if it is not one of the keys,
+/// if the frame was sent in the other direction or if it does not have the sequence number of the key.
+/// With an empty key the data is returned as is
+pub fn decrypt(encrypted_data: &[u8], key: FrameKey) -> Result<Vec<u8>, String> {
+    if key.key().is_empty() {
+        return Ok(encrypted_data.to_vec());
+    }
+
+    if encrypted_data.len() < HEADER_SIZE + NONCE_SIZE {
+        return Err(
+            "Encrypted data is shorter than the key id, the sequence number and the nonce"
+                .to_string(),
+        );
+    }
 
-    // Split the nonce and ciphertext
-    let (nonce, ciphertext) = encrypted_data.split_at(12);
+    // Split the key id and sequence number, the nonce and the ciphertext
+    let (header, encrypted_data) = encrypted_data.split_at(HEADER_SIZE);
+    let (nonce, ciphertext) = encrypted_data.split_at(NONCE_SIZE);
     let nonce = Nonce::from_slice(nonce);
 
-    match cipher.decrypt(nonce, ciphertext) {
-        Ok(data) => Ok(data),
-        Err(_) => Err("Error decrypting data".to_string()),
+    let id = key_id(header).ok_or("Invalid key id")?;
+    let cipher = match key.find(id) {
+        Some(key) => Aes256Gcm::new(aes_gcm::Key::<Aes256Gcm>::from_slice(key)),
+        None => return Err(format!("Frame encrypted with the unknown key {}", id)),
+    };
+
+    let payload = Payload {
+        msg: ciphertext,
+        aad: &associated_data(header, key),
+    };
+    let data = match cipher.decrypt(nonce, payload) {
+        Ok(data) => data,
+        Err(_) => return Err("Error decrypting data".to_string()),
+    };
+
+    // Only checked once authenticated, so a forged number is reported as such
+    let sequence = &header[KEY_ID_SIZE..];
+    let sequence = u64::from_be_bytes(sequence.try_into().map_err(|_| "Invalid sequence")?);
+    if sequence != key.sequence() {
+        return Err(format!(
+            "Frame {} received instead of frame {}",
+            sequence,
+            key.sequence()
+        ));
     }
+
+    Ok(data)
+}
+
+/// Returns the data authenticated with the content of a frame: its header and its direction
+fn associated_data(header: &[u8], key: FrameKey) -> Vec<u8> {
+    let mut associated_data = header.to_vec();
+    associated_data.push(key.direction().to_byte());
+    associated_data
+}
+
+/// Returns the id of the key an encrypted frame says it is encrypted with, None if it is too short
+pub fn key_id(encrypted_data: &[u8]) -> Option<u32> {
+    let id = encrypted_data.get(..KEY_ID_SIZE)?;
+    Some(u32::from_be_bytes(id.try_into().ok()?))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::encryptation::{frame_key::Direction, keyring::Keyring};
+
+    const KEY: &[u8; 32] = b"01234567890123456789012345678901";
 
     #[test]
     fn test_encrypt_decrypt() {
-        let key = b"01234567890123456789012345678901";
         let data = b"Hello world!";
 
-        let encrypted_data = encrypt(data.to_vec(), key);
-        let decrypted_data = decrypt(&encrypted_data.unwrap(), key).unwrap();
+        let encrypted_data = encrypt(data.to_vec(), KEY.into());
+        let decrypted_data = decrypt(&encrypted_data.unwrap(), KEY.into()).unwrap();
 
         assert_eq!(data.to_vec(), decrypted_data);
     }
+
+    #[test]
+    fn test_empty_key_leaves_the_data_as_is() {
+        let data = b"Hello world!";
+
+        let encrypted_data = encrypt(data.to_vec(), [].as_slice().into()).unwrap();
+        assert_eq!(encrypted_data, data.to_vec());
+        assert_eq!(
+            decrypt(&encrypted_data, [].as_slice().into()).unwrap(),
+            data.to_vec()
+        );
+    }
+
+    #[test]
+    fn test_decrypt_data_shorter_than_the_nonce() {
+        assert!(decrypt(&[0; 12], KEY.into()).is_err());
+    }
+
+    #[test]
+    fn test_frame_of_another_sequence_number_is_rejected() {
+        let encrypted_data = encrypt(b"close-incident".to_vec(), FrameKey::new(KEY, 4)).unwrap();
+
+        assert!(decrypt(&encrypted_data, FrameKey::new(KEY, 4)).is_ok());
+        assert!(decrypt(&encrypted_data, FrameKey::new(KEY, 5)).is_err());
+        assert!(decrypt(&encrypted_data, FrameKey::new(KEY, 3)).is_err());
+    }
+
+    #[test]
+    fn test_changed_sequence_number_is_rejected() {
+        let mut encrypted_data =
+            encrypt(b"close-incident".to_vec(), FrameKey::new(KEY, 4)).unwrap();
+        encrypted_data[HEADER_SIZE - 1] = 5;
+
+        assert!(decrypt(&encrypted_data, FrameKey::new(KEY, 5)).is_err());
+    }
+
+    #[test]
+    fn test_frame_reflected_to_its_sender_is_rejected() {
+        let to_client = FrameKey::new(KEY, 4).with_direction(Direction::ToClient);
+        let encrypted_data = encrypt(b"close-incident".to_vec(), to_client).unwrap();
+
+        assert!(decrypt(&encrypted_data, to_client).is_ok());
+        assert!(decrypt(&encrypted_data, FrameKey::new(KEY, 4)).is_err());
+    }
+
+    #[test]
+    fn test_keyring_decrypts_the_frames_of_its_previous_keys() {
+        let mut keyring = Keyring::new(1, KEY);
+        let previous = encrypt(b"close-incident".to_vec(), (&keyring).into()).unwrap();
+        keyring.rotate(2, &[7; 32]);
+        let current = encrypt(b"close-incident".to_vec(), (&keyring).into()).unwrap();
+
+        assert_eq!(key_id(&previous), Some(1));
+        assert_eq!(key_id(&current), Some(2));
+        assert!(decrypt(&previous, (&keyring).into()).is_ok());
+        assert!(decrypt(&current, (&keyring).into()).is_ok());
+        assert!(decrypt(&current, FrameKey::with_id(1, KEY, 0)).is_err());
+    }
+
+    #[test]
+    fn test_changed_key_id_is_rejected() {
+        let mut keyring = Keyring::new(1, KEY);
+        keyring.rotate(2, KEY);
+        let mut encrypted_data = encrypt(b"close-incident".to_vec(), (&keyring).into()).unwrap();
+        encrypted_data[KEY_ID_SIZE - 1] = 1;
+
+        assert!(decrypt(&encrypted_data, (&keyring).into()).is_err());
+    }
 }
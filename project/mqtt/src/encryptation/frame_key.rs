@@ -0,0 +1,129 @@
+use super::keyring::Keyring;
+
+/// Represents the key a frame is encrypted with, its sequence number and its direction.
+/// Each side numbers the frames it sends on a connection from 0, and the number is authenticated along
+/// with the content, so a frame that is replayed, dropped or reordered does not have the number expected.
+/// The direction is authenticated too, so a frame of the server sent back to it as the frame of a client
+/// with the same number fails to decrypt.
+/// The id of the key travels in the frame too, so the keys of a keyring can decrypt the frames
+/// encrypted with any of them.
+/// A key on its own has the id 0 and the sequence number 0, the one of the Connect and Connack,
+/// and encrypts the frames sent to the server
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameKey<'a> {
+    keys: Keys<'a>,
+    sequence: u64,
+    direction: Direction,
+}
+
+/// Represents the side of the connection a frame is sent to
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Direction {
+    /// Frames sent by a client to the server
+    #[default]
+    ToServer,
+    /// Frames sent by the server to a client
+    ToClient,
+}
+
+impl Direction {
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            Direction::ToServer => 0,
+            Direction::ToClient => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Keys<'a> {
+    /// A single key and its id
+    Single(u32, &'a [u8]),
+    /// Every key of a keyring, encrypting with the current one
+    Ring(&'a Keyring),
+}
+
+impl<'a> FrameKey<'a> {
+    pub fn new(key: &'a [u8], sequence: u64) -> Self {
+        FrameKey::with_id(0, key, sequence)
+    }
+
+    /// Creates the frame key of a key with the specified id
+    pub fn with_id(id: u32, key: &'a [u8], sequence: u64) -> Self {
+        FrameKey {
+            keys: Keys::Single(id, key),
+            sequence,
+            direction: Direction::default(),
+        }
+    }
+
+    /// Returns the same keys with another sequence number
+    pub fn with_sequence(self, sequence: u64) -> Self {
+        FrameKey { sequence, ..self }
+    }
+
+    /// Returns the same keys for the frames sent in another direction
+    pub fn with_direction(self, direction: Direction) -> Self {
+        FrameKey { direction, ..self }
+    }
+
+    /// Returns the key frames are encrypted with, empty if they are not encrypted
+    pub fn key(&self) -> &'a [u8] {
+        match self.keys {
+            Keys::Single(_, key) => key,
+            Keys::Ring(keyring) => keyring.current().map_or(&[], |(_, key)| key),
+        }
+    }
+
+    /// Returns the id of the key frames are encrypted with
+    pub fn id(&self) -> u32 {
+        match self.keys {
+            Keys::Single(id, _) => id,
+            Keys::Ring(keyring) => keyring.current().map_or(0, |(id, _)| id),
+        }
+    }
+
+    /// Returns the key with the id to decrypt a frame, None if it is not one of these keys
+    pub fn find(&self, id: u32) -> Option<&'a [u8]> {
+        match self.keys {
+            Keys::Single(key_id, key) => (key_id == id).then_some(key),
+            Keys::Ring(keyring) => keyring.get(id),
+        }
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+}
+
+impl<'a> From<&'a [u8]> for FrameKey<'a> {
+    fn from(key: &'a [u8]) -> Self {
+        FrameKey::new(key, 0)
+    }
+}
+
+impl<'a, const N: usize> From<&'a [u8; N]> for FrameKey<'a> {
+    fn from(key: &'a [u8; N]) -> Self {
+        FrameKey::new(key, 0)
+    }
+}
+
+impl<'a> From<&'a Vec<u8>> for FrameKey<'a> {
+    fn from(key: &'a Vec<u8>) -> Self {
+        FrameKey::new(key, 0)
+    }
+}
+
+impl<'a> From<&'a Keyring> for FrameKey<'a> {
+    fn from(keyring: &'a Keyring) -> Self {
+        FrameKey {
+            keys: Keys::Ring(keyring),
+            sequence: 0,
+            direction: Direction::default(),
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use aes_gcm::aead::{Aead, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret};
+
+use crate::{
+    errors::error::{MqttError, MqttResult},
+    EncodedString, Login,
+};
+
+/// Size of the public keys exchanged in the Connect and Connack packets
+pub const PUBLIC_KEY_SIZE: usize = 32;
+/// Size of the confirmation the server sends along with its public key
+pub const CONFIRMATION_SIZE: usize = 32;
+
+const SESSION_KEY_SIZE: usize = 32;
+const SESSION_KEY_INFO: &[u8] = b"mqtt session key";
+const LOGIN_KEY_INFO: &[u8] = b"mqtt login key";
+const NONCE_SIZE: usize = 12;
+
+/// Represents one side of the agreement of the key of a session.
+/// Each side sends the public key of an ephemeral X25519 key pair, and the session key is derived from
+/// their shared secret and the key of the client. Only someone that knows the key of the client can
+/// derive it, so a leaked key exposes the sessions of that client and no other, and recording the
+/// traffic is not enough to read it later.
+/// The server proves it knows the key of the client with a confirmation sent in the Connack
+pub struct KeyExchange {
+    secret: EphemeralSecret,
+    public_key: PublicKey,
+}
+
+impl KeyExchange {
+    /// Generates a new ephemeral key pair
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public_key = PublicKey::from(&secret);
+
+        KeyExchange { secret, public_key }
+    }
+
+    /// Returns the public key sent to the other side
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_SIZE] {
+        self.public_key.to_bytes()
+    }
+
+    /// Derives the session key on the server from the public key of the client.
+    /// Returns the session key and the confirmation sent to the client
+    pub fn accept(
+        self,
+        client_public_key: &[u8; PUBLIC_KEY_SIZE],
+        client_id: &[u8],
+        client_key: &[u8],
+    ) -> MqttResult<(Vec<u8>, [u8; CONFIRMATION_SIZE])> {
+        let server_public_key = self.public_key();
+        let shared_secret = self
+            .secret
+            .diffie_hellman(&PublicKey::from(*client_public_key));
+
+        let (session_key, confirmation) = derive_keys(
+            shared_secret,
+            client_public_key,
+            &server_public_key,
+            client_id,
+            client_key,
+        )?;
+
+        Ok((session_key, confirmation.finalize().into_bytes().into()))
+    }
+
+    /// Derives the session key on the client from the public key of the server, failing if its
+    /// confirmation shows the server does not know the key of the client
+    pub fn complete(
+        self,
+        server_public_key: &[u8; PUBLIC_KEY_SIZE],
+        confirmation: &[u8; CONFIRMATION_SIZE],
+        client_id: &[u8],
+        client_key: &[u8],
+    ) -> MqttResult<Vec<u8>> {
+        let client_public_key = self.public_key();
+        let shared_secret = self
+            .secret
+            .diffie_hellman(&PublicKey::from(*server_public_key));
+
+        let (session_key, expected_confirmation) = derive_keys(
+            shared_secret,
+            &client_public_key,
+            server_public_key,
+            client_id,
+            client_key,
+        )?;
+
+        expected_confirmation
+            .verify_slice(confirmation)
+            .map_err(|_| MqttError::KeyExchange("Invalid confirmation".to_string()))?;
+
+        Ok(session_key)
+    }
+}
+
+impl Default for KeyExchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Encrypts the login of the Connect of a client with a key derived from the key of the client, so the key
+/// the Connect is encrypted with, shared by every client, does not expose it.
+/// It is bound to the id of the client and the public key of its key share, so it is not accepted in the
+/// Connect of another client. The sealed login has an empty username and the encrypted login as password
+pub fn seal_login(
+    login: &Login,
+    client_id: &[u8],
+    public_key: &[u8; PUBLIC_KEY_SIZE],
+    client_key: &[u8],
+) -> MqttResult<Login> {
+    let mut nonce = [0; NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+
+    let mut data = vec![login.password().is_some() as u8];
+    data.extend(login.to_bytes());
+    let payload = Payload {
+        msg: &data,
+        aad: &[client_id, public_key].concat(),
+    };
+    let ciphertext = login_cipher(client_key)?
+        .encrypt(Nonce::from_slice(&nonce), payload)
+        .map_err(|_| MqttError::KeyExchange("Error sealing the login".to_string()))?;
+
+    Ok(Login::new(
+        EncodedString::new(Vec::new()),
+        Some(EncodedString::new([nonce.as_slice(), &ciphertext].concat())),
+    ))
+}
+
+/// Decrypts a login sealed by `seal_login`, failing if it was not sealed with the key of the client
+/// for the id and the public key of its Connect
+pub fn open_login(
+    login: &Login,
+    client_id: &[u8],
+    public_key: &[u8; PUBLIC_KEY_SIZE],
+    client_key: &[u8],
+) -> MqttResult<Login> {
+    let sealed = login
+        .password()
+        .filter(|_| login.username().content().is_empty())
+        .map(|password| password.content().as_slice())
+        .filter(|sealed| sealed.len() > NONCE_SIZE)
+        .ok_or_else(|| MqttError::KeyExchange("The login is not sealed".to_string()))?;
+
+    let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+    let payload = Payload {
+        msg: ciphertext,
+        aad: &[client_id, public_key].concat(),
+    };
+    let data = login_cipher(client_key)?
+        .decrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|_| MqttError::KeyExchange("Invalid sealed login".to_string()))?;
+
+    let (has_password, mut login) = data
+        .split_first()
+        .ok_or_else(|| MqttError::KeyExchange("Invalid sealed login".to_string()))?;
+    Login::from_bytes(&mut login, *has_password == 1)
+}
+
+/// Returns the cipher of the logins of a client, with a key derived from the key of the client
+fn login_cipher(client_key: &[u8]) -> MqttResult<Aes256Gcm> {
+    let mut login_key = [0; SESSION_KEY_SIZE];
+    Hkdf::<Sha256>::new(None, client_key)
+        .expand(LOGIN_KEY_INFO, &mut login_key)
+        .map_err(|err| MqttError::KeyExchange(err.to_string()))?;
+
+    <Aes256Gcm as aes_gcm::KeyInit>::new_from_slice(&login_key)
+        .map_err(|err| MqttError::KeyExchange(err.to_string()))
+}
+
+/// Derives the session key and the confirmation of the server, bound to both public keys and the id of the client
+fn derive_keys(
+    shared_secret: SharedSecret,
+    client_public_key: &[u8; PUBLIC_KEY_SIZE],
+    server_public_key: &[u8; PUBLIC_KEY_SIZE],
+    client_id: &[u8],
+    client_key: &[u8],
+) -> MqttResult<(Vec<u8>, Hmac<Sha256>)> {
+    // A public key of low order gives a known shared secret
+    if !shared_secret.was_contributory() {
+        return Err(MqttError::KeyExchange("Invalid public key".to_string()));
+    }
+
+    let info = [
+        SESSION_KEY_INFO,
+        client_id,
+        client_public_key,
+        server_public_key,
+    ]
+    .concat();
+    let mut keys = [0; SESSION_KEY_SIZE * 2];
+    Hkdf::<Sha256>::new(Some(client_key), shared_secret.as_bytes())
+        .expand(&info, &mut keys)
+        .map_err(|err| MqttError::KeyExchange(err.to_string()))?;
+
+    let (session_key, confirmation_key) = keys.split_at(SESSION_KEY_SIZE);
+    let mut confirmation = Hmac::<Sha256>::new_from_slice(confirmation_key)
+        .map_err(|err| MqttError::KeyExchange(err.to_string()))?;
+    confirmation.update(client_public_key);
+    confirmation.update(server_public_key);
+
+    Ok((session_key.to_vec(), confirmation))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_KEY: &[u8] = b"01234567890123456789012345678901";
+
+    #[test]
+    fn test_both_sides_derive_the_same_session_key() {
+        let client = KeyExchange::new();
+        let server = KeyExchange::new();
+        let client_public_key = client.public_key();
+        let server_public_key = server.public_key();
+
+        let (server_key, confirmation) = server
+            .accept(&client_public_key, b"drone-7", CLIENT_KEY)
+            .unwrap();
+        let client_key = client
+            .complete(&server_public_key, &confirmation, b"drone-7", CLIENT_KEY)
+            .unwrap();
+
+        assert_eq!(server_key, client_key);
+        assert_eq!(server_key.len(), 32);
+        assert_ne!(server_key, CLIENT_KEY);
+    }
+
+    #[test]
+    fn test_sessions_have_different_keys() {
+        let (first_key, _) = KeyExchange::new()
+            .accept(&KeyExchange::new().public_key(), b"drone-7", CLIENT_KEY)
+            .unwrap();
+        let (second_key, _) = KeyExchange::new()
+            .accept(&KeyExchange::new().public_key(), b"drone-7", CLIENT_KEY)
+            .unwrap();
+
+        assert_ne!(first_key, second_key);
+    }
+
+    #[test]
+    fn test_server_without_the_key_of_the_client_is_rejected() {
+        let client = KeyExchange::new();
+        let server = KeyExchange::new();
+        let client_public_key = client.public_key();
+        let server_public_key = server.public_key();
+
+        let (_, confirmation) = server
+            .accept(
+                &client_public_key,
+                b"drone-7",
+                b"another key of 32 bytes........",
+            )
+            .unwrap();
+
+        assert!(matches!(
+            client.complete(&server_public_key, &confirmation, b"drone-7", CLIENT_KEY),
+            Err(MqttError::KeyExchange(_))
+        ));
+    }
+
+    #[test]
+    fn test_sealed_login_is_opened_only_with_the_key_and_the_connect_of_the_client() {
+        let login = Login::new(
+            EncodedString::new(b"drone7".to_vec()),
+            Some(EncodedString::new(b"sauron".to_vec())),
+        );
+        let public_key = KeyExchange::new().public_key();
+
+        let sealed = seal_login(&login, b"drone-7", &public_key, CLIENT_KEY).unwrap();
+
+        assert!(sealed.username().content().is_empty());
+        assert!(!sealed
+            .password()
+            .unwrap()
+            .content()
+            .windows(6)
+            .any(|window| window == b"sauron"));
+        assert_eq!(
+            open_login(&sealed, b"drone-7", &public_key, CLIENT_KEY).unwrap(),
+            login
+        );
+        assert!(open_login(&sealed, b"drone-8", &public_key, CLIENT_KEY).is_err());
+        assert!(open_login(&sealed, b"drone-7", &[9; PUBLIC_KEY_SIZE], CLIENT_KEY).is_err());
+        assert!(open_login(
+            &sealed,
+            b"drone-7",
+            &public_key,
+            b"another key of 32 bytes........"
+        )
+        .is_err());
+        assert!(open_login(&login, b"drone-7", &public_key, CLIENT_KEY).is_err());
+    }
+
+    #[test]
+    fn test_public_key_of_low_order_is_rejected() {
+        let result = KeyExchange::new().accept(&[0; PUBLIC_KEY_SIZE], b"drone-7", CLIENT_KEY);
+
+        assert!(matches!(result, Err(MqttError::KeyExchange(_))));
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, ErrorKind, Write},
+};
+
+use crate::FrameKey;
+
+/// Size of the keys the frames are encrypted with
+pub const KEY_SIZE: usize = 32;
+
+/// Topic of the Publish packets the server sends to push a new key to the connected clients,
+/// whose message is the id of the key and the key separated by `;`
+pub const KEY_ROTATION_TOPIC: &str = "$key-rotation";
+
+/// Keys a keyring keeps: the current one and the previous one, for the other side to move to the current one
+pub const KEPT_KEYS: usize = 2;
+
+const SEPARATOR: u8 = b';';
+/// Length of a key written in hexadecimal
+const HEX_KEY_SIZE: usize = 2 * KEY_SIZE;
+
+/// Represents the keys an encrypted connection accepts, each one with an id that travels in the frames
+/// encrypted with it. New frames are encrypted with the current key, and the previous one is still
+/// decrypted, so the key can be rotated while the other side still uses the older one.
+/// Older keys are retired, so a leaked key stops being accepted after two rotations
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Keyring {
+    /// Keys by id, the last one being the current key
+    keys: Vec<(u32, Vec<u8>)>,
+}
+
+impl Keyring {
+    /// Creates a keyring with the specified key as the current one
+    pub fn new(id: u32, key: &[u8]) -> Self {
+        Keyring {
+            keys: vec![(id, key.to_vec())],
+        }
+    }
+
+    /// Makes the key the current one. The key that was current is kept as the previous key,
+    /// a previous key with the same id is replaced and the older keys are retired
+    pub fn rotate(&mut self, id: u32, key: &[u8]) {
+        self.keys.retain(|(key_id, _)| *key_id != id);
+        self.keys.push((id, key.to_vec()));
+
+        let retired = self.keys.len().saturating_sub(KEPT_KEYS);
+        self.keys.drain(..retired);
+    }
+
+    /// Rotates the keys of a keyring file into the keyring, so its last key becomes the current one.
+    /// The file has lines of the form `key_id = key`, from the oldest key to the newest, read by `read_keys`.
+    /// A missing file has no keys
+    pub fn load(&mut self, path: &str) -> io::Result<()> {
+        for (id, key) in read_keys(path)? {
+            let id = id.parse().map_err(|_| {
+                io::Error::new(ErrorKind::InvalidData, format!("Invalid key id {}", id))
+            })?;
+            self.rotate(id, &key);
+        }
+        Ok(())
+    }
+
+    /// Returns the id and the key the frames are encrypted with, None if the keyring is empty
+    pub fn current(&self) -> Option<(u32, &[u8])> {
+        self.keys.last().map(|(id, key)| (*id, key.as_slice()))
+    }
+
+    /// Returns the key with the id, None if it is not in the keyring
+    pub fn get(&self, id: u32) -> Option<&[u8]> {
+        self.keys
+            .iter()
+            .find(|(key_id, _)| *key_id == id)
+            .map(|(_, key)| key.as_slice())
+    }
+
+    /// Returns the key with the id to encrypt frames with it instead of the current one
+    pub fn frame_key(&self, id: u32) -> Option<FrameKey<'_>> {
+        self.get(id).map(|key| FrameKey::with_id(id, key, 0))
+    }
+
+    /// Returns the ids of the keys, from the oldest to the current one
+    pub fn ids(&self) -> Vec<u32> {
+        self.keys.iter().map(|(id, _)| *id).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+/// Adds a key at the end of a keyring file, as its newest key.
+/// The key is written in hexadecimal, since its bytes may be any
+pub fn append_to_file(path: &str, id: u32, key: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{} = {}", id, bytes_to_hex(key))
+}
+
+/// Reads the lines of the form `id = key` of a file of keys, such as a keyring file or the file of the keys
+/// of each client, where the key has 32 characters or is written as 64 hexadecimal digits.
+/// A missing file has no keys
+pub fn read_keys(path: &str) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err),
+    };
+
+    let mut keys = Vec::new();
+    for line in content.lines() {
+        let parts: Vec<&str> = line.split('=').map(|s| s.trim()).collect();
+        if parts.len() != 2 {
+            continue;
+        }
+
+        let key = parse_key(parts[1].trim_matches('"')).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid key for {}", parts[0]),
+            )
+        })?;
+        keys.push((parts[0].to_string(), key.to_vec()));
+    }
+
+    Ok(keys)
+}
+
+/// Reads a key of 32 characters or written as 64 hexadecimal digits, None if it is neither
+pub fn parse_key(key: &str) -> Option<[u8; KEY_SIZE]> {
+    match key.len() {
+        KEY_SIZE => key.as_bytes().try_into().ok(),
+        HEX_KEY_SIZE => hex_to_bytes(key).ok()?.try_into().ok(),
+        _ => None,
+    }
+}
+
+/// Convert a slice of bytes to a hexadecimal string
+pub fn bytes_to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+/// Convert a hexadecimal string to a vector of bytes
+pub fn hex_to_bytes(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return Err(format!("Invalid hexadecimal string {}", hex));
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|e| e.to_string()))
+        .collect()
+}
+
+/// Returns the message that pushes the key with the id to the clients
+pub fn rotation_message(id: u32, key: &[u8]) -> Vec<u8> {
+    let mut message = id.to_string().into_bytes();
+    message.push(SEPARATOR);
+    message.extend_from_slice(key);
+    message
+}
+
+/// Reads the id and the key of a rotation message, None if it is malformed or the key does not have
+/// the size of the keys
+pub fn parse_rotation_message(message: &[u8]) -> Option<(u32, Vec<u8>)> {
+    let separator = message.iter().position(|&byte| byte == SEPARATOR)?;
+    let (id, key) = (&message[..separator], &message[separator + 1..]);
+
+    let id = std::str::from_utf8(id).ok()?.parse().ok()?;
+    if key.len() != KEY_SIZE {
+        return None;
+    }
+
+    Some((id, key.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotated_key_becomes_the_current_one() {
+        let mut keyring = Keyring::new(1, &[1; KEY_SIZE]);
+        keyring.rotate(2, &[2; KEY_SIZE]);
+
+        assert_eq!(keyring.current(), Some((2, [2; KEY_SIZE].as_slice())));
+        assert_eq!(keyring.get(1), Some([1; KEY_SIZE].as_slice()));
+        assert_eq!(keyring.get(3), None);
+        assert_eq!(keyring.ids(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_rotating_to_a_previous_id_replaces_its_key() {
+        let mut keyring = Keyring::new(1, &[1; KEY_SIZE]);
+        keyring.rotate(2, &[2; KEY_SIZE]);
+        keyring.rotate(1, &[3; KEY_SIZE]);
+
+        assert_eq!(keyring.current(), Some((1, [3; KEY_SIZE].as_slice())));
+        assert_eq!(keyring.ids(), vec![2, 1]);
+    }
+
+    #[test]
+    fn test_keys_older_than_the_previous_one_are_retired() {
+        let mut keyring = Keyring::new(1, &[1; KEY_SIZE]);
+        keyring.rotate(2, &[2; KEY_SIZE]);
+        keyring.rotate(3, &[3; KEY_SIZE]);
+
+        assert_eq!(keyring.ids(), vec![2, 3]);
+        assert_eq!(keyring.get(1), None);
+    }
+
+    #[test]
+    fn test_keys_appended_to_a_file_are_loaded_in_order() {
+        let path = std::env::temp_dir().join(format!("{}_keyring.txt", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let mut missing = Keyring::new(0, &[0; KEY_SIZE]);
+        missing.load(path).unwrap();
+        append_to_file(path, 1, &[b'='; KEY_SIZE]).unwrap();
+        append_to_file(path, 2, &[b'\n'; KEY_SIZE]).unwrap();
+        let mut keyring = Keyring::new(0, &[0; KEY_SIZE]);
+        let loaded = keyring.load(path);
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(missing, Keyring::new(0, &[0; KEY_SIZE]));
+        assert!(loaded.is_ok());
+        assert_eq!(keyring.ids(), vec![1, 2]);
+        assert_eq!(keyring.current(), Some((2, [b'\n'; KEY_SIZE].as_slice())));
+    }
+
+    #[test]
+    fn test_key_is_parsed_from_characters_or_hexadecimal() {
+        let hex = "3D".repeat(KEY_SIZE);
+
+        assert_eq!(parse_key(&"k".repeat(KEY_SIZE)), Some([b'k'; KEY_SIZE]));
+        assert_eq!(parse_key(&hex), Some([b'='; KEY_SIZE]));
+        assert_eq!(parse_key("short"), None);
+        assert_eq!(parse_key(&"zz".repeat(KEY_SIZE)), None);
+    }
+
+    #[test]
+    fn test_rotation_message() {
+        let message = rotation_message(7, &[b'k'; KEY_SIZE]);
+
+        assert_eq!(&message[..2], b"7;");
+        assert_eq!(
+            parse_rotation_message(&message),
+            Some((7, vec![b'k'; KEY_SIZE]))
+        );
+    }
+
+    #[test]
+    fn test_invalid_rotation_messages() {
+        assert_eq!(parse_rotation_message(b"7"), None);
+        assert_eq!(parse_rotation_message(b"seven;0123"), None);
+        assert_eq!(parse_rotation_message(b"7;short"), None);
+    }
+}
@@ -1,4 +1,11 @@
 /// Module for encryptation
 pub mod encryping_tool;
+/// Key and sequence number of the encrypted frames
+pub mod frame_key;
+/// Agreement of the session keys of encrypted connections
+pub mod key_exchange;
+/// Current and previous keys of encrypted connections
+pub mod keyring;
 
-pub const EXTRA_DATA_SIZE: usize = 28;
+/// Bytes an encrypted frame adds to the content: the key id, the sequence number, the nonce and the tag
+pub const EXTRA_DATA_SIZE: usize = 40;
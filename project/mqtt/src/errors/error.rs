@@ -19,6 +19,16 @@ pub enum MqttError {
     ErrorDecryption(String),
     InvalidWildcard(String),
     InvalidReturnCode(String),
+    InvalidProperty(String),
+    ConnectionRefused(String),
+    PublishRejected(String),
+    NotConnected,
+    AckTimeout(String),
+    UnexpectedPacket(String),
+    NoPacketIdentifierAvailable,
+    PacketTooLarge(usize),
+    Tls(String),
+    KeyExchange(String),
     IoError(std::io::Error),
 }
 
@@ -40,6 +50,20 @@ impl fmt::Display for MqttError {
             MqttError::ErrorDecryption(msg) => write!(f, "Error decrypting content: {}", msg),
             MqttError::InvalidWildcard(msg) => write!(f, "Invalid Wildcard: {}", msg),
             MqttError::InvalidReturnCode(msg) => write!(f, "Invalid Return Code: {}", msg),
+            MqttError::InvalidProperty(msg) => write!(f, "Invalid property: {}", msg),
+            MqttError::ConnectionRefused(msg) => write!(f, "Connection refused: {}", msg),
+            MqttError::PublishRejected(msg) => write!(f, "Publish rejected: {}", msg),
+            MqttError::NotConnected => write!(f, "The client is not connected"),
+            MqttError::AckTimeout(msg) => write!(f, "Timed out waiting for {}", msg),
+            MqttError::UnexpectedPacket(msg) => write!(f, "Unexpected packet: {}", msg),
+            MqttError::NoPacketIdentifierAvailable => {
+                write!(f, "Every packet identifier is in use")
+            }
+            MqttError::PacketTooLarge(size) => {
+                write!(f, "The packet of {} bytes exceeds the maximum size", size)
+            }
+            MqttError::Tls(msg) => write!(f, "TLS error: {}", msg),
+            MqttError::KeyExchange(msg) => write!(f, "Key exchange error: {}", msg),
             MqttError::IoError(e) => write!(f, "I/O error: {}", e),
         }
     }
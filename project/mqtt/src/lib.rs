@@ -1,38 +1,79 @@
-//! This library implements the MQTT protocol version 3.1.1.
+//! This library implements the MQTT protocol versions 3.1.1 and 5.0.
 //!
 //! Its main goal is to provide an interface for the creation and manipulation of MQTT packets.
 //!
-//! Using from_bytes and to_bytes you can convert the packets to and from bytes, respectively.
+//! Using from_bytes and to_bytes you can convert the packets to and from bytes, respectively, in the
+//! format of MQTT 3.1.1. The `_with_version` variants take the `ProtocolVersion` of the connection,
+//! which its Connect chooses, so the packets of MQTT 5.0 carry their properties and reason codes.
+//! The content of the packets is encrypted with the key they receive. With `NO_ENCRYPTION` the packets
+//! follow the standard MQTT 3.1.1 wire format, so other MQTT clients and servers can read them.
+//!
+//! Encrypted connections agree a key for each session in the Connect and Connack packets with `key_exchange`,
+//! so the key the packets are encrypted with is not the same for every client nor every connection.
+//! A client with a key of its own also seals its login with it, so the key every client shares does not expose it.
+//! Each encrypted packet carries the sequence number of a `FrameKey`, so a packet that is replayed or
+//! reordered fails to decrypt. Its `Direction` is authenticated too, so a packet sent back to its sender
+//! fails as well. It also carries the id of its key, so a `Keyring` with the current key and the
+//! previous ones decrypts the packets of clients that were not moved to the current key yet.
+//!
+//! The client module provides a synchronous client that handles the connection, acknowledgements and pings.
+//!
+//! The tls module loads the certificates and keys used by the TLS connections of clients and servers.
+//!
+//! The codec module buffers partial reads and writes, so packets can be sent and received on non blocking sockets.
 
 use {
     encryptation::encryping_tool::{decrypt, encrypt},
     errors::error::{MqttError, MqttResult},
     model::{
         components::{
-            encoded_string::EncodedString, fixed_header::FixedHeader, login::Login, qos::QoS,
+            encoded_string::EncodedString, fixed_header::FixedHeader, login::Login,
+            properties::Properties, protocol_version::ProtocolVersion, qos::QoS,
             remaining_length::RemainingLength, topic_filter::TopicFilter, topic_level::TopicLevel,
             topic_name::TopicName, will::Will,
         },
         packets::{
-            connack::Connack, connect::Connect, disconnect::Disconnect, pingreq::Pingreq,
-            pingresp::Pingresp, puback::Puback, publish::Publish, suback::Suback,
-            subscribe::Subscribe, unsuback::Unsuback, unsubscribe::Unsubscribe,
+            auth::Auth, connack::Connack, connect::Connect, disconnect::Disconnect,
+            pingreq::Pingreq, pingresp::Pingresp, puback::Puback, pubcomp::Pubcomp,
+            publish::Publish, pubrec::Pubrec, pubrel::Pubrel, suback::Suback, subscribe::Subscribe,
+            unsuback::Unsuback, unsubscribe::Unsubscribe,
         },
         return_codes::{
-            connect_return_code::ConnectReturnCode, suback_return_code::SubackReturnCode,
+            connect_return_code::ConnectReturnCode, reason_code::ReasonCode,
+            suback_return_code::SubackReturnCode,
         },
     },
     std::io::Read,
 };
 
+/// synchronous client that keeps a connection to the server
+pub mod client;
+
+/// incremental packet framing for non blocking sockets
+pub mod codec;
+
 /// error handling
 pub mod errors;
 
 /// mqtt model
 pub mod model;
 
+/// certificates and keys for TLS connections
+pub mod tls;
+
 /// encryptation for packet
 mod encryptation;
 
+/// agreement of the session keys of encrypted connections and sealing of the logins in the Connect
+pub use encryptation::key_exchange;
+
+/// key, sequence number and direction of the encrypted packets
+pub use encryptation::frame_key::{Direction, FrameKey};
+
+/// current and previous keys, and their rotation
+pub use encryptation::keyring;
+
+/// Key that leaves the content of the packets unencrypted, as in standard MQTT
+pub const NO_ENCRYPTION: &[u8] = &[];
+
 const PROTOCOL_NAME: [u8; 4] = [b'M', b'Q', b'T', b'T'];
-const PROTOCOL_LEVEL: u8 = 0x04;
@@ -5,7 +5,7 @@ use crate::{errors::error::MqttResult, Read};
 const LENGTH_SIZE: usize = 2;
 
 /// Represents an encoded string. Contains the length of the string and the content in a byte vector.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct EncodedString {
     length: u16,
     content: Vec<u8>,
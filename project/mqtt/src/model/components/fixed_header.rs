@@ -1,4 +1,4 @@
-use crate::{encryptation::EXTRA_DATA_SIZE, model::packets::*, MqttResult, Read, RemainingLength};
+use crate::{encryptation::EXTRA_DATA_SIZE, MqttResult, Read, RemainingLength};
 
 /// Represents the fixed header of an MQTT packet.
 pub struct FixedHeader {
@@ -47,17 +47,18 @@ impl FixedHeader {
     }
 
     /// Return the remaining length of the fixed header considering encrypted data.
+    /// Packets without content are encrypted too, so they carry their sequence number
     pub fn remaining_length_encrypted(&self) -> usize {
-        match self.first_byte >> 4 {
-            CONNECT_PACKET_TYPE
-            | CONNACK_PACKET_TYPE
-            | SUBSCRIBE_PACKET_TYPE
-            | SUBACK_PACKET_TYPE
-            | PUBLISH_PACKET_TYPE
-            | PUBACK_PACKET_TYPE
-            | UNSUBSCRIBE_PACKET_TYPE
-            | UNSUBACK_PACKET_TYPE => self.remaining_length.value() + EXTRA_DATA_SIZE,
-            _ => self.remaining_length.value(),
+        self.remaining_length.value() + EXTRA_DATA_SIZE
+    }
+
+    /// Returns the length of the content that follows the fixed header when the packet
+    /// is encrypted with the key. An empty key means the packet is not encrypted
+    pub fn content_length(&self, key: &[u8]) -> usize {
+        if key.is_empty() {
+            self.remaining_length.value()
+        } else {
+            self.remaining_length_encrypted()
         }
     }
 }
@@ -0,0 +1,123 @@
+use crate::{
+    encryptation::key_exchange::{CONFIRMATION_SIZE, PUBLIC_KEY_SIZE},
+    MqttError, MqttResult, Read,
+};
+
+/// Represents the part of the agreement of a session key sent at the end of a Connect or a Connack:
+/// the public key of the sender and, from the server, the confirmation that it knows the key of the client.
+/// Only encrypted connections send it, so packets in standard MQTT never have one
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyShare {
+    public_key: [u8; PUBLIC_KEY_SIZE],
+    confirmation: Option<[u8; CONFIRMATION_SIZE]>,
+}
+
+impl KeyShare {
+    /// Creates the key share of a client
+    pub fn new(public_key: [u8; PUBLIC_KEY_SIZE]) -> Self {
+        KeyShare {
+            public_key,
+            confirmation: None,
+        }
+    }
+
+    /// Creates the key share of the server
+    pub fn with_confirmation(
+        public_key: [u8; PUBLIC_KEY_SIZE],
+        confirmation: [u8; CONFIRMATION_SIZE],
+    ) -> Self {
+        KeyShare {
+            public_key,
+            confirmation: Some(confirmation),
+        }
+    }
+
+    /// Reads the key share that ends a packet, None if the packet ends before it.
+    /// The key share of the server has a confirmation
+    pub fn from_bytes(stream: &mut dyn Read, confirmed: bool) -> MqttResult<Option<Self>> {
+        let mut bytes = Vec::new();
+        stream.read_to_end(&mut bytes)?;
+        if bytes.is_empty() {
+            return Ok(None);
+        }
+
+        let expected_length = if confirmed {
+            PUBLIC_KEY_SIZE + CONFIRMATION_SIZE
+        } else {
+            PUBLIC_KEY_SIZE
+        };
+        if bytes.len() != expected_length {
+            return Err(MqttError::InvalidRemainingLength);
+        }
+
+        let (public_key, confirmation) = bytes.split_at(PUBLIC_KEY_SIZE);
+        Ok(Some(KeyShare {
+            public_key: public_key
+                .try_into()
+                .map_err(|_| MqttError::InvalidRemainingLength)?,
+            confirmation: confirmation.try_into().ok(),
+        }))
+    }
+
+    /// Converts the KeyShare into a vector of bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.public_key.to_vec();
+
+        if let Some(confirmation) = &self.confirmation {
+            bytes.extend(confirmation);
+        }
+
+        bytes
+    }
+
+    /// Returns the public key of the sender
+    pub fn public_key(&self) -> &[u8; PUBLIC_KEY_SIZE] {
+        &self.public_key
+    }
+
+    /// Returns the confirmation of the server, None in the key share of a client
+    pub fn confirmation(&self) -> Option<&[u8; CONFIRMATION_SIZE]> {
+        self.confirmation.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_share_to_bytes_and_back() {
+        let client_key_share = KeyShare::new([1; PUBLIC_KEY_SIZE]);
+        let server_key_share =
+            KeyShare::with_confirmation([2; PUBLIC_KEY_SIZE], [3; CONFIRMATION_SIZE]);
+
+        let client_bytes = client_key_share.to_bytes();
+        let server_bytes = server_key_share.to_bytes();
+
+        assert_eq!(client_bytes.len(), PUBLIC_KEY_SIZE);
+        assert_eq!(
+            KeyShare::from_bytes(&mut client_bytes.as_slice(), false).unwrap(),
+            Some(client_key_share)
+        );
+        assert_eq!(
+            KeyShare::from_bytes(&mut server_bytes.as_slice(), true).unwrap(),
+            Some(server_key_share)
+        );
+    }
+
+    #[test]
+    fn test_packet_without_key_share() {
+        assert_eq!(
+            KeyShare::from_bytes(&mut [].as_slice(), false).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_key_share_of_another_length_is_rejected() {
+        let bytes = vec![0; PUBLIC_KEY_SIZE + 1];
+
+        assert!(KeyShare::from_bytes(&mut bytes.as_slice(), false).is_err());
+        assert!(KeyShare::from_bytes(&mut bytes.as_slice(), true).is_err());
+    }
+}
@@ -2,8 +2,14 @@
 pub mod encoded_string;
 /// fixed header of MQTT packets
 pub mod fixed_header;
+/// public keys of the agreement of session keys
+pub mod key_share;
 /// login
 pub mod login;
+/// MQTT 5.0 properties
+pub mod properties;
+/// protocol versions
+pub mod protocol_version;
 /// quality of service
 pub mod qos;
 /// calculate the remaining length
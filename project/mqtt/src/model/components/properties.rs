@@ -0,0 +1,422 @@
+use std::io::Read;
+
+use crate::{EncodedString, MqttError, MqttResult, RemainingLength};
+
+/// The variable byte integers are encoded in at most 4 bytes
+const MAX_VARIABLE_BYTE_INTEGER_SIZE: usize = 4;
+
+/// Represents a property of an MQTT 5.0 packet. Each packet type allows some of them
+#[derive(Debug, Clone, PartialEq)]
+pub enum Property {
+    PayloadFormatIndicator(u8),
+    MessageExpiryInterval(u32),
+    ContentType(String),
+    ResponseTopic(String),
+    CorrelationData(Vec<u8>),
+    SubscriptionIdentifier(u32),
+    SessionExpiryInterval(u32),
+    AssignedClientIdentifier(String),
+    ServerKeepAlive(u16),
+    AuthenticationMethod(String),
+    AuthenticationData(Vec<u8>),
+    RequestProblemInformation(u8),
+    WillDelayInterval(u32),
+    RequestResponseInformation(u8),
+    ResponseInformation(String),
+    ServerReference(String),
+    ReasonString(String),
+    ReceiveMaximum(u16),
+    TopicAliasMaximum(u16),
+    TopicAlias(u16),
+    MaximumQoS(u8),
+    RetainAvailable(u8),
+    UserProperty(String, String),
+    MaximumPacketSize(u32),
+    WildcardSubscriptionAvailable(u8),
+    SubscriptionIdentifierAvailable(u8),
+    SharedSubscriptionAvailable(u8),
+}
+
+impl Property {
+    /// Returns the identifier that precedes the property in the packet
+    pub fn identifier(&self) -> u8 {
+        match self {
+            Property::PayloadFormatIndicator(_) => 0x01,
+            Property::MessageExpiryInterval(_) => 0x02,
+            Property::ContentType(_) => 0x03,
+            Property::ResponseTopic(_) => 0x08,
+            Property::CorrelationData(_) => 0x09,
+            Property::SubscriptionIdentifier(_) => 0x0B,
+            Property::SessionExpiryInterval(_) => 0x11,
+            Property::AssignedClientIdentifier(_) => 0x12,
+            Property::ServerKeepAlive(_) => 0x13,
+            Property::AuthenticationMethod(_) => 0x15,
+            Property::AuthenticationData(_) => 0x16,
+            Property::RequestProblemInformation(_) => 0x17,
+            Property::WillDelayInterval(_) => 0x18,
+            Property::RequestResponseInformation(_) => 0x19,
+            Property::ResponseInformation(_) => 0x1A,
+            Property::ServerReference(_) => 0x1C,
+            Property::ReasonString(_) => 0x1F,
+            Property::ReceiveMaximum(_) => 0x21,
+            Property::TopicAliasMaximum(_) => 0x22,
+            Property::TopicAlias(_) => 0x23,
+            Property::MaximumQoS(_) => 0x24,
+            Property::RetainAvailable(_) => 0x25,
+            Property::UserProperty(_, _) => 0x26,
+            Property::MaximumPacketSize(_) => 0x27,
+            Property::WildcardSubscriptionAvailable(_) => 0x28,
+            Property::SubscriptionIdentifierAvailable(_) => 0x29,
+            Property::SharedSubscriptionAvailable(_) => 0x2A,
+        }
+    }
+
+    /// Reads the value of the property with the identifier
+    fn from_bytes(identifier: u8, stream: &mut dyn Read) -> MqttResult<Self> {
+        let property = match identifier {
+            0x01 => Property::PayloadFormatIndicator(read_byte(stream)?),
+            0x02 => Property::MessageExpiryInterval(read_four_byte_integer(stream)?),
+            0x03 => Property::ContentType(read_string(stream)?),
+            0x08 => Property::ResponseTopic(read_string(stream)?),
+            0x09 => Property::CorrelationData(read_binary_data(stream)?),
+            0x0B => Property::SubscriptionIdentifier(read_variable_byte_integer(stream)?),
+            0x11 => Property::SessionExpiryInterval(read_four_byte_integer(stream)?),
+            0x12 => Property::AssignedClientIdentifier(read_string(stream)?),
+            0x13 => Property::ServerKeepAlive(read_two_byte_integer(stream)?),
+            0x15 => Property::AuthenticationMethod(read_string(stream)?),
+            0x16 => Property::AuthenticationData(read_binary_data(stream)?),
+            0x17 => Property::RequestProblemInformation(read_byte(stream)?),
+            0x18 => Property::WillDelayInterval(read_four_byte_integer(stream)?),
+            0x19 => Property::RequestResponseInformation(read_byte(stream)?),
+            0x1A => Property::ResponseInformation(read_string(stream)?),
+            0x1C => Property::ServerReference(read_string(stream)?),
+            0x1F => Property::ReasonString(read_string(stream)?),
+            0x21 => Property::ReceiveMaximum(read_two_byte_integer(stream)?),
+            0x22 => Property::TopicAliasMaximum(read_two_byte_integer(stream)?),
+            0x23 => Property::TopicAlias(read_two_byte_integer(stream)?),
+            0x24 => Property::MaximumQoS(read_byte(stream)?),
+            0x25 => Property::RetainAvailable(read_byte(stream)?),
+            0x26 => Property::UserProperty(read_string(stream)?, read_string(stream)?),
+            0x27 => Property::MaximumPacketSize(read_four_byte_integer(stream)?),
+            0x28 => Property::WildcardSubscriptionAvailable(read_byte(stream)?),
+            0x29 => Property::SubscriptionIdentifierAvailable(read_byte(stream)?),
+            0x2A => Property::SharedSubscriptionAvailable(read_byte(stream)?),
+            _ => {
+                return Err(MqttError::InvalidProperty(format!(
+                    "Unknown property identifier {}",
+                    identifier
+                )))
+            }
+        };
+
+        Ok(property)
+    }
+
+    /// Converts the property, with its identifier, into a vector of bytes
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.identifier()];
+
+        match self {
+            Property::PayloadFormatIndicator(value)
+            | Property::RequestProblemInformation(value)
+            | Property::RequestResponseInformation(value)
+            | Property::MaximumQoS(value)
+            | Property::RetainAvailable(value)
+            | Property::WildcardSubscriptionAvailable(value)
+            | Property::SubscriptionIdentifierAvailable(value)
+            | Property::SharedSubscriptionAvailable(value) => bytes.push(*value),
+            Property::ServerKeepAlive(value)
+            | Property::ReceiveMaximum(value)
+            | Property::TopicAliasMaximum(value)
+            | Property::TopicAlias(value) => bytes.extend(value.to_be_bytes()),
+            Property::MessageExpiryInterval(value)
+            | Property::SessionExpiryInterval(value)
+            | Property::WillDelayInterval(value)
+            | Property::MaximumPacketSize(value) => bytes.extend(value.to_be_bytes()),
+            Property::SubscriptionIdentifier(value) => {
+                bytes.extend(RemainingLength::new(*value).to_bytes())
+            }
+            Property::ContentType(value)
+            | Property::ResponseTopic(value)
+            | Property::AssignedClientIdentifier(value)
+            | Property::AuthenticationMethod(value)
+            | Property::ResponseInformation(value)
+            | Property::ServerReference(value)
+            | Property::ReasonString(value) => {
+                bytes.extend(EncodedString::from_string(value).to_bytes())
+            }
+            Property::CorrelationData(value) | Property::AuthenticationData(value) => {
+                bytes.extend(EncodedString::new(value.clone()).to_bytes())
+            }
+            Property::UserProperty(name, value) => {
+                bytes.extend(EncodedString::from_string(name).to_bytes());
+                bytes.extend(EncodedString::from_string(value).to_bytes());
+            }
+        }
+
+        bytes
+    }
+}
+
+/// Represents the properties of an MQTT 5.0 packet, which follow its variable header preceded by their length.
+/// Every property appears at most once, except the user properties, which keep their order
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Properties {
+    properties: Vec<Property>,
+}
+
+impl Properties {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a property, replacing the previous one with the same identifier unless both are user properties
+    pub fn with(mut self, property: Property) -> Self {
+        self.set(property);
+        self
+    }
+
+    /// Adds a property, replacing the previous one with the same identifier unless both are user properties
+    pub fn set(&mut self, property: Property) {
+        if !matches!(property, Property::UserProperty(_, _)) {
+            self.remove(property.identifier());
+        }
+        self.properties.push(property);
+    }
+
+    /// Removes the properties with the identifier
+    pub fn remove(&mut self, identifier: u8) {
+        self.properties
+            .retain(|property| property.identifier() != identifier);
+    }
+
+    /// Converts a stream of bytes, starting with the length of the properties, into Properties
+    pub fn from_bytes(stream: &mut dyn Read) -> MqttResult<Self> {
+        let length = read_variable_byte_integer(stream)?;
+
+        let mut bytes = Vec::new();
+        (&mut *stream).take(length as u64).read_to_end(&mut bytes)?;
+        if bytes.len() < length as usize {
+            return Err(MqttError::InvalidRemainingLength);
+        }
+
+        let stream = &mut bytes.as_slice();
+        let mut properties = Properties::new();
+        while !stream.is_empty() {
+            let identifier = read_byte(stream)?;
+            let property = Property::from_bytes(identifier, stream)?;
+
+            let repeatable = matches!(
+                property,
+                Property::UserProperty(_, _) | Property::SubscriptionIdentifier(_)
+            );
+            if !repeatable && properties.get(identifier).is_some() {
+                return Err(MqttError::InvalidProperty(format!(
+                    "Property {} included more than once",
+                    identifier
+                )));
+            }
+            properties.properties.push(property);
+        }
+
+        Ok(properties)
+    }
+
+    /// Converts the properties into a vector of bytes that starts with their length
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let content: Vec<u8> = self
+            .properties
+            .iter()
+            .flat_map(Property::to_bytes)
+            .collect();
+
+        let mut bytes = RemainingLength::new(content.len() as u32).to_bytes();
+        bytes.extend(content);
+        bytes
+    }
+
+    /// Returns the amount of bytes of the properties in a packet, including their length
+    pub fn length(&self) -> usize {
+        self.to_bytes().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.properties.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Property> {
+        self.properties.iter()
+    }
+
+    /// Returns the first property with the identifier
+    pub fn get(&self, identifier: u8) -> Option<&Property> {
+        self.properties
+            .iter()
+            .find(|property| property.identifier() == identifier)
+    }
+
+    /// Returns the seconds after which the server discards the message, None if it does not expire
+    pub fn message_expiry_interval(&self) -> Option<u32> {
+        self.properties.iter().find_map(|property| match property {
+            Property::MessageExpiryInterval(interval) => Some(*interval),
+            _ => None,
+        })
+    }
+
+    /// Returns the seconds the server keeps the session after the client disconnects
+    pub fn session_expiry_interval(&self) -> Option<u32> {
+        self.properties.iter().find_map(|property| match property {
+            Property::SessionExpiryInterval(interval) => Some(*interval),
+            _ => None,
+        })
+    }
+
+    /// Returns the topic the receiver of a request publishes its response to
+    pub fn response_topic(&self) -> Option<&str> {
+        self.properties.iter().find_map(|property| match property {
+            Property::ResponseTopic(topic) => Some(topic.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Returns the data that relates a response to its request
+    pub fn correlation_data(&self) -> Option<&[u8]> {
+        self.properties.iter().find_map(|property| match property {
+            Property::CorrelationData(data) => Some(data.as_slice()),
+            _ => None,
+        })
+    }
+
+    /// Returns the name and value of the user properties, in the order they were added
+    pub fn user_properties(&self) -> Vec<(&str, &str)> {
+        self.properties
+            .iter()
+            .filter_map(|property| match property {
+                Property::UserProperty(name, value) => Some((name.as_str(), value.as_str())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the human readable reason of a reason code
+    pub fn reason_string(&self) -> Option<&str> {
+        self.properties.iter().find_map(|property| match property {
+            Property::ReasonString(reason) => Some(reason.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Returns the name of the method of the enhanced authentication
+    pub fn authentication_method(&self) -> Option<&str> {
+        self.properties.iter().find_map(|property| match property {
+            Property::AuthenticationMethod(method) => Some(method.as_str()),
+            _ => None,
+        })
+    }
+}
+
+fn read_byte(stream: &mut dyn Read) -> MqttResult<u8> {
+    let mut buffer = [0; 1];
+    stream.read_exact(&mut buffer)?;
+    Ok(buffer[0])
+}
+
+fn read_two_byte_integer(stream: &mut dyn Read) -> MqttResult<u16> {
+    let mut buffer = [0; 2];
+    stream.read_exact(&mut buffer)?;
+    Ok(u16::from_be_bytes(buffer))
+}
+
+fn read_four_byte_integer(stream: &mut dyn Read) -> MqttResult<u32> {
+    let mut buffer = [0; 4];
+    stream.read_exact(&mut buffer)?;
+    Ok(u32::from_be_bytes(buffer))
+}
+
+fn read_binary_data(stream: &mut dyn Read) -> MqttResult<Vec<u8>> {
+    Ok(EncodedString::from_bytes(stream)?.content().to_vec())
+}
+
+fn read_string(stream: &mut dyn Read) -> MqttResult<String> {
+    String::from_utf8(read_binary_data(stream)?)
+        .map_err(|_| MqttError::InvalidProperty("String that is not UTF-8".to_string()))
+}
+
+/// Reads a variable byte integer. It must be encoded in the least amount of bytes,
+/// so the properties read always have the length they are encoded with again
+fn read_variable_byte_integer(stream: &mut dyn Read) -> MqttResult<u32> {
+    let mut value = 0;
+    let mut multiplier = 1;
+
+    for size in 1..=MAX_VARIABLE_BYTE_INTEGER_SIZE {
+        let byte = read_byte(stream)?;
+        value += (byte & 127) as u32 * multiplier;
+
+        if byte & 128 == 0 {
+            if size > 1 && byte == 0 {
+                return Err(MqttError::InvalidRemainingLength);
+            }
+            return Ok(value);
+        }
+
+        multiplier *= 128;
+    }
+
+    Err(MqttError::InvalidRemainingLength)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_properties_to_bytes_and_back() {
+        let properties = Properties::new()
+            .with(Property::MessageExpiryInterval(60))
+            .with(Property::ResponseTopic("commands/1/response".to_string()))
+            .with(Property::CorrelationData(vec![1, 2, 3]))
+            .with(Property::UserProperty("drone".to_string(), "1".to_string()))
+            .with(Property::UserProperty("drone".to_string(), "2".to_string()))
+            .with(Property::SubscriptionIdentifier(300));
+
+        let bytes = properties.to_bytes();
+        assert_eq!(bytes.len(), properties.length());
+
+        let read = Properties::from_bytes(&mut bytes.as_slice()).unwrap();
+        assert_eq!(read, properties);
+        assert_eq!(read.message_expiry_interval(), Some(60));
+        assert_eq!(read.response_topic(), Some("commands/1/response"));
+        assert_eq!(read.correlation_data(), Some([1, 2, 3].as_slice()));
+        assert_eq!(read.user_properties(), vec![("drone", "1"), ("drone", "2")]);
+    }
+
+    #[test]
+    fn test_empty_properties_are_a_zero_length() {
+        assert_eq!(Properties::new().to_bytes(), vec![0]);
+        assert!(Properties::from_bytes(&mut [0].as_slice())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_set_replaces_the_property_with_the_same_identifier() {
+        let mut properties = Properties::new().with(Property::MessageExpiryInterval(60));
+        properties.set(Property::MessageExpiryInterval(10));
+
+        assert_eq!(properties.message_expiry_interval(), Some(10));
+        assert_eq!(properties.iter().count(), 1);
+    }
+
+    #[test]
+    fn test_invalid_properties() {
+        // Unknown identifier
+        assert!(Properties::from_bytes(&mut [2, 0x7F, 0].as_slice()).is_err());
+        // Length longer than the stream
+        assert!(Properties::from_bytes(&mut [5, 0x01, 0].as_slice()).is_err());
+        // Message expiry interval twice
+        let bytes = [10, 0x02, 0, 0, 0, 1, 0x02, 0, 0, 0, 2];
+        assert!(Properties::from_bytes(&mut bytes.as_slice()).is_err());
+        // Length not encoded in the least amount of bytes
+        assert!(Properties::from_bytes(&mut [0x80, 0x00].as_slice()).is_err());
+    }
+}
@@ -0,0 +1,47 @@
+use crate::{MqttError, MqttResult};
+
+/// Represents the versions of MQTT, told apart by the protocol level of the Connect packet.
+/// The rest of the packets of a connection follow the version its Connect chose
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProtocolVersion {
+    /// MQTT 3.1.1, protocol level 4
+    #[default]
+    V311,
+    /// MQTT 5.0, protocol level 5, whose packets carry properties and reason codes
+    V5,
+}
+
+impl ProtocolVersion {
+    /// Returns the protocol level of the version
+    pub fn level(&self) -> u8 {
+        match self {
+            ProtocolVersion::V311 => 0x04,
+            ProtocolVersion::V5 => 0x05,
+        }
+    }
+
+    /// Converts a protocol level into a version
+    pub fn from_level(level: u8) -> MqttResult<Self> {
+        match level {
+            0x04 => Ok(ProtocolVersion::V311),
+            0x05 => Ok(ProtocolVersion::V5),
+            _ => Err(MqttError::InvalidProtocolLevel),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protocol_levels() {
+        assert_eq!(
+            ProtocolVersion::from_level(4).unwrap(),
+            ProtocolVersion::V311
+        );
+        assert_eq!(ProtocolVersion::from_level(5).unwrap(), ProtocolVersion::V5);
+        assert_eq!(ProtocolVersion::V5.level(), 5);
+        assert!(ProtocolVersion::from_level(3).is_err());
+    }
+}
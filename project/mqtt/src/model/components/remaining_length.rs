@@ -1,6 +1,7 @@
 use crate::{MqttError, MqttResult, Read};
 
-const MAX_MULTIPLIER: u32 = u32::pow(128, 3);
+/// The remaining length is encoded in at most 4 bytes
+const MAX_LENGTH_BYTES: usize = 4;
 const MAX_LENGTH: u32 = u32::pow(128, 4); // 268.435.455 bytes
 
 /// Represents the remaining length of an MQTT packet.
@@ -22,25 +23,21 @@ impl RemainingLength {
         let mut multiplier = 1;
         let mut value = 0;
 
-        loop {
+        for _ in 0..MAX_LENGTH_BYTES {
             let mut buffer = [0];
             stream.read_exact(&mut buffer)?;
 
             let byte = buffer[0];
             value += (byte & 127) as u32 * multiplier;
 
-            multiplier *= 128;
-
-            if multiplier > MAX_MULTIPLIER {
-                return Err(MqttError::InvalidRemainingLength);
-            }
-
             if byte & 128 == 0 {
-                break;
+                return Ok(RemainingLength { value });
             }
+
+            multiplier *= 128;
         }
 
-        Ok(RemainingLength { value })
+        Err(MqttError::InvalidRemainingLength)
     }
 
     /// Converts the remaining length of an MQTT packet into a byte vector.
@@ -101,6 +98,13 @@ mod tests {
         assert_eq!(result, RemainingLength { value: 150 });
     }
 
+    #[test]
+    fn test_from_bytes_maximum_length() {
+        let mut cursor = Cursor::new(vec![0xFF, 0xFF, 0xFF, 0x7F]);
+        let result = RemainingLength::from_bytes(&mut cursor).unwrap();
+        assert_eq!(result.value(), 268_435_455);
+    }
+
     #[test]
     fn test_from_bytes_malformed_length() {
         let data: Vec<u8> = vec![0xFF, 0xFF, 0xFF, 0xFF, 0x07];
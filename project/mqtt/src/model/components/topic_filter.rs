@@ -98,6 +98,15 @@ impl TopicFilter {
         filter_levels.len() == name_levels.len()
     }
 
+    /// Returns whether every topic name matched by another topic filter is also matched by this one.
+    pub fn contains(&self, topic_filter: &TopicFilter) -> bool {
+        if self.server_reserved != topic_filter.server_reserved() {
+            return false;
+        }
+
+        contains_levels(&self.levels, topic_filter.levels())
+    }
+
     /// Returns the length of the topic filter in bytes.
     pub fn length(&self) -> usize {
         self.to_bytes().len()
@@ -114,6 +123,35 @@ impl TopicFilter {
     }
 }
 
+/// Returns whether the levels of a topic filter match every topic matched by the levels of another one.
+fn contains_levels(levels: &[TopicLevel], other_levels: &[TopicLevel]) -> bool {
+    match (levels.split_first(), other_levels.split_first()) {
+        (Some((TopicLevel::MultiLevelWildcard, _)), _) => true,
+        (None, None) => true,
+        (Some((TopicLevel::SingleLevelWildcard, rest)), Some((other_level, other_rest))) => {
+            *other_level != TopicLevel::MultiLevelWildcard && contains_levels(rest, other_rest)
+        }
+        (
+            Some((TopicLevel::Literal(level), rest)),
+            Some((TopicLevel::Literal(other_level), other_rest)),
+        ) => level == other_level && contains_levels(rest, other_rest),
+        _ => false,
+    }
+}
+
+impl From<&TopicName> for TopicFilter {
+    /// Converts a topic name into the topic filter that matches only that topic.
+    fn from(topic_name: &TopicName) -> Self {
+        let levels = topic_name
+            .levels()
+            .iter()
+            .map(|level| TopicLevel::Literal(level.clone()))
+            .collect();
+
+        Self::new(levels, topic_name.server_reserved())
+    }
+}
+
 impl Display for TopicFilter {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         let levels: Vec<String> = self.levels.iter().map(|level| level.to_string()).collect();
@@ -326,6 +364,22 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_contains() {
+        let filter = |bytes: &[u8]| TopicFilter::from_bytes(&mut from_slice(bytes)).unwrap();
+
+        assert!(filter(b"drone-data/#").contains(&filter(b"drone-data/+")));
+        assert!(filter(b"drone-data/#").contains(&filter(b"drone-data")));
+        assert!(filter(b"drone-data/+").contains(&filter(b"drone-data/1")));
+        assert!(filter(b"#").contains(&filter(b"+/+/#")));
+        assert!(filter(b"new-incident").contains(&filter(b"new-incident")));
+
+        assert!(!filter(b"drone-data/+").contains(&filter(b"drone-data/#")));
+        assert!(!filter(b"drone-data/1").contains(&filter(b"drone-data/+")));
+        assert!(!filter(b"drone-data/+").contains(&filter(b"drone-data/1/battery")));
+        assert!(!filter(b"#").contains(&filter(b"$SYS/#")));
+    }
+
     #[test]
     fn test_rejecting_topic_names() {
         {
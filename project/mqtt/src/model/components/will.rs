@@ -1,12 +1,14 @@
-use crate::{errors::error::MqttResult, EncodedString, QoS, Read, TopicName};
+use crate::{errors::error::MqttResult, EncodedString, Properties, QoS, Read, TopicName};
 
 /// Represents a message that will be published in case the client disconnects unexpectedly.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Will {
     qos: QoS,
     retain: bool,
     topic: TopicName,
     message: EncodedString,
+    /// Will properties of MQTT 5.0, which the Connect sends before the topic
+    properties: Properties,
 }
 
 impl Will {
@@ -16,9 +18,16 @@ impl Will {
             retain,
             topic,
             message,
+            properties: Properties::new(),
         }
     }
 
+    /// Sets the properties of the will message, only sent in MQTT 5.0
+    pub fn with_properties(mut self, properties: Properties) -> Will {
+        self.properties = properties;
+        self
+    }
+
     /// Converts a stream of bytes into a Will.
     pub fn from_bytes(stream: &mut dyn Read, qos: QoS, retain: bool) -> MqttResult<Will> {
         let topic = TopicName::from_bytes(stream)?;
@@ -55,6 +64,11 @@ impl Will {
     pub fn message(&self) -> &EncodedString {
         &self.message
     }
+
+    /// Returns the properties of the Will.
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
 }
 
 #[cfg(test)]
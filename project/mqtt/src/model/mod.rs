@@ -6,3 +6,5 @@ pub mod packet;
 pub mod packets;
 /// packet return codes
 pub mod return_codes;
+/// subscription tree indexed by topic levels
+pub mod topic_tree;
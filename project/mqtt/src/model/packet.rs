@@ -1,19 +1,24 @@
-use std::io::{Cursor, Read};
+use std::io::{Cursor, ErrorKind, Read};
 
 use crate::{
-    decrypt, Connack, Connect, Disconnect, FixedHeader, MqttError, MqttResult, Pingreq, Pingresp,
-    Puback, Publish, Suback, Subscribe, Unsuback, Unsubscribe,
+    decrypt, Auth, Connack, Connect, Disconnect, FixedHeader, FrameKey, MqttError, MqttResult,
+    Pingreq, Pingresp, ProtocolVersion, Puback, Pubcomp, Publish, Pubrec, Pubrel, Suback,
+    Subscribe, Unsuback, Unsubscribe,
 };
 
 use super::packets::*;
 
-/// A packet of information that is sent over the network. MQTT has fourteen types of packets.
+/// A packet of information that is sent over the network. MQTT has fourteen types of packets,
+/// and MQTT 5.0 adds the AUTH packet.
 #[derive(Debug)]
 pub enum Packet {
     Connect(Connect),
     Connack(Connack),
     Publish(Publish),
     Puback(Puback),
+    Pubrec(Pubrec),
+    Pubrel(Pubrel),
+    Pubcomp(Pubcomp),
     Subscribe(Subscribe),
     Suback(Suback),
     Unsubscribe(Unsubscribe),
@@ -21,18 +26,37 @@ pub enum Packet {
     Pingreq(Pingreq),
     Pingresp(Pingresp),
     Disconnect(Disconnect),
+    Auth(Auth),
 }
 
 impl Packet {
-    /// Converts a byte stream into an MQTT packet.
-    pub fn from_bytes(stream: &mut dyn Read, key: &[u8]) -> MqttResult<Self> {
+    /// Converts a byte stream into an MQTT packet. With an empty key the content is not encrypted,
+    /// as in standard MQTT, and otherwise it must have the sequence number of the key.
+    pub fn from_bytes<'a>(stream: &mut dyn Read, key: impl Into<FrameKey<'a>>) -> MqttResult<Self> {
+        Packet::from_bytes_with_version(stream, key, ProtocolVersion::V311)
+    }
+
+    /// Converts a byte stream in the format of the version into an MQTT packet. A Connect is read
+    /// in the version its protocol level chooses.
+    pub fn from_bytes_with_version<'a>(
+        stream: &mut dyn Read,
+        key: impl Into<FrameKey<'a>>,
+        version: ProtocolVersion,
+    ) -> MqttResult<Self> {
+        let key = key.into();
         let fixed_header = FixedHeader::from_bytes(stream)?;
 
         let packet_type = fixed_header.first_byte() >> 4;
-        let remaining_length = fixed_header.remaining_length_encrypted();
+        let remaining_length = fixed_header.content_length(key.key());
 
-        let encrypted_content = &mut vec![0; remaining_length];
-        stream.read_exact(encrypted_content)?;
+        // Reads the content as it arrives instead of allocating whatever the remaining length claims
+        let encrypted_content = &mut Vec::new();
+        (&mut *stream)
+            .take(remaining_length as u64)
+            .read_to_end(encrypted_content)?;
+        if encrypted_content.len() < remaining_length {
+            return Err(MqttError::IoError(ErrorKind::UnexpectedEof.into()));
+        }
 
         let content = match decrypt(encrypted_content, key) {
             Ok(content) => content,
@@ -47,31 +71,51 @@ impl Packet {
                 Packet::Connect(connect_packet)
             }
             CONNACK_PACKET_TYPE => {
-                let connack_packet = Connack::from_bytes(fixed_header, stream)?;
+                let connack_packet =
+                    Connack::from_bytes_with_version(fixed_header, stream, version)?;
 
                 Packet::Connack(connack_packet)
             }
             PUBLISH_PACKET_TYPE => {
-                let publish_packet = Publish::from_bytes(fixed_header, stream)?;
+                let publish_packet =
+                    Publish::from_bytes_with_version(fixed_header, stream, version)?;
 
                 Packet::Publish(publish_packet)
             }
             SUBSCRIBE_PACKET_TYPE => {
-                let subscribe_packet = Subscribe::from_bytes(fixed_header, stream)?;
+                let subscribe_packet =
+                    Subscribe::from_bytes_with_version(fixed_header, stream, version)?;
 
                 Packet::Subscribe(subscribe_packet)
             }
             SUBACK_PACKET_TYPE => {
-                let suback_packet = Suback::from_bytes(fixed_header, stream)?;
+                let suback_packet = Suback::from_bytes_with_version(fixed_header, stream, version)?;
                 Packet::Suback(suback_packet)
             }
             PUBACK_PACKET_TYPE => {
-                let puback_packet = Puback::from_bytes(fixed_header, stream)?;
+                let puback_packet = Puback::from_bytes_with_version(fixed_header, stream, version)?;
 
                 Packet::Puback(puback_packet)
             }
+            PUBREC_PACKET_TYPE => {
+                let pubrec_packet = Pubrec::from_bytes_with_version(fixed_header, stream, version)?;
+
+                Packet::Pubrec(pubrec_packet)
+            }
+            PUBREL_PACKET_TYPE => {
+                let pubrel_packet = Pubrel::from_bytes_with_version(fixed_header, stream, version)?;
+
+                Packet::Pubrel(pubrel_packet)
+            }
+            PUBCOMP_PACKET_TYPE => {
+                let pubcomp_packet =
+                    Pubcomp::from_bytes_with_version(fixed_header, stream, version)?;
+
+                Packet::Pubcomp(pubcomp_packet)
+            }
             DISCONNECT_PACKET_TYPE => {
-                let disconnect_packet = Disconnect::from_bytes(fixed_header)?;
+                let disconnect_packet =
+                    Disconnect::from_bytes_with_version(fixed_header, stream, version)?;
 
                 Packet::Disconnect(disconnect_packet)
             }
@@ -84,14 +128,20 @@ impl Packet {
                 Packet::Pingresp(pingresp_packet)
             }
             UNSUBSCRIBE_PACKET_TYPE => {
-                let unsubscribe_packet = Unsubscribe::from_bytes(fixed_header, stream)?;
+                let unsubscribe_packet =
+                    Unsubscribe::from_bytes_with_version(fixed_header, stream, version)?;
 
                 Packet::Unsubscribe(unsubscribe_packet)
             }
             UNSUBACK_PACKET_TYPE => {
-                let unsuback_packet = Unsuback::from_bytes(fixed_header, stream)?;
+                let unsuback_packet =
+                    Unsuback::from_bytes_with_version(fixed_header, stream, version)?;
                 Packet::Unsuback(unsuback_packet)
             }
+            AUTH_PACKET_TYPE if version == ProtocolVersion::V5 => {
+                let auth_packet = Auth::from_bytes(fixed_header, stream)?;
+                Packet::Auth(auth_packet)
+            }
             _ => return Err(MqttError::InvalidPacketType(packet_type.to_string())),
         };
 
@@ -105,7 +155,18 @@ impl Packet {
     }
 
     /// Converts the MQTT packet into a byte vector.
-    pub fn to_bytes(&self, key: &[u8]) -> Vec<u8> {
+    pub fn to_bytes<'a>(&self, key: impl Into<FrameKey<'a>>) -> Vec<u8> {
+        self.to_bytes_with_version(key, ProtocolVersion::V311)
+    }
+
+    /// Converts the MQTT packet into a byte vector in the format of the version. A Connect is
+    /// written in the version it carries.
+    pub fn to_bytes_with_version<'a>(
+        &self,
+        key: impl Into<FrameKey<'a>>,
+        version: ProtocolVersion,
+    ) -> Vec<u8> {
+        let key = key.into();
         let mut packet_bytes = vec![];
 
         match self {
@@ -113,22 +174,31 @@ impl Packet {
                 packet_bytes.extend(connect_packet.to_bytes(key));
             }
             Packet::Connack(connack_packet) => {
-                packet_bytes.extend(connack_packet.to_bytes(key));
+                packet_bytes.extend(connack_packet.to_bytes_with_version(key, version));
             }
             Packet::Publish(publish_packet) => {
-                packet_bytes.extend(publish_packet.to_bytes(key));
+                packet_bytes.extend(publish_packet.to_bytes_with_version(key, version));
             }
             Packet::Subscribe(subscribe_packet) => {
-                packet_bytes.extend(subscribe_packet.to_bytes(key));
+                packet_bytes.extend(subscribe_packet.to_bytes_with_version(key, version));
             }
             Packet::Suback(suback_packet) => {
-                packet_bytes.extend(suback_packet.to_bytes(key));
+                packet_bytes.extend(suback_packet.to_bytes_with_version(key, version));
             }
             Packet::Puback(puback_packet) => {
-                packet_bytes.extend(puback_packet.to_bytes(key));
+                packet_bytes.extend(puback_packet.to_bytes_with_version(key, version));
+            }
+            Packet::Pubrec(pubrec_packet) => {
+                packet_bytes.extend(pubrec_packet.to_bytes_with_version(key, version));
+            }
+            Packet::Pubrel(pubrel_packet) => {
+                packet_bytes.extend(pubrel_packet.to_bytes_with_version(key, version));
+            }
+            Packet::Pubcomp(pubcomp_packet) => {
+                packet_bytes.extend(pubcomp_packet.to_bytes_with_version(key, version));
             }
             Packet::Disconnect(disconnect_packet) => {
-                packet_bytes.extend(disconnect_packet.to_bytes(key));
+                packet_bytes.extend(disconnect_packet.to_bytes_with_version(key, version));
             }
             Packet::Pingreq(pingreq_packet) => {
                 packet_bytes.extend(pingreq_packet.to_bytes(key));
@@ -137,12 +207,80 @@ impl Packet {
                 packet_bytes.extend(pingresp_packet.to_bytes(key));
             }
             Packet::Unsubscribe(unsubscribe_packet) => {
-                packet_bytes.extend(unsubscribe_packet.to_bytes(key));
+                packet_bytes.extend(unsubscribe_packet.to_bytes_with_version(key, version));
             }
             Packet::Unsuback(unsuback_packet) => {
-                packet_bytes.extend(unsuback_packet.to_bytes(key));
+                packet_bytes.extend(unsuback_packet.to_bytes_with_version(key, version));
+            }
+            Packet::Auth(auth_packet) => {
+                packet_bytes.extend(auth_packet.to_bytes(key));
             }
         }
         packet_bytes
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NO_ENCRYPTION;
+
+    const KEY: [u8; 32] = [0; 32];
+
+    #[test]
+    fn test_remaining_length_larger_than_the_stream() {
+        // Publish claiming the maximum remaining length, followed by a few bytes
+        let bytes = vec![0b0011_0000, 0xFF, 0xFF, 0xFF, 0x7F, 0x00, 0x01];
+
+        assert!(Packet::from_bytes(&mut bytes.as_slice(), &KEY).is_err());
+    }
+
+    #[test]
+    fn test_content_that_can_not_be_decrypted() {
+        let bytes = vec![0b0011_0000, 0x02, 0x00, 0x01];
+
+        assert!(Packet::from_bytes(&mut bytes.as_slice(), &KEY).is_err());
+    }
+
+    #[test]
+    fn test_packets_without_encryption_follow_the_standard_format() {
+        // QoS 1 Publish to "a/b" with packet identifier 10 and message "hi"
+        let bytes = vec![
+            0b0011_0010,
+            0x09,
+            0x00,
+            0x03,
+            b'a',
+            b'/',
+            b'b',
+            0x00,
+            0x0A,
+            b'h',
+            b'i',
+        ];
+
+        let packet = Packet::from_bytes(&mut bytes.as_slice(), NO_ENCRYPTION).unwrap();
+        assert_eq!(packet.to_bytes(NO_ENCRYPTION), bytes);
+
+        let puback = Packet::Puback(Puback::new(Some(10)));
+        assert_eq!(
+            puback.to_bytes(NO_ENCRYPTION),
+            vec![0b0100_0000, 0x02, 0x00, 0x0A]
+        );
+    }
+
+    #[test]
+    fn test_auth_is_only_read_in_mqtt_5() {
+        let bytes = vec![0b1111_0000, 0x00];
+
+        assert!(Packet::from_bytes(&mut bytes.as_slice(), NO_ENCRYPTION).is_err());
+        assert!(matches!(
+            Packet::from_bytes_with_version(
+                &mut bytes.as_slice(),
+                NO_ENCRYPTION,
+                ProtocolVersion::V5
+            ),
+            Ok(Packet::Auth(_))
+        ));
+    }
+}
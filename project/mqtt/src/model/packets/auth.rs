@@ -0,0 +1,100 @@
+use super::{read_reason_code, reason_code_bytes, AUTH_PACKET_TYPE, RESERVED_FIXED_HEADER_FLAGS};
+use crate::{
+    encrypt, FixedHeader, FrameKey, MqttError, MqttResult, Properties, Read, ReasonCode,
+    RemainingLength,
+};
+
+/// Represents an AUTH packet of MQTT 5.0. The client and the server exchange it to go on with the
+/// enhanced authentication that the authentication method of the Connect started. It does not exist in MQTT 3.1.1
+#[derive(Debug, PartialEq)]
+pub struct Auth {
+    reason_code: ReasonCode,
+    properties: Properties,
+}
+
+impl Auth {
+    pub fn new(reason_code: ReasonCode, properties: Properties) -> Self {
+        Self {
+            reason_code,
+            properties,
+        }
+    }
+
+    /// Converts a stream of bytes into an Auth.
+    pub fn from_bytes(fixed_header: FixedHeader, stream: &mut dyn Read) -> MqttResult<Self> {
+        // Fixed Header
+        let fixed_header_flags = fixed_header.first_byte() & 0b0000_1111;
+
+        if fixed_header_flags != RESERVED_FIXED_HEADER_FLAGS {
+            return Err(MqttError::InvalidFixedHeaderFlags);
+        }
+
+        let (reason_code, properties) =
+            read_reason_code(stream, fixed_header.remaining_length().value())?;
+
+        Ok(Auth {
+            reason_code,
+            properties,
+        })
+    }
+
+    /// Converts the Auth into a vector of bytes.
+    pub fn to_bytes<'a>(&self, key: impl Into<FrameKey<'a>>) -> Vec<u8> {
+        let variable_header_bytes = reason_code_bytes(self.reason_code, &self.properties);
+
+        // Fixed Header
+        let mut packet_bytes = vec![AUTH_PACKET_TYPE << 4 | RESERVED_FIXED_HEADER_FLAGS];
+
+        let remaining_length_value = variable_header_bytes.len() as u32;
+        let remaining_length_bytes = RemainingLength::new(remaining_length_value).to_bytes();
+        packet_bytes.extend(remaining_length_bytes);
+
+        match encrypt(variable_header_bytes, key.into()) {
+            Ok(encrypted_bytes) => packet_bytes.extend(encrypted_bytes),
+            Err(_) => return vec![],
+        }
+
+        packet_bytes
+    }
+
+    /// Returns the reason code
+    pub fn reason_code(&self) -> ReasonCode {
+        self.reason_code
+    }
+
+    /// Returns the properties, with the authentication method and data
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{model::components::properties::Property, NO_ENCRYPTION};
+
+    #[test]
+    fn test_auth_to_bytes_and_back() {
+        let properties = Properties::new()
+            .with(Property::AuthenticationMethod("SCRAM-SHA-1".to_string()))
+            .with(Property::AuthenticationData(vec![1, 2, 3]));
+        let auth = Auth::new(ReasonCode::ContinueAuthentication, properties);
+
+        let bytes = auth.to_bytes(NO_ENCRYPTION);
+        assert_eq!(bytes[0], 0b1111_0000);
+        assert_eq!(bytes[2], 0x18);
+
+        let stream = &mut bytes.as_slice();
+        let fixed_header = FixedHeader::from_bytes(stream).unwrap();
+        assert_eq!(Auth::from_bytes(fixed_header, stream).unwrap(), auth);
+    }
+
+    #[test]
+    fn test_empty_auth_is_success() {
+        let fixed_header = FixedHeader::new(AUTH_PACKET_TYPE << 4, RemainingLength::new(0));
+        let auth = Auth::from_bytes(fixed_header, &mut [].as_slice()).unwrap();
+
+        assert_eq!(auth.reason_code(), ReasonCode::Success);
+        assert!(auth.properties().is_empty());
+    }
+}
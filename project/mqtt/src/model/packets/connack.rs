@@ -2,7 +2,8 @@ use std::fmt::{Display, Formatter};
 
 use super::{CONNACK_PACKET_TYPE, DEFAULT_VARIABLE_HEADER_LENGTH, RESERVED_FIXED_HEADER_FLAGS};
 use crate::{
-    encrypt, ConnectReturnCode, FixedHeader, MqttError, MqttResult, Read, RemainingLength,
+    encrypt, model::components::key_share::KeyShare, ConnectReturnCode, FixedHeader, FrameKey,
+    MqttError, MqttResult, Properties, ProtocolVersion, Read, ReasonCode, RemainingLength,
 };
 
 /// Represents a CONNECT packet of MQTT that is used to accept a connection from a client.
@@ -11,7 +12,12 @@ pub struct Connack {
     // Variable Header Fields
     session_present: bool,
     connect_return_code: ConnectReturnCode,
+    /// Reason code of MQTT 5.0, which matches the connect return code
+    reason_code: ReasonCode,
+    properties: Properties,
     // Connack no tiene payload
+    /// Public key and confirmation of the server to agree the session key, only sent over encrypted connections
+    key_share: Option<KeyShare>,
 }
 
 impl Connack {
@@ -20,11 +26,42 @@ impl Connack {
         Self {
             session_present,
             connect_return_code,
+            reason_code: connect_return_code.to_reason_code(),
+            properties: Properties::new(),
+            key_share: None,
         }
     }
 
+    /// Sets the reason code of MQTT 5.0, and the connect return code closest to it for MQTT 3.1.1
+    pub fn with_reason_code(mut self, reason_code: ReasonCode) -> Self {
+        self.reason_code = reason_code;
+        self.connect_return_code = ConnectReturnCode::from_reason_code(reason_code);
+        self
+    }
+
+    /// Sets the properties, only sent in MQTT 5.0
+    pub fn with_properties(mut self, properties: Properties) -> Self {
+        self.properties = properties;
+        self
+    }
+
+    /// Adds the public key and confirmation of the server to agree the session key
+    pub fn with_key_share(mut self, key_share: KeyShare) -> Self {
+        self.key_share = Some(key_share);
+        self
+    }
+
     /// Converts a stream of bytes into a Connack.
     pub fn from_bytes(fixed_header: FixedHeader, stream: &mut dyn Read) -> MqttResult<Self> {
+        Connack::from_bytes_with_version(fixed_header, stream, ProtocolVersion::V311)
+    }
+
+    /// Converts a stream of bytes in the format of the version into a Connack.
+    pub fn from_bytes_with_version(
+        fixed_header: FixedHeader,
+        stream: &mut dyn Read,
+        version: ProtocolVersion,
+    ) -> MqttResult<Self> {
         // Fixed Header
         let fixed_header_flags = fixed_header.first_byte() & 0b0000_1111;
 
@@ -40,13 +77,39 @@ impl Connack {
 
         let session_present = (connect_ack & 0b0000_0001) == 0b0000_0001;
 
-        let connect_return_code = ConnectReturnCode::from_byte(variable_header_buffer[1])?;
+        let connack = match version {
+            ProtocolVersion::V311 => {
+                let connect_return_code = ConnectReturnCode::from_byte(variable_header_buffer[1])?;
+                Connack::new(session_present, connect_return_code)
+            }
+            ProtocolVersion::V5 => {
+                let reason_code = ReasonCode::from_byte(variable_header_buffer[1])?;
+                let properties = Properties::from_bytes(stream)?;
+                Connack::new(session_present, ConnectReturnCode::ConnectionAccepted)
+                    .with_reason_code(reason_code)
+                    .with_properties(properties)
+            }
+        };
+
+        let key_share = KeyShare::from_bytes(stream, true)?;
 
-        Ok(Connack::new(session_present, connect_return_code))
+        Ok(Connack {
+            key_share,
+            ..connack
+        })
     }
 
     /// Converts the Connack into a vector of bytes.
-    pub fn to_bytes(&self, key: &[u8]) -> Vec<u8> {
+    pub fn to_bytes<'a>(&self, key: impl Into<FrameKey<'a>>) -> Vec<u8> {
+        self.to_bytes_with_version(key, ProtocolVersion::V311)
+    }
+
+    /// Converts the Connack into a vector of bytes in the format of the version.
+    pub fn to_bytes_with_version<'a>(
+        &self,
+        key: impl Into<FrameKey<'a>>,
+        version: ProtocolVersion,
+    ) -> Vec<u8> {
         // Variable Header
         let mut variable_header_bytes = vec![];
 
@@ -54,18 +117,26 @@ impl Connack {
 
         variable_header_bytes.push(session_present);
 
-        let connect_return_code_bytes = self.connect_return_code.to_byte();
+        match version {
+            ProtocolVersion::V311 => variable_header_bytes.push(self.connect_return_code.to_byte()),
+            ProtocolVersion::V5 => {
+                variable_header_bytes.push(self.reason_code.to_byte());
+                variable_header_bytes.extend(self.properties.to_bytes());
+            }
+        }
 
-        variable_header_bytes.push(connect_return_code_bytes);
+        if let Some(key_share) = &self.key_share {
+            variable_header_bytes.extend(key_share.to_bytes());
+        }
 
         // Fixed Header
         let mut fixed_header_bytes = vec![CONNACK_PACKET_TYPE << 4 | RESERVED_FIXED_HEADER_FLAGS];
 
-        let remaining_length_value = DEFAULT_VARIABLE_HEADER_LENGTH as u32;
+        let remaining_length_value = variable_header_bytes.len() as u32;
         let remaining_length_bytes = RemainingLength::new(remaining_length_value).to_bytes();
         fixed_header_bytes.extend(remaining_length_bytes);
 
-        let encrypted_bytes = match encrypt(variable_header_bytes, key) {
+        let encrypted_bytes = match encrypt(variable_header_bytes, key.into()) {
             Ok(bytes) => bytes,
             Err(_) => return vec![],
         };
@@ -88,6 +159,21 @@ impl Connack {
     pub fn connect_return_code(&self) -> &ConnectReturnCode {
         &self.connect_return_code
     }
+
+    /// Returns the reason code of MQTT 5.0, which matches the connect return code in MQTT 3.1.1
+    pub fn reason_code(&self) -> ReasonCode {
+        self.reason_code
+    }
+
+    /// Returns the properties, always empty in MQTT 3.1.1
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    /// Returns the public key and confirmation of the server to agree the session key
+    pub fn key_share(&self) -> Option<&KeyShare> {
+        self.key_share.as_ref()
+    }
 }
 
 impl Display for Connack {
@@ -104,7 +190,11 @@ impl Display for Connack {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{encryptation::encryping_tool::decrypt, ConnectReturnCode};
+    use crate::{
+        encryptation::encryping_tool::decrypt,
+        model::{components::properties::Property, packet::Packet},
+        ConnectReturnCode, NO_ENCRYPTION,
+    };
 
     const KEY: &[u8; 32] = &[0; 32];
 
@@ -143,7 +233,7 @@ mod test {
         let connack = Connack::new(session_present, connect_return_code);
         let connack_encrypted_bytes = connack.to_bytes(KEY);
         let fixed_header_bytes = connack_encrypted_bytes[0..2].to_vec();
-        let decrypted_bytes = decrypt(&connack_encrypted_bytes[2..], KEY).unwrap();
+        let decrypted_bytes = decrypt(&connack_encrypted_bytes[2..], KEY.into()).unwrap();
         let connack_bytes = [fixed_header_bytes, decrypted_bytes].concat();
 
         let connect_return_code = ConnectReturnCode::ConnectionAccepted;
@@ -187,4 +277,43 @@ mod test {
         let connack = Connack::from_bytes(fixed_header, &mut connack_bytes.as_slice());
         assert!(connack.is_err());
     }
+
+    #[test]
+    fn test_connack_with_key_share_to_bytes_and_back() {
+        let key_share = KeyShare::with_confirmation([7; 32], [8; 32]);
+        let connack = Connack::new(true, ConnectReturnCode::ConnectionAccepted)
+            .with_key_share(key_share.clone());
+
+        let connack_bytes = connack.to_bytes(KEY);
+        let connack = match Packet::from_bytes(&mut connack_bytes.as_slice(), KEY) {
+            Ok(Packet::Connack(connack)) => connack,
+            packet => panic!("Expected a Connack, received {:?}", packet),
+        };
+
+        assert!(connack.session_present());
+        assert_eq!(connack.key_share(), Some(&key_share));
+    }
+
+    #[test]
+    fn test_mqtt_5_connack_to_bytes_and_back() {
+        let properties = Properties::new().with(Property::SessionExpiryInterval(300));
+        let connack = Connack::new(false, ConnectReturnCode::ConnectionAccepted)
+            .with_reason_code(ReasonCode::BadAuthenticationMethod)
+            .with_properties(properties.clone());
+
+        let connack_bytes = connack.to_bytes_with_version(NO_ENCRYPTION, ProtocolVersion::V5);
+        assert_eq!(connack_bytes[3], 0x8C);
+
+        let stream = &mut connack_bytes.as_slice();
+        let fixed_header = FixedHeader::from_bytes(stream).unwrap();
+        let connack =
+            Connack::from_bytes_with_version(fixed_header, stream, ProtocolVersion::V5).unwrap();
+
+        assert_eq!(connack.reason_code(), ReasonCode::BadAuthenticationMethod);
+        assert_eq!(
+            connack.connect_return_code(),
+            &ConnectReturnCode::NotAuthorized
+        );
+        assert_eq!(connack.properties(), &properties);
+    }
 }
@@ -1,20 +1,27 @@
 use super::{CONNECT_PACKET_TYPE, RESERVED_FIXED_HEADER_FLAGS};
 use crate::{
-    encrypt, errors::error::MqttResult, EncodedString, FixedHeader, Login, MqttError, QoS, Read,
-    RemainingLength, Will, PROTOCOL_LEVEL, PROTOCOL_NAME,
+    encrypt, errors::error::MqttResult, model::components::key_share::KeyShare, EncodedString,
+    FixedHeader, FrameKey, Login, MqttError, Properties, ProtocolVersion, QoS, Read,
+    RemainingLength, Will, PROTOCOL_NAME,
 };
 
 /// Represents a MQTT CONNECT packet used to initialize a connection with the server.
+/// Its protocol level chooses the version of MQTT of the rest of the packets of the connection
 #[derive(Debug)]
 pub struct Connect {
     // Variable Header Fields
+    protocol_version: ProtocolVersion,
+    /// Clean session in MQTT 3.1.1, clean start in MQTT 5.0
     clean_session: bool,
     keep_alive: u16,
+    properties: Properties,
 
     // Payload Fields
     client_id: EncodedString,
     will: Option<Will>,
     login: Option<Login>,
+    /// Public key of the client to agree the session key, only sent over encrypted connections
+    key_share: Option<KeyShare>,
 }
 
 impl Connect {
@@ -27,15 +34,36 @@ impl Connect {
         login: Option<Login>,
     ) -> Self {
         Self {
+            protocol_version: ProtocolVersion::V311,
             clean_session,
             keep_alive,
+            properties: Properties::new(),
             client_id,
             will,
             login,
+            key_share: None,
         }
     }
 
-    /// Converts a stream of bytes into a Connect.
+    /// Adds the public key of the client to agree the session key
+    pub fn with_key_share(mut self, key_share: KeyShare) -> Self {
+        self.key_share = Some(key_share);
+        self
+    }
+
+    /// Sets the version of MQTT of the connection, MQTT 3.1.1 by default
+    pub fn with_protocol_version(mut self, protocol_version: ProtocolVersion) -> Self {
+        self.protocol_version = protocol_version;
+        self
+    }
+
+    /// Sets the properties, only sent in MQTT 5.0
+    pub fn with_properties(mut self, properties: Properties) -> Self {
+        self.properties = properties;
+        self
+    }
+
+    /// Converts a stream of bytes into a Connect of either version.
     pub fn from_bytes(fixed_header: FixedHeader, stream: &mut dyn Read) -> MqttResult<Self> {
         // Fixed Header
         let fixed_header_flags = fixed_header.first_byte() & 0b0000_1111;
@@ -47,22 +75,15 @@ impl Connect {
         // Variable Header
 
         let protocol_name = EncodedString::from_bytes(stream)?;
-        let protocol_name_content = protocol_name.content();
 
-        for i in 0..PROTOCOL_NAME.len() {
-            if protocol_name_content[i] != PROTOCOL_NAME[i] {
-                return Err(MqttError::InvalidProtocolName);
-            }
+        if protocol_name.content().as_slice() != PROTOCOL_NAME {
+            return Err(MqttError::InvalidProtocolName);
         }
 
         let protocol_level_buffer = &mut [0; 1];
         stream.read_exact(protocol_level_buffer)?;
 
-        let protocol_level_byte = protocol_level_buffer[0];
-
-        if protocol_level_byte != PROTOCOL_LEVEL {
-            return Err(MqttError::InvalidProtocolLevel);
-        }
+        let protocol_version = ProtocolVersion::from_level(protocol_level_buffer[0])?;
 
         let flags_buffer = &mut [0; 1];
         stream.read_exact(flags_buffer)?;
@@ -99,11 +120,21 @@ impl Connect {
 
         let keep_alive = u16::from_be_bytes(*keep_alive_buffer);
 
+        let properties = match protocol_version {
+            ProtocolVersion::V311 => Properties::new(),
+            ProtocolVersion::V5 => Properties::from_bytes(stream)?,
+        };
+
         // Payload
         let client_id = EncodedString::from_bytes(stream)?;
 
         let will = if will_flag {
-            Some(Will::from_bytes(stream, will_qos, will_retain)?)
+            let will_properties = match protocol_version {
+                ProtocolVersion::V311 => Properties::new(),
+                ProtocolVersion::V5 => Properties::from_bytes(stream)?,
+            };
+            let will = Will::from_bytes(stream, will_qos, will_retain)?;
+            Some(will.with_properties(will_properties))
         } else {
             None
         };
@@ -114,23 +145,31 @@ impl Connect {
             None
         };
 
-        Ok(Connect::new(
+        let key_share = KeyShare::from_bytes(stream, false)?;
+
+        Ok(Connect {
+            protocol_version,
             clean_session,
             keep_alive,
+            properties,
             client_id,
             will,
             login,
-        ))
+            key_share,
+        })
     }
 
-    /// Converts the Connect into a vector of bytes.
-    pub fn to_bytes(&self, key: &[u8]) -> Vec<u8> {
+    /// Converts the Connect into a vector of bytes in the format of its version.
+    pub fn to_bytes<'a>(&self, key: impl Into<FrameKey<'a>>) -> Vec<u8> {
         // Payload
         let mut payload_bytes = vec![];
 
         payload_bytes.extend(self.client_id.to_bytes());
 
         if let Some(will) = &self.will {
+            if self.protocol_version == ProtocolVersion::V5 {
+                payload_bytes.extend(will.properties().to_bytes());
+            }
             payload_bytes.extend(will.to_bytes());
         }
 
@@ -138,13 +177,17 @@ impl Connect {
             payload_bytes.extend(login.to_bytes());
         }
 
+        if let Some(key_share) = &self.key_share {
+            payload_bytes.extend(key_share.to_bytes());
+        }
+
         // Variable Header
         let mut variable_header_bytes = vec![];
 
         let protocol_name = EncodedString::new(PROTOCOL_NAME.to_vec());
         variable_header_bytes.extend(protocol_name.to_bytes());
 
-        variable_header_bytes.push(PROTOCOL_LEVEL);
+        variable_header_bytes.push(self.protocol_version.level());
 
         let (will_flag, will_qos, retain_flag) = match &self.will {
             Some(will) => (true, will.qos(), will.retain()),
@@ -166,6 +209,10 @@ impl Connect {
         variable_header_bytes.push(flags_byte);
         variable_header_bytes.extend(&self.keep_alive.to_be_bytes());
 
+        if self.protocol_version == ProtocolVersion::V5 {
+            variable_header_bytes.extend(self.properties.to_bytes());
+        }
+
         let mut fixed_header_bytes = vec![CONNECT_PACKET_TYPE << 4 | RESERVED_FIXED_HEADER_FLAGS];
 
         // Fixed Header
@@ -176,7 +223,7 @@ impl Connect {
 
         // Packet
         let data_bytes = [&variable_header_bytes[..], &payload_bytes[..]].concat();
-        let encrypted_bytes = match encrypt(data_bytes, key) {
+        let encrypted_bytes = match encrypt(data_bytes, key.into()) {
             Ok(bytes) => bytes,
             Err(_) => return vec![],
         };
@@ -189,7 +236,12 @@ impl Connect {
         packet_bytes
     }
 
-    /// Returns if the session is clean.
+    /// Returns the version of MQTT chosen by the Connect.
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.protocol_version
+    }
+
+    /// Returns if the session is clean, or in MQTT 5.0 if the session starts clean.
     pub fn clean_session(&self) -> bool {
         self.clean_session
     }
@@ -204,6 +256,11 @@ impl Connect {
         &self.client_id
     }
 
+    /// Returns the properties of the Connect, always empty in MQTT 3.1.1
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
+
     /// Returns a reference to the Connect's Will.
     pub fn will(&self) -> Option<&Will> {
         self.will.as_ref()
@@ -213,12 +270,21 @@ impl Connect {
     pub fn login(&self) -> Option<&Login> {
         self.login.as_ref()
     }
+
+    /// Returns the public key of the client to agree the session key
+    pub fn key_share(&self) -> Option<&KeyShare> {
+        self.key_share.as_ref()
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{encryptation::encryping_tool::decrypt, FixedHeader, TopicName};
+    use crate::{
+        encryptation::encryping_tool::decrypt,
+        model::{components::properties::Property, packet::Packet},
+        FixedHeader, TopicName,
+    };
 
     const KEY: &[u8; 32] = &[0; 32];
 
@@ -232,7 +298,7 @@ mod test {
     #[allow(dead_code)]
     fn variable_header_bytes(flags: u8, keep_alive: u16) -> Vec<u8> {
         let protocol_name_bytes = EncodedString::new(PROTOCOL_NAME.to_vec()).to_bytes();
-        let protocol_level_byte = vec![PROTOCOL_LEVEL];
+        let protocol_level_byte = vec![ProtocolVersion::V311.level()];
 
         let keep_alive_bytes = keep_alive.to_be_bytes();
 
@@ -262,7 +328,7 @@ mod test {
         let connect = Connect::new(clean_session, keep_alive, client_id, None, None);
         let connect_encrypted_bytes = connect.to_bytes(KEY);
         let fixed_header_bytes = connect_encrypted_bytes[0..2].to_vec();
-        let decrypted_bytes = decrypt(&connect_encrypted_bytes[2..], KEY).unwrap();
+        let decrypted_bytes = decrypt(&connect_encrypted_bytes[2..], KEY.into()).unwrap();
         let connect_bytes = [fixed_header_bytes, decrypted_bytes].concat();
 
         let expected_header_bytes = header_bytes(RemainingLength::new(13), 0b0000_0000, 10);
@@ -291,7 +357,7 @@ mod test {
 
         let connect_encrypted_bytes = connect.to_bytes(KEY);
         let fixed_header_bytes = connect_encrypted_bytes[0..2].to_vec();
-        let decrypted_bytes = decrypt(&connect_encrypted_bytes[2..], KEY).unwrap();
+        let decrypted_bytes = decrypt(&connect_encrypted_bytes[2..], KEY.into()).unwrap();
         let connect_bytes = [fixed_header_bytes, decrypted_bytes].concat();
 
         let expected_header_bytes = header_bytes(RemainingLength::new(39), 0b0010_1100, 10);
@@ -320,7 +386,7 @@ mod test {
 
         let connect_encrypted_bytes = connect.to_bytes(KEY);
         let fixed_header_bytes = connect_encrypted_bytes[0..2].to_vec();
-        let decrypted_bytes = decrypt(&connect_encrypted_bytes[2..], KEY).unwrap();
+        let decrypted_bytes = decrypt(&connect_encrypted_bytes[2..], KEY.into()).unwrap();
         let connect_bytes = [fixed_header_bytes, decrypted_bytes].concat();
 
         let expected_header_bytes = header_bytes(RemainingLength::new(33), 0b1100_0000, 10);
@@ -432,6 +498,19 @@ mod test {
         assert!(connect.is_err());
     }
 
+    #[test]
+    fn test_short_protocol_name() {
+        let connect_bytes = EncodedString::new(b"MQ".to_vec()).to_bytes();
+
+        let fixed_header = FixedHeader::new(
+            CONNECT_PACKET_TYPE << 4 | RESERVED_FIXED_HEADER_FLAGS,
+            RemainingLength::new(13),
+        );
+
+        let connect = Connect::from_bytes(fixed_header, &mut connect_bytes.as_slice());
+        assert!(matches!(connect, Err(MqttError::InvalidProtocolName)));
+    }
+
     #[test]
     fn test_invalid_protocol_level() {
         let protocol_name = EncodedString::new(PROTOCOL_NAME.to_vec()).to_bytes();
@@ -451,7 +530,7 @@ mod test {
     #[test]
     fn test_invalid_flags() {
         let protocol_name_bytes = EncodedString::new(PROTOCOL_NAME.to_vec()).to_bytes();
-        let protocol_level_byte = vec![PROTOCOL_LEVEL];
+        let protocol_level_byte = vec![ProtocolVersion::V311.level()];
 
         let mut connect_bytes = vec![];
         connect_bytes.extend(protocol_name_bytes);
@@ -512,4 +591,55 @@ mod test {
             assert!(connect.is_err());
         }
     }
+
+    #[test]
+    fn test_connect_with_key_share_to_bytes_and_back() {
+        let key_share = KeyShare::new([7; 32]);
+        let connect = Connect::new(true, 10, EncodedString::new(b"a".to_vec()), None, None)
+            .with_key_share(key_share.clone());
+
+        let connect_bytes = connect.to_bytes(KEY);
+        let connect = match Packet::from_bytes(&mut connect_bytes.as_slice(), KEY) {
+            Ok(Packet::Connect(connect)) => connect,
+            packet => panic!("Expected a Connect, received {:?}", packet),
+        };
+
+        assert_eq!(connect.client_id(), &EncodedString::new(b"a".to_vec()));
+        assert_eq!(connect.key_share(), Some(&key_share));
+    }
+
+    #[test]
+    fn test_mqtt_5_connect_to_bytes_and_back() {
+        let will_properties = Properties::new().with(Property::MessageExpiryInterval(60));
+        let will = Will::new(
+            QoS::AtLeast,
+            false,
+            TopicName::new(vec![b"drone".to_vec(), b"lost".to_vec()], false),
+            EncodedString::new(b"1".to_vec()),
+        )
+        .with_properties(will_properties);
+        let properties = Properties::new().with(Property::SessionExpiryInterval(300));
+        let key_share = KeyShare::new([7; 32]);
+        let connect = Connect::new(
+            true,
+            10,
+            EncodedString::new(b"a".to_vec()),
+            Some(will.clone()),
+            None,
+        )
+        .with_protocol_version(ProtocolVersion::V5)
+        .with_properties(properties.clone())
+        .with_key_share(key_share.clone());
+
+        let connect_bytes = connect.to_bytes(KEY);
+        let connect = match Packet::from_bytes(&mut connect_bytes.as_slice(), KEY) {
+            Ok(Packet::Connect(connect)) => connect,
+            packet => panic!("Expected a Connect, received {:?}", packet),
+        };
+
+        assert_eq!(connect.protocol_version(), ProtocolVersion::V5);
+        assert_eq!(connect.properties(), &properties);
+        assert_eq!(connect.will(), Some(&will));
+        assert_eq!(connect.key_share(), Some(&key_share));
+    }
 }
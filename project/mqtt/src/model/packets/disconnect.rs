@@ -1,17 +1,56 @@
-use super::{DISCONNECT_PACKET_TYPE, RESERVED_FIXED_HEADER_FLAGS};
-use crate::{FixedHeader, MqttError, MqttResult, RemainingLength};
+use super::{
+    read_reason_code, reason_code_bytes, DISCONNECT_PACKET_TYPE, RESERVED_FIXED_HEADER_FLAGS,
+};
+use crate::{
+    encrypt, FixedHeader, FrameKey, MqttError, MqttResult, Properties, ProtocolVersion, Read,
+    ReasonCode, RemainingLength,
+};
 
 /// Represents a DISCONNECT packet in MQTT. The client uses it to disconnect from the server.
-#[derive(Debug, Default, PartialEq)]
-pub struct Disconnect;
+/// In MQTT 5.0 it may carry a reason code and properties, and both sides can send it
+#[derive(Debug, PartialEq)]
+pub struct Disconnect {
+    reason_code: ReasonCode,
+    properties: Properties,
+}
+
+impl Default for Disconnect {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Disconnect {
     pub fn new() -> Self {
-        Self
+        Self {
+            reason_code: ReasonCode::Success,
+            properties: Properties::new(),
+        }
+    }
+
+    /// Sets the reason code, only sent in MQTT 5.0
+    pub fn with_reason_code(mut self, reason_code: ReasonCode) -> Self {
+        self.reason_code = reason_code;
+        self
+    }
+
+    /// Sets the properties, only sent in MQTT 5.0
+    pub fn with_properties(mut self, properties: Properties) -> Self {
+        self.properties = properties;
+        self
     }
 
     /// Converts a stream of bytes into a Disconnect.
     pub fn from_bytes(fixed_header: FixedHeader) -> MqttResult<Self> {
+        Disconnect::from_bytes_with_version(fixed_header, &mut [].as_slice(), ProtocolVersion::V311)
+    }
+
+    /// Converts a stream of bytes in the format of the version into a Disconnect.
+    pub fn from_bytes_with_version(
+        fixed_header: FixedHeader,
+        stream: &mut dyn Read,
+        version: ProtocolVersion,
+    ) -> MqttResult<Self> {
         // Fixed Header
         let fixed_header_flags = fixed_header.first_byte() & 0b0000_1111;
 
@@ -19,38 +58,87 @@ impl Disconnect {
             return Err(MqttError::InvalidFixedHeaderFlags);
         }
 
-        Ok(Disconnect::new())
+        let (reason_code, properties) = match version {
+            ProtocolVersion::V311 => (ReasonCode::Success, Properties::new()),
+            ProtocolVersion::V5 => {
+                read_reason_code(stream, fixed_header.remaining_length().value())?
+            }
+        };
+
+        Ok(Disconnect {
+            reason_code,
+            properties,
+        })
     }
 
-    /// Converts the Disconnect into a vector of bytes.
-    pub fn to_bytes(&self, _key: &[u8]) -> Vec<u8> {
+    /// Converts the Disconnect into a vector of bytes. It has no content, but when it is encrypted
+    /// it still carries its sequence number
+    pub fn to_bytes<'a>(&self, key: impl Into<FrameKey<'a>>) -> Vec<u8> {
+        self.to_bytes_with_version(key, ProtocolVersion::V311)
+    }
+
+    /// Converts the Disconnect into a vector of bytes in the format of the version.
+    pub fn to_bytes_with_version<'a>(
+        &self,
+        key: impl Into<FrameKey<'a>>,
+        version: ProtocolVersion,
+    ) -> Vec<u8> {
+        let variable_header_bytes = match version {
+            ProtocolVersion::V311 => vec![],
+            ProtocolVersion::V5 => reason_code_bytes(self.reason_code, &self.properties),
+        };
+
         // Fixed Header
         let mut packet_bytes = vec![DISCONNECT_PACKET_TYPE << 4 | RESERVED_FIXED_HEADER_FLAGS];
 
-        let remaining_length_value = 0;
+        let remaining_length_value = variable_header_bytes.len() as u32;
         let remaining_length_bytes = RemainingLength::new(remaining_length_value).to_bytes();
         packet_bytes.extend(remaining_length_bytes);
 
+        match encrypt(variable_header_bytes, key.into()) {
+            Ok(encrypted_bytes) => packet_bytes.extend(encrypted_bytes),
+            Err(_) => return vec![],
+        }
+
         packet_bytes
     }
+
+    /// Returns the reason code, always normal disconnection in MQTT 3.1.1
+    pub fn reason_code(&self) -> ReasonCode {
+        self.reason_code
+    }
+
+    /// Returns the properties, always empty in MQTT 3.1.1
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{encryptation::EXTRA_DATA_SIZE, NO_ENCRYPTION};
 
     const KEY: &[u8; 32] = &[0; 32];
 
     #[test]
     fn test_disconnect_to_bytes() {
         let disconnect = Disconnect::new();
-        let bytes = disconnect.to_bytes(KEY);
+        let bytes = disconnect.to_bytes(NO_ENCRYPTION);
 
         let expected_bytes: Vec<u8> = vec![0b1110_0000, 0x00];
 
         assert_eq!(bytes, expected_bytes);
     }
 
+    #[test]
+    fn test_encrypted_disconnect_carries_its_sequence_number() {
+        let bytes = Disconnect::new().to_bytes(FrameKey::new(KEY, 3));
+
+        assert_eq!(bytes.len(), 2 + EXTRA_DATA_SIZE);
+        assert_eq!(&bytes[6..14], &3_u64.to_be_bytes());
+    }
+
     #[test]
     fn test_disconnect_from_bytes() {
         let remaining_length = RemainingLength::new(2_u32);
@@ -58,4 +146,17 @@ mod tests {
         let disconnect = Disconnect::from_bytes(fixed_header).unwrap();
         assert_eq!(disconnect, Disconnect::new());
     }
+
+    #[test]
+    fn test_mqtt_5_disconnect_with_will_message() {
+        let disconnect = Disconnect::new().with_reason_code(ReasonCode::DisconnectWithWillMessage);
+        let bytes = disconnect.to_bytes_with_version(NO_ENCRYPTION, ProtocolVersion::V5);
+        assert_eq!(bytes, vec![0b1110_0000, 0x01, 0x04]);
+
+        let stream = &mut bytes.as_slice();
+        let fixed_header = FixedHeader::from_bytes(stream).unwrap();
+        let read =
+            Disconnect::from_bytes_with_version(fixed_header, stream, ProtocolVersion::V5).unwrap();
+        assert_eq!(read, disconnect);
+    }
 }
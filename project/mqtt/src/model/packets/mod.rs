@@ -1,3 +1,7 @@
+use crate::{MqttResult, Properties, ProtocolVersion, Read, ReasonCode};
+
+/// AUTH
+pub mod auth;
 /// CONNACK
 pub mod connack;
 /// CONNECT
@@ -10,8 +14,14 @@ pub mod pingreq;
 pub mod pingresp;
 /// PUBACK
 pub mod puback;
+/// PUBCOMP
+pub mod pubcomp;
 /// PUBLISH
 pub mod publish;
+/// PUBREC
+pub mod pubrec;
+/// PUBREL
+pub mod pubrel;
 /// SUBACK
 pub mod suback;
 /// SUBSCRIBE
@@ -25,6 +35,9 @@ pub const CONNECT_PACKET_TYPE: u8 = 0x1;
 pub const CONNACK_PACKET_TYPE: u8 = 0x2;
 pub const PUBLISH_PACKET_TYPE: u8 = 0x3;
 pub const PUBACK_PACKET_TYPE: u8 = 0x4;
+pub const PUBREC_PACKET_TYPE: u8 = 0x5;
+pub const PUBREL_PACKET_TYPE: u8 = 0x6;
+pub const PUBCOMP_PACKET_TYPE: u8 = 0x7;
 pub const SUBSCRIBE_PACKET_TYPE: u8 = 0x8;
 pub const SUBACK_PACKET_TYPE: u8 = 0x9;
 pub const UNSUBSCRIBE_PACKET_TYPE: u8 = 0xA;
@@ -32,7 +45,51 @@ pub const UNSUBACK_PACKET_TYPE: u8 = 0xB;
 pub const PINGREQ_PACKET_TYPE: u8 = 0xC;
 pub const PINGRESP_PACKET_TYPE: u8 = 0xD;
 pub const DISCONNECT_PACKET_TYPE: u8 = 0xE;
+pub const AUTH_PACKET_TYPE: u8 = 0xF;
 
 const RESERVED_FIXED_HEADER_FLAGS: u8 = 0x00;
 
 const DEFAULT_VARIABLE_HEADER_LENGTH: usize = 2;
+
+/// Reads the reason code and the properties of an MQTT 5.0 packet that leaves them out when the reason
+/// is success and there are no properties, given the amount of bytes left of the packet
+fn read_reason_code(stream: &mut dyn Read, length: usize) -> MqttResult<(ReasonCode, Properties)> {
+    if length == 0 {
+        return Ok((ReasonCode::Success, Properties::new()));
+    }
+
+    let mut reason_code_buffer = [0; 1];
+    stream.read_exact(&mut reason_code_buffer)?;
+    let reason_code = ReasonCode::from_byte(reason_code_buffer[0])?;
+
+    let properties = if length > 1 {
+        Properties::from_bytes(stream)?
+    } else {
+        Properties::new()
+    };
+
+    Ok((reason_code, properties))
+}
+
+/// Converts the reason code and the properties of an MQTT 5.0 packet into bytes, leaving them out
+/// when the reason is success and there are no properties
+fn reason_code_bytes(reason_code: ReasonCode, properties: &Properties) -> Vec<u8> {
+    let mut bytes = vec![];
+
+    if reason_code != ReasonCode::Success || !properties.is_empty() {
+        bytes.push(reason_code.to_byte());
+    }
+    if !properties.is_empty() {
+        bytes.extend(properties.to_bytes());
+    }
+
+    bytes
+}
+
+/// Returns the amount of bytes of the properties in a packet of the version, none in MQTT 3.1.1
+fn properties_length(properties: &Properties, version: ProtocolVersion) -> usize {
+    match version {
+        ProtocolVersion::V311 => 0,
+        ProtocolVersion::V5 => properties.length(),
+    }
+}
@@ -1,5 +1,5 @@
 use super::{PINGRESP_PACKET_TYPE, RESERVED_FIXED_HEADER_FLAGS};
-use crate::{FixedHeader, MqttError, MqttResult, RemainingLength};
+use crate::{encrypt, FixedHeader, FrameKey, MqttError, MqttResult, RemainingLength};
 
 /// Represents a PINGRESP packet from MQTT. The server responds to the client's PING request.
 #[derive(Debug, Default, PartialEq)]
@@ -22,8 +22,9 @@ impl Pingresp {
         Ok(Pingresp::new())
     }
 
-    /// Converts the Pingresp into a vector of bytes.
-    pub fn to_bytes(&self, _key: &[u8]) -> Vec<u8> {
+    /// Converts the Pingresp into a vector of bytes. It has no content, but when it is encrypted
+    /// it still carries its sequence number
+    pub fn to_bytes<'a>(&self, key: impl Into<FrameKey<'a>>) -> Vec<u8> {
         // Fixed Header
         let mut packet_bytes = vec![PINGRESP_PACKET_TYPE << 4 | RESERVED_FIXED_HEADER_FLAGS];
 
@@ -31,6 +32,11 @@ impl Pingresp {
         let remaining_length_bytes = RemainingLength::new(remaining_length_value).to_bytes();
         packet_bytes.extend(remaining_length_bytes);
 
+        match encrypt(vec![], key.into()) {
+            Ok(encrypted_bytes) => packet_bytes.extend(encrypted_bytes),
+            Err(_) => return vec![],
+        }
+
         packet_bytes
     }
 }
@@ -38,19 +44,28 @@ impl Pingresp {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{encryptation::EXTRA_DATA_SIZE, NO_ENCRYPTION};
 
     const KEY: &[u8; 32] = &[0; 32];
 
     #[test]
     fn test_pingresp_to_bytes() {
         let pingresp = Pingresp::new();
-        let bytes = pingresp.to_bytes(KEY);
+        let bytes = pingresp.to_bytes(NO_ENCRYPTION);
 
         let expected_bytes: Vec<u8> = vec![0b1101_0000, 0x00];
 
         assert_eq!(bytes, expected_bytes);
     }
 
+    #[test]
+    fn test_encrypted_pingresp_carries_its_sequence_number() {
+        let bytes = Pingresp::new().to_bytes(FrameKey::new(KEY, 3));
+
+        assert_eq!(bytes.len(), 2 + EXTRA_DATA_SIZE);
+        assert_eq!(&bytes[6..14], &3_u64.to_be_bytes());
+    }
+
     #[test]
     fn test_pingresp_from_bytes() {
         let remaining_length = RemainingLength::new(2_u32);
@@ -1,23 +1,54 @@
 use std::fmt::{self, Display, Formatter};
 
-use super::{PUBACK_PACKET_TYPE, RESERVED_FIXED_HEADER_FLAGS};
-use crate::{encrypt, FixedHeader, MqttError, MqttResult, Read, RemainingLength};
+use super::{read_reason_code, reason_code_bytes, PUBACK_PACKET_TYPE, RESERVED_FIXED_HEADER_FLAGS};
+use crate::{
+    encrypt, FixedHeader, FrameKey, MqttError, MqttResult, Properties, ProtocolVersion, Read,
+    ReasonCode, RemainingLength,
+};
 
 const PACKAGE_IDENTIFIER_LENGTH: usize = 2;
 
 /// Represents a PUBACK packet from MQTT. The server uses it to confirm the reception of a PUBLISH packet.
+/// In MQTT 5.0 it also tells with its reason code whether the message was accepted
 #[derive(Debug, PartialEq)]
 pub struct Puback {
     packet_identifier: Option<u16>,
+    reason_code: ReasonCode,
+    properties: Properties,
 }
 
 impl Puback {
     pub fn new(packet_identifier: Option<u16>) -> Self {
-        Self { packet_identifier }
+        Self {
+            packet_identifier,
+            reason_code: ReasonCode::Success,
+            properties: Properties::new(),
+        }
+    }
+
+    /// Sets the reason code, only sent in MQTT 5.0
+    pub fn with_reason_code(mut self, reason_code: ReasonCode) -> Self {
+        self.reason_code = reason_code;
+        self
+    }
+
+    /// Sets the properties, only sent in MQTT 5.0
+    pub fn with_properties(mut self, properties: Properties) -> Self {
+        self.properties = properties;
+        self
     }
 
     /// Converts a stream of bytes into a Puback.
     pub fn from_bytes(fixed_header: FixedHeader, stream: &mut dyn Read) -> MqttResult<Self> {
+        Puback::from_bytes_with_version(fixed_header, stream, ProtocolVersion::V311)
+    }
+
+    /// Converts a stream of bytes in the format of the version into a Puback.
+    pub fn from_bytes_with_version(
+        fixed_header: FixedHeader,
+        stream: &mut dyn Read,
+        version: ProtocolVersion,
+    ) -> MqttResult<Self> {
         // Fixed Header
         let fixed_header_flags = fixed_header.first_byte() & 0b0000_1111;
 
@@ -31,11 +62,36 @@ impl Puback {
 
         let packet_identifier = Some(u16::from_be_bytes(packet_identifier_buffer));
 
-        Ok(Puback::new(packet_identifier))
+        let (reason_code, properties) = match version {
+            ProtocolVersion::V311 => (ReasonCode::Success, Properties::new()),
+            ProtocolVersion::V5 => {
+                let length = fixed_header
+                    .remaining_length()
+                    .value()
+                    .checked_sub(PACKAGE_IDENTIFIER_LENGTH)
+                    .ok_or(MqttError::InvalidRemainingLength)?;
+                read_reason_code(stream, length)?
+            }
+        };
+
+        Ok(Puback {
+            packet_identifier,
+            reason_code,
+            properties,
+        })
     }
 
     /// Converts the Puback into a vector of bytes.
-    pub fn to_bytes(&self, key: &[u8]) -> Vec<u8> {
+    pub fn to_bytes<'a>(&self, key: impl Into<FrameKey<'a>>) -> Vec<u8> {
+        self.to_bytes_with_version(key, ProtocolVersion::V311)
+    }
+
+    /// Converts the Puback into a vector of bytes in the format of the version.
+    pub fn to_bytes_with_version<'a>(
+        &self,
+        key: impl Into<FrameKey<'a>>,
+        version: ProtocolVersion,
+    ) -> Vec<u8> {
         // Variable Header
         let mut variable_header_bytes = vec![];
 
@@ -44,6 +100,10 @@ impl Puback {
             variable_header_bytes.extend_from_slice(&packet_identifier.to_be_bytes());
         }
 
+        if version == ProtocolVersion::V5 {
+            variable_header_bytes.extend(reason_code_bytes(self.reason_code, &self.properties));
+        }
+
         // Fixed Header
         let mut fixed_header_bytes = vec![PUBACK_PACKET_TYPE << 4 | RESERVED_FIXED_HEADER_FLAGS];
 
@@ -52,7 +112,7 @@ impl Puback {
         fixed_header_bytes.extend(remaining_length_bytes);
 
         // Packet
-        let encrypted_bytes = match encrypt(variable_header_bytes, key) {
+        let encrypted_bytes = match encrypt(variable_header_bytes, key.into()) {
             Ok(bytes) => bytes,
             Err(_) => return vec![],
         };
@@ -69,6 +129,16 @@ impl Puback {
     pub fn packet_identifier(&self) -> Option<u16> {
         self.packet_identifier
     }
+
+    /// Returns the reason code, always success in MQTT 3.1.1
+    pub fn reason_code(&self) -> ReasonCode {
+        self.reason_code
+    }
+
+    /// Returns the properties, always empty in MQTT 3.1.1
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
 }
 
 impl Display for Puback {
@@ -87,7 +157,10 @@ impl Display for Puback {
 
 #[cfg(test)]
 mod tests {
-    use crate::encryptation::encryping_tool::decrypt;
+    use crate::{
+        encryptation::encryping_tool::decrypt, model::components::properties::Property,
+        NO_ENCRYPTION,
+    };
 
     use super::*;
 
@@ -98,7 +171,7 @@ mod tests {
         let puback = Puback::new(Some(42));
         let encrypted_bytes = puback.to_bytes(KEY);
         let fixed_header = encrypted_bytes[0..2].to_vec();
-        let decrypted_bytes = decrypt(&encrypted_bytes[2..], KEY).unwrap();
+        let decrypted_bytes = decrypt(&encrypted_bytes[2..], KEY.into()).unwrap();
         let puback_bytes = [&fixed_header[..], &decrypted_bytes[..]].concat();
 
         let expected_bytes: Vec<u8> = vec![0b0100_0000, 0x02, 0x00, 0x2A];
@@ -113,4 +186,35 @@ mod tests {
         let puback = Puback::from_bytes(fixed_header, &mut stream).unwrap();
         assert_eq!(puback, Puback::new(Some(42)));
     }
+
+    #[test]
+    fn test_mqtt_5_puback_leaves_out_the_success_reason_code() {
+        let puback = Puback::new(Some(42));
+
+        assert_eq!(
+            puback.to_bytes_with_version(NO_ENCRYPTION, ProtocolVersion::V5),
+            vec![0b0100_0000, 0x02, 0x00, 0x2A]
+        );
+    }
+
+    #[test]
+    fn test_mqtt_5_puback_with_reason_code_to_bytes_and_back() {
+        let properties = Properties::new().with(Property::ReasonString("denied".to_string()));
+        let puback = Puback::new(Some(42))
+            .with_reason_code(ReasonCode::NotAuthorized)
+            .with_properties(properties.clone());
+
+        let bytes = puback.to_bytes_with_version(NO_ENCRYPTION, ProtocolVersion::V5);
+        let stream = &mut bytes.as_slice();
+        let fixed_header = FixedHeader::from_bytes(stream).unwrap();
+        let read =
+            Puback::from_bytes_with_version(fixed_header, stream, ProtocolVersion::V5).unwrap();
+
+        assert_eq!(read.packet_identifier(), Some(42));
+        assert_eq!(read.reason_code(), ReasonCode::NotAuthorized);
+        assert_eq!(read.properties(), &properties);
+
+        // MQTT 3.1.1 leaves them out
+        assert_eq!(puback.to_bytes(NO_ENCRYPTION).len(), 4);
+    }
 }
@@ -0,0 +1,167 @@
+use super::{
+    read_reason_code, reason_code_bytes, DEFAULT_VARIABLE_HEADER_LENGTH, PUBCOMP_PACKET_TYPE,
+    RESERVED_FIXED_HEADER_FLAGS,
+};
+use crate::{
+    encrypt, FixedHeader, FrameKey, MqttError, MqttResult, Properties, ProtocolVersion, Read,
+    ReasonCode, RemainingLength,
+};
+
+/// Represents a PUBCOMP packet from MQTT. It is the response to a PUBREL packet, the last step of the exactly once delivery.
+#[derive(Debug)]
+pub struct Pubcomp {
+    packet_identifier: u16,
+    reason_code: ReasonCode,
+    properties: Properties,
+}
+
+impl Pubcomp {
+    pub fn new(packet_identifier: u16) -> Self {
+        Self {
+            packet_identifier,
+            reason_code: ReasonCode::Success,
+            properties: Properties::new(),
+        }
+    }
+
+    /// Sets the reason code, only sent in MQTT 5.0
+    pub fn with_reason_code(mut self, reason_code: ReasonCode) -> Self {
+        self.reason_code = reason_code;
+        self
+    }
+
+    /// Sets the properties, only sent in MQTT 5.0
+    pub fn with_properties(mut self, properties: Properties) -> Self {
+        self.properties = properties;
+        self
+    }
+
+    /// Converts a stream of bytes into a Pubcomp.
+    pub fn from_bytes(fixed_header: FixedHeader, stream: &mut dyn Read) -> MqttResult<Self> {
+        Pubcomp::from_bytes_with_version(fixed_header, stream, ProtocolVersion::V311)
+    }
+
+    /// Converts a stream of bytes in the format of the version into a Pubcomp.
+    pub fn from_bytes_with_version(
+        fixed_header: FixedHeader,
+        stream: &mut dyn Read,
+        version: ProtocolVersion,
+    ) -> MqttResult<Self> {
+        // Fixed Header
+        let fixed_header_flags = fixed_header.first_byte() & 0b0000_1111;
+
+        if fixed_header_flags != RESERVED_FIXED_HEADER_FLAGS {
+            return Err(MqttError::InvalidFixedHeaderFlags);
+        }
+
+        // Variable Header
+        let mut variable_header_buffer = [0; DEFAULT_VARIABLE_HEADER_LENGTH];
+        stream.read_exact(&mut variable_header_buffer)?;
+
+        let packet_identifier =
+            u16::from_be_bytes([variable_header_buffer[0], variable_header_buffer[1]]);
+
+        let (reason_code, properties) = match version {
+            ProtocolVersion::V311 => (ReasonCode::Success, Properties::new()),
+            ProtocolVersion::V5 => {
+                let length = fixed_header
+                    .remaining_length()
+                    .value()
+                    .checked_sub(DEFAULT_VARIABLE_HEADER_LENGTH)
+                    .ok_or(MqttError::InvalidRemainingLength)?;
+                read_reason_code(stream, length)?
+            }
+        };
+
+        Ok(Pubcomp {
+            packet_identifier,
+            reason_code,
+            properties,
+        })
+    }
+
+    /// Converts the Pubcomp into a vector of bytes.
+    pub fn to_bytes<'a>(&self, key: impl Into<FrameKey<'a>>) -> Vec<u8> {
+        self.to_bytes_with_version(key, ProtocolVersion::V311)
+    }
+
+    /// Converts the Pubcomp into a vector of bytes in the format of the version.
+    pub fn to_bytes_with_version<'a>(
+        &self,
+        key: impl Into<FrameKey<'a>>,
+        version: ProtocolVersion,
+    ) -> Vec<u8> {
+        // Variable Header
+        let mut variable_header_bytes = self.packet_identifier.to_be_bytes().to_vec();
+
+        if version == ProtocolVersion::V5 {
+            variable_header_bytes.extend(reason_code_bytes(self.reason_code, &self.properties));
+        }
+
+        // Fixed Header
+        let mut fixed_header_bytes = vec![PUBCOMP_PACKET_TYPE << 4 | RESERVED_FIXED_HEADER_FLAGS];
+
+        let remaining_length_value = variable_header_bytes.len() as u32;
+        let remaining_length_bytes = RemainingLength::new(remaining_length_value).to_bytes();
+        fixed_header_bytes.extend(remaining_length_bytes);
+
+        let encrypted_bytes = match encrypt(variable_header_bytes, key.into()) {
+            Ok(bytes) => bytes,
+            Err(_) => return vec![],
+        };
+
+        let mut packet_bytes = vec![];
+
+        packet_bytes.extend(fixed_header_bytes);
+        packet_bytes.extend(encrypted_bytes);
+
+        packet_bytes
+    }
+
+    /// Returns the packet identifier.
+    pub fn packet_identifier(&self) -> u16 {
+        self.packet_identifier
+    }
+
+    /// Returns the reason code, always success in MQTT 3.1.1
+    pub fn reason_code(&self) -> ReasonCode {
+        self.reason_code
+    }
+
+    /// Returns the properties, always empty in MQTT 3.1.1
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encryptation::encryping_tool::decrypt;
+
+    use super::*;
+
+    const KEY: &[u8; 32] = &[0; 32];
+
+    #[test]
+    fn test_pubcomp_to_bytes() {
+        let pubcomp = Pubcomp::new(42);
+        let encrypted_bytes = pubcomp.to_bytes(KEY);
+        let fixed_header_bytes = encrypted_bytes[0..2].to_vec();
+        let decrypted_bytes = decrypt(&encrypted_bytes[2..], KEY.into()).unwrap();
+        let pubcomp_bytes = [&fixed_header_bytes[..], &decrypted_bytes[..]].concat();
+
+        let expected_bytes: Vec<u8> = vec![0b0111_0000, 0x02, 0x00, 0x2A];
+
+        assert_eq!(pubcomp_bytes, expected_bytes);
+    }
+
+    #[test]
+    fn test_pubcomp_from_bytes() {
+        let bytes: Vec<u8> = vec![0x00, 0x2A];
+
+        let fixed_header = FixedHeader::new(0b0111_0000, RemainingLength::new(2));
+        let pubcomp = Pubcomp::from_bytes(fixed_header, &mut bytes.as_slice()).unwrap();
+
+        assert_eq!(pubcomp.packet_identifier(), 42);
+    }
+}
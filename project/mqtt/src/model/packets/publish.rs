@@ -1,5 +1,8 @@
-use super::{DEFAULT_VARIABLE_HEADER_LENGTH, PUBLISH_PACKET_TYPE};
-use crate::{encrypt, FixedHeader, MqttResult, QoS, Read, RemainingLength, TopicName};
+use super::{properties_length, DEFAULT_VARIABLE_HEADER_LENGTH, PUBLISH_PACKET_TYPE};
+use crate::{
+    encrypt, FixedHeader, FrameKey, MqttError, MqttResult, Properties, ProtocolVersion, QoS, Read,
+    RemainingLength, TopicName,
+};
 
 /// Represents a PUBLISH packet of MQTT. The client uses it to publish a message to a topic.
 #[derive(Debug, Clone)]
@@ -9,6 +12,8 @@ pub struct Publish {
     retain: bool,
     topic: TopicName,
     package_identifier: Option<u16>,
+    /// Properties of MQTT 5.0, such as the message expiry interval, user properties and correlation data
+    properties: Properties,
     message: Vec<u8>,
 }
 
@@ -27,12 +32,28 @@ impl Publish {
             retain,
             topic,
             package_identifier,
+            properties: Properties::new(),
             message,
         }
     }
 
+    /// Sets the properties, only sent in MQTT 5.0
+    pub fn with_properties(mut self, properties: Properties) -> Self {
+        self.properties = properties;
+        self
+    }
+
     /// Converts a stream of bytes into a Publish.
     pub fn from_bytes(fixed_header: FixedHeader, stream: &mut dyn Read) -> MqttResult<Self> {
+        Publish::from_bytes_with_version(fixed_header, stream, ProtocolVersion::V311)
+    }
+
+    /// Converts a stream of bytes in the format of the version into a Publish.
+    pub fn from_bytes_with_version(
+        fixed_header: FixedHeader,
+        stream: &mut dyn Read,
+        version: ProtocolVersion,
+    ) -> MqttResult<Self> {
         // Fixed Header
 
         let fixed_header_flags = fixed_header.first_byte() & 0b0000_1111;
@@ -57,28 +78,41 @@ impl Publish {
             }
         };
 
-        let variable_header_len =
-            topic.length() + package_identifier.map_or(0, |_| DEFAULT_VARIABLE_HEADER_LENGTH);
+        let properties = match version {
+            ProtocolVersion::V311 => Properties::new(),
+            ProtocolVersion::V5 => Properties::from_bytes(stream)?,
+        };
+
+        let variable_header_len = topic.length()
+            + package_identifier.map_or(0, |_| DEFAULT_VARIABLE_HEADER_LENGTH)
+            + properties_length(&properties, version);
 
         // Payload
 
-        let payload_len = remaining_length - variable_header_len;
+        let payload_len = remaining_length
+            .checked_sub(variable_header_len)
+            .ok_or(MqttError::InvalidRemainingLength)?;
 
         let mut message = vec![0; payload_len];
         stream.read_exact(&mut message)?;
 
-        Ok(Publish::new(
-            dup,
-            qos,
-            retain,
-            topic,
-            package_identifier,
-            message,
-        ))
+        Ok(
+            Publish::new(dup, qos, retain, topic, package_identifier, message)
+                .with_properties(properties),
+        )
     }
 
     /// Converts the Publish into a vector of bytes.
-    pub fn to_bytes(&self, key: &[u8]) -> Vec<u8> {
+    pub fn to_bytes<'a>(&self, key: impl Into<FrameKey<'a>>) -> Vec<u8> {
+        self.to_bytes_with_version(key, ProtocolVersion::V311)
+    }
+
+    /// Converts the Publish into a vector of bytes in the format of the version.
+    pub fn to_bytes_with_version<'a>(
+        &self,
+        key: impl Into<FrameKey<'a>>,
+        version: ProtocolVersion,
+    ) -> Vec<u8> {
         // Payload
         let payload_bytes = &self.message;
 
@@ -91,6 +125,10 @@ impl Publish {
             variable_header_bytes.extend(&package_identifier.to_be_bytes());
         }
 
+        if version == ProtocolVersion::V5 {
+            variable_header_bytes.extend(self.properties.to_bytes());
+        }
+
         // Fixed Header
         let fixed_header_flags = (if self.dup { 1 } else { 0 } << 3)
             | (self.qos.to_byte() << 1)
@@ -108,7 +146,7 @@ impl Publish {
         data_bytes.extend(variable_header_bytes);
         data_bytes.extend(payload_bytes);
 
-        let encrypted_bytes = match encrypt(data_bytes, key) {
+        let encrypted_bytes = match encrypt(data_bytes, key.into()) {
             Ok(bytes) => bytes,
             Err(_) => return vec![],
         };
@@ -146,6 +184,16 @@ impl Publish {
         self.package_identifier
     }
 
+    /// Returns the properties of the packet, always empty in MQTT 3.1.1
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    /// Returns a mutable reference to the properties of the packet.
+    pub fn properties_mut(&mut self) -> &mut Properties {
+        &mut self.properties
+    }
+
     /// Returns the message of the packet.
     pub fn message(&self) -> &Vec<u8> {
         &self.message
@@ -155,7 +203,7 @@ impl Publish {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::EncodedString;
+    use crate::{model::components::properties::Property, EncodedString};
     use std::io::Cursor;
 
     const KEY: &[u8; 32] = &[0; 32];
@@ -182,6 +230,19 @@ mod tests {
         assert_eq!(publish.message(), &vec![b'c']);
     }
 
+    #[test]
+    fn test_remaining_length_shorter_than_the_topic() {
+        let mut stream =
+            std::io::Cursor::new(vec![0b0011_0000, 2_u8, 0x00, 0x03, b'a', b'/', b'b', b'c']);
+
+        let fixed_header = FixedHeader::from_bytes(&mut stream).unwrap();
+
+        assert!(matches!(
+            Publish::from_bytes(fixed_header, &mut stream),
+            Err(MqttError::InvalidRemainingLength)
+        ));
+    }
+
     #[test]
     fn test_to_bytes() {
         let bytes = &mut from_slice(b"a/b");
@@ -191,11 +252,37 @@ mod tests {
 
         let encrypted_bytes = publish.to_bytes(KEY);
         let fixed_header = encrypted_bytes[0..2].to_vec();
-        let decrypted_bytes = crate::decrypt(&encrypted_bytes[2..], KEY).unwrap();
+        let decrypted_bytes = crate::decrypt(&encrypted_bytes[2..], KEY.into()).unwrap();
         let bytes = [&fixed_header[..], &decrypted_bytes[..]].concat();
 
         let expected_bytes = vec![0b0011_0000, 6_u8, 0x00, 0x03, b'a', b'/', b'b', b'c'];
 
         assert_eq!(bytes, expected_bytes);
     }
+
+    #[test]
+    fn test_mqtt_5_publish_to_bytes_and_back() {
+        let topic_name = TopicName::from_bytes(&mut from_slice(b"a/b")).unwrap();
+        let properties = Properties::new()
+            .with(Property::ResponseTopic("a/reply".to_string()))
+            .with(Property::CorrelationData(vec![1, 2]))
+            .with(Property::UserProperty("drone".to_string(), "7".to_string()));
+        let publish = Publish::new(false, QoS::AtLeast, false, topic_name, Some(3), vec![b'c'])
+            .with_properties(properties.clone());
+
+        let bytes = publish.to_bytes_with_version(KEY, ProtocolVersion::V5);
+        let stream = &mut bytes.as_slice();
+        let fixed_header = FixedHeader::from_bytes(stream).unwrap();
+        let decrypted_bytes = crate::decrypt(stream, KEY.into()).unwrap();
+        let publish = Publish::from_bytes_with_version(
+            fixed_header,
+            &mut decrypted_bytes.as_slice(),
+            ProtocolVersion::V5,
+        )
+        .unwrap();
+
+        assert_eq!(publish.package_identifier(), Some(3));
+        assert_eq!(publish.properties(), &properties);
+        assert_eq!(publish.message(), &vec![b'c']);
+    }
 }
@@ -1,7 +1,10 @@
-use super::{DEFAULT_VARIABLE_HEADER_LENGTH, RESERVED_FIXED_HEADER_FLAGS, SUBACK_PACKET_TYPE};
+use super::{
+    properties_length, DEFAULT_VARIABLE_HEADER_LENGTH, RESERVED_FIXED_HEADER_FLAGS,
+    SUBACK_PACKET_TYPE,
+};
 use crate::{
-    encrypt, errors::error::MqttResult, FixedHeader, MqttError, Read, RemainingLength,
-    SubackReturnCode,
+    encrypt, errors::error::MqttResult, FixedHeader, FrameKey, MqttError, Properties,
+    ProtocolVersion, Read, ReasonCode, RemainingLength, SubackReturnCode,
 };
 
 /// Represents a SUBACK packet of MQTT. The server uses it to confirm the subscription to one or more topics.
@@ -9,18 +12,58 @@ use crate::{
 pub struct Suback {
     packet_identifier: u16,
     suback_return_codes: Vec<SubackReturnCode>,
+    /// Reason codes of MQTT 5.0, which match the return codes
+    reason_codes: Vec<ReasonCode>,
+    properties: Properties,
 }
 
 impl Suback {
     pub fn new(packet_identifier: u16, suback_return_codes: Vec<SubackReturnCode>) -> Self {
+        let reason_codes = suback_return_codes
+            .iter()
+            .map(SubackReturnCode::to_reason_code)
+            .collect();
+
+        Self {
+            packet_identifier,
+            suback_return_codes,
+            reason_codes,
+            properties: Properties::new(),
+        }
+    }
+
+    /// Creates a Suback with the reason codes of MQTT 5.0, and the return codes closest to them for MQTT 3.1.1
+    pub fn with_reason_codes(packet_identifier: u16, reason_codes: Vec<ReasonCode>) -> Self {
+        let suback_return_codes = reason_codes
+            .iter()
+            .map(|reason_code| SubackReturnCode::from_reason_code(*reason_code))
+            .collect();
+
         Self {
             packet_identifier,
             suback_return_codes,
+            reason_codes,
+            properties: Properties::new(),
         }
     }
 
+    /// Sets the properties, only sent in MQTT 5.0
+    pub fn with_properties(mut self, properties: Properties) -> Self {
+        self.properties = properties;
+        self
+    }
+
     /// Converts a stream of bytes into a Suback.
     pub fn from_bytes(fixed_header: FixedHeader, stream: &mut dyn Read) -> MqttResult<Self> {
+        Suback::from_bytes_with_version(fixed_header, stream, ProtocolVersion::V311)
+    }
+
+    /// Converts a stream of bytes in the format of the version into a Suback.
+    pub fn from_bytes_with_version(
+        fixed_header: FixedHeader,
+        stream: &mut dyn Read,
+        version: ProtocolVersion,
+    ) -> MqttResult<Self> {
         // Fixed Header
         let fixed_header_flags = fixed_header.first_byte() & 0b0000_1111;
 
@@ -37,28 +80,65 @@ impl Suback {
         let packet_identifier =
             u16::from_be_bytes([variable_header_buffer[0], variable_header_buffer[1]]);
 
-        let mut return_codes = vec![];
+        let properties = match version {
+            ProtocolVersion::V311 => Properties::new(),
+            ProtocolVersion::V5 => Properties::from_bytes(stream)?,
+        };
 
         // Payload
-        let mut payload_buffer = vec![0; remaining_length - DEFAULT_VARIABLE_HEADER_LENGTH];
+        let payload_length = remaining_length
+            .checked_sub(DEFAULT_VARIABLE_HEADER_LENGTH + properties_length(&properties, version))
+            .ok_or(MqttError::InvalidRemainingLength)?;
+        let mut payload_buffer = vec![0; payload_length];
         stream.read_exact(&mut payload_buffer)?;
 
-        for &return_code_byte in payload_buffer.iter() {
-            let return_code = SubackReturnCode::from_byte(return_code_byte)?;
-            return_codes.push(return_code);
-        }
+        let suback = match version {
+            ProtocolVersion::V311 => {
+                let return_codes = payload_buffer
+                    .iter()
+                    .map(|byte| SubackReturnCode::from_byte(*byte))
+                    .collect::<MqttResult<Vec<SubackReturnCode>>>()?;
+                Suback::new(packet_identifier, return_codes)
+            }
+            ProtocolVersion::V5 => {
+                let reason_codes = payload_buffer
+                    .iter()
+                    .map(|byte| ReasonCode::from_byte(*byte))
+                    .collect::<MqttResult<Vec<ReasonCode>>>()?;
+                Suback::with_reason_codes(packet_identifier, reason_codes)
+            }
+        };
 
-        Ok(Suback::new(packet_identifier, return_codes))
+        Ok(suback.with_properties(properties))
     }
 
     /// Converts the Suback into a vector of bytes.
-    pub fn to_bytes(&self, key: &[u8]) -> Vec<u8> {
+    pub fn to_bytes<'a>(&self, key: impl Into<FrameKey<'a>>) -> Vec<u8> {
+        self.to_bytes_with_version(key, ProtocolVersion::V311)
+    }
+
+    /// Converts the Suback into a vector of bytes in the format of the version.
+    pub fn to_bytes_with_version<'a>(
+        &self,
+        key: impl Into<FrameKey<'a>>,
+        version: ProtocolVersion,
+    ) -> Vec<u8> {
         // Variable Header
         let mut variable_header_bytes = self.packet_identifier.to_be_bytes().to_vec();
 
         // Payload
-        for return_code in &self.suback_return_codes {
-            variable_header_bytes.push(return_code.to_byte());
+        match version {
+            ProtocolVersion::V311 => {
+                for return_code in &self.suback_return_codes {
+                    variable_header_bytes.push(return_code.to_byte());
+                }
+            }
+            ProtocolVersion::V5 => {
+                variable_header_bytes.extend(self.properties.to_bytes());
+                for reason_code in &self.reason_codes {
+                    variable_header_bytes.push(reason_code.to_byte());
+                }
+            }
         }
 
         // Fixed Header
@@ -68,7 +148,7 @@ impl Suback {
         let remaining_length_bytes = RemainingLength::new(remaining_length_value).to_bytes();
         fixed_header_bytes.extend(remaining_length_bytes);
 
-        let encrypted_bytes = match encrypt(variable_header_bytes, key) {
+        let encrypted_bytes = match encrypt(variable_header_bytes, key.into()) {
             Ok(bytes) => bytes,
             Err(_) => return vec![],
         };
@@ -90,11 +170,21 @@ impl Suback {
     pub fn suback_return_codes(&self) -> &Vec<SubackReturnCode> {
         &self.suback_return_codes
     }
+
+    /// Returns the reason codes of MQTT 5.0, which match the return codes in MQTT 3.1.1
+    pub fn reason_codes(&self) -> &Vec<ReasonCode> {
+        &self.reason_codes
+    }
+
+    /// Returns the properties, always empty in MQTT 3.1.1
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::encryptation::encryping_tool::decrypt;
+    use crate::{encryptation::encryping_tool::decrypt, NO_ENCRYPTION};
 
     use super::*;
 
@@ -115,7 +205,7 @@ mod tests {
 
         let encrypted_bytes = suback.to_bytes(KEY);
         let fixed_header_bytes = &encrypted_bytes[0..2];
-        let decrypted_bytes = decrypt(&encrypted_bytes[2..], KEY).unwrap();
+        let decrypted_bytes = decrypt(&encrypted_bytes[2..], KEY.into()).unwrap();
         let suback_bytes = [fixed_header_bytes, &decrypted_bytes[..]].concat();
 
         assert_eq!(suback_bytes, expected_bytes);
@@ -137,4 +227,30 @@ mod tests {
         assert_eq!(return_codes[1], SubackReturnCode::SuccessMaximumQoS1);
         assert_eq!(return_codes[2], SubackReturnCode::SuccessMaximumQoS2);
     }
+
+    #[test]
+    fn test_mqtt_5_suback_to_bytes_and_back() {
+        let suback =
+            Suback::with_reason_codes(42, vec![ReasonCode::GrantedQoS1, ReasonCode::NotAuthorized]);
+
+        let bytes = suback.to_bytes_with_version(NO_ENCRYPTION, ProtocolVersion::V5);
+        assert_eq!(bytes, vec![0b1001_0000, 5, 0, 42, 0, 0x01, 0x87]);
+
+        let stream = &mut bytes.as_slice();
+        let fixed_header = FixedHeader::from_bytes(stream).unwrap();
+        let suback =
+            Suback::from_bytes_with_version(fixed_header, stream, ProtocolVersion::V5).unwrap();
+
+        assert_eq!(
+            suback.reason_codes(),
+            &vec![ReasonCode::GrantedQoS1, ReasonCode::NotAuthorized]
+        );
+        assert_eq!(
+            suback.suback_return_codes(),
+            &vec![
+                SubackReturnCode::SuccessMaximumQoS1,
+                SubackReturnCode::Failure
+            ]
+        );
+    }
 }
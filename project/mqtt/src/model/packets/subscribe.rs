@@ -1,11 +1,18 @@
-use super::{DEFAULT_VARIABLE_HEADER_LENGTH, RESERVED_FIXED_HEADER_FLAGS, SUBSCRIBE_PACKET_TYPE};
-use crate::{encrypt, FixedHeader, MqttError, MqttResult, QoS, Read, RemainingLength, TopicFilter};
+use super::{properties_length, DEFAULT_VARIABLE_HEADER_LENGTH, SUBSCRIBE_PACKET_TYPE};
+use crate::{
+    encrypt, FixedHeader, FrameKey, MqttError, MqttResult, Properties, ProtocolVersion, QoS, Read,
+    RemainingLength, TopicFilter,
+};
+
+/// The SUBSCRIBE fixed header flags are reserved and must be set to 0010.
+const SUBSCRIBE_FIXED_HEADER_FLAGS: u8 = 0x02;
 
 /// Represents a SUBSCRIBE packet of MQTT. The client uses it to subscribe to one or more topics.
 #[derive(Debug)]
 pub struct Subscribe {
     packet_identifier: u16,
     topics: Vec<(TopicFilter, QoS)>,
+    properties: Properties,
 }
 
 impl Subscribe {
@@ -13,15 +20,33 @@ impl Subscribe {
         Self {
             packet_identifier,
             topics,
+            properties: Properties::new(),
         }
     }
 
+    /// Sets the properties, only sent in MQTT 5.0
+    pub fn with_properties(mut self, properties: Properties) -> Self {
+        self.properties = properties;
+        self
+    }
+
     /// Converts a byte stream into a Subscribe.
     pub fn from_bytes(fixed_header: FixedHeader, stream: &mut dyn Read) -> MqttResult<Self> {
+        Subscribe::from_bytes_with_version(fixed_header, stream, ProtocolVersion::V311)
+    }
+
+    /// Converts a byte stream in the format of the version into a Subscribe.
+    /// The no local, retain as published and retain handling options of MQTT 5.0 are read but not kept,
+    /// the server handles every subscription as MQTT 3.1.1 does
+    pub fn from_bytes_with_version(
+        fixed_header: FixedHeader,
+        stream: &mut dyn Read,
+        version: ProtocolVersion,
+    ) -> MqttResult<Self> {
         // Fixed Header
         let fixed_header_flags = fixed_header.first_byte() & 0b0000_1111;
 
-        if fixed_header_flags != RESERVED_FIXED_HEADER_FLAGS {
+        if fixed_header_flags != SUBSCRIBE_FIXED_HEADER_FLAGS {
             return Err(MqttError::InvalidFixedHeaderFlags);
         }
 
@@ -31,18 +56,39 @@ impl Subscribe {
 
         let packet_identifier = u16::from_be_bytes(variable_header_buffer);
 
+        let properties = match version {
+            ProtocolVersion::V311 => Properties::new(),
+            ProtocolVersion::V5 => Properties::from_bytes(stream)?,
+        };
+
         // Payload
         let mut topics = Vec::new();
-        let mut remaining_length = fixed_header.remaining_length().value() - 2; // Del variable header
+        let mut remaining_length = fixed_header
+            .remaining_length()
+            .value()
+            .checked_sub(DEFAULT_VARIABLE_HEADER_LENGTH + properties_length(&properties, version))
+            .ok_or(MqttError::InvalidRemainingLength)?;
 
         while remaining_length > 0 {
             let topic_filter = TopicFilter::from_bytes(stream)?;
 
             let qos_buffer = &mut [0; 1];
             stream.read_exact(qos_buffer)?;
-            let qos = QoS::from_byte(qos_buffer[0])?;
-
-            remaining_length -= topic_filter.length() + 1; // Del qos
+            let qos = match version {
+                ProtocolVersion::V311 => QoS::from_byte(qos_buffer[0])?,
+                ProtocolVersion::V5 => {
+                    let options = qos_buffer[0];
+                    let retain_handling = (options >> 4) & 0b11;
+                    if options & 0b1100_0000 != 0 || retain_handling == 3 {
+                        return Err(MqttError::InvalidReserverdFlag);
+                    }
+                    QoS::from_byte(options & 0b11)?
+                }
+            };
+
+            remaining_length = remaining_length
+                .checked_sub(topic_filter.length() + 1) // Del qos
+                .ok_or(MqttError::InvalidRemainingLength)?;
 
             topics.push((topic_filter, qos));
         }
@@ -54,17 +100,31 @@ impl Subscribe {
         Ok(Self {
             packet_identifier,
             topics,
+            properties,
         })
     }
 
     /// Converts the Subscribe into a vector of bytes.
-    pub fn to_bytes(&self, key: &[u8]) -> Vec<u8> {
+    pub fn to_bytes<'a>(&self, key: impl Into<FrameKey<'a>>) -> Vec<u8> {
+        self.to_bytes_with_version(key, ProtocolVersion::V311)
+    }
+
+    /// Converts the Subscribe into a vector of bytes in the format of the version.
+    pub fn to_bytes_with_version<'a>(
+        &self,
+        key: impl Into<FrameKey<'a>>,
+        version: ProtocolVersion,
+    ) -> Vec<u8> {
         // Variable Header
         let mut variable_header_bytes = vec![];
 
         let packet_identifier_bytes = self.packet_identifier.to_be_bytes();
         variable_header_bytes.extend_from_slice(&packet_identifier_bytes);
 
+        if version == ProtocolVersion::V5 {
+            variable_header_bytes.extend(self.properties.to_bytes());
+        }
+
         // Payload
         let mut payload_bytes = vec![];
 
@@ -74,7 +134,8 @@ impl Subscribe {
         }
 
         // Fixed Header
-        let mut fixed_header_bytes = vec![SUBSCRIBE_PACKET_TYPE << 4 | RESERVED_FIXED_HEADER_FLAGS];
+        let mut fixed_header_bytes =
+            vec![SUBSCRIBE_PACKET_TYPE << 4 | SUBSCRIBE_FIXED_HEADER_FLAGS];
 
         let remaining_length_value =
             variable_header_bytes.len() as u32 + payload_bytes.len() as u32;
@@ -82,7 +143,7 @@ impl Subscribe {
         fixed_header_bytes.extend(remaining_length_bytes);
 
         let data_bytes = [&variable_header_bytes[..], &payload_bytes[..]].concat();
-        let encrypted_bytes = match encrypt(data_bytes, key) {
+        let encrypted_bytes = match encrypt(data_bytes, key.into()) {
             Ok(bytes) => bytes,
             Err(_) => return vec![],
         };
@@ -104,6 +165,11 @@ impl Subscribe {
     pub fn topics(&self) -> Vec<(TopicFilter, QoS)> {
         self.topics.clone()
     }
+
+    /// Returns the properties, always empty in MQTT 3.1.1
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
 }
 
 #[cfg(test)]
@@ -132,7 +198,10 @@ mod tests {
             0x00, 0x01, 0x00, 0x06, b't', b'o', b'p', b'i', b'c', b'1', 0x00,
         ]);
 
-        let fixed_header = FixedHeader::new(SUBSCRIBE_PACKET_TYPE << 4, RemainingLength::new(11));
+        let fixed_header = FixedHeader::new(
+            SUBSCRIBE_PACKET_TYPE << 4 | SUBSCRIBE_FIXED_HEADER_FLAGS,
+            RemainingLength::new(11),
+        );
         let subscribe = Subscribe::from_bytes(fixed_header, &mut stream).unwrap();
 
         assert_eq!(subscribe.packet_identifier(), packet_identifier);
@@ -149,15 +218,72 @@ mod tests {
         let subscribe = Subscribe::new(packet_identifier, topics);
         let encrypted_bytes = subscribe.to_bytes(KEY);
         let fixed_header = encrypted_bytes[0..2].to_vec();
-        let decrypted_bytes = decrypt(&encrypted_bytes[2..], KEY).unwrap();
+        let decrypted_bytes = decrypt(&encrypted_bytes[2..], KEY.into()).unwrap();
         let subscribe_bytes = [&fixed_header[..], &decrypted_bytes[..]].concat();
 
         let expected_bytes = vec![
-            128_u8, 0x0b, // Fixed Header
+            0x82, 0x0b, // Fixed Header
             0x00, 0x01, // Packet Identifier
             0x00, 6_u8, b't', b'o', b'p', b'i', b'c', b'1', 0x00, // Topic Filter
         ];
 
         assert_eq!(subscribe_bytes, expected_bytes);
     }
+
+    #[test]
+    fn test_mqtt_5_subscribe_ignores_the_subscription_options() {
+        let mut stream = Cursor::new(vec![
+            0x00,
+            0x01,
+            0x00,
+            0x00,
+            0x06,
+            b't',
+            b'o',
+            b'p',
+            b'i',
+            b'c',
+            b'1',
+            0b0010_1101,
+        ]);
+
+        let fixed_header = FixedHeader::new(
+            SUBSCRIBE_PACKET_TYPE << 4 | SUBSCRIBE_FIXED_HEADER_FLAGS,
+            RemainingLength::new(12),
+        );
+        let subscribe =
+            Subscribe::from_bytes_with_version(fixed_header, &mut stream, ProtocolVersion::V5)
+                .unwrap();
+
+        assert_eq!(subscribe.topics()[0].1, QoS::AtLeast);
+        assert!(subscribe.properties().is_empty());
+    }
+
+    #[test]
+    fn test_mqtt_5_subscribe_with_reserved_options() {
+        let mut stream = Cursor::new(vec![
+            0x00,
+            0x01,
+            0x00,
+            0x00,
+            0x06,
+            b't',
+            b'o',
+            b'p',
+            b'i',
+            b'c',
+            b'1',
+            0b0011_0000,
+        ]);
+
+        let fixed_header = FixedHeader::new(
+            SUBSCRIBE_PACKET_TYPE << 4 | SUBSCRIBE_FIXED_HEADER_FLAGS,
+            RemainingLength::new(12),
+        );
+
+        assert!(
+            Subscribe::from_bytes_with_version(fixed_header, &mut stream, ProtocolVersion::V5)
+                .is_err()
+        );
+    }
 }
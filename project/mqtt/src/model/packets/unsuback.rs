@@ -1,19 +1,53 @@
 use super::{DEFAULT_VARIABLE_HEADER_LENGTH, RESERVED_FIXED_HEADER_FLAGS, UNSUBACK_PACKET_TYPE};
-use crate::{encrypt, FixedHeader, MqttError, MqttResult, Read, RemainingLength};
+use crate::{
+    encrypt, FixedHeader, FrameKey, MqttError, MqttResult, Properties, ProtocolVersion, Read,
+    ReasonCode, RemainingLength,
+};
 
 /// Represents an UNSUBACK packet from MQTT. The server uses it to confirm the unsubscription of one or more topics.
+/// In MQTT 5.0 it has a reason code for each topic
 #[derive(Debug)]
 pub struct Unsuback {
     packet_identifier: u16,
+    properties: Properties,
+    reason_codes: Vec<ReasonCode>,
 }
 
 impl Unsuback {
     pub fn new(packet_identifier: u16) -> Self {
-        Self { packet_identifier }
+        Self {
+            packet_identifier,
+            properties: Properties::new(),
+            reason_codes: vec![],
+        }
+    }
+
+    /// Creates an Unsuback with a reason code of MQTT 5.0 for each topic of the Unsubscribe
+    pub fn with_reason_codes(packet_identifier: u16, reason_codes: Vec<ReasonCode>) -> Self {
+        Self {
+            packet_identifier,
+            properties: Properties::new(),
+            reason_codes,
+        }
+    }
+
+    /// Sets the properties, only sent in MQTT 5.0
+    pub fn with_properties(mut self, properties: Properties) -> Self {
+        self.properties = properties;
+        self
     }
 
     /// Converts a stream of bytes into an Unsuback.
     pub fn from_bytes(fixed_header: FixedHeader, stream: &mut dyn Read) -> MqttResult<Self> {
+        Unsuback::from_bytes_with_version(fixed_header, stream, ProtocolVersion::V311)
+    }
+
+    /// Converts a stream of bytes in the format of the version into an Unsuback.
+    pub fn from_bytes_with_version(
+        fixed_header: FixedHeader,
+        stream: &mut dyn Read,
+        version: ProtocolVersion,
+    ) -> MqttResult<Self> {
         // Fixed Header
         let fixed_header_flags = fixed_header.first_byte() & 0b0000_1111;
 
@@ -28,13 +62,53 @@ impl Unsuback {
         let packet_identifier =
             u16::from_be_bytes([variable_header_buffer[0], variable_header_buffer[1]]);
 
-        Ok(Unsuback::new(packet_identifier))
+        if version == ProtocolVersion::V311 {
+            return Ok(Unsuback::new(packet_identifier));
+        }
+
+        let properties = Properties::from_bytes(stream)?;
+
+        // Payload
+        let payload_length = fixed_header
+            .remaining_length()
+            .value()
+            .checked_sub(DEFAULT_VARIABLE_HEADER_LENGTH + properties.length())
+            .ok_or(MqttError::InvalidRemainingLength)?;
+        let mut payload_buffer = vec![0; payload_length];
+        stream.read_exact(&mut payload_buffer)?;
+
+        let reason_codes = payload_buffer
+            .iter()
+            .map(|byte| ReasonCode::from_byte(*byte))
+            .collect::<MqttResult<Vec<ReasonCode>>>()?;
+
+        Ok(
+            Unsuback::with_reason_codes(packet_identifier, reason_codes)
+                .with_properties(properties),
+        )
     }
 
     /// Converts the Unsuback into a vector of bytes.
-    pub fn to_bytes(&self, key: &[u8]) -> Vec<u8> {
+    pub fn to_bytes<'a>(&self, key: impl Into<FrameKey<'a>>) -> Vec<u8> {
+        self.to_bytes_with_version(key, ProtocolVersion::V311)
+    }
+
+    /// Converts the Unsuback into a vector of bytes in the format of the version.
+    pub fn to_bytes_with_version<'a>(
+        &self,
+        key: impl Into<FrameKey<'a>>,
+        version: ProtocolVersion,
+    ) -> Vec<u8> {
         // Variable Header
-        let variable_header_bytes = self.packet_identifier.to_be_bytes().to_vec();
+        let mut variable_header_bytes = self.packet_identifier.to_be_bytes().to_vec();
+
+        // Payload
+        if version == ProtocolVersion::V5 {
+            variable_header_bytes.extend(self.properties.to_bytes());
+            for reason_code in &self.reason_codes {
+                variable_header_bytes.push(reason_code.to_byte());
+            }
+        }
 
         // Fixed Header
         let mut fixed_header_bytes = vec![UNSUBACK_PACKET_TYPE << 4 | RESERVED_FIXED_HEADER_FLAGS];
@@ -43,7 +117,7 @@ impl Unsuback {
         let remaining_length_bytes = RemainingLength::new(remaining_length_value).to_bytes();
         fixed_header_bytes.extend(remaining_length_bytes);
 
-        let encrypted_bytes = match encrypt(variable_header_bytes, key) {
+        let encrypted_bytes = match encrypt(variable_header_bytes, key.into()) {
             Ok(bytes) => bytes,
             Err(_) => return vec![],
         };
@@ -60,6 +134,16 @@ impl Unsuback {
     pub fn packet_identifier(&self) -> u16 {
         self.packet_identifier
     }
+
+    /// Returns the reason codes for each topic, always empty in MQTT 3.1.1
+    pub fn reason_codes(&self) -> &Vec<ReasonCode> {
+        &self.reason_codes
+    }
+
+    /// Returns the properties, always empty in MQTT 3.1.1
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
 }
 
 #[cfg(test)]
@@ -75,7 +159,7 @@ mod tests {
         let unsuback = Unsuback::new(42);
         let encrypted_bytes = unsuback.to_bytes(KEY);
         let fixed_header_bytes = encrypted_bytes[0..2].to_vec();
-        let decrypted_bytes = decrypt(&encrypted_bytes[2..], KEY).unwrap();
+        let decrypted_bytes = decrypt(&encrypted_bytes[2..], KEY.into()).unwrap();
         let unsuback_bytes = [&fixed_header_bytes[..], &decrypted_bytes[..]].concat();
 
         let expected_bytes: Vec<u8> = vec![0b1011_0000, 0x02, 0x00, 0x2A];
@@ -92,4 +176,29 @@ mod tests {
 
         assert_eq!(unsuback.packet_identifier(), 42);
     }
+
+    #[test]
+    fn test_mqtt_5_unsuback_to_bytes_and_back() {
+        let unsuback = Unsuback::with_reason_codes(
+            42,
+            vec![ReasonCode::Success, ReasonCode::NoSubscriptionExisted],
+        );
+
+        let bytes = unsuback.to_bytes_with_version(KEY, ProtocolVersion::V5);
+        let stream = &mut bytes.as_slice();
+        let fixed_header = FixedHeader::from_bytes(stream).unwrap();
+        let decrypted_bytes = decrypt(stream, KEY.into()).unwrap();
+        let unsuback = Unsuback::from_bytes_with_version(
+            fixed_header,
+            &mut decrypted_bytes.as_slice(),
+            ProtocolVersion::V5,
+        )
+        .unwrap();
+
+        assert_eq!(unsuback.packet_identifier(), 42);
+        assert_eq!(
+            unsuback.reason_codes(),
+            &vec![ReasonCode::Success, ReasonCode::NoSubscriptionExisted]
+        );
+    }
 }
@@ -1,11 +1,18 @@
-use super::{DEFAULT_VARIABLE_HEADER_LENGTH, RESERVED_FIXED_HEADER_FLAGS, UNSUBSCRIBE_PACKET_TYPE};
-use crate::{encrypt, FixedHeader, MqttError, MqttResult, Read, RemainingLength, TopicFilter};
+use super::{properties_length, DEFAULT_VARIABLE_HEADER_LENGTH, UNSUBSCRIBE_PACKET_TYPE};
+use crate::{
+    encrypt, FixedHeader, FrameKey, MqttError, MqttResult, Properties, ProtocolVersion, Read,
+    RemainingLength, TopicFilter,
+};
+
+/// The UNSUBSCRIBE fixed header flags are reserved and must be set to 0010.
+const UNSUBSCRIBE_FIXED_HEADER_FLAGS: u8 = 0x02;
 
 /// Represents an UNSUBSCRIBE packet from MQTT. The client uses it to unsubscribe from one or more topics.
 #[derive(Debug)]
 pub struct Unsubscribe {
     // Variable Header
     packet_identifier: u16,
+    properties: Properties,
     // Payload
     topics: Vec<TopicFilter>,
 }
@@ -14,16 +21,32 @@ impl Unsubscribe {
     pub fn new(packet_identifier: u16, topics: Vec<TopicFilter>) -> Self {
         Self {
             packet_identifier,
+            properties: Properties::new(),
             topics,
         }
     }
 
+    /// Sets the properties, only sent in MQTT 5.0
+    pub fn with_properties(mut self, properties: Properties) -> Self {
+        self.properties = properties;
+        self
+    }
+
     /// Converts a stream of bytes into an Unsubscribe.
     pub fn from_bytes(fixed_header: FixedHeader, stream: &mut dyn Read) -> MqttResult<Self> {
+        Unsubscribe::from_bytes_with_version(fixed_header, stream, ProtocolVersion::V311)
+    }
+
+    /// Converts a stream of bytes in the format of the version into an Unsubscribe.
+    pub fn from_bytes_with_version(
+        fixed_header: FixedHeader,
+        stream: &mut dyn Read,
+        version: ProtocolVersion,
+    ) -> MqttResult<Self> {
         // Fixed Header
         let fixed_header_flags = fixed_header.first_byte() & 0b0000_1111;
 
-        if fixed_header_flags != RESERVED_FIXED_HEADER_FLAGS {
+        if fixed_header_flags != UNSUBSCRIBE_FIXED_HEADER_FLAGS {
             return Err(MqttError::InvalidFixedHeaderFlags);
         }
 
@@ -33,14 +56,24 @@ impl Unsubscribe {
 
         let packet_identifier = u16::from_be_bytes(variable_header_buffer);
 
-        let mut remaining_length =
-            fixed_header.remaining_length().value() - DEFAULT_VARIABLE_HEADER_LENGTH;
+        let properties = match version {
+            ProtocolVersion::V311 => Properties::new(),
+            ProtocolVersion::V5 => Properties::from_bytes(stream)?,
+        };
+
+        let mut remaining_length = fixed_header
+            .remaining_length()
+            .value()
+            .checked_sub(DEFAULT_VARIABLE_HEADER_LENGTH + properties_length(&properties, version))
+            .ok_or(MqttError::InvalidRemainingLength)?;
 
         // Payload
         let mut topics = vec![];
         while remaining_length > 0 {
             let topic_filter = TopicFilter::from_bytes(stream)?;
-            remaining_length -= topic_filter.length();
+            remaining_length = remaining_length
+                .checked_sub(topic_filter.length())
+                .ok_or(MqttError::InvalidRemainingLength)?;
 
             topics.push(topic_filter);
         }
@@ -51,18 +84,32 @@ impl Unsubscribe {
 
         Ok(Self {
             packet_identifier,
+            properties,
             topics,
         })
     }
 
     /// Converts the Unsubscribe into a vector of bytes.
-    pub fn to_bytes(&self, key: &[u8]) -> Vec<u8> {
+    pub fn to_bytes<'a>(&self, key: impl Into<FrameKey<'a>>) -> Vec<u8> {
+        self.to_bytes_with_version(key, ProtocolVersion::V311)
+    }
+
+    /// Converts the Unsubscribe into a vector of bytes in the format of the version.
+    pub fn to_bytes_with_version<'a>(
+        &self,
+        key: impl Into<FrameKey<'a>>,
+        version: ProtocolVersion,
+    ) -> Vec<u8> {
         // Variable Header
         let mut variable_header_bytes = vec![];
 
         let packet_identifier_bytes = self.packet_identifier.to_be_bytes();
         variable_header_bytes.extend_from_slice(&packet_identifier_bytes);
 
+        if version == ProtocolVersion::V5 {
+            variable_header_bytes.extend(self.properties.to_bytes());
+        }
+
         // Payload
         let mut payload_bytes = vec![];
 
@@ -72,7 +119,7 @@ impl Unsubscribe {
 
         // Fixed Header
         let mut fixed_header_bytes =
-            vec![UNSUBSCRIBE_PACKET_TYPE << 4 | RESERVED_FIXED_HEADER_FLAGS];
+            vec![UNSUBSCRIBE_PACKET_TYPE << 4 | UNSUBSCRIBE_FIXED_HEADER_FLAGS];
 
         let remaining_length_value =
             variable_header_bytes.len() as u32 + payload_bytes.len() as u32;
@@ -80,7 +127,7 @@ impl Unsubscribe {
         fixed_header_bytes.extend(remaining_length_bytes);
 
         let data_bytes = [&variable_header_bytes[..], &payload_bytes[..]].concat();
-        let encrypted_bytes = match encrypt(data_bytes, key) {
+        let encrypted_bytes = match encrypt(data_bytes, key.into()) {
             Ok(bytes) => bytes,
             Err(_) => return vec![],
         };
@@ -102,6 +149,11 @@ impl Unsubscribe {
     pub fn topics(&self) -> &Vec<TopicFilter> {
         &self.topics
     }
+
+    /// Returns the properties, always empty in MQTT 3.1.1
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
 }
 
 #[cfg(test)]
@@ -131,7 +183,10 @@ mod tests {
             0x00, 0x01, 0x00, 0x06, b't', b'o', b'p', b'i', b'c', b'1',
         ]);
 
-        let fixed_header = FixedHeader::new(UNSUBSCRIBE_PACKET_TYPE << 4, RemainingLength::new(10));
+        let fixed_header = FixedHeader::new(
+            UNSUBSCRIBE_PACKET_TYPE << 4 | UNSUBSCRIBE_FIXED_HEADER_FLAGS,
+            RemainingLength::new(10),
+        );
         let unsubscribe = Unsubscribe::from_bytes(fixed_header, &mut stream).unwrap();
 
         assert_eq!(unsubscribe.packet_identifier(), packet_identifier);
@@ -148,11 +203,11 @@ mod tests {
         let unsubscribe = Unsubscribe::new(packet_identifier, topics);
         let encrypted_bytes = unsubscribe.to_bytes(KEY);
         let fixed_header_bytes = &encrypted_bytes[0..2];
-        let decrypted_bytes = decrypt(&encrypted_bytes[2..], KEY).unwrap();
+        let decrypted_bytes = decrypt(&encrypted_bytes[2..], KEY.into()).unwrap();
         let unsubscribe_bytes = [fixed_header_bytes, &decrypted_bytes[..]].concat();
 
         let expected_bytes = vec![
-            160_u8, 10_u8, 0x00, 0x01, 0x00, 0x06, b't', b'o', b'p', b'i', b'c', b'1',
+            0xA2, 10_u8, 0x00, 0x01, 0x00, 0x06, b't', b'o', b'p', b'i', b'c', b'1',
         ];
 
         assert_eq!(unsubscribe_bytes, expected_bytes);
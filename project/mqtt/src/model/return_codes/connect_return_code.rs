@@ -1,7 +1,8 @@
+use super::reason_code::ReasonCode;
 use crate::{MqttError, MqttResult};
 
 /// Represents the different connection return codes in MQTT.
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum ConnectReturnCode {
     ConnectionAccepted,
     UnacceptableProtocolVersion,
@@ -39,6 +40,36 @@ impl ConnectReturnCode {
             ))),
         }
     }
+
+    /// Converts the return code to the reason code of an MQTT 5.0 Connack
+    pub fn to_reason_code(&self) -> ReasonCode {
+        match self {
+            ConnectReturnCode::ConnectionAccepted => ReasonCode::Success,
+            ConnectReturnCode::UnacceptableProtocolVersion => {
+                ReasonCode::UnsupportedProtocolVersion
+            }
+            ConnectReturnCode::IdentifierRejected => ReasonCode::ClientIdentifierNotValid,
+            ConnectReturnCode::ServerUnavailable => ReasonCode::ServerUnavailable,
+            ConnectReturnCode::BadUsernameOrPassword => ReasonCode::BadUserNameOrPassword,
+            ConnectReturnCode::NotAuthorized => ReasonCode::NotAuthorized,
+        }
+    }
+
+    /// Converts the reason code of an MQTT 5.0 Connack to the closest return code
+    pub fn from_reason_code(reason_code: ReasonCode) -> Self {
+        match reason_code {
+            ReasonCode::Success => ConnectReturnCode::ConnectionAccepted,
+            ReasonCode::UnsupportedProtocolVersion => {
+                ConnectReturnCode::UnacceptableProtocolVersion
+            }
+            ReasonCode::ClientIdentifierNotValid => ConnectReturnCode::IdentifierRejected,
+            ReasonCode::BadUserNameOrPassword => ConnectReturnCode::BadUsernameOrPassword,
+            ReasonCode::NotAuthorized
+            | ReasonCode::Banned
+            | ReasonCode::BadAuthenticationMethod => ConnectReturnCode::NotAuthorized,
+            _ => ConnectReturnCode::ServerUnavailable,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -85,4 +116,19 @@ mod tests {
             ConnectReturnCode::NotAuthorized
         );
     }
+
+    #[test]
+    fn test_reason_codes_convert_back_to_the_return_codes() {
+        for byte in 0x00..=0x05 {
+            let return_code = ConnectReturnCode::from_byte(byte).unwrap();
+            assert_eq!(
+                ConnectReturnCode::from_reason_code(return_code.to_reason_code()),
+                return_code
+            );
+        }
+        assert_eq!(
+            ConnectReturnCode::from_reason_code(ReasonCode::Banned),
+            ConnectReturnCode::NotAuthorized
+        );
+    }
 }
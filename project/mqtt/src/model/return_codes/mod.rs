@@ -1,4 +1,6 @@
 /// CONNECT Return Codes
 pub mod connect_return_code;
+/// MQTT 5.0 Reason Codes
+pub mod reason_code;
 /// SUBACK Return Codes
 pub mod suback_return_code;
@@ -1,7 +1,8 @@
+use super::reason_code::ReasonCode;
 use crate::{MqttError, MqttResult};
 
 /// Represents the different return codes of a Suback in MQTT.
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub enum SubackReturnCode {
     SuccessMaximumQoS0,
     SuccessMaximumQoS1,
@@ -33,6 +34,26 @@ impl SubackReturnCode {
             ))),
         }
     }
+
+    /// Converts the return code to the reason code of an MQTT 5.0 Suback
+    pub fn to_reason_code(&self) -> ReasonCode {
+        match self {
+            SubackReturnCode::SuccessMaximumQoS0 => ReasonCode::Success,
+            SubackReturnCode::SuccessMaximumQoS1 => ReasonCode::GrantedQoS1,
+            SubackReturnCode::SuccessMaximumQoS2 => ReasonCode::GrantedQoS2,
+            SubackReturnCode::Failure => ReasonCode::UnspecifiedError,
+        }
+    }
+
+    /// Converts the reason code of an MQTT 5.0 Suback to a return code, every failure being a Failure
+    pub fn from_reason_code(reason_code: ReasonCode) -> Self {
+        match reason_code {
+            ReasonCode::Success => SubackReturnCode::SuccessMaximumQoS0,
+            ReasonCode::GrantedQoS1 => SubackReturnCode::SuccessMaximumQoS1,
+            ReasonCode::GrantedQoS2 => SubackReturnCode::SuccessMaximumQoS2,
+            _ => SubackReturnCode::Failure,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,318 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+use crate::{TopicFilter, TopicLevel, TopicName};
+
+/// Subscription tree that indexes values by the levels of a topic filter, with nodes for the `+` and `#` wildcards.
+/// It is used to find the subscribers of a topic name without checking every subscription, and to find
+/// which of the stored topic names are matched by a topic filter, such as the topics of the retained messages.
+/// Topics reserved by the server are kept apart, since wildcards at the first level do not match them
+#[derive(Debug)]
+pub struct TopicTree<T> {
+    root: TopicNode<T>,
+    server_reserved_root: TopicNode<T>,
+}
+
+/// Represents a level of the tree. The values of a node are the ones stored with the filter that ends on it
+#[derive(Debug)]
+struct TopicNode<T> {
+    values: HashSet<T>,
+    literals: HashMap<Vec<u8>, TopicNode<T>>,
+    single_level_wildcard: Option<Box<TopicNode<T>>>,
+    /// Values stored with a filter ending in `#` at this level
+    multi_level_wildcard: HashSet<T>,
+}
+
+impl<T: Eq + Hash + Clone> TopicTree<T> {
+    pub fn new() -> Self {
+        Self {
+            root: TopicNode::new(),
+            server_reserved_root: TopicNode::new(),
+        }
+    }
+
+    /// Stores a value under a topic filter. Returns false if it was already stored under that filter
+    pub fn insert(&mut self, topic_filter: &TopicFilter, value: T) -> bool {
+        self.root_mut(topic_filter.server_reserved())
+            .insert(topic_filter.levels(), value)
+    }
+
+    /// Removes a value stored under a topic filter. Returns false if it was not stored under that filter
+    pub fn remove(&mut self, topic_filter: &TopicFilter, value: &T) -> bool {
+        self.root_mut(topic_filter.server_reserved())
+            .remove(topic_filter.levels(), value)
+    }
+
+    /// Returns the values stored under every topic filter that matches a topic name
+    pub fn matches(&self, topic_name: &TopicName) -> HashSet<T> {
+        let mut values = HashSet::new();
+        self.root(topic_name.server_reserved())
+            .collect_matches(topic_name.levels(), &mut values);
+        values
+    }
+
+    /// Returns the values stored under the topic names matched by a topic filter.
+    /// Values stored under filters with wildcards are only returned if the filter walks through the same wildcards
+    pub fn matched_by(&self, topic_filter: &TopicFilter) -> HashSet<T> {
+        let mut values = HashSet::new();
+        self.root(topic_filter.server_reserved())
+            .collect_matched_by(topic_filter.levels(), &mut values);
+        values
+    }
+
+    /// Returns whether the tree has no values
+    pub fn is_empty(&self) -> bool {
+        self.root.is_empty() && self.server_reserved_root.is_empty()
+    }
+
+    fn root(&self, server_reserved: bool) -> &TopicNode<T> {
+        if server_reserved {
+            &self.server_reserved_root
+        } else {
+            &self.root
+        }
+    }
+
+    fn root_mut(&mut self, server_reserved: bool) -> &mut TopicNode<T> {
+        if server_reserved {
+            &mut self.server_reserved_root
+        } else {
+            &mut self.root
+        }
+    }
+}
+
+impl<T: Eq + Hash + Clone> Default for TopicTree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Eq + Hash + Clone> TopicNode<T> {
+    fn new() -> Self {
+        Self {
+            values: HashSet::new(),
+            literals: HashMap::new(),
+            single_level_wildcard: None,
+            multi_level_wildcard: HashSet::new(),
+        }
+    }
+
+    fn insert(&mut self, levels: &[TopicLevel], value: T) -> bool {
+        match levels.split_first() {
+            None => self.values.insert(value),
+            Some((TopicLevel::MultiLevelWildcard, _)) => self.multi_level_wildcard.insert(value),
+            Some((TopicLevel::SingleLevelWildcard, rest)) => self
+                .single_level_wildcard
+                .get_or_insert_with(|| Box::new(TopicNode::new()))
+                .insert(rest, value),
+            Some((TopicLevel::Literal(level), rest)) => self
+                .literals
+                .entry(level.clone())
+                .or_insert_with(TopicNode::new)
+                .insert(rest, value),
+        }
+    }
+
+    /// Removes a value, dropping the nodes left without values
+    fn remove(&mut self, levels: &[TopicLevel], value: &T) -> bool {
+        match levels.split_first() {
+            None => self.values.remove(value),
+            Some((TopicLevel::MultiLevelWildcard, _)) => self.multi_level_wildcard.remove(value),
+            Some((TopicLevel::SingleLevelWildcard, rest)) => {
+                let child = match &mut self.single_level_wildcard {
+                    Some(child) => child,
+                    None => return false,
+                };
+                let removed = child.remove(rest, value);
+                if child.is_empty() {
+                    self.single_level_wildcard = None;
+                }
+                removed
+            }
+            Some((TopicLevel::Literal(level), rest)) => {
+                let child = match self.literals.get_mut(level) {
+                    Some(child) => child,
+                    None => return false,
+                };
+                let removed = child.remove(rest, value);
+                if child.is_empty() {
+                    self.literals.remove(level);
+                }
+                removed
+            }
+        }
+    }
+
+    fn collect_matches(&self, levels: &[Vec<u8>], values: &mut HashSet<T>) {
+        // A filter ending in `#` also matches the level before the wildcard
+        values.extend(self.multi_level_wildcard.iter().cloned());
+
+        match levels.split_first() {
+            None => values.extend(self.values.iter().cloned()),
+            Some((level, rest)) => {
+                if let Some(child) = self.literals.get(level) {
+                    child.collect_matches(rest, values);
+                }
+                if let Some(child) = &self.single_level_wildcard {
+                    child.collect_matches(rest, values);
+                }
+            }
+        }
+    }
+
+    fn collect_matched_by(&self, levels: &[TopicLevel], values: &mut HashSet<T>) {
+        match levels.split_first() {
+            None => values.extend(self.values.iter().cloned()),
+            Some((TopicLevel::MultiLevelWildcard, _)) => self.collect_literals(values),
+            Some((TopicLevel::SingleLevelWildcard, rest)) => {
+                for child in self.literals.values() {
+                    child.collect_matched_by(rest, values);
+                }
+            }
+            Some((TopicLevel::Literal(level), rest)) => {
+                if let Some(child) = self.literals.get(level) {
+                    child.collect_matched_by(rest, values);
+                }
+            }
+        }
+    }
+
+    /// Collects the values of the node and of every node below it reached through literal levels
+    fn collect_literals(&self, values: &mut HashSet<T>) {
+        values.extend(self.values.iter().cloned());
+        for child in self.literals.values() {
+            child.collect_literals(values);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.values.is_empty()
+            && self.literals.is_empty()
+            && self.single_level_wildcard.is_none()
+            && self.multi_level_wildcard.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::EncodedString;
+
+    fn topic_filter(topic_filter: &str) -> TopicFilter {
+        let bytes = EncodedString::new(topic_filter.as_bytes().to_vec()).to_bytes();
+        TopicFilter::from_bytes(&mut Cursor::new(bytes)).unwrap()
+    }
+
+    fn topic_name(topic_name: &str) -> TopicName {
+        let bytes = EncodedString::new(topic_name.as_bytes().to_vec()).to_bytes();
+        TopicName::from_bytes(&mut Cursor::new(bytes)).unwrap()
+    }
+
+    fn setup_tree() -> TopicTree<&'static str> {
+        let mut tree = TopicTree::new();
+        tree.insert(&topic_filter("drone-data/1"), "exact");
+        tree.insert(&topic_filter("drone-data/+"), "single");
+        tree.insert(&topic_filter("drone-data/#"), "multi");
+        tree.insert(&topic_filter("#"), "all");
+        tree.insert(&topic_filter("+/1/status"), "status");
+        tree.insert(&topic_filter("$SYS/#"), "sys");
+        tree
+    }
+
+    #[test]
+    fn test_matches_with_wildcards() {
+        let tree = setup_tree();
+
+        assert_eq!(
+            tree.matches(&topic_name("drone-data/1")),
+            HashSet::from(["exact", "single", "multi", "all"])
+        );
+        assert_eq!(
+            tree.matches(&topic_name("drone-data")),
+            HashSet::from(["multi", "all"])
+        );
+        assert_eq!(
+            tree.matches(&topic_name("drone-data/1/status")),
+            HashSet::from(["multi", "all", "status"])
+        );
+        assert_eq!(
+            tree.matches(&topic_name("new-incident")),
+            HashSet::from(["all"])
+        );
+    }
+
+    #[test]
+    fn test_wildcards_do_not_match_server_reserved_topics() {
+        let tree = setup_tree();
+
+        assert_eq!(
+            tree.matches(&topic_name("$SYS/broker/uptime")),
+            HashSet::from(["sys"])
+        );
+    }
+
+    #[test]
+    fn test_remove_drops_the_value() {
+        let mut tree = setup_tree();
+
+        assert!(tree.remove(&topic_filter("drone-data/+"), &"single"));
+        assert!(!tree.remove(&topic_filter("drone-data/+"), &"single"));
+        assert!(!tree.remove(&topic_filter("drone-data/2"), &"exact"));
+
+        assert_eq!(
+            tree.matches(&topic_name("drone-data/1")),
+            HashSet::from(["exact", "multi", "all"])
+        );
+    }
+
+    #[test]
+    fn test_tree_is_empty_after_removing_every_value() {
+        let mut tree = TopicTree::new();
+        tree.insert(&topic_filter("a/+/c/#"), 1);
+        tree.insert(&topic_filter("a/b"), 2);
+
+        assert!(tree.remove(&topic_filter("a/+/c/#"), &1));
+        assert!(tree.remove(&topic_filter("a/b"), &2));
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn test_matched_by() {
+        let mut tree = TopicTree::new();
+        for topic in [
+            "new-incident",
+            "drone-data/1",
+            "drone-data/2",
+            "drone-data/1/battery",
+        ] {
+            tree.insert(&TopicFilter::from(&topic_name(topic)), topic);
+        }
+        tree.insert(
+            &TopicFilter::from(&topic_name("$SYS/uptime")),
+            "$SYS/uptime",
+        );
+
+        assert_eq!(
+            tree.matched_by(&topic_filter("drone-data/+")),
+            HashSet::from(["drone-data/1", "drone-data/2"])
+        );
+        assert_eq!(
+            tree.matched_by(&topic_filter("drone-data/#")),
+            HashSet::from(["drone-data/1", "drone-data/2", "drone-data/1/battery"])
+        );
+        assert_eq!(
+            tree.matched_by(&topic_filter("new-incident")),
+            HashSet::from(["new-incident"])
+        );
+        assert_eq!(tree.matched_by(&topic_filter("#")).len(), 4);
+        assert_eq!(
+            tree.matched_by(&topic_filter("$SYS/#")),
+            HashSet::from(["$SYS/uptime"])
+        );
+    }
+}
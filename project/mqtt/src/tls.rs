@@ -0,0 +1,66 @@
+use std::{fs::File, io::BufReader, sync::Arc};
+
+use rustls::{
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    RootCertStore,
+};
+
+use crate::errors::error::{MqttError, MqttResult};
+
+/// Returns the cryptography used by the TLS connections
+pub fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Reads the certificates of a PEM file, in the order they appear
+pub fn load_certificates(path: &str) -> MqttResult<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let certificates = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certificates.is_empty() {
+        return Err(MqttError::Tls(format!("No certificates found in {}", path)));
+    }
+
+    Ok(certificates)
+}
+
+/// Reads the first private key of a PEM file
+pub fn load_private_key(path: &str) -> MqttResult<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| MqttError::Tls(format!("No private key found in {}", path)))
+}
+
+/// Reads the certificate authorities of a PEM file, trusted to sign the certificates of the peers
+pub fn load_root_store(path: &str) -> MqttResult<RootCertStore> {
+    let mut root_store = RootCertStore::empty();
+
+    for certificate in load_certificates(path)? {
+        root_store
+            .add(certificate)
+            .map_err(|err| MqttError::Tls(err.to_string()))?;
+    }
+
+    Ok(root_store)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_files_without_certificates_are_rejected() {
+        let path = "test_tls_empty.pem";
+        std::fs::write(path, "not a certificate\n").unwrap();
+
+        let certificates = load_certificates(path);
+        let private_key = load_private_key(path);
+        std::fs::remove_file(path).unwrap();
+
+        assert!(matches!(certificates, Err(MqttError::Tls(_))));
+        assert!(matches!(private_key, Err(MqttError::Tls(_))));
+        assert!(load_certificates("test_tls_missing.pem").is_err());
+    }
+}
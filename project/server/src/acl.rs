@@ -0,0 +1,284 @@
+use std::{fs, io::Cursor};
+
+use mqtt::model::components::{
+    encoded_string::EncodedString, topic_filter::TopicFilter, topic_level::TopicLevel,
+    topic_name::TopicName,
+};
+
+use crate::error::{ServerError, ServerResult};
+
+/// Id of the client that administers the server
+pub(crate) const ADMIN_ID: &[u8] = b"admin";
+const ANY_CLIENT: &str = "*";
+const CLIENT_ID_PLACEHOLDER: &str = "%c";
+const USERNAME_PLACEHOLDER: &str = "%u";
+const SYSTEM_TOPICS: &[u8] = b"$SYS";
+
+/// Represents the access a rule grants over the topics matched by its pattern
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn from_str(access: &str) -> Option<Self> {
+        match access {
+            "read" => Some(Access::Read),
+            "write" => Some(Access::Write),
+            "readwrite" => Some(Access::ReadWrite),
+            _ => None,
+        }
+    }
+
+    fn can_read(&self) -> bool {
+        matches!(self, Access::Read | Access::ReadWrite)
+    }
+
+    fn can_write(&self) -> bool {
+        matches!(self, Access::Write | Access::ReadWrite)
+    }
+}
+
+/// Represents a line of the ACL file: the client it applies to, the access it grants and the topic filter pattern.
+/// The pattern may contain `%c` and `%u`, replaced by the client id and the username of the client
+#[derive(Debug)]
+struct AclRule {
+    client_id: String,
+    access: Access,
+    pattern: String,
+}
+
+impl AclRule {
+    fn applies_to(&self, client_id: &str) -> bool {
+        self.client_id == ANY_CLIENT || self.client_id == client_id
+    }
+
+    /// Returns the topic filter of the rule for a client, or None if its client id or username
+    /// can not be placed in a topic filter
+    fn topic_filter(&self, client_id: &str, username: &str) -> Option<TopicFilter> {
+        let pattern = self.pattern.as_str();
+        if (pattern.contains(CLIENT_ID_PLACEHOLDER) && !is_valid_substitution(client_id))
+            || (pattern.contains(USERNAME_PLACEHOLDER) && !is_valid_substitution(username))
+        {
+            return None;
+        }
+
+        let pattern = pattern
+            .replace(CLIENT_ID_PLACEHOLDER, client_id)
+            .replace(USERNAME_PLACEHOLDER, username);
+        parse_topic_filter(&pattern)
+    }
+}
+
+/// Represents the access control list of the server, read from the ACL file.
+/// A client may only publish to the topics matched by a write rule and subscribe to the
+/// topic filters covered by a read rule. Without an ACL file every client has full access,
+/// except to the `$SYS` topics with the statistics of the broker, which only the admin may read
+#[derive(Debug)]
+pub struct Acl {
+    rules: Option<Vec<AclRule>>,
+}
+
+impl Acl {
+    /// Creates an ACL that allows every client to publish and subscribe to any topic but the `$SYS` ones
+    pub fn allow_all() -> Self {
+        Acl { rules: None }
+    }
+
+    /// Reads the ACL from a file with lines of the form `client_id = access = pattern`,
+    /// where the client id may be `*` to apply to every client and the access is `read`, `write` or `readwrite`
+    pub fn from_file(path: &str) -> ServerResult<Self> {
+        let content = fs::read_to_string(path)?;
+        Acl::from_content(&content)
+    }
+
+    /// Reads the ACL from the content of an ACL file
+    pub(crate) fn from_content(content: &str) -> ServerResult<Self> {
+        let mut rules = Vec::new();
+
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let parts: Vec<&str> = line.split('=').map(|s| s.trim()).collect();
+            if parts.len() != 3 {
+                return Err(ServerError::InvalidAcl(format!("Invalid rule: {}", line)));
+            }
+
+            let access = Access::from_str(parts[1])
+                .ok_or_else(|| ServerError::InvalidAcl(format!("Invalid access: {}", parts[1])))?;
+
+            let rule = AclRule {
+                client_id: parts[0].to_string(),
+                access,
+                pattern: parts[2].to_string(),
+            };
+            if rule.topic_filter("client", "username").is_none() {
+                return Err(ServerError::InvalidAcl(format!(
+                    "Invalid pattern: {}",
+                    parts[2]
+                )));
+            }
+
+            rules.push(rule);
+        }
+
+        Ok(Acl { rules: Some(rules) })
+    }
+
+    /// Returns whether a client may publish to a topic
+    pub fn can_publish(&self, client_id: &[u8], username: &[u8], topic_name: &TopicName) -> bool {
+        self.is_allowed(client_id, username, Access::can_write, |topic_filter| {
+            topic_filter.match_topic_name(topic_name.clone())
+        })
+    }
+
+    /// Returns whether a client may subscribe to a topic filter, that is, whether every topic
+    /// it matches is matched by a read rule.
+    /// Since `#` does not match the topics that start with `$`, the `$SYS` topics need a rule of their own
+    pub fn can_subscribe(
+        &self,
+        client_id: &[u8],
+        username: &[u8],
+        topic_filter: &TopicFilter,
+    ) -> bool {
+        if self.rules.is_none() && is_system_topic_filter(topic_filter) {
+            return client_id == ADMIN_ID;
+        }
+
+        self.is_allowed(client_id, username, Access::can_read, |rule_filter| {
+            rule_filter.contains(topic_filter)
+        })
+    }
+
+    fn is_allowed(
+        &self,
+        client_id: &[u8],
+        username: &[u8],
+        grants: fn(&Access) -> bool,
+        matches: impl Fn(&TopicFilter) -> bool,
+    ) -> bool {
+        let rules = match &self.rules {
+            Some(rules) => rules,
+            None => return true,
+        };
+
+        let client_id = String::from_utf8_lossy(client_id);
+        let username = String::from_utf8_lossy(username);
+
+        rules
+            .iter()
+            .filter(|rule| rule.applies_to(&client_id) && grants(&rule.access))
+            .filter_map(|rule| rule.topic_filter(&client_id, &username))
+            .any(|topic_filter| matches(&topic_filter))
+    }
+}
+
+/// Returns whether a client id or username can replace a placeholder without adding levels or wildcards
+fn is_valid_substitution(value: &str) -> bool {
+    !value.is_empty() && !value.contains(['/', '+', '#'])
+}
+
+/// Returns whether a topic filter matches the `$SYS` topics
+fn is_system_topic_filter(topic_filter: &TopicFilter) -> bool {
+    matches!(
+        topic_filter.levels().first(),
+        Some(TopicLevel::Literal(level)) if level == SYSTEM_TOPICS
+    )
+}
+
+/// Parses a topic filter written as text, None if it is not valid
+pub(crate) fn parse_topic_filter(pattern: &str) -> Option<TopicFilter> {
+    let bytes = EncodedString::new(pattern.as_bytes().to_vec()).to_bytes();
+    TopicFilter::from_bytes(&mut Cursor::new(bytes)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACL: &str = "
+        # The monitor has full access
+        admin = readwrite = #
+        admin = write = $client-register
+        admin = read = $SYS/#
+        * = read = new-incident
+        * = write = drone-data/%c
+        * = readwrite = users/%u/#
+    ";
+
+    fn topic_filter(topic_filter: &str) -> TopicFilter {
+        parse_topic_filter(topic_filter).unwrap()
+    }
+
+    fn topic_name(topic_name: &str) -> TopicName {
+        let bytes = EncodedString::new(topic_name.as_bytes().to_vec()).to_bytes();
+        TopicName::from_bytes(&mut Cursor::new(bytes)).unwrap()
+    }
+
+    #[test]
+    fn test_publish_is_allowed_by_the_matching_rules() {
+        let acl = Acl::from_content(ACL).unwrap();
+
+        assert!(acl.can_publish(b"admin", b"admin", &topic_name("close-incident/1")));
+        assert!(acl.can_publish(b"admin", b"admin", &topic_name("$client-register")));
+        assert!(acl.can_publish(b"1", b"drone", &topic_name("drone-data/1")));
+        assert!(acl.can_publish(b"1", b"drone", &topic_name("users/drone/status")));
+
+        assert!(!acl.can_publish(b"1", b"drone", &topic_name("drone-data/2")));
+        assert!(!acl.can_publish(b"1", b"drone", &topic_name("new-incident")));
+        assert!(!acl.can_publish(b"1", b"drone", &topic_name("$client-register")));
+    }
+
+    #[test]
+    fn test_subscription_must_be_covered_by_a_rule() {
+        let acl = Acl::from_content(ACL).unwrap();
+
+        assert!(acl.can_subscribe(b"admin", b"admin", &topic_filter("drone-data/+")));
+        assert!(acl.can_subscribe(b"1", b"drone", &topic_filter("new-incident")));
+        assert!(acl.can_subscribe(b"1", b"drone", &topic_filter("users/drone/+")));
+
+        assert!(!acl.can_subscribe(b"1", b"drone", &topic_filter("drone-data/1")));
+        assert!(!acl.can_subscribe(b"1", b"drone", &topic_filter("#")));
+        assert!(!acl.can_subscribe(b"1", b"drone", &topic_filter("users/+/status")));
+    }
+
+    #[test]
+    fn test_system_topics_are_only_read_by_the_admin() {
+        let acl = Acl::from_content(ACL).unwrap();
+        assert!(acl.can_subscribe(b"admin", b"admin", &topic_filter("$SYS/#")));
+        assert!(!acl.can_subscribe(b"1", b"drone", &topic_filter("$SYS/#")));
+
+        let acl = Acl::allow_all();
+        assert!(acl.can_subscribe(b"admin", b"admin", &topic_filter("$SYS/broker/uptime")));
+        assert!(!acl.can_subscribe(b"1", b"drone", &topic_filter("$SYS/#")));
+        assert!(!acl.can_subscribe(b"1", b"drone", &topic_filter("$SYS/broker/+")));
+    }
+
+    #[test]
+    fn test_placeholders_with_wildcards_do_not_match() {
+        let acl = Acl::from_content(ACL).unwrap();
+
+        assert!(!acl.can_publish(b"1/2", b"drone", &topic_name("drone-data/1/2")));
+        assert!(!acl.can_subscribe(b"1", b"#", &topic_filter("users/#")));
+    }
+
+    #[test]
+    fn test_allow_all() {
+        let acl = Acl::allow_all();
+
+        assert!(acl.can_publish(b"1", b"drone", &topic_name("new-incident")));
+        assert!(acl.can_subscribe(b"1", b"drone", &topic_filter("#")));
+    }
+
+    #[test]
+    fn test_invalid_rules_are_rejected() {
+        assert!(Acl::from_content("admin = all = #").is_err());
+        assert!(Acl::from_content("admin = read").is_err());
+        assert!(Acl::from_content("admin = read = a/#/b").is_err());
+    }
+}
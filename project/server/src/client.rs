@@ -1,82 +1,183 @@
+use std::collections::{HashSet, VecDeque};
 use std::fmt;
-use std::io::Write;
-use std::net::TcpStream;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use mqtt::model::components::qos::QoS;
 use mqtt::model::components::topic_filter::TopicFilter;
 use mqtt::model::components::topic_name::TopicName;
+use mqtt::model::components::will::Will;
+use mqtt::model::packet::Packet;
 use mqtt::model::packets::publish::Publish;
 
+use crate::connection::Connection;
+use crate::offline_queue::OfflineQueue;
+
 /// Represents the state of the client in the server
-/// The client is identified by its id and has a list of subscriptions of topics
+/// The client is identified by its id and has a list of subscriptions of topics, with the QoS granted to each
+/// It also keeps track of the QoS 1 and 2 flows that are in progress with the client
+/// and of the will message to publish if the client disconnects unexpectedly
 #[derive(Debug)]
 pub struct Client {
     pub id: Vec<u8>,
-    pub subscriptions: Vec<TopicFilter>,
+    /// Username the client logged in with, used by the ACL rules
+    pub username: Vec<u8>,
+    pub subscriptions: Vec<(TopicFilter, QoS)>,
     pub alive: AtomicBool,
-    pub stream: Option<TcpStream>,
+    /// Connection of the client, None while it is disconnected
+    pub connection: Option<Connection>,
+    /// Packet identifiers of QoS 2 publishes received from the client that are waiting for a PUBREL
+    pub awaiting_pubrel: HashSet<u16>,
+    /// QoS 1 publishes sent to the client that are waiting for a PUBACK and QoS 2 publishes waiting
+    /// for a PUBREC, in the order they were sent
+    pub inflight: VecDeque<InflightMessage>,
+    /// QoS 1 and 2 publishes waiting for room in the inflight window, at the QoS of their delivery.
+    /// They are bounded by the same policy as the queues of the clients that are not connected
+    pub pending: OfflineQueue,
+    /// Packet identifiers of QoS 2 publishes sent to the client that are waiting for a PUBCOMP
+    pub awaiting_pubcomp: HashSet<u16>,
+    /// Will message sent in the Connect packet, discarded when the client disconnects gracefully
+    pub will: Option<Will>,
+    /// Whether the client started with a clean session, discarding the one stored before
+    pub clean_session: bool,
+    /// Seconds the session is kept after the client disconnects, u32::MAX to keep it forever.
+    /// In MQTT 3.1.1 it is 0 for a clean session and u32::MAX otherwise
+    pub session_expiry_interval: u32,
+    /// When the client disconnected, None while it is connected
+    pub disconnected_at: Option<Instant>,
+    next_packet_identifier: u16,
 }
 
 impl Client {
     pub fn new(
         id: Vec<u8>,
-        stream: Option<TcpStream>,
-        _clean_session: bool,
+        username: Vec<u8>,
+        connection: Option<Connection>,
+        clean_session: bool,
         _keep_alive: u16,
+        will: Option<Will>,
     ) -> Client {
         Client {
             id,
+            username,
             subscriptions: Vec::new(),
             alive: AtomicBool::new(true),
-            stream,
+            connection,
+            awaiting_pubrel: HashSet::new(),
+            inflight: VecDeque::new(),
+            pending: OfflineQueue::new(),
+            awaiting_pubcomp: HashSet::new(),
+            will,
+            clean_session,
+            session_expiry_interval: if clean_session { 0 } else { u32::MAX },
+            disconnected_at: None,
+            next_packet_identifier: 1,
         }
     }
 
-    pub fn new_from_backup(id: Vec<u8>, subscriptions: Vec<TopicFilter>) -> Client {
+    /// Sets the seconds the session is kept after the client disconnects, sent in the Connect of MQTT 5.0
+    pub fn with_session_expiry_interval(mut self, session_expiry_interval: u32) -> Client {
+        self.session_expiry_interval = session_expiry_interval;
+        self
+    }
+
+    pub fn new_from_backup(id: Vec<u8>, subscriptions: Vec<(TopicFilter, QoS)>) -> Client {
         Client {
             id,
+            username: Vec::new(),
             subscriptions,
             alive: AtomicBool::new(true),
-            stream: None,
+            connection: None,
+            awaiting_pubrel: HashSet::new(),
+            inflight: VecDeque::new(),
+            pending: OfflineQueue::new(),
+            awaiting_pubcomp: HashSet::new(),
+            will: None,
+            clean_session: false,
+            session_expiry_interval: u32::MAX,
+            disconnected_at: None,
+            next_packet_identifier: 1,
         }
     }
 
-    /// Adds a subscription to a client
-    pub fn add_subscription(&mut self, topic: TopicFilter) {
-        self.subscriptions.push(topic);
+    /// Adds a subscription to a client with the granted QoS, replacing the previous one to the same topic filter
+    pub fn add_subscription(&mut self, topic: TopicFilter, qos: QoS) {
+        self.remove_subscription(&topic);
+        self.subscriptions.push((topic, qos));
     }
 
     /// Unsubscribes the client from a topic
     pub fn remove_subscription(&mut self, topic: &TopicFilter) {
-        self.subscriptions.retain(|t| t != topic);
+        self.subscriptions.retain(|(t, _)| t != topic);
     }
 
-    /// Checks if the client is subscribed to a topic
-    pub fn is_subscribed(&self, topic: &TopicName) -> bool {
+    /// Returns the highest QoS granted to the subscriptions that match a topic, None if none matches it
+    pub fn granted_qos(&self, topic_name: &TopicName) -> Option<QoS> {
         self.subscriptions
             .iter()
-            .any(|t| t.match_topic_name(topic.clone()))
+            .filter(|(topic_filter, _)| topic_filter.match_topic_name(topic_name.clone()))
+            .map(|(_, qos)| qos.clone())
+            .max()
+    }
+
+    /// Returns the amount of QoS 1 and 2 publishes sent to the client whose delivery did not end
+    pub fn inflight_len(&self) -> usize {
+        self.inflight.len() + self.awaiting_pubcomp.len()
+    }
+
+    /// Removes a publish sent to the client from the inflight ones, returning it if it was waiting for
+    /// the acknowledgement of its QoS
+    pub fn acknowledge(&mut self, packet_identifier: u16, qos: QoS) -> Option<Publish> {
+        let position = self.inflight.iter().position(|inflight| {
+            inflight.publish.package_identifier() == Some(packet_identifier)
+                && inflight.publish.qos() == &qos
+        })?;
+
+        self.inflight
+            .remove(position)
+            .map(|inflight| inflight.publish)
+    }
+
+    /// Returns a new packet identifier for a message sent by the server to the client, skipping the ones
+    /// of the messages whose delivery did not end.
+    /// Packet identifiers are non zero and wrap around after reaching the maximum value
+    pub fn next_packet_identifier(&mut self) -> u16 {
+        for _ in 0..u16::MAX {
+            let packet_identifier = self.next_packet_identifier;
+            self.next_packet_identifier = self.next_packet_identifier.checked_add(1).unwrap_or(1);
+
+            if !self.is_packet_identifier_in_use(packet_identifier) {
+                return packet_identifier;
+            }
+        }
+
+        // The inflight window keeps far fewer messages than there are packet identifiers
+        self.next_packet_identifier
+    }
+
+    /// Returns whether a packet identifier belongs to a publish sent to the client that was not acknowledged
+    fn is_packet_identifier_in_use(&self, packet_identifier: u16) -> bool {
+        self.awaiting_pubcomp.contains(&packet_identifier)
+            || self
+                .inflight
+                .iter()
+                .any(|inflight| inflight.publish.package_identifier() == Some(packet_identifier))
     }
 
     /// Sends a message to the client
-    pub fn send_message(
-        &self,
-        publish_packet: Publish,
-        logfile: &Arc<crate::logfile::Logger>,
-        key: &[u8],
-    ) {
+    pub fn send_message(&self, publish_packet: Publish, logfile: &Arc<crate::logfile::Logger>) {
         let message_str = String::from_utf8_lossy(publish_packet.message()).to_string();
         let client_id_str = String::from_utf8_lossy(&self.id).to_string();
 
-        let mut stream = match &self.stream {
-            Some(stream) => stream,
+        let connection = match &self.connection {
+            Some(connection) => connection,
             None => {
                 logfile.log_sending_message_error(message_str, client_id_str);
                 return;
             }
         };
-        match stream.write_all(publish_packet.to_bytes(key).as_slice()) {
+        match connection.send(&Packet::Publish(publish_packet)) {
             Ok(_) => {
                 logfile.log_sent_message(message_str, client_id_str);
             }
@@ -84,19 +185,48 @@ impl Client {
         }
     }
 
+    /// Returns whether the client is disconnected and its session outlived its expiry interval
+    pub fn session_expired(&self, now: Instant) -> bool {
+        let disconnected_at = match self.disconnected_at {
+            Some(disconnected_at) => disconnected_at,
+            None => return false,
+        };
+
+        self.session_expiry_interval != u32::MAX
+            && now.duration_since(disconnected_at)
+                >= Duration::from_secs(self.session_expiry_interval as u64)
+    }
+
     /// Gets the id of the client
     pub fn id(&self) -> Vec<u8> {
         self.id.clone()
     }
 }
 
+/// Represents a QoS 1 or 2 publish sent to a client that was not acknowledged yet
+#[derive(Debug, Clone)]
+pub struct InflightMessage {
+    pub publish: Publish,
+    /// When the publish was last sent, to send it again if the acknowledgement takes too long
+    pub sent_at: Instant,
+}
+
+impl InflightMessage {
+    pub fn new(publish: Publish) -> Self {
+        InflightMessage {
+            publish,
+            sent_at: Instant::now(),
+        }
+    }
+}
+
 impl fmt::Display for Client {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let id = String::from_utf8_lossy(&self.id);
         let subscriptions = self
             .subscriptions
             .iter()
-            .map(|topic| topic.to_string())
+            .map(|(topic, qos)| format!("{} (QoS {})", topic, qos.to_byte()))
             .collect::<Vec<String>>()
             .join(", ");
 
@@ -112,21 +242,25 @@ impl fmt::Display for Client {
 
 #[cfg(test)]
 mod tests {
-    use std::{net::TcpListener, vec};
+    use std::{
+        net::{TcpListener, TcpStream},
+        vec,
+    };
 
-    use mqtt::model::components::topic_level::TopicLevel;
+    use mqtt::{keyring::Keyring, model::components::topic_level::TopicLevel};
 
     use super::*;
 
-    fn setup_stream() -> TcpStream {
+    fn setup_connection() -> Connection {
         let listener = TcpListener::bind("127.0.0.1:0").unwrap();
         let port = listener.local_addr().unwrap().port();
-        TcpStream::connect(format!("127.0.0.1:{}", port)).unwrap()
+        let stream = TcpStream::connect(format!("127.0.0.1:{}", port)).unwrap();
+        Connection::new(stream, usize::MAX, Keyring::new(0, &[0; 32])).unwrap()
     }
 
     fn setup_client() -> Client {
-        let stream = setup_stream();
-        Client::new(vec![1, 2, 3], Some(stream), true, 60)
+        let connection = setup_connection();
+        Client::new(vec![1, 2, 3], vec![], Some(connection), true, 60, None)
     }
 
     fn setup_topic_filter() -> Vec<TopicFilter> {
@@ -157,16 +291,17 @@ mod tests {
     fn test_add_subscription() {
         let mut client = setup_client();
         let topic = setup_topic_filter();
-        client.add_subscription(topic[0].clone());
+        client.add_subscription(topic[0].clone(), QoS::AtMost);
+        client.add_subscription(topic[0].clone(), QoS::AtLeast);
         assert_eq!(client.subscriptions.len(), 1);
-        assert_eq!(client.subscriptions[0], topic[0]);
+        assert_eq!(client.subscriptions[0], (topic[0].clone(), QoS::AtLeast));
     }
 
     #[test]
     fn test_remove_subscription() {
         let mut client = setup_client();
         let topic = setup_topic_filter();
-        client.add_subscription(topic[0].clone());
+        client.add_subscription(topic[0].clone(), QoS::AtMost);
         client.remove_subscription(&topic[0]);
         assert!(client.subscriptions.is_empty());
     }
@@ -175,21 +310,98 @@ mod tests {
     fn test_is_subscribed() {
         let mut client = setup_client();
         let topic_filter = setup_topic_filter();
-        client.add_subscription(topic_filter[0].clone());
+        client.add_subscription(topic_filter[0].clone(), QoS::AtMost);
         let topic_name = TopicName::new(vec![b"topic".to_vec()], false);
-        assert!(!client.is_subscribed(&topic_name));
+        assert!(client.granted_qos(&topic_name).is_none());
         let topic_name = TopicName::new(vec![b"topic".to_vec(), b"level".to_vec()], false);
-        assert!(client.is_subscribed(&topic_name));
+        assert!(client.granted_qos(&topic_name).is_some());
     }
 
     #[test]
     fn test_adding_multiple_subscriptions() {
         let mut client = setup_client();
         let topic_filter = setup_topic_filter();
-        client.add_subscription(topic_filter[0].clone());
-        client.add_subscription(topic_filter[1].clone());
+        client.add_subscription(topic_filter[0].clone(), QoS::AtMost);
+        client.add_subscription(topic_filter[1].clone(), QoS::Exactly);
         assert_eq!(client.subscriptions.len(), 2);
-        assert_eq!(client.subscriptions[0], topic_filter[0]);
-        assert_eq!(client.subscriptions[1], topic_filter[1]);
+        assert_eq!(
+            client.subscriptions[0],
+            (topic_filter[0].clone(), QoS::AtMost)
+        );
+        assert_eq!(
+            client.subscriptions[1],
+            (topic_filter[1].clone(), QoS::Exactly)
+        );
+    }
+
+    #[test]
+    fn test_granted_qos_is_the_highest_of_the_matching_subscriptions() {
+        let mut client = setup_client();
+        let topic_filter = setup_topic_filter();
+        let wildcard = TopicFilter::new(
+            vec![
+                TopicLevel::Literal(b"topic".to_vec()),
+                TopicLevel::SingleLevelWildcard,
+            ],
+            false,
+        );
+        let topic_name = TopicName::new(vec![b"topic".to_vec(), b"level".to_vec()], false);
+        assert_eq!(client.granted_qos(&topic_name), None);
+
+        client.add_subscription(topic_filter[0].clone(), QoS::AtLeast);
+        client.add_subscription(wildcard, QoS::AtMost);
+        client.add_subscription(topic_filter[1].clone(), QoS::Exactly);
+
+        assert_eq!(client.granted_qos(&topic_name), Some(QoS::AtLeast));
+    }
+
+    #[test]
+    fn test_acknowledge_matches_the_qos_of_the_inflight_message() {
+        let mut client = setup_client();
+        let topic_name = TopicName::new(vec![b"new-incident".to_vec()], false);
+        let publish = Publish::new(false, QoS::Exactly, false, topic_name, Some(1), vec![]);
+        client.inflight.push_back(InflightMessage::new(publish));
+        client.awaiting_pubcomp.insert(2);
+
+        assert_eq!(client.inflight_len(), 2);
+        assert!(client.acknowledge(1, QoS::AtLeast).is_none());
+        assert!(client.acknowledge(1, QoS::Exactly).is_some());
+        assert_eq!(client.inflight_len(), 1);
+    }
+
+    #[test]
+    fn test_next_packet_identifier() {
+        let mut client = setup_client();
+        assert_eq!(client.next_packet_identifier(), 1);
+        assert_eq!(client.next_packet_identifier(), 2);
+
+        client.next_packet_identifier = u16::MAX;
+        assert_eq!(client.next_packet_identifier(), u16::MAX);
+        assert_eq!(client.next_packet_identifier(), 1);
+    }
+
+    #[test]
+    fn test_next_packet_identifier_skips_the_ones_in_use() {
+        let mut client = setup_client();
+        let topic_name = TopicName::new(vec![b"new-incident".to_vec()], false);
+        let publish = Publish::new(false, QoS::AtLeast, false, topic_name, Some(1), vec![]);
+        client.inflight.push_back(InflightMessage::new(publish));
+        client.awaiting_pubcomp.insert(2);
+
+        assert_eq!(client.next_packet_identifier(), 3);
+    }
+
+    #[test]
+    fn test_session_expiry() {
+        let now = Instant::now();
+        let mut client = setup_client().with_session_expiry_interval(60);
+        assert!(!client.session_expired(now));
+
+        client.disconnected_at = Some(now);
+        assert!(!client.session_expired(now + Duration::from_secs(59)));
+        assert!(client.session_expired(now + Duration::from_secs(60)));
+
+        client.session_expiry_interval = u32::MAX;
+        assert!(!client.session_expired(now + Duration::from_secs(u32::MAX as u64)));
     }
 }
@@ -1,8 +1,7 @@
 use std::{
     collections::HashMap,
-    fs::{self, OpenOptions},
+    fs::OpenOptions,
     io::Write,
-    net::TcpStream,
     sync::{
         mpsc::{self, Sender},
         Arc, Mutex,
@@ -10,28 +9,37 @@ use std::{
     thread,
 };
 
-use mqtt::model::{
-    packets::{connack::Connack, connect::Connect},
-    return_codes::connect_return_code::ConnectReturnCode,
+use mqtt::{
+    key_exchange::{open_login, KeyExchange},
+    model::{
+        components::{key_share::KeyShare, protocol_version::ProtocolVersion},
+        packet::Packet,
+        packets::{connack::Connack, connect::Connect},
+        return_codes::{connect_return_code::ConnectReturnCode, reason_code::ReasonCode},
+    },
 };
 
 use crate::{
     client::Client,
+    connection::Connection,
+    credentials::{hash_password, verify_password, LoginEntry, LoginFile},
     error::{ServerError, ServerResult},
 };
 
 /// Represents a client ID
 type ClientId = Vec<u8>;
-/// Represents a tuple of a username, password, and whether the client is connected
-type Logins = (Vec<u8>, Vec<u8>, bool); // username, password, is_connected
+/// Represents a tuple of a username and password hash
+type Logins = (Vec<u8>, String); // username, password_hash
 /// Represents a map of client IDs to login information
 type Clients = HashMap<ClientId, Logins>;
 
 /// Represents a manager that handles clients in the server such as registering and authenticating them
-/// and processing connect packets validating the login information
+/// and processing connect packets validating the login information.
+/// It also keeps the keys of the clients that have their own, from which the keys of their sessions are derived
 #[derive(Debug, Clone)]
 pub struct ClientManager {
     registered_clients: Arc<Mutex<Clients>>,
+    client_keys: Arc<HashMap<ClientId, Vec<u8>>>,
     file_sender: Sender<String>,
 }
 
@@ -65,10 +73,22 @@ impl ClientManager {
 
         Self {
             registered_clients: Arc::new(Mutex::new(registered_clients)),
+            client_keys: Arc::new(HashMap::new()),
             file_sender: sender,
         }
     }
 
+    /// Sets the keys of the clients that have their own. The rest use the key of the server
+    pub fn with_client_keys(mut self, client_keys: HashMap<ClientId, Vec<u8>>) -> Self {
+        self.client_keys = Arc::new(client_keys);
+        self
+    }
+
+    /// Returns whether a client has a key of its own instead of using the key of the server
+    pub fn has_own_key(&self, client_id: &[u8]) -> bool {
+        self.client_keys.contains_key(client_id)
+    }
+
     /// Registers a client with the specified client ID, username, and password
     pub fn register_client(
         &self,
@@ -76,8 +96,9 @@ impl ClientManager {
         username: Vec<u8>,
         password: Vec<u8>,
     ) -> ServerResult<()> {
-        self.add_client(client_id.clone(), username.clone(), password.clone())?;
-        self.save_client(client_id, username, password)?;
+        let password_hash = hash_password(&password)?;
+        self.add_client(client_id.clone(), username.clone(), password_hash.clone())?;
+        self.save_client(client_id, username, password_hash)?;
 
         Ok(())
     }
@@ -87,128 +108,200 @@ impl ClientManager {
         &self,
         client_id: Vec<u8>,
         username: Vec<u8>,
-        password: Vec<u8>,
+        password_hash: String,
     ) -> ServerResult<()> {
         let mut registered_clients = self.registered_clients.lock()?;
-        registered_clients.insert(
-            client_id.clone(),
-            (username.clone(), password.clone(), false),
-        );
+        registered_clients.insert(client_id, (username, password_hash));
 
         Ok(())
     }
 
-    /// Saves a client to the login file
+    /// Saves a client to the login file. Only the hash of its password is written
     fn save_client(
         &self,
         client_id: Vec<u8>,
         username: Vec<u8>,
-        password: Vec<u8>,
+        password_hash: String,
     ) -> ServerResult<()> {
-        let client_id = String::from_utf8(client_id)?;
-
-        let username = String::from_utf8(username)?;
-
-        let password = String::from_utf8(password)?;
-
-        let login_entry = format!("{} = {} = {}", client_id, username, password);
+        let login_entry = LoginEntry {
+            client_id: String::from_utf8(client_id)?,
+            username: String::from_utf8(username)?,
+            password_hash,
+        };
 
-        self.file_sender.send(login_entry)?;
+        self.file_sender.send(login_entry.to_line())?;
 
         Ok(())
     }
 
-    /// Authenticates a client with the specified client ID, username, and password
+    /// Authenticates a client with the specified client ID, username, and password,
+    /// checking the password against the stored hash.
+    /// The hash is checked without holding the lock of the registered clients, since it takes a while.
+    /// A client that is already connected is authenticated too, since the task handler lets its new connection
+    /// take over the old one
     pub fn authenticate_client(
         &self,
         client_id: Vec<u8>,
         username: Vec<u8>,
         password: Vec<u8>,
     ) -> ServerResult<bool> {
-        let mut registered_clients = self.registered_clients.lock()?;
-
-        if let Some((stored_username, stored_password, is_connected)) =
-            registered_clients.get_mut(&client_id)
-        {
-            if *is_connected {
-                return Ok(false);
-            }
-
-            if stored_username == &username && stored_password == &password {
-                *is_connected = true;
-                return Ok(true);
+        let stored_password_hash = match self.registered_clients.lock()?.get(&client_id) {
+            Some((stored_username, stored_password_hash)) if stored_username == &username => {
+                stored_password_hash.clone()
             }
-        }
-        Ok(false)
-    }
-
-    /// Disconnects a client with the specified client ID
-    pub fn disconnect_client(&self, client_id: Vec<u8>) -> ServerResult<()> {
-        let mut registered_clients = self.registered_clients.lock()?;
-        if let Some((_, _, is_connected)) = registered_clients.get_mut(&client_id) {
-            *is_connected = false;
-        }
+            _ => return Ok(false),
+        };
 
-        Ok(())
+        Ok(verify_password(&password, &stored_password_hash))
     }
 
-    /// Processes a connect packet by validating the login information and authenticating the client
+    /// Processes a connect packet by validating the login information and authenticating the client.
+    /// A client that presented a certificate over TLS must use the subject name of the certificate as
+    /// its id, and takes it if its Connect packet has an empty one.
+    /// A client of an encrypted connection must send its key share, and the key of its session is agreed
+    /// from its own key or, if it has none, from the key of the server.
+    /// The enhanced authentication of MQTT 5.0 is not supported, so a Connect with an authentication method is refused.
+    /// Checking the password takes a while, so the server calls it from the threads that authenticate the clients
     pub fn process_connect_packet(
         &self,
         connect_packet: Connect,
-        stream: TcpStream,
-        key: &[u8],
+        connection: &Connection,
     ) -> Option<Client> {
-        let client_id = connect_packet.client_id().content().to_vec();
-        let (username, password) = match self.get_login_info(&connect_packet) {
-            Ok(login) => login,
-            Err(_) => {
-                self.failure_connection(stream, ConnectReturnCode::BadUsernameOrPassword, key);
+        if connect_packet
+            .properties()
+            .authentication_method()
+            .is_some()
+        {
+            let connack = Connack::new(false, ConnectReturnCode::NotAuthorized)
+                .with_reason_code(ReasonCode::BadAuthenticationMethod);
+            self.send_failure_connack(connection, connack);
+            return None;
+        }
+
+        let mut client_id = connect_packet.client_id().content().to_vec();
+        if let Some(certificate_name) = connection.certificate_name() {
+            if client_id.is_empty() {
+                client_id = certificate_name.into_bytes();
+            } else if client_id != certificate_name.as_bytes() {
+                self.failure_connection(connection, ConnectReturnCode::IdentifierRejected);
+                return None;
+            }
+        }
+        if !connection.key().is_empty() {
+            if let Err(err) = self.agree_session_key(&connect_packet, &client_id, connection) {
+                println!("Error agreeing the session key: {:?}", err);
+                self.failure_connection(connection, ConnectReturnCode::NotAuthorized);
                 return None;
             }
+        }
+        let will = connect_packet.will().cloned();
+        let clean_session = connect_packet.clean_session();
+        let session_expiry_interval = match connect_packet.protocol_version() {
+            ProtocolVersion::V311 if clean_session => 0,
+            ProtocolVersion::V311 => u32::MAX,
+            ProtocolVersion::V5 => connect_packet
+                .properties()
+                .session_expiry_interval()
+                .unwrap_or(0),
         };
+        let (username, password) =
+            match self.get_login_info(&connect_packet, &client_id, connection) {
+                Ok(login) => login,
+                Err(_) => {
+                    self.failure_connection(connection, ConnectReturnCode::BadUsernameOrPassword);
+                    return None;
+                }
+            };
 
-        match self.authenticate_client(client_id.clone(), username, password) {
-            Ok(true) => {
-                let stream = match stream.try_clone() {
-                    Ok(stream) => stream,
-                    Err(err) => {
-                        println!("Error cloning stream: {:?}", err);
-                        return None;
-                    }
-                };
-                Some(Client::new(client_id.clone(), Some(stream), true, 0))
-            }
+        match self.authenticate_client(client_id.clone(), username.clone(), password) {
+            Ok(true) => Some(
+                Client::new(
+                    client_id.clone(),
+                    username,
+                    Some(connection.clone()),
+                    clean_session,
+                    0,
+                    will,
+                )
+                .with_session_expiry_interval(session_expiry_interval),
+            ),
 
             Ok(false) => {
-                self.failure_connection(stream, ConnectReturnCode::IdentifierRejected, key);
+                self.failure_connection(connection, ConnectReturnCode::IdentifierRejected);
                 None
             }
             Err(err) => {
                 println!("Error authenticating client: {:?}", err);
                 None
-            } 
+            }
         }
     }
 
-    /// Handles a failed connection by sending a Connack packet with the specified return code
-    fn failure_connection(
+    /// Derives the key of the session from the key share of the client, leaving it in the connection
+    /// until the Connack is sent
+    fn agree_session_key(
         &self,
-        mut stream: TcpStream,
-        return_code: ConnectReturnCode,
-        key: &[u8],
-    ) {
-        let connack = Connack::new(false, return_code);
-        let connack_bytes = connack.to_bytes(key);
-
-        if let Err(err) = stream.write_all(&connack_bytes) {
+        connect_packet: &Connect,
+        client_id: &[u8],
+        connection: &Connection,
+    ) -> ServerResult<()> {
+        let client_public_key = connect_packet
+            .key_share()
+            .ok_or_else(|| ServerError::ClientConnection("No key share provided".to_string()))?
+            .public_key();
+        let client_key = match self.client_keys.get(client_id) {
+            Some(client_key) => client_key.as_slice(),
+            None => connection.key(),
+        };
+
+        let key_exchange = KeyExchange::new();
+        let public_key = key_exchange.public_key();
+        let (session_key, confirmation) =
+            key_exchange.accept(client_public_key, client_id, client_key)?;
+        connection.start_session(
+            session_key,
+            KeyShare::with_confirmation(public_key, confirmation),
+        )?;
+
+        Ok(())
+    }
+
+    /// Handles a failed connection by sending a Connack packet with the specified return code
+    fn failure_connection(&self, connection: &Connection, return_code: ConnectReturnCode) {
+        self.send_failure_connack(connection, Connack::new(false, return_code));
+    }
+
+    /// Sends a Connack packet that refuses the connection
+    fn send_failure_connack(&self, connection: &Connection, connack: Connack) {
+        if let Err(err) = connection.send(&Packet::Connack(connack)) {
             println!("Error sending Connack packet: {:?}", err);
         }
     }
 
-    /// Gets the login information from a connect packet
-    pub fn get_login_info(&self, connect_packet: &Connect) -> ServerResult<(Vec<u8>, Vec<u8>)> {
+    /// Gets the login information from a connect packet.
+    /// On encrypted connections, the clients with a key of their own seal their login with it, so the key
+    /// shared by every client does not expose it, and a login that is not sealed is refused
+    pub fn get_login_info(
+        &self,
+        connect_packet: &Connect,
+        client_id: &[u8],
+        connection: &Connection,
+    ) -> ServerResult<(Vec<u8>, Vec<u8>)> {
         let login = connect_packet.login().ok_or(ServerError::NoLoginProvided)?;
+        let opened_login;
+        let login = match self.client_keys.get(client_id) {
+            Some(client_key) if !connection.key().is_empty() => {
+                let public_key = connect_packet
+                    .key_share()
+                    .ok_or_else(|| {
+                        ServerError::ClientConnection("No key share provided".to_string())
+                    })?
+                    .public_key();
+                opened_login = open_login(login, client_id, public_key, client_key)?;
+                &opened_login
+            }
+            _ => login,
+        };
         let username = login.username().content().to_vec();
         let password = login
             .password()
@@ -218,23 +311,30 @@ impl ClientManager {
         Ok((username, password))
     }
 
-    /// Makes the initial registrations reading the configuration file
+    /// Makes the initial registrations reading the configuration file.
+    /// If the file has plaintext passwords it is rewritten with their hashes
     fn intials_registers(path: &str) -> HashMap<ClientId, Logins> {
-        let content = match fs::read_to_string(path) {
-            Ok(content) => content,
-            Err(_) => return HashMap::new(),
+        let login_file = match LoginFile::open(path) {
+            Ok(login_file) => login_file,
+            Err(err) => {
+                eprintln!("Failed to read login file: {}", err);
+                return HashMap::new();
+            }
         };
 
+        if login_file.is_migrated() {
+            match login_file.save() {
+                Ok(_) => println!("Hashed the plaintext passwords of the login file"),
+                Err(err) => eprintln!("Failed to hash the passwords of the login file: {}", err),
+            }
+        }
+
         let mut registered_clients = HashMap::new();
 
-        for line in content.lines() {
-            let parts: Vec<&str> = line.split('=').map(|s| s.trim()).collect();
-            if parts.len() == 3 {
-                let client_id = parts[0].as_bytes().to_vec();
-                let username = parts[1].as_bytes().to_vec();
-                let password = parts[2].as_bytes().to_vec();
-                registered_clients.insert(client_id, (username, password, false));
-            }
+        for entry in login_file.entries() {
+            let client_id = entry.client_id.as_bytes().to_vec();
+            let username = entry.username.as_bytes().to_vec();
+            registered_clients.insert(client_id, (username, entry.password_hash.clone()));
         }
 
         registered_clients
@@ -243,11 +343,97 @@ impl ClientManager {
 
 #[cfg(test)]
 mod tests {
+    use std::{net::TcpListener, net::TcpStream, time::Duration};
+
+    use mqtt::{
+        key_exchange::seal_login,
+        keyring::Keyring,
+        model::components::{encoded_string::EncodedString, login::Login},
+        Direction, FrameKey,
+    };
+    use rustls::{ClientConnection, StreamOwned};
+
     use super::*;
+    use crate::credentials::testing::TempFile;
+    use crate::tls::testing::tls_configs;
+
+    /// Accepts a TLS connection from a client that presents a certificate issued to the name
+    fn accept_tls_connection(client_name: &str) -> Connection {
+        let (server_config, client_config) =
+            tls_configs("test_tls_client_manager", Some(client_name));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let session =
+                ClientConnection::new(client_config, "localhost".try_into().unwrap()).unwrap();
+            let mut stream = StreamOwned::new(session, TcpStream::connect(address).unwrap());
+            stream.write_all(&[0]).unwrap();
+            thread::sleep(Duration::from_secs(1));
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let connection = Connection::with_tls(stream, server_config, usize::MAX).unwrap();
+        let mut decoder = mqtt::codec::packet_decoder::PacketDecoder::new();
+        while connection.certificate_name().is_none() {
+            connection.flush().unwrap();
+            let _ = connection.read_available(&mut decoder, usize::MAX);
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        connection
+    }
+
+    /// Accepts a connection encrypted with the key, returning it and the socket of the client
+    fn accept_encrypted_connection(key: &[u8]) -> (Connection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let keyring = Keyring::new(0, key);
+        (Connection::new(stream, usize::MAX, keyring).unwrap(), peer)
+    }
+
+    fn connect_packet(client_id: &str) -> Connect {
+        let login = Login::new(
+            EncodedString::from_string(&"drone".to_string()),
+            Some(EncodedString::from_string(&"password".to_string())),
+        );
+        Connect::new(
+            true,
+            0,
+            EncodedString::from_string(&client_id.to_string()),
+            None,
+            Some(login),
+        )
+    }
+
+    /// Returns the Connect of a client with a key of its own, whose login is sealed with the key specified
+    fn sealed_connect_packet(
+        client_id: &str,
+        key_exchange: &KeyExchange,
+        client_key: &[u8],
+    ) -> Connect {
+        let public_key = key_exchange.public_key();
+        let login = Login::new(
+            EncodedString::from_string(&"drone".to_string()),
+            Some(EncodedString::from_string(&"password".to_string())),
+        );
+        let login = seal_login(&login, client_id.as_bytes(), &public_key, client_key).unwrap();
+        Connect::new(
+            true,
+            0,
+            EncodedString::from_string(&client_id.to_string()),
+            None,
+            Some(login),
+        )
+        .with_key_share(KeyShare::new(public_key))
+    }
 
     #[test]
     fn test_register_client() {
-        let client_manager = ClientManager::new("test_login_file.txt");
+        let login_file = TempFile::new("test_register_client.txt");
+        let client_manager = ClientManager::new(login_file.path());
         let client_id = b"client1".to_vec();
         let username = b"username".to_vec();
         let password = b"password".to_vec();
@@ -258,13 +444,13 @@ mod tests {
         let registered_clients = client_manager.registered_clients.lock().unwrap();
         let logins = registered_clients.get(&client_id).unwrap();
         assert_eq!(logins.0, username);
-        assert_eq!(logins.1, password);
-        assert!(!logins.2);
+        assert!(verify_password(&password, &logins.1));
     }
 
     #[test]
     fn test_authenticate_client() {
-        let client_manager = ClientManager::new("test_login_file.txt");
+        let login_file = TempFile::new("test_authenticate_client.txt");
+        let client_manager = ClientManager::new(login_file.path());
         let client_id = b"client1".to_vec();
         let username = b"username".to_vec();
         let password = b"password".to_vec();
@@ -272,6 +458,10 @@ mod tests {
         let _ =
             client_manager.register_client(client_id.clone(), username.clone(), password.clone());
 
+        assert!(client_manager
+            .authenticate_client(client_id.clone(), username.clone(), password.clone())
+            .unwrap());
+        // A client that reconnects before its previous connection is dropped takes it over
         assert!(client_manager
             .authenticate_client(client_id.clone(), username.clone(), password.clone())
             .unwrap());
@@ -282,4 +472,125 @@ mod tests {
             .authenticate_client(client_id.clone(), username.clone(), b"wrong".to_vec())
             .unwrap());
     }
+
+    #[test]
+    fn test_client_certificate_name_is_the_client_id() {
+        let login_file = TempFile::new("test_client_certificate_name.txt");
+        let client_manager = ClientManager::new(login_file.path());
+        client_manager
+            .register_client(b"drone-7".to_vec(), b"drone".to_vec(), b"password".to_vec())
+            .unwrap();
+        let connection = accept_tls_connection("drone-7");
+
+        assert!(client_manager
+            .process_connect_packet(connect_packet("drone-8"), &connection)
+            .is_none());
+
+        let client = client_manager
+            .process_connect_packet(connect_packet(""), &connection)
+            .unwrap();
+        assert_eq!(client.id(), b"drone-7".to_vec());
+    }
+
+    #[test]
+    fn test_session_key_is_agreed_from_the_key_of_the_client() {
+        let server_key = [0; 32];
+        let client_key = [7; 32];
+        let login_file = TempFile::new("test_session_key_is_agreed.txt");
+        let client_manager = ClientManager::new(login_file.path())
+            .with_client_keys(HashMap::from([(b"drone-7".to_vec(), client_key.to_vec())]));
+        client_manager
+            .register_client(b"drone-7".to_vec(), b"drone".to_vec(), b"password".to_vec())
+            .unwrap();
+        let (connection, mut peer) = accept_encrypted_connection(&server_key);
+
+        let key_exchange = KeyExchange::new();
+        let connect = sealed_connect_packet("drone-7", &key_exchange, &client_key);
+        assert!(client_manager
+            .process_connect_packet(connect, &connection)
+            .is_some());
+        connection
+            .send_connack(Connack::new(false, ConnectReturnCode::ConnectionAccepted))
+            .unwrap();
+
+        let key_share = match Packet::from_bytes(
+            &mut peer,
+            FrameKey::from(&server_key).with_direction(Direction::ToClient),
+        )
+        .unwrap()
+        {
+            Packet::Connack(connack) => connack.key_share().unwrap().clone(),
+            packet => panic!("Expected a Connack, received {:?}", packet),
+        };
+        let session_key = key_exchange
+            .complete(
+                key_share.public_key(),
+                key_share.confirmation().unwrap(),
+                b"drone-7",
+                &client_key,
+            )
+            .unwrap();
+        assert_eq!(connection.key(), session_key.as_slice());
+    }
+
+    #[test]
+    fn test_login_not_sealed_with_the_key_of_the_client_is_refused() {
+        let login_file = TempFile::new("test_login_not_sealed.txt");
+        let client_manager = ClientManager::new(login_file.path())
+            .with_client_keys(HashMap::from([(b"drone-7".to_vec(), [7; 32].to_vec())]));
+        client_manager
+            .register_client(b"drone-7".to_vec(), b"drone".to_vec(), b"password".to_vec())
+            .unwrap();
+
+        for connect in [
+            connect_packet("drone-7")
+                .with_key_share(KeyShare::new(KeyExchange::new().public_key())),
+            sealed_connect_packet("drone-7", &KeyExchange::new(), &[0; 32]),
+        ] {
+            let (connection, mut peer) = accept_encrypted_connection(&[0; 32]);
+            assert!(client_manager
+                .process_connect_packet(connect, &connection)
+                .is_none());
+
+            match Packet::from_bytes(
+                &mut peer,
+                FrameKey::from(&[0; 32]).with_direction(Direction::ToClient),
+            )
+            .unwrap()
+            {
+                Packet::Connack(connack) => assert_eq!(
+                    *connack.connect_return_code(),
+                    ConnectReturnCode::BadUsernameOrPassword
+                ),
+                packet => panic!("Expected a Connack, received {:?}", packet),
+            }
+        }
+    }
+
+    #[test]
+    fn test_encrypted_connection_without_key_share_is_refused() {
+        let login_file = TempFile::new("test_encrypted_connection_without_key_share.txt");
+        let client_manager = ClientManager::new(login_file.path());
+        client_manager
+            .register_client(b"drone-7".to_vec(), b"drone".to_vec(), b"password".to_vec())
+            .unwrap();
+        let (connection, mut peer) = accept_encrypted_connection(&[0; 32]);
+
+        assert!(client_manager
+            .process_connect_packet(connect_packet("drone-7"), &connection)
+            .is_none());
+
+        match Packet::from_bytes(
+            &mut peer,
+            FrameKey::from(&[0; 32]).with_direction(Direction::ToClient),
+        )
+        .unwrap()
+        {
+            Packet::Connack(connack) => assert_eq!(
+                *connack.connect_return_code(),
+                ConnectReturnCode::NotAuthorized
+            ),
+            packet => panic!("Expected a Connack, received {:?}", packet),
+        }
+    }
 }
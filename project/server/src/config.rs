@@ -1,13 +1,44 @@
 use std::{fs, io, path::Path};
 
+use crate::offline_queue::OverflowPolicy;
+
+const DEFAULT_SEGS_TO_CONNECT: u32 = 10;
+const DEFAULT_MAX_PENDING_CONNECTIONS: usize = 64;
+const DEFAULT_MAX_OUTGOING_BYTES: usize = 1024 * 1024;
+const DEFAULT_MAX_PACKET_SIZE: usize = 256 * 1024;
+const DEFAULT_MAX_INFLIGHT_MESSAGES: usize = 20;
+const DEFAULT_SEGS_TO_RETRY: u32 = 10;
+const DEFAULT_SEGS_TO_PUBLISH_STATS: u32 = 10;
+const DEFAULT_MAX_OFFLINE_MESSAGES: usize = 1000;
+const DEFAULT_MAX_OFFLINE_BYTES: usize = 1024 * 1024;
+
 /// Represents the configuration of the server
 #[derive(Debug, Clone)]
 pub struct Config {
     address: String,
+    plain_address: String,
+    tls_address: String,
+    tls_certificate_file: String,
+    tls_private_key_file: String,
+    tls_client_ca_file: String,
     key: [u8; 32],
     log_file: String,
     login_file: String,
+    client_keys_file: String,
+    keyring_file: String,
+    acl_file: String,
     segs_to_disconnect: u32,
+    segs_to_connect: u32,
+    max_pending_connections: usize,
+    max_outgoing_bytes: usize,
+    max_packet_size: usize,
+    max_inflight_messages: usize,
+    segs_to_retry: u32,
+    segs_to_publish_stats: u32,
+    max_offline_messages: usize,
+    max_offline_bytes: usize,
+    offline_overflow_policy: OverflowPolicy,
+    queue_rules_file: String,
     initialize_with_backup: bool,
     backup_file: String,
     segs_to_backup: u32,
@@ -20,10 +51,29 @@ impl Config {
 
         let mut config = Config {
             address: String::new(),
+            plain_address: String::new(),
+            tls_address: String::new(),
+            tls_certificate_file: String::new(),
+            tls_private_key_file: String::new(),
+            tls_client_ca_file: String::new(),
             key: [0; 32],
             log_file: String::new(),
             login_file: String::new(),
+            client_keys_file: String::new(),
+            keyring_file: String::new(),
+            acl_file: String::new(),
             segs_to_disconnect: 0,
+            segs_to_connect: DEFAULT_SEGS_TO_CONNECT,
+            max_pending_connections: DEFAULT_MAX_PENDING_CONNECTIONS,
+            max_outgoing_bytes: DEFAULT_MAX_OUTGOING_BYTES,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+            max_inflight_messages: DEFAULT_MAX_INFLIGHT_MESSAGES,
+            segs_to_retry: DEFAULT_SEGS_TO_RETRY,
+            segs_to_publish_stats: DEFAULT_SEGS_TO_PUBLISH_STATS,
+            max_offline_messages: DEFAULT_MAX_OFFLINE_MESSAGES,
+            max_offline_bytes: DEFAULT_MAX_OFFLINE_BYTES,
+            offline_overflow_policy: OverflowPolicy::DropOldest,
+            queue_rules_file: String::new(),
             initialize_with_backup: false,
             backup_file: String::new(),
             segs_to_backup: 0,
@@ -34,6 +84,19 @@ impl Config {
             if parts.len() == 2 {
                 match parts[0] {
                     "address" => config.address = parts[1].trim_matches('"').to_string(),
+                    "plain_address" => {
+                        config.plain_address = parts[1].trim_matches('"').to_string()
+                    }
+                    "tls_address" => config.tls_address = parts[1].trim_matches('"').to_string(),
+                    "tls_certificate_file" => {
+                        config.tls_certificate_file = parts[1].trim_matches('"').to_string()
+                    }
+                    "tls_private_key_file" => {
+                        config.tls_private_key_file = parts[1].trim_matches('"').to_string()
+                    }
+                    "tls_client_ca_file" => {
+                        config.tls_client_ca_file = parts[1].trim_matches('"').to_string()
+                    }
                     "key" => {
                         let key_str = parts[1].trim_matches('"');
                         if key_str.len() != 32 {
@@ -50,6 +113,11 @@ impl Config {
                     }
                     "log_file" => config.log_file = parts[1].trim_matches('"').to_string(),
                     "login_file" => config.login_file = parts[1].trim_matches('"').to_string(),
+                    "client_keys_file" => {
+                        config.client_keys_file = parts[1].trim_matches('"').to_string()
+                    }
+                    "keyring_file" => config.keyring_file = parts[1].trim_matches('"').to_string(),
+                    "acl_file" => config.acl_file = parts[1].trim_matches('"').to_string(),
                     "segs_to_disconnect" => {
                         config.segs_to_disconnect = parts[1].parse().map_err(|_| {
                             io::Error::new(
@@ -58,6 +126,92 @@ impl Config {
                             )
                         })?
                     }
+                    "segs_to_connect" => {
+                        config.segs_to_connect = parts[1].parse().map_err(|_| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "Invalid segs_to_connect value",
+                            )
+                        })?
+                    }
+                    "max_pending_connections" => {
+                        config.max_pending_connections = parts[1].parse().map_err(|_| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "Invalid max_pending_connections value",
+                            )
+                        })?
+                    }
+                    "max_outgoing_bytes" => {
+                        config.max_outgoing_bytes = parts[1].parse().map_err(|_| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "Invalid max_outgoing_bytes value",
+                            )
+                        })?
+                    }
+                    "max_packet_size" => {
+                        config.max_packet_size = parts[1].parse().map_err(|_| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "Invalid max_packet_size value",
+                            )
+                        })?
+                    }
+                    "max_inflight_messages" => {
+                        config.max_inflight_messages = parts[1].parse().map_err(|_| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "Invalid max_inflight_messages value",
+                            )
+                        })?
+                    }
+                    "segs_to_retry" => {
+                        config.segs_to_retry = parts[1].parse().map_err(|_| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "Invalid segs_to_retry value",
+                            )
+                        })?
+                    }
+                    "segs_to_publish_stats" => {
+                        config.segs_to_publish_stats = parts[1].parse().map_err(|_| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "Invalid segs_to_publish_stats value",
+                            )
+                        })?
+                    }
+                    "max_offline_messages" => {
+                        config.max_offline_messages = parts[1].parse().map_err(|_| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "Invalid max_offline_messages value",
+                            )
+                        })?
+                    }
+                    "max_offline_bytes" => {
+                        config.max_offline_bytes = parts[1].parse().map_err(|_| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "Invalid max_offline_bytes value",
+                            )
+                        })?
+                    }
+                    "offline_overflow_policy" => {
+                        config.offline_overflow_policy = OverflowPolicy::from_str(
+                            parts[1].trim_matches('"'),
+                        )
+                        .ok_or_else(|| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "Invalid offline_overflow_policy value",
+                            )
+                        })?
+                    }
+                    "queue_rules_file" => {
+                        config.queue_rules_file = parts[1].trim_matches('"').to_string()
+                    }
                     "initialize_with_backup" => {
                         config.initialize_with_backup =
                             matches!(parts[1].to_lowercase().as_str(), "true")
@@ -84,6 +238,45 @@ impl Config {
         &self.address
     }
 
+    /// Returns the address of the listener that speaks standard MQTT 3.1.1 without encryption,
+    /// None if the server only accepts encrypted connections
+    pub fn get_plain_address(&self) -> Option<String> {
+        if self.plain_address.is_empty() {
+            None
+        } else {
+            Some(self.plain_address.clone())
+        }
+    }
+
+    /// Returns the address of the listener that speaks MQTT over TLS, None if TLS is disabled
+    pub fn get_tls_address(&self) -> Option<String> {
+        if self.tls_address.is_empty() {
+            None
+        } else {
+            Some(self.tls_address.clone())
+        }
+    }
+
+    /// Returns the PEM file with the certificate chain the server presents over TLS
+    pub fn get_tls_certificate_file(&self) -> &str {
+        &self.tls_certificate_file
+    }
+
+    /// Returns the PEM file with the private key of the certificate of the server
+    pub fn get_tls_private_key_file(&self) -> &str {
+        &self.tls_private_key_file
+    }
+
+    /// Returns the PEM file with the certificate authorities that sign the certificates of the clients,
+    /// None if the clients connecting over TLS do not need a certificate
+    pub fn get_tls_client_ca_file(&self) -> Option<String> {
+        if self.tls_client_ca_file.is_empty() {
+            None
+        } else {
+            Some(self.tls_client_ca_file.clone())
+        }
+    }
+
     /// Returns the log file of the server
     pub fn get_log_file(&self) -> &str {
         &self.log_file
@@ -94,6 +287,102 @@ impl Config {
         &self.login_file
     }
 
+    /// Returns the file with the key of each client, None if every client uses the key of the server
+    pub fn get_client_keys_file(&self) -> Option<String> {
+        if self.client_keys_file.is_empty() {
+            None
+        } else {
+            Some(self.client_keys_file.clone())
+        }
+    }
+
+    /// Returns the file with the current and previous keys of the encryption, where rotated keys are added.
+    /// None if the server only has the key of the settings
+    pub fn get_keyring_file(&self) -> Option<String> {
+        if self.keyring_file.is_empty() {
+            None
+        } else {
+            Some(self.keyring_file.clone())
+        }
+    }
+
+    /// Returns the ACL file of the server, None if every client has full access
+    pub fn get_acl_file(&self) -> Option<String> {
+        if self.acl_file.is_empty() {
+            None
+        } else {
+            Some(self.acl_file.clone())
+        }
+    }
+
+    /// Returns the seconds to wait for a packet before disconnecting a client without keep alive
+    pub fn get_segs_to_disconnect(&self) -> u32 {
+        self.segs_to_disconnect
+    }
+
+    /// Returns the seconds a new connection has to send its Connect packet
+    pub fn get_segs_to_connect(&self) -> u32 {
+        self.segs_to_connect
+    }
+
+    /// Returns the maximum number of connections waiting for their Connect packet at the same time
+    pub fn get_max_pending_connections(&self) -> usize {
+        self.max_pending_connections
+    }
+
+    /// Returns the maximum amount of bytes queued for a client before its connection is closed
+    pub fn get_max_outgoing_bytes(&self) -> usize {
+        self.max_outgoing_bytes
+    }
+
+    /// Returns the maximum size in bytes of the packets received. Larger packets close the connection
+    pub fn get_max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    /// Returns the maximum number of QoS 1 and 2 messages sent to a client and not yet acknowledged.
+    /// The next ones wait until the client acknowledges one
+    pub fn get_max_inflight_messages(&self) -> usize {
+        self.max_inflight_messages.max(1)
+    }
+
+    /// Returns the seconds to wait for the acknowledgement of a message before sending it again.
+    /// With 0 the messages are only sent again when the client resumes its session
+    pub fn get_segs_to_retry(&self) -> u32 {
+        self.segs_to_retry
+    }
+
+    /// Returns the seconds between the publications of the statistics of the broker on the `$SYS` topics.
+    /// With 0 they are not published
+    pub fn get_segs_to_publish_stats(&self) -> u32 {
+        self.segs_to_publish_stats
+    }
+
+    /// Returns the maximum number of messages queued for a client that is not connected, 0 for no limit
+    pub fn get_max_offline_messages(&self) -> usize {
+        self.max_offline_messages
+    }
+
+    /// Returns the maximum bytes of the payloads queued for a client that is not connected, 0 for no limit
+    pub fn get_max_offline_bytes(&self) -> usize {
+        self.max_offline_bytes
+    }
+
+    /// Returns what is done with the messages for a client whose offline queue is full
+    pub fn get_offline_overflow_policy(&self) -> OverflowPolicy {
+        self.offline_overflow_policy
+    }
+
+    /// Returns the file with the time to live and mode of the queued messages of each topic,
+    /// None if every topic keeps all its messages until they are delivered
+    pub fn get_queue_rules_file(&self) -> Option<String> {
+        if self.queue_rules_file.is_empty() {
+            None
+        } else {
+            Some(self.queue_rules_file.clone())
+        }
+    }
+
     /// Returns the key of the encryption
     pub fn get_key(&self) -> &[u8; 32] {
         &self.key
@@ -0,0 +1,542 @@
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
+
+use mio::{Registry, Token};
+use mqtt::{
+    codec::{packet_decoder::PacketDecoder, packet_encoder::PacketEncoder},
+    keyring::Keyring,
+    model::{
+        components::{key_share::KeyShare, protocol_version::ProtocolVersion},
+        packet::Packet,
+        packets::{connack::Connack, disconnect::Disconnect},
+        return_codes::reason_code::ReasonCode,
+    },
+    Direction, FrameKey,
+};
+use rustls::ServerConfig;
+
+use crate::{stats::BrokerStats, transport::Transport};
+
+/// Represents the socket of a connected client shared by the I/O loop of the server and the task handler.
+/// The socket is non blocking: packets are written right away while the socket accepts them and the rest
+/// is queued until the I/O loop flushes it, so a slow client never blocks the thread that sends to it.
+/// The connection is closed if the outgoing queue grows past its limit.
+/// Each connection keeps the keyring of the listener that accepted it, empty if it speaks plain MQTT or TLS.
+/// The Connect may be encrypted with any key of the keyring, and the Connack is encrypted with the same one.
+/// An encrypted connection switches to the key agreed for its session once the Connack is sent.
+/// The encoder of the outgoing queue numbers the encrypted packets, so they are encoded under its lock
+/// in the order they are sent, in the version of MQTT the Connect of the client chose
+#[derive(Clone)]
+pub struct Connection {
+    inner: Arc<ConnectionInner>,
+}
+
+struct ConnectionInner {
+    transport: Transport,
+    keyring: Keyring,
+    /// Id of the key of the keyring the client encrypted its Connect with
+    key_id: OnceLock<u32>,
+    session_key: OnceLock<Vec<u8>>,
+    /// Key agreed with the client and the key share that completes the agreement in the Connack
+    pending_session: Mutex<Option<(Vec<u8>, KeyShare)>>,
+    outgoing: Mutex<PacketEncoder>,
+    max_outgoing_bytes: usize,
+    closed: AtomicBool,
+    /// Traffic of the broker the bytes read and written are added to
+    stats: OnceLock<Arc<BrokerStats>>,
+}
+
+impl Connection {
+    /// Creates a connection over a socket whose packets are encrypted with the keys of the keyring,
+    /// not encrypted if it is empty, setting it as non blocking
+    pub fn new(stream: TcpStream, max_outgoing_bytes: usize, keyring: Keyring) -> io::Result<Self> {
+        Self::with_transport(Transport::tcp(stream)?, max_outgoing_bytes, keyring)
+    }
+
+    /// Creates a connection over a TLS session on the socket, whose packets are not encrypted again
+    pub fn with_tls(
+        stream: TcpStream,
+        config: Arc<ServerConfig>,
+        max_outgoing_bytes: usize,
+    ) -> io::Result<Self> {
+        let transport = Transport::tls(stream, config)?;
+        Self::with_transport(transport, max_outgoing_bytes, Keyring::default())
+    }
+
+    fn with_transport(
+        transport: Transport,
+        max_outgoing_bytes: usize,
+        keyring: Keyring,
+    ) -> io::Result<Self> {
+        let mut outgoing = PacketEncoder::new();
+        outgoing.set_direction(Direction::ToClient);
+
+        Ok(Connection {
+            inner: Arc::new(ConnectionInner {
+                transport,
+                keyring,
+                key_id: OnceLock::new(),
+                session_key: OnceLock::new(),
+                pending_session: Mutex::new(None),
+                outgoing: Mutex::new(outgoing),
+                max_outgoing_bytes,
+                closed: AtomicBool::new(false),
+                stats: OnceLock::new(),
+            }),
+        })
+    }
+
+    /// Registers the socket in the poll of the I/O loop, which tells when it can be read or written.
+    /// It must be registered before the connection is shared
+    pub fn register(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        match Arc::get_mut(&mut self.inner) {
+            Some(inner) => inner.transport.register(registry, token),
+            None => Err(io::Error::other("The connection is already shared")),
+        }
+    }
+
+    /// Sends a packet to the client. It is written right away if nothing else is waiting to be sent,
+    /// and the bytes the socket does not accept are queued. Fails if the connection is closed or if the
+    /// outgoing queue exceeds its limit, in which case the connection is closed
+    pub fn send(&self, packet: &Packet) -> io::Result<()> {
+        self.send_with(|outgoing| outgoing.encode(packet, self.sending_key()))
+    }
+
+    /// Adds bytes to the outgoing queue with the specified function and writes them
+    fn send_with(&self, encode: impl FnOnce(&mut PacketEncoder)) -> io::Result<()> {
+        if self.is_closed() {
+            return Err(io::Error::new(
+                ErrorKind::NotConnected,
+                "The connection is closed",
+            ));
+        }
+
+        let mut outgoing = self.lock_outgoing()?;
+
+        encode(&mut outgoing);
+        match outgoing.write_to(&mut &self.inner.transport) {
+            Ok(written) => self.count_sent(written),
+            Err(err) => {
+                drop(outgoing);
+                self.close();
+                return Err(err);
+            }
+        }
+
+        if outgoing.pending() > self.inner.max_outgoing_bytes {
+            outgoing.clear();
+            drop(outgoing);
+            self.close();
+            return Err(io::Error::new(
+                ErrorKind::WouldBlock,
+                "The outgoing queue of the connection is full",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Keeps the key agreed for the session of the client until the Connack is sent
+    pub fn start_session(&self, session_key: Vec<u8>, key_share: KeyShare) -> io::Result<()> {
+        *self
+            .inner
+            .pending_session
+            .lock()
+            .map_err(|_| io::Error::other("The session lock was poisoned"))? =
+            Some((session_key, key_share));
+
+        Ok(())
+    }
+
+    /// Sends the Connack that accepts the client. If a session key was agreed, the Connack carries
+    /// the key share of the server and the packets after it are encrypted with the session key
+    pub fn send_connack(&self, connack: Connack) -> io::Result<()> {
+        let pending_session = self
+            .inner
+            .pending_session
+            .lock()
+            .map_err(|_| io::Error::other("The session lock was poisoned"))?
+            .take();
+
+        match pending_session {
+            Some((session_key, key_share)) => self.send_with(|outgoing| {
+                let connack = Packet::Connack(connack.with_key_share(key_share));
+                outgoing.encode(&connack, self.bootstrap_key());
+                // The key changes before the Connack is sent, since the client answers it with the new key
+                let _ = self.inner.session_key.set(session_key);
+            }),
+            None => self.send(&Packet::Connack(connack)),
+        }
+    }
+
+    /// Writes as much of the outgoing queue as the socket accepts. Returns true if something was written
+    pub fn flush(&self) -> io::Result<bool> {
+        let written = self.lock_outgoing()?.write_to(&mut &self.inner.transport)?;
+        self.count_sent(written);
+        let records_written = self.inner.transport.write_pending()?;
+
+        Ok(written > 0 || records_written)
+    }
+
+    /// Reads the bytes available in the socket without blocking, passing them to the decoder.
+    /// Returns the amount of bytes read, failing with UnexpectedEof if the client closed the connection
+    pub fn read_available(
+        &self,
+        decoder: &mut PacketDecoder,
+        max_bytes: usize,
+    ) -> io::Result<usize> {
+        let mut total = 0;
+
+        while total < max_bytes {
+            match decoder.read_from(&mut &self.inner.transport) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "The client closed the connection",
+                    ))
+                }
+                Ok(read) => total += read,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        if let Some(stats) = self.inner.stats.get() {
+            stats.add_received_bytes(total);
+        }
+        Ok(total)
+    }
+
+    /// Adds the bytes read and written from then on to the traffic of the broker
+    pub fn count_traffic(&self, stats: Arc<BrokerStats>) {
+        let _ = self.inner.stats.set(stats);
+    }
+
+    fn count_sent(&self, bytes: usize) {
+        if let Some(stats) = self.inner.stats.get() {
+            stats.add_sent_bytes(bytes);
+        }
+    }
+
+    /// Keeps the id of the key the client encrypted its Connect with, which encrypts the Connack too
+    pub fn select_key(&self, key_id: u32) {
+        let _ = self.inner.key_id.set(key_id);
+    }
+
+    /// Sets the version of MQTT the Connect of the client chose, in which the packets are sent from then on
+    pub fn set_protocol_version(&self, protocol_version: ProtocolVersion) -> io::Result<()> {
+        self.lock_outgoing()?.set_protocol_version(protocol_version);
+        Ok(())
+    }
+
+    /// Returns the keys the packets received are decrypted with: any key of the keyring until the key
+    /// of the session is agreed
+    pub fn keys(&self) -> FrameKey<'_> {
+        match self.inner.session_key.get() {
+            Some(session_key) => session_key.into(),
+            None => (&self.inner.keyring).into(),
+        }
+    }
+
+    /// Returns the key the packets of the connection are encrypted with, empty if they are not encrypted.
+    /// It is the key of the keyring the client connected with until the key of the session is agreed
+    pub fn key(&self) -> &[u8] {
+        self.sending_key().key()
+    }
+
+    /// Returns the key of the session, or the one of the keyring before it is agreed
+    fn sending_key(&self) -> FrameKey<'_> {
+        match self.inner.session_key.get() {
+            Some(session_key) => session_key.into(),
+            None => self.bootstrap_key(),
+        }
+    }
+
+    /// Returns the key of the keyring the client encrypted its Connect with, the current one if it is unknown
+    fn bootstrap_key(&self) -> FrameKey<'_> {
+        let keyring = &self.inner.keyring;
+        self.inner
+            .key_id
+            .get()
+            .and_then(|key_id| keyring.frame_key(*key_id))
+            .unwrap_or(keyring.into())
+    }
+
+    /// Returns the subject name of the verified certificate the client presented over TLS,
+    /// None if it did not present one
+    pub fn certificate_name(&self) -> Option<String> {
+        self.inner.transport.peer_name()
+    }
+
+    /// Closes the connection. Later sends fail and the I/O loop drops it
+    pub fn close(&self) {
+        if !self.inner.closed.swap(true, Ordering::SeqCst) {
+            self.inner.transport.shutdown();
+        }
+    }
+
+    /// Closes the connection because a new connection of the same client took over its session.
+    /// A client of MQTT 5.0 is told so with a Disconnect before the socket is closed
+    pub fn take_over(&self) {
+        let protocol_version = match self.lock_outgoing() {
+            Ok(outgoing) => outgoing.protocol_version(),
+            Err(_) => ProtocolVersion::V311,
+        };
+        if protocol_version == ProtocolVersion::V5 {
+            let disconnect = Disconnect::new().with_reason_code(ReasonCode::SessionTakenOver);
+            let _ = self.send(&Packet::Disconnect(disconnect));
+        }
+
+        self.close();
+    }
+
+    /// Returns true if both are the same connection, and not just connections of the same client
+    pub fn same_connection(&self, other: &Connection) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+
+    /// Returns true if the connection was closed
+    pub fn is_closed(&self) -> bool {
+        self.inner.closed.load(Ordering::SeqCst)
+    }
+
+    fn lock_outgoing(&self) -> io::Result<std::sync::MutexGuard<'_, PacketEncoder>> {
+        self.inner
+            .outgoing
+            .lock()
+            .map_err(|_| io::Error::other("The outgoing queue lock was poisoned"))
+    }
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("transport", &self.inner.transport)
+            .field("encrypted", &!self.key().is_empty())
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+        time::Duration,
+    };
+
+    use mqtt::{
+        model::{
+            packets::pingresp::Pingresp, return_codes::connect_return_code::ConnectReturnCode,
+        },
+        Direction, FrameKey,
+    };
+    use rustls::{ClientConnection, StreamOwned};
+
+    use super::*;
+    use crate::tls::testing::tls_configs;
+
+    fn setup_connection(max_outgoing_bytes: usize) -> (Connection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        (
+            Connection::new(stream, max_outgoing_bytes, Keyring::new(0, &[0; 32])).unwrap(),
+            peer,
+        )
+    }
+
+    /// Returns the key a client decrypts the packets the server sends to it with
+    fn to_client(key: &[u8], sequence: u64) -> FrameKey<'_> {
+        FrameKey::new(key, sequence).with_direction(Direction::ToClient)
+    }
+
+    #[test]
+    fn test_send_does_not_block_when_the_client_does_not_read() {
+        let (connection, mut peer) = setup_connection(usize::MAX);
+        let bytes = vec![0; 1024 * 1024];
+
+        for _ in 0..16 {
+            connection
+                .send_with(|outgoing| outgoing.queue(&bytes))
+                .unwrap();
+        }
+
+        let reader = thread::spawn(move || {
+            let mut received = vec![0; 16 * bytes.len()];
+            peer.read_exact(&mut received).unwrap();
+        });
+
+        while !reader.is_finished() {
+            connection.flush().unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn test_connection_is_closed_when_the_outgoing_queue_is_full() {
+        let (connection, _peer) = setup_connection(1024);
+        let bytes = vec![0; 1024 * 1024];
+
+        let mut result = Ok(());
+        for _ in 0..16 {
+            result = connection.send_with(|outgoing| outgoing.queue(&bytes));
+            if result.is_err() {
+                break;
+            }
+        }
+
+        assert!(result.is_err());
+        assert!(connection.is_closed());
+        assert!(connection.send(&Packet::Pingresp(Pingresp::new())).is_err());
+    }
+
+    #[test]
+    fn test_traffic_of_the_connection_is_counted() {
+        let (connection, mut peer) = setup_connection(usize::MAX);
+        let stats = Arc::new(BrokerStats::new());
+        connection.count_traffic(stats.clone());
+
+        connection
+            .send_with(|outgoing| outgoing.queue(&[0; 10]))
+            .unwrap();
+        peer.write_all(&[0; 5]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let _ = connection.read_available(&mut PacketDecoder::new(), usize::MAX);
+
+        let traffic = stats.traffic();
+        assert_eq!(traffic.bytes_sent, 10);
+        assert_eq!(traffic.bytes_received, 5);
+    }
+
+    #[test]
+    fn test_connack_switches_to_the_key_of_the_session() {
+        let (connection, mut peer) = setup_connection(usize::MAX);
+        let key_share = KeyShare::with_confirmation([2; 32], [3; 32]);
+        connection
+            .start_session(vec![1; 32], key_share.clone())
+            .unwrap();
+        assert_eq!(connection.key(), &[0; 32]);
+
+        let connack = Connack::new(false, ConnectReturnCode::ConnectionAccepted);
+        connection.send_connack(connack).unwrap();
+
+        match Packet::from_bytes(&mut peer, to_client(&[0; 32], 0)).unwrap() {
+            Packet::Connack(connack) => assert_eq!(connack.key_share(), Some(&key_share)),
+            packet => panic!("Expected a Connack, received {:?}", packet),
+        }
+        assert_eq!(connection.key(), &[1; 32]);
+    }
+
+    #[test]
+    fn test_connack_is_encrypted_with_the_key_of_the_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut keyring = Keyring::new(1, &[1; 32]);
+        keyring.rotate(2, &[2; 32]);
+        let connection = Connection::new(stream, usize::MAX, keyring).unwrap();
+        assert_eq!(connection.key(), &[2; 32]);
+
+        connection.select_key(1);
+        let connack = Connack::new(false, ConnectReturnCode::ConnectionAccepted);
+        connection.send_connack(connack).unwrap();
+
+        assert_eq!(connection.key(), &[1; 32]);
+        assert!(matches!(
+            Packet::from_bytes(
+                &mut peer,
+                FrameKey::with_id(1, &[1; 32], 0).with_direction(Direction::ToClient)
+            )
+            .unwrap(),
+            Packet::Connack(_)
+        ));
+    }
+
+    #[test]
+    fn test_packets_after_the_connack_are_numbered() {
+        let (connection, mut peer) = setup_connection(usize::MAX);
+        connection
+            .send(&Packet::Connack(Connack::new(
+                false,
+                ConnectReturnCode::ConnectionAccepted,
+            )))
+            .unwrap();
+        connection.send(&Packet::Pingresp(Pingresp::new())).unwrap();
+        connection.send(&Packet::Pingresp(Pingresp::new())).unwrap();
+
+        Packet::from_bytes(&mut peer, to_client(&[0; 32], 0)).unwrap();
+        Packet::from_bytes(&mut peer, to_client(&[0; 32], 1)).unwrap();
+        assert!(Packet::from_bytes(&mut peer, to_client(&[0; 32], 1)).is_err());
+    }
+
+    #[test]
+    fn test_packet_of_the_server_reflected_back_to_it_is_rejected() {
+        let (connection, mut peer) = setup_connection(usize::MAX);
+        connection
+            .send(&Packet::Connack(Connack::new(
+                false,
+                ConnectReturnCode::ConnectionAccepted,
+            )))
+            .unwrap();
+
+        let mut bytes = [0; 1024];
+        let read = peer.read(&mut bytes).unwrap();
+
+        // The Connack has the same sequence number the Connect of the client has
+        let mut decoder = PacketDecoder::new();
+        decoder.extend(&bytes[..read]);
+        assert!(decoder.decode(connection.keys()).is_err());
+    }
+
+    #[test]
+    fn test_tls_connection_exchanges_packets_with_a_client_certificate() {
+        let (server_config, client_config) = tls_configs("test_tls_connection", Some("drone-7"));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let session =
+                ClientConnection::new(client_config, "localhost".try_into().unwrap()).unwrap();
+            let mut stream = StreamOwned::new(session, TcpStream::connect(address).unwrap());
+            stream.write_all(b"ping").unwrap();
+
+            let mut received = [0; 4];
+            stream.read_exact(&mut received).unwrap();
+            received
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let connection = Connection::with_tls(stream, server_config, usize::MAX).unwrap();
+        assert!(connection.key().is_empty());
+
+        let mut decoder = PacketDecoder::new();
+        while decoder.buffered() < 4 {
+            connection.flush().unwrap();
+            connection.read_available(&mut decoder, usize::MAX).unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(connection.certificate_name(), Some("drone-7".to_string()));
+
+        connection
+            .send_with(|outgoing| outgoing.queue(b"pong"))
+            .unwrap();
+        while !client.is_finished() {
+            connection.flush().unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(&client.join().unwrap(), b"pong");
+    }
+}
@@ -0,0 +1,322 @@
+use std::{collections::HashMap, fs, io::ErrorKind};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+
+use mqtt::keyring::{read_keys, Keyring};
+
+use crate::error::{ServerError, ServerResult};
+
+/// Prefix of the passwords stored as Argon2 hashes in PHC format
+const HASH_PREFIX: &str = "$argon2";
+
+/// Hashes a password with Argon2id and a random salt, returning it in PHC format
+pub fn hash_password(password: &[u8]) -> ServerResult<String> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Argon2::default()
+        .hash_password(password, &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| ServerError::PasswordHash(err.to_string()))
+}
+
+/// Returns whether a password matches a hash in PHC format
+pub fn verify_password(password: &[u8], password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(hash) => Argon2::default().verify_password(password, &hash).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Reads the file of client keys, with lines of the form `client_id = key` where the key has 32 characters
+/// or is written as 64 hexadecimal digits.
+/// Returns the key of each client by its id. A missing file has no keys
+pub fn read_client_keys(path: &str) -> ServerResult<HashMap<Vec<u8>, Vec<u8>>> {
+    Ok(read_keys(path)?
+        .into_iter()
+        .map(|(client_id, key)| (client_id.into_bytes(), key))
+        .collect())
+}
+
+/// Reads the keyring file, with lines of the form `key_id = key` from the oldest key to the current one.
+/// Without keys in the file, the keyring only has the key of the settings, with the id 0
+pub fn read_keyring(path: &str, key: &[u8]) -> ServerResult<Keyring> {
+    let mut keyring = Keyring::default();
+    keyring.load(path)?;
+
+    if keyring.is_empty() {
+        keyring = Keyring::new(0, key);
+    }
+    Ok(keyring)
+}
+
+/// Represents a line of the login file. The password is only kept as a hash
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoginEntry {
+    pub client_id: String,
+    pub username: String,
+    pub password_hash: String,
+}
+
+impl LoginEntry {
+    /// Returns the entry as a line of the login file
+    pub fn to_line(&self) -> String {
+        format!(
+            "{} = {} = {}",
+            self.client_id, self.username, self.password_hash
+        )
+    }
+}
+
+/// Represents the login file of the server, with lines of the form `client_id = username = password_hash`.
+/// Passwords found in plaintext, as written by older versions of the server, are hashed when the file is read
+#[derive(Debug)]
+pub struct LoginFile {
+    path: String,
+    entries: Vec<LoginEntry>,
+    migrated: bool,
+}
+
+impl LoginFile {
+    /// Reads a login file, hashing the plaintext passwords. A missing file has no entries
+    pub fn open(path: &str) -> ServerResult<Self> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut login_file = LoginFile {
+            path: path.to_string(),
+            entries: Vec::new(),
+            migrated: false,
+        };
+
+        for line in content.lines() {
+            // The hash may contain '=', so only the first two separate fields
+            let parts: Vec<&str> = line.splitn(3, '=').map(|s| s.trim()).collect();
+            if parts.len() != 3 {
+                continue;
+            }
+
+            let password_hash = if parts[2].starts_with(HASH_PREFIX) {
+                parts[2].to_string()
+            } else {
+                login_file.migrated = true;
+                hash_password(parts[2].as_bytes())?
+            };
+
+            login_file.insert(LoginEntry {
+                client_id: parts[0].to_string(),
+                username: parts[1].to_string(),
+                password_hash,
+            });
+        }
+
+        Ok(login_file)
+    }
+
+    /// Returns whether plaintext passwords were hashed when reading the file, so it should be saved
+    pub fn is_migrated(&self) -> bool {
+        self.migrated
+    }
+
+    /// Returns the entries of the file
+    pub fn entries(&self) -> &[LoginEntry] {
+        &self.entries
+    }
+
+    /// Adds a client or replaces the username and password of an existing one.
+    /// Returns true if the client already existed
+    pub fn set_login(
+        &mut self,
+        client_id: &str,
+        username: &str,
+        password: &[u8],
+    ) -> ServerResult<bool> {
+        let entry = LoginEntry {
+            client_id: client_id.to_string(),
+            username: username.to_string(),
+            password_hash: hash_password(password)?,
+        };
+
+        Ok(self.insert(entry))
+    }
+
+    /// Writes the entries to a temporary file and then replaces the login file with it,
+    /// so the file is never left half written
+    pub fn save(&self) -> ServerResult<()> {
+        let mut content = String::new();
+        for entry in &self.entries {
+            content.push_str(&entry.to_line());
+            content.push('\n');
+        }
+
+        let temporary_path = format!("{}.tmp", self.path);
+        fs::write(&temporary_path, content)?;
+        fs::rename(&temporary_path, &self.path)?;
+
+        Ok(())
+    }
+
+    /// Inserts an entry, replacing the one of the same client. Returns true if it was replaced
+    fn insert(&mut self, entry: LoginEntry) -> bool {
+        match self
+            .entries
+            .iter_mut()
+            .find(|existing| existing.client_id == entry.client_id)
+        {
+            Some(existing) => {
+                *existing = entry;
+                true
+            }
+            None => {
+                self.entries.push(entry);
+                false
+            }
+        }
+    }
+}
+
+/// Files written by the tests
+#[cfg(test)]
+pub mod testing {
+    use std::{env, fs, path::PathBuf, process};
+
+    /// A file in the temporary directory with a name unique to the test process, removed when dropped
+    pub struct TempFile {
+        path: PathBuf,
+    }
+
+    impl TempFile {
+        pub fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("{}_{}", process::id(), name));
+            let _ = fs::remove_file(&path);
+            TempFile { path }
+        }
+
+        pub fn path(&self) -> &str {
+            self.path.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt::keyring::append_to_file;
+
+    use super::*;
+
+    #[test]
+    fn test_verify_password() {
+        let password_hash = hash_password(b"sauron").unwrap();
+
+        assert!(password_hash.starts_with(HASH_PREFIX));
+        assert!(!password_hash.contains("sauron"));
+        assert!(verify_password(b"sauron", &password_hash));
+        assert!(!verify_password(b"saruman", &password_hash));
+        assert!(!verify_password(b"sauron", "sauron"));
+    }
+
+    #[test]
+    fn test_read_client_keys() {
+        let client_keys_file = testing::TempFile::new("test_client_keys.txt");
+        let path = client_keys_file.path();
+        fs::write(
+            path,
+            "7 = \"01234567890123456789012345678901\"\nmonitor = abcdefghijabcdefghijabcdefghijab\n",
+        )
+        .unwrap();
+
+        let client_keys = read_client_keys(path).unwrap();
+        fs::write(path, "7 = short\n").unwrap();
+        let invalid = read_client_keys(path);
+
+        assert_eq!(client_keys.len(), 2);
+        assert_eq!(
+            client_keys[b"7".as_slice()],
+            b"01234567890123456789012345678901"
+        );
+        assert!(invalid.is_err());
+        assert!(read_client_keys("test_client_keys_missing.txt")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_keyring_file_keeps_the_appended_key_as_the_current_one() {
+        let keyring_file = testing::TempFile::new("test_keyring.txt");
+        let path = keyring_file.path();
+        let default = read_keyring(path, &[0; 32]).unwrap();
+
+        append_to_file(path, 1, b"01234567890123456789012345678901").unwrap();
+        append_to_file(path, 2, b"abcdefghijabcdefghijabcdefghijab").unwrap();
+        let keyring = read_keyring(path, &[0; 32]).unwrap();
+
+        assert_eq!(default, Keyring::new(0, &[0; 32]));
+        assert_eq!(
+            keyring.current(),
+            Some((2, b"abcdefghijabcdefghijabcdefghijab".as_slice()))
+        );
+        assert_eq!(keyring.ids(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_appended_keys_of_any_bytes_are_read_back() {
+        let keyring_file = testing::TempFile::new("test_keyring_any_bytes.txt");
+        let mut key = [b'='; 32];
+        key[1] = b'"';
+        key[2] = b'\n';
+        key[3] = 0xff;
+        append_to_file(keyring_file.path(), 1, &key).unwrap();
+
+        let keyring = read_keyring(keyring_file.path(), &[0; 32]).unwrap();
+        assert_eq!(keyring.current(), Some((1, key.as_slice())));
+    }
+
+    #[test]
+    fn test_plaintext_login_file_is_migrated() {
+        let login_migration_file = testing::TempFile::new("test_login_migration.txt");
+        let path = login_migration_file.path();
+        fs::write(path, "1 = drone1 = sauron\nadmin = admin = admin\n").unwrap();
+
+        let login_file = LoginFile::open(path).unwrap();
+        assert!(login_file.is_migrated());
+        login_file.save().unwrap();
+
+        let content = fs::read_to_string(path).unwrap();
+        assert!(!content.contains("sauron"));
+
+        let login_file = LoginFile::open(path).unwrap();
+
+        assert!(!login_file.is_migrated());
+        assert_eq!(login_file.entries().len(), 2);
+        assert_eq!(login_file.entries()[0].username, "drone1");
+        assert!(verify_password(
+            b"sauron",
+            &login_file.entries()[0].password_hash
+        ));
+    }
+
+    #[test]
+    fn test_set_login_rotates_the_password() {
+        let mut login_file = LoginFile::open("test_login_missing.txt").unwrap();
+
+        assert!(!login_file.set_login("1", "drone1", b"sauron").unwrap());
+        assert!(login_file.set_login("1", "drone1", b"saruman").unwrap());
+
+        assert_eq!(login_file.entries().len(), 1);
+        assert!(verify_password(
+            b"saruman",
+            &login_file.entries()[0].password_hash
+        ));
+    }
+}
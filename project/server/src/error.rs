@@ -20,6 +20,10 @@ pub enum ServerError {
     Utf8Error(FromUtf8Error),
     NoLoginProvided,
     NoPasswordProvided,
+    InvalidAcl(String),
+    InvalidQueueRules(String),
+    PasswordHash(String),
+    Tls(String),
 }
 
 impl fmt::Display for ServerError {
@@ -35,6 +39,10 @@ impl fmt::Display for ServerError {
             ServerError::Utf8Error(err) => write!(f, "UTF-8 error: {}", err),
             ServerError::NoLoginProvided => write!(f, "No login provided"),
             ServerError::NoPasswordProvided => write!(f, "No password provided"),
+            ServerError::InvalidAcl(msg) => write!(f, "Invalid ACL: {}", msg),
+            ServerError::InvalidQueueRules(msg) => write!(f, "Invalid queue rules: {}", msg),
+            ServerError::PasswordHash(msg) => write!(f, "Password hash error: {}", msg),
+            ServerError::Tls(msg) => write!(f, "TLS error: {}", msg),
         }
     }
 }
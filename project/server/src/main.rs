@@ -2,36 +2,106 @@
 //! It recieves messages from the clients and sends them to the corresponding client.
 
 use config::Config;
+use credentials::LoginFile;
 use error::{ServerError, ServerResult};
 use server::Server;
 use std::env;
+use std::io::{self, BufRead, Write};
 use std::path::Path;
 
+mod acl;
 mod client;
 mod client_manager;
 mod config;
+mod connection;
+mod credentials;
 mod error;
 mod logfile;
+mod offline_queue;
 mod server;
+mod stats;
 mod task_handler;
+mod tls;
+mod transport;
 
 static SERVER_ARGS: usize = 2;
+static ADD_USER_ARGS: usize = 5;
+static MIGRATE_LOGINS_ARGS: usize = 3;
+
+const ADD_USER_COMMAND: &str = "add-user";
+const MIGRATE_LOGINS_COMMAND: &str = "migrate-logins";
 
 fn main() -> ServerResult<()> {
     let argv: Vec<String> = env::args().collect();
-    if argv.len() != SERVER_ARGS {
-        let app_name = &argv[0];
-        return Err(ServerError::ArgumentError(format!(
-            "Usage: {} <toml-file>",
-            app_name
-        )));
-    }
 
-    let config_path = Path::new(&argv[1]);
+    match argv.get(1).map(|arg| arg.as_str()) {
+        Some(ADD_USER_COMMAND) if argv.len() == ADD_USER_ARGS => {
+            add_user(&argv[2], &argv[3], &argv[4])
+        }
+        Some(MIGRATE_LOGINS_COMMAND) if argv.len() == MIGRATE_LOGINS_ARGS => {
+            migrate_logins(&argv[2])
+        }
+        Some(_) if argv.len() == SERVER_ARGS => run_server(&argv[1]),
+        _ => {
+            let app_name = &argv[0];
+            Err(ServerError::ArgumentError(format!(
+                "Usage: {0} <toml-file>\n       {0} {1} <toml-file> <client-id> <username>\n       {0} {2} <toml-file>",
+                app_name, ADD_USER_COMMAND, MIGRATE_LOGINS_COMMAND
+            )))
+        }
+    }
+}
 
-    let config = Config::from_file(config_path)?;
+fn run_server(config_path: &str) -> ServerResult<()> {
+    let config = Config::from_file(Path::new(config_path))?;
 
     let server = Server::new(config)?;
 
     server.server_run()
 }
+
+/// Adds a client to the login file of the configuration, or changes the username and password of an existing one.
+/// The password is read from the standard input so it is not left in the shell history.
+/// A running server reads the change when it is restarted
+fn add_user(config_path: &str, client_id: &str, username: &str) -> ServerResult<()> {
+    let config = Config::from_file(Path::new(config_path))?;
+
+    print!("Password for {}: ", client_id);
+    io::stdout().flush()?;
+    let mut password = String::new();
+    io::stdin().lock().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err(ServerError::NoPasswordProvided);
+    }
+
+    let mut login_file = LoginFile::open(config.get_login_file())?;
+    let rotated = login_file.set_login(client_id, username, password.as_bytes())?;
+    login_file.save()?;
+
+    if rotated {
+        println!("Updated the credentials of {}", client_id);
+    } else {
+        println!("Added {}", client_id);
+    }
+
+    Ok(())
+}
+
+/// Rewrites the login file of the configuration replacing the plaintext passwords with their hashes
+fn migrate_logins(config_path: &str) -> ServerResult<()> {
+    let config = Config::from_file(Path::new(config_path))?;
+
+    let login_file = LoginFile::open(config.get_login_file())?;
+    if login_file.is_migrated() {
+        login_file.save()?;
+        println!(
+            "Hashed the plaintext passwords of {}",
+            config.get_login_file()
+        );
+    } else {
+        println!("{} has no plaintext passwords", config.get_login_file());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,453 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    time::{Duration, Instant},
+};
+
+use mqtt::model::{
+    components::{properties::Property, topic_filter::TopicFilter, topic_name::TopicName},
+    packets::publish::Publish,
+};
+
+use crate::{
+    acl::parse_topic_filter,
+    error::{ServerError, ServerResult},
+};
+
+const ALL_MESSAGES_MODE: &str = "all";
+const LATEST_VALUE_MODE: &str = "latest";
+
+/// Represents a publish kept by the server to send it later, together with the moment it was stored
+/// to honour its message expiry interval and the time to live of its topic
+#[derive(Debug, Clone)]
+pub struct StoredMessage {
+    pub publish: Publish,
+    stored_at: Instant,
+    time_to_live: Option<Duration>,
+}
+
+impl StoredMessage {
+    pub fn new(publish: Publish) -> Self {
+        StoredMessage {
+            publish,
+            stored_at: Instant::now(),
+            time_to_live: None,
+        }
+    }
+
+    /// Creates a message restored from a backup, stored the time specified ago
+    pub fn restored(publish: Publish, stored_for: Duration) -> Self {
+        let now = Instant::now();
+        StoredMessage {
+            publish,
+            stored_at: now.checked_sub(stored_for).unwrap_or(now),
+            time_to_live: None,
+        }
+    }
+
+    /// Returns the time since the message was stored
+    pub fn stored_for(&self) -> Duration {
+        self.stored_at.elapsed()
+    }
+
+    /// Returns the time to live of the topic of the message, None if it is kept until its message expiry interval
+    pub fn time_to_live(&self) -> Option<Duration> {
+        self.time_to_live
+    }
+
+    /// Sets the time after which the message is discarded, whatever its message expiry interval
+    pub fn with_time_to_live(mut self, time_to_live: Option<Duration>) -> Self {
+        self.time_to_live = time_to_live;
+        self
+    }
+
+    /// Returns the publish to send, with its message expiry interval reduced by the time it was stored.
+    /// None if the message expired
+    pub fn publish(&self) -> Option<Publish> {
+        let elapsed = self.stored_at.elapsed();
+        if self.is_expired_after(elapsed) {
+            return None;
+        }
+
+        let mut publish = self.publish.clone();
+        if let Some(interval) = self.publish.properties().message_expiry_interval() {
+            let elapsed = u32::try_from(elapsed.as_secs()).unwrap_or(u32::MAX);
+            publish
+                .properties_mut()
+                .set(Property::MessageExpiryInterval(
+                    interval.saturating_sub(elapsed),
+                ));
+        }
+        Some(publish)
+    }
+
+    /// Returns whether the message outlived its message expiry interval or the time to live of its topic
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_after(self.stored_at.elapsed())
+    }
+
+    /// Returns whether the message is expired once the time specified passed since it was stored
+    fn is_expired_after(&self, elapsed: Duration) -> bool {
+        let message_expired = self
+            .publish
+            .properties()
+            .message_expiry_interval()
+            .is_some_and(|interval| elapsed.as_secs() >= interval as u64);
+        let topic_expired = self
+            .time_to_live
+            .is_some_and(|time_to_live| elapsed >= time_to_live);
+
+        message_expired || topic_expired
+    }
+
+    /// Returns the bytes of the message counted against the limit of the queue
+    fn size(&self) -> usize {
+        self.publish.message().len()
+    }
+}
+
+/// Represents what is done with a message for a client whose offline queue is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    /// The oldest messages of the queue are dropped to make room
+    DropOldest,
+    /// The new message is dropped
+    DropNewest,
+    /// The session of the client is discarded, so it starts a new one when it reconnects
+    Disconnect,
+}
+
+impl OverflowPolicy {
+    pub fn from_str(policy: &str) -> Option<Self> {
+        match policy {
+            "drop_oldest" => Some(OverflowPolicy::DropOldest),
+            "drop_newest" => Some(OverflowPolicy::DropNewest),
+            "disconnect" => Some(OverflowPolicy::Disconnect),
+            _ => None,
+        }
+    }
+}
+
+/// Represents a line of the queue rules file: the topics it applies to, how long their messages are kept
+/// and whether only the latest message of each topic is kept
+#[derive(Debug)]
+struct TopicRule {
+    topic_filter: TopicFilter,
+    time_to_live: Option<Duration>,
+    latest_only: bool,
+}
+
+/// Represents the limits of the queues of the messages for the clients that are not connected: the messages
+/// and bytes each queue may hold, what is done when it is full and the rules of the topics.
+/// The first rule whose topic filter matches the topic of a message applies to it
+#[derive(Debug)]
+pub struct QueuePolicy {
+    max_messages: usize,
+    max_bytes: usize,
+    overflow_policy: OverflowPolicy,
+    topic_rules: Vec<TopicRule>,
+}
+
+impl QueuePolicy {
+    /// Creates the policy of queues that hold up to the messages and bytes specified, 0 meaning no limit
+    pub fn new(max_messages: usize, max_bytes: usize, overflow_policy: OverflowPolicy) -> Self {
+        QueuePolicy {
+            max_messages,
+            max_bytes,
+            overflow_policy,
+            topic_rules: Vec::new(),
+        }
+    }
+
+    /// Creates the policy of queues without limits
+    pub fn unbounded() -> Self {
+        QueuePolicy::new(0, 0, OverflowPolicy::DropOldest)
+    }
+
+    /// Reads the rules of the topics from a file with lines of the form `pattern = time_to_live = mode`,
+    /// where the time to live is in seconds, 0 to keep the messages until they are delivered, and the mode
+    /// is `all` to keep every message or `latest` to keep only the latest one of each topic
+    pub fn with_rules_file(self, path: &str) -> ServerResult<Self> {
+        let content = fs::read_to_string(path)?;
+        self.with_rules(&content)
+    }
+
+    /// Reads the rules of the topics from the content of a queue rules file
+    pub(crate) fn with_rules(mut self, content: &str) -> ServerResult<Self> {
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let parts: Vec<&str> = line.split('=').map(|s| s.trim()).collect();
+            if parts.len() != 3 {
+                return Err(ServerError::InvalidQueueRules(format!(
+                    "Invalid rule: {}",
+                    line
+                )));
+            }
+
+            let topic_filter = parse_topic_filter(parts[0]).ok_or_else(|| {
+                ServerError::InvalidQueueRules(format!("Invalid topic filter: {}", parts[0]))
+            })?;
+            let time_to_live = match parts[1].parse::<u64>() {
+                Ok(0) => None,
+                Ok(seconds) => Some(Duration::from_secs(seconds)),
+                Err(_) => {
+                    return Err(ServerError::InvalidQueueRules(format!(
+                        "Invalid time to live: {}",
+                        parts[1]
+                    )))
+                }
+            };
+            let latest_only = match parts[2] {
+                ALL_MESSAGES_MODE => false,
+                LATEST_VALUE_MODE => true,
+                mode => {
+                    return Err(ServerError::InvalidQueueRules(format!(
+                        "Invalid mode: {}",
+                        mode
+                    )))
+                }
+            };
+
+            self.topic_rules.push(TopicRule {
+                topic_filter,
+                time_to_live,
+                latest_only,
+            });
+        }
+
+        Ok(self)
+    }
+
+    fn topic_rule(&self, topic_name: &TopicName) -> Option<&TopicRule> {
+        self.topic_rules
+            .iter()
+            .find(|rule| rule.topic_filter.match_topic_name(topic_name.clone()))
+    }
+
+    fn is_full(&self, queue: &OfflineQueue, size: usize) -> bool {
+        (self.max_messages > 0 && queue.len() >= self.max_messages)
+            || (self.max_bytes > 0 && queue.bytes() + size > self.max_bytes)
+    }
+}
+
+/// Represents the result of queueing a message
+#[derive(Debug, PartialEq)]
+pub enum QueueOutcome {
+    /// The message was queued, dropping the amount of older messages specified
+    Queued(usize),
+    /// The queue was full and the message was dropped
+    Dropped,
+    /// The queue was full and its policy discards the session of the client
+    Overflow,
+}
+
+/// Represents the messages queued for a client that is not connected, in the order they were published
+#[derive(Debug, Default)]
+pub struct OfflineQueue {
+    messages: VecDeque<StoredMessage>,
+    bytes: usize,
+}
+
+impl OfflineQueue {
+    pub fn new() -> Self {
+        OfflineQueue::default()
+    }
+
+    /// Queues a message following the policy. The expired messages are dropped first, and in latest value mode
+    /// the message replaces the ones of its topic
+    pub fn push(&mut self, publish: Publish, policy: &QueuePolicy) -> QueueOutcome {
+        let rule = policy.topic_rule(publish.topic());
+        let message =
+            StoredMessage::new(publish).with_time_to_live(rule.and_then(|rule| rule.time_to_live));
+
+        let before = self.len();
+        self.retain(|stored| !stored.is_expired());
+        if rule.is_some_and(|rule| rule.latest_only) {
+            self.retain(|stored| stored.publish.topic() != message.publish.topic());
+        }
+        let mut dropped = before - self.len();
+
+        while policy.is_full(self, message.size()) {
+            match policy.overflow_policy {
+                OverflowPolicy::DropOldest if !self.is_empty() => {
+                    self.pop_front();
+                    dropped += 1;
+                }
+                OverflowPolicy::Disconnect => return QueueOutcome::Overflow,
+                _ => return QueueOutcome::Dropped,
+            }
+        }
+
+        self.bytes += message.size();
+        self.messages.push_back(message);
+        QueueOutcome::Queued(dropped)
+    }
+
+    /// Queues a message restored from a backup, outside the limits of the policy. Expired messages are not queued
+    pub fn push_stored(&mut self, message: StoredMessage) {
+        if message.is_expired() {
+            return;
+        }
+        self.bytes += message.size();
+        self.messages.push_back(message);
+    }
+
+    /// Returns the messages that did not expire, with the moment they were stored
+    pub fn messages(&self) -> impl Iterator<Item = &StoredMessage> {
+        self.messages.iter().filter(|message| !message.is_expired())
+    }
+
+    /// Returns the publishes of the messages that did not expire
+    pub fn publishes(&self) -> impl Iterator<Item = Publish> + '_ {
+        self.messages.iter().filter_map(StoredMessage::publish)
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Returns the bytes of the payloads of the messages
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn clear(&mut self) {
+        self.messages.clear();
+        self.bytes = 0;
+    }
+
+    /// Removes the oldest message of the queue and returns it
+    pub fn pop_front(&mut self) -> Option<StoredMessage> {
+        let message = self.messages.pop_front()?;
+        self.bytes -= message.size();
+        Some(message)
+    }
+
+    fn retain(&mut self, keep: impl Fn(&StoredMessage) -> bool) {
+        self.messages.retain(|message| keep(message));
+        self.bytes = self.messages.iter().map(StoredMessage::size).sum();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt::model::components::{properties::Properties, qos::QoS};
+
+    use super::*;
+
+    fn publish(topic: &str, message: &str) -> Publish {
+        let levels = topic
+            .split('/')
+            .map(|level| level.as_bytes().to_vec())
+            .collect();
+        Publish::new(
+            false,
+            QoS::AtLeast,
+            false,
+            TopicName::new(levels, false),
+            None,
+            message.as_bytes().to_vec(),
+        )
+    }
+
+    fn messages(queue: &OfflineQueue) -> Vec<String> {
+        queue
+            .publishes()
+            .map(|publish| String::from_utf8_lossy(publish.message()).to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let policy = QueuePolicy::new(2, 0, OverflowPolicy::DropOldest);
+        let mut queue = OfflineQueue::new();
+
+        queue.push(publish("new-incident", "1"), &policy);
+        queue.push(publish("new-incident", "2"), &policy);
+
+        assert_eq!(
+            queue.push(publish("new-incident", "3"), &policy),
+            QueueOutcome::Queued(1)
+        );
+        assert_eq!(messages(&queue), vec!["2", "3"]);
+    }
+
+    #[test]
+    fn test_drop_newest_and_disconnect() {
+        let policy = QueuePolicy::new(0, 4, OverflowPolicy::DropNewest);
+        let mut queue = OfflineQueue::new();
+        queue.push(publish("new-incident", "123"), &policy);
+
+        assert_eq!(
+            queue.push(publish("new-incident", "45"), &policy),
+            QueueOutcome::Dropped
+        );
+        assert_eq!(queue.bytes(), 3);
+
+        let policy = QueuePolicy::new(1, 0, OverflowPolicy::Disconnect);
+        assert_eq!(
+            queue.push(publish("new-incident", "4"), &policy),
+            QueueOutcome::Overflow
+        );
+        assert_eq!(messages(&queue), vec!["123"]);
+    }
+
+    #[test]
+    fn test_latest_value_and_time_to_live_rules() {
+        let policy = QueuePolicy::unbounded()
+            .with_rules(
+                "# Only the current state of the drones matters
+                drone-data/+ = 0 = latest
+                camera-data = 1 = all",
+            )
+            .unwrap();
+        let mut queue = OfflineQueue::new();
+
+        queue.push(publish("drone-data/1", "old"), &policy);
+        queue.push(publish("drone-data/2", "other"), &policy);
+        queue.push(publish("drone-data/1", "new"), &policy);
+        assert_eq!(messages(&queue), vec!["other", "new"]);
+
+        let mut camera_data =
+            StoredMessage::new(publish("camera-data", "frame")).with_time_to_live(None);
+        assert!(!camera_data.is_expired());
+        camera_data.stored_at = Instant::now() - Duration::from_secs(2);
+        camera_data.time_to_live = policy
+            .topic_rule(camera_data.publish.topic())
+            .and_then(|rule| rule.time_to_live);
+        assert!(camera_data.is_expired());
+    }
+
+    #[test]
+    fn test_message_expiry_interval_is_reduced_by_the_time_stored() {
+        let expiring = publish("new-incident", "incident")
+            .with_properties(Properties::new().with(Property::MessageExpiryInterval(60)));
+        let mut message = StoredMessage::new(expiring);
+        message.stored_at = Instant::now() - Duration::from_secs(10);
+
+        let publish = message.publish().unwrap();
+        assert_eq!(publish.properties().message_expiry_interval(), Some(50));
+
+        message.stored_at = Instant::now() - Duration::from_secs(60);
+        assert!(message.publish().is_none());
+    }
+
+    #[test]
+    fn test_invalid_rules_are_rejected() {
+        for rules in [
+            "drone-data = 0",
+            "drone-data = -1 = all",
+            "drone-data = 0 = some",
+        ] {
+            assert!(QueuePolicy::unbounded().with_rules(rules).is_err());
+        }
+    }
+}
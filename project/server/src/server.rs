@@ -1,10 +1,15 @@
 use std::{
-    net::{TcpListener, TcpStream},
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    io::ErrorKind,
+    net::{Shutdown, TcpListener, TcpStream},
     sync::{
-        mpsc::{self, Sender},
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender, TryRecvError},
         Arc, RwLock,
     },
     thread,
+    time::{Duration, Instant},
 };
 
 pub use mqtt::model::{
@@ -12,7 +17,24 @@ pub use mqtt::model::{
     packets::{connect::Connect, publish::Publish, subscribe::Subscribe, unsubscribe::Unsubscribe},
 };
 
-use crate::{client::Client, client_manager::ClientManager};
+use mio::{Events, Poll, Registry, Token, Waker};
+use mqtt::{
+    codec::packet_decoder::PacketDecoder, keyring::Keyring,
+    model::return_codes::reason_code::ReasonCode,
+};
+use rustls::ServerConfig;
+use thread_pool::thread_pool::ThreadPool;
+
+use crate::{
+    acl::Acl,
+    client::Client,
+    client_manager::ClientManager,
+    connection::Connection,
+    credentials::{read_client_keys, read_keyring},
+    offline_queue::QueuePolicy,
+    stats::BrokerStats,
+    tls::server_config,
+};
 
 use super::{
     config::Config,
@@ -21,10 +43,20 @@ use super::{
     task_handler::{Task, TaskHandler},
 };
 
+/// Maximum amount of bytes read from a connection before moving on to the next one
+const MAX_READ_PER_POLL: usize = 64 * 1024;
+/// Maximum amount of readiness events handled in an iteration of the I/O loop
+const MAX_EVENTS: usize = 1024;
+/// Token of the waker that tells the I/O loop new connections were accepted or authenticated
+const WAKER: Token = Token(0);
+/// Number of threads that authenticate the clients, so checking their passwords does not block the I/O loop
+const AUTHENTICATION_THREADS: usize = 4;
+
 /// Represents the MQTT server that will be handling all messages
 /// The server has a configuration, a channel to send messages to clients, a log file, and a client manager
-/// The server will be listening for incoming connections and handling them
-/// It creates a new client for each connection and a new thread for each client
+/// The server will be listening for incoming connections and handing them to a single I/O loop,
+/// which waits for the sockets of the clients to be ready, reads their packets and flushes what is queued for them
+#[derive(Clone)]
 pub struct Server {
     /// Configuration of the server
     config: Config,
@@ -34,6 +66,100 @@ pub struct Server {
     log_file: Arc<Logger>,
     /// Manages the registered clients in the server
     client_manager: Arc<RwLock<ClientManager>>,
+    /// Number of connections that have not sent their Connect packet yet
+    pending_connections: Arc<AtomicUsize>,
+    /// Current and previous keys of the encrypted listener, rotated by the task handler
+    keyring: Arc<RwLock<Keyring>>,
+    /// Traffic of the broker, counted by the connections and published by the task handler
+    stats: Arc<BrokerStats>,
+}
+
+/// Represents how the packets of the connections of a listener are protected
+#[derive(Clone, Debug)]
+enum Security {
+    /// Encrypted with the keys of the keyring of the server
+    Encrypted,
+    /// Sent as standard MQTT without protection
+    Plain,
+    /// Sent as standard MQTT inside a TLS session with the settings of the listener
+    Tls(Arc<ServerConfig>),
+}
+
+/// Represents a connection served by the I/O loop
+struct ConnectionState {
+    connection: Connection,
+    /// Bytes received that do not form a whole packet yet
+    decoder: PacketDecoder,
+    /// Id of the client, None until its Connect packet is accepted
+    client_id: Option<Vec<u8>>,
+    /// Keep alive of the Connect packet being authenticated. The packets that follow it wait in the decoder
+    authenticating: Option<u16>,
+    /// How long the connection may stay without sending anything once connected
+    timeout: Option<Duration>,
+    /// Moment after which the connection is closed if nothing arrives
+    deadline: Option<Instant>,
+    /// Earliest moment the timers of the I/O loop check the deadline at, None if they do not
+    scheduled: Option<Instant>,
+}
+
+/// Represents the result of serving a connection in an iteration of the I/O loop
+#[derive(PartialEq)]
+enum PollResult {
+    /// Everything the socket had was read
+    Idle,
+    /// The read limit was reached, so the socket may have more
+    Pending,
+    Closed,
+}
+
+/// Represents the end of the channel the accept threads hand the connections to the I/O loop through
+#[derive(Clone)]
+struct ConnectionsSender {
+    sender: Sender<(TcpStream, Security)>,
+    /// Wakes the I/O loop up so it registers the connections
+    waker: Arc<Waker>,
+}
+
+impl ConnectionsSender {
+    fn send(&self, stream: TcpStream, security: Security) -> ServerResult<()> {
+        self.sender.send((stream, security))?;
+        self.waker.wake()?;
+        Ok(())
+    }
+}
+
+/// Represents the threads that authenticate the Connect packets received by the I/O loop.
+/// The client of each one, None if it was refused, is sent back with the token of its connection
+struct Authenticator {
+    pool: ThreadPool,
+    sender: Sender<(Token, Option<Client>)>,
+    /// Wakes the I/O loop up so it receives the authenticated clients
+    waker: Arc<Waker>,
+}
+
+impl Authenticator {
+    fn authenticate(
+        &self,
+        token: Token,
+        connect_packet: Connect,
+        connection: Connection,
+        client_manager: Arc<RwLock<ClientManager>>,
+    ) {
+        let sender = self.sender.clone();
+        let waker = self.waker.clone();
+
+        self.pool.execute(move || {
+            let client = match client_manager.read() {
+                Ok(client_manager) => {
+                    client_manager.process_connect_packet(connect_packet, &connection)
+                }
+                Err(_) => None,
+            };
+            if sender.send((token, client)).is_ok() {
+                let _ = waker.wake();
+            }
+        });
+    }
 }
 
 impl Server {
@@ -44,17 +170,44 @@ impl Server {
         let (client_actions_sender, client_actions_receiver) = mpsc::channel();
 
         let log_file = Arc::new(Logger::new(config.get_log_file()));
-        let client_manager = ClientManager::new(config.get_login_file());
+        let mut client_manager = ClientManager::new(config.get_login_file());
+        if let Some(client_keys_file) = config.get_client_keys_file() {
+            client_manager = client_manager.with_client_keys(read_client_keys(&client_keys_file)?);
+        }
         // let backup_file = config.get_backup_file();
         let client_manager = Arc::new(RwLock::new(client_manager));
 
+        let keyring = match config.get_keyring_file() {
+            Some(keyring_file) => read_keyring(&keyring_file, config.get_key())?,
+            None => Keyring::new(0, config.get_key()),
+        };
+        let keyring = Arc::new(RwLock::new(keyring));
+
+        let acl = match config.get_acl_file() {
+            Some(acl_file) => Acl::from_file(&acl_file)?,
+            None => Acl::allow_all(),
+        };
+
+        let mut queue_policy = QueuePolicy::new(
+            config.get_max_offline_messages(),
+            config.get_max_offline_bytes(),
+            config.get_offline_overflow_policy(),
+        );
+        if let Some(queue_rules_file) = config.get_queue_rules_file() {
+            queue_policy = queue_policy.with_rules_file(&queue_rules_file)?;
+        }
+
         let task_handler = TaskHandler::new(
             client_actions_receiver,
             &config,
             client_manager.clone(),
             log_file.clone(),
+            acl,
+            queue_policy,
+            keyring.clone(),
         );
 
+        let stats = task_handler.stats();
         task_handler.initialize_task_handler_thread();
 
         Ok(Server {
@@ -62,23 +215,92 @@ impl Server {
             client_actions_sender,
             log_file,
             client_manager,
+            pending_connections: Arc::new(AtomicUsize::new(0)),
+            keyring,
+            stats,
         })
     }
 
     /// Starts the server
+    /// Accepted connections are handed to the I/O loop thread, so a slow client does not block the others.
+    /// If a plain address is configured, a second listener accepts clients that speak standard MQTT without encryption,
+    /// and if a TLS address is configured, another one accepts clients that speak standard MQTT over TLS
     pub fn server_run(&self) -> ServerResult<()> {
         let address = self.config.get_address();
-        let key = self.config.get_key();
 
         self.log_file
             .info(&format!("Server running on address: {}\n", address));
         let listener = TcpListener::bind(address)?;
 
+        let connections_sender = self.initialize_io_loop_thread()?;
+
+        if let Some(plain_address) = self.config.get_plain_address() {
+            self.log_file.info(&format!(
+                "Server accepting unencrypted connections on address: {}\n",
+                plain_address
+            ));
+            let plain_listener = TcpListener::bind(plain_address)?;
+
+            let server = self.clone();
+            let connections_sender = connections_sender.clone();
+            thread::spawn(move || {
+                server.accept_connections(plain_listener, Security::Plain, connections_sender)
+            });
+        }
+
+        if let Some(tls_address) = self.config.get_tls_address() {
+            self.log_file.info(&format!(
+                "Server accepting TLS connections on address: {}\n",
+                tls_address
+            ));
+            let tls_config = server_config(
+                self.config.get_tls_certificate_file(),
+                self.config.get_tls_private_key_file(),
+                self.config.get_tls_client_ca_file().as_deref(),
+            )?;
+            let tls_listener = TcpListener::bind(tls_address)?;
+
+            let server = self.clone();
+            let connections_sender = connections_sender.clone();
+            thread::spawn(move || {
+                server.accept_connections(
+                    tls_listener,
+                    Security::Tls(tls_config),
+                    connections_sender,
+                )
+            });
+        }
+
+        self.accept_connections(listener, Security::Encrypted, connections_sender)
+    }
+
+    /// Accepts the connections of a listener, handing them to the I/O loop along with how
+    /// their packets are protected
+    fn accept_connections(
+        &self,
+        listener: TcpListener,
+        security: Security,
+        connections_sender: ConnectionsSender,
+    ) -> ServerResult<()> {
+        let max_pending_connections = self.config.get_max_pending_connections();
+
         for stream_result in listener.incoming() {
             match stream_result {
                 Ok(stream) => {
                     self.log_file.info("New connection received");
-                    self.handle_new_connection(stream, key)?;
+
+                    if self.pending_connections.fetch_add(1, Ordering::SeqCst)
+                        >= max_pending_connections
+                    {
+                        self.pending_connections.fetch_sub(1, Ordering::SeqCst);
+                        self.log_file.error(
+                            "Too many connections waiting for a Connect packet. Connection rejected",
+                        );
+                        let _ = stream.shutdown(Shutdown::Both);
+                        continue;
+                    }
+
+                    connections_sender.send(stream, security.clone())?;
                 }
                 Err(err) => {
                     self.log_file
@@ -90,104 +312,413 @@ impl Server {
         Ok(())
     }
 
-    /// Handles a new connection by checking if it is a valid packet
-    pub fn handle_new_connection(&self, mut stream: TcpStream, key: &[u8; 32]) -> ServerResult<()> {
-        match Packet::from_bytes(&mut stream, key) {
-            Ok(packet) => self.handle_incoming_packet(packet, stream)?,
-            Err(err) => {
+    /// Initializes the I/O loop thread, which serves the connections handed to it through the returned sender
+    fn initialize_io_loop_thread(&self) -> ServerResult<ConnectionsSender> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (sender, connections_receiver) = mpsc::channel();
+
+        let server = self.clone();
+        let loop_waker = waker.clone();
+        thread::spawn(move || server.run_io_loop(poll, loop_waker, connections_receiver));
+
+        Ok(ConnectionsSender { sender, waker })
+    }
+
+    /// Serves every connection from a single thread, which sleeps until a socket is ready, a connection
+    /// is handed to it or authenticated, or a deadline is reached. Each ready connection gets the packets that arrived read
+    /// and the bytes queued for it flushed, and the connections whose deadline passed are closed.
+    /// The sockets are registered once for reading and writing, and the poll only tells when they become
+    /// ready, so a connection that reaches the read limit is served again without waiting for the poll
+    fn run_io_loop(
+        &self,
+        mut poll: Poll,
+        waker: Arc<Waker>,
+        connections_receiver: Receiver<(TcpStream, Security)>,
+    ) {
+        let (sender, authenticated) = mpsc::channel();
+        let authenticator = Authenticator {
+            pool: ThreadPool::new(AUTHENTICATION_THREADS),
+            sender,
+            waker,
+        };
+        let mut events = Events::with_capacity(MAX_EVENTS);
+        let mut connections: HashMap<Token, ConnectionState> = HashMap::new();
+        let mut timers: BinaryHeap<Reverse<(Instant, Token)>> = BinaryHeap::new();
+        let mut pending: Vec<Token> = Vec::new();
+        let mut next_token = WAKER.0 + 1;
+
+        loop {
+            let timeout = match pending.is_empty() {
+                true => timers.peek().map(|Reverse((deadline, _))| {
+                    deadline.saturating_duration_since(Instant::now())
+                }),
+                false => Some(Duration::ZERO),
+            };
+            if let Err(err) = poll.poll(&mut events, timeout) {
+                if err.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
                 self.log_file
-                    .error(&format!("Error reading packet: {:?}", err));
+                    .error(&format!("Error polling connections: {:?}", err));
+                return;
+            }
+
+            let mut ready = std::mem::take(&mut pending);
+            for event in events.iter() {
+                if event.token() != WAKER {
+                    ready.push(event.token());
+                    continue;
+                }
+
+                loop {
+                    match connections_receiver.try_recv() {
+                        Ok((stream, security)) => {
+                            let token = Token(next_token);
+                            next_token += 1;
+                            let state =
+                                self.new_connection_state(stream, security, poll.registry(), token);
+                            if let Some(state) = state {
+                                connections.insert(token, state);
+                                ready.push(token);
+                            }
+                        }
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => return,
+                    }
+                }
+
+                while let Ok((token, client)) = authenticated.try_recv() {
+                    let state = match connections.get_mut(&token) {
+                        Some(state) => state,
+                        None => continue,
+                    };
+                    if self.connect_authenticated_client(state, client) {
+                        ready.push(token);
+                    } else if let Some(state) = connections.remove(&token) {
+                        self.close_connection(&state);
+                    }
+                }
+            }
+
+            for token in ready {
+                let state = match connections.get_mut(&token) {
+                    Some(state) => state,
+                    None => continue,
+                };
+
+                match self.poll_connection(state, token, &authenticator) {
+                    PollResult::Idle => schedule_deadline(&mut timers, token, state),
+                    PollResult::Pending => {
+                        schedule_deadline(&mut timers, token, state);
+                        pending.push(token);
+                    }
+                    PollResult::Closed => {
+                        if let Some(state) = connections.remove(&token) {
+                            self.close_connection(&state);
+                        }
+                    }
+                }
             }
+
+            self.close_expired_connections(&mut timers, &mut connections);
         }
-        Ok(())
     }
 
-    /// Handles an incoming packet from a connection. If it is a Connect packet, it will create a new client. Otherwise, it will log an error.
-    pub fn handle_incoming_packet(&self, packet: Packet, stream: TcpStream) -> ServerResult<()> {
-        match packet {
-            Packet::Connect(connect_packet) => self.connect_new_client(connect_packet, stream),
-            _ => {
-                self.log_file.error("Received an unsupported packet type");
-                Err(ServerError::UnsupportedPacket)
+    /// Closes the connections whose deadline passed, checking the timers that are due.
+    /// A connection that received something since its timer was set gets a timer for its new deadline
+    fn close_expired_connections(
+        &self,
+        timers: &mut BinaryHeap<Reverse<(Instant, Token)>>,
+        connections: &mut HashMap<Token, ConnectionState>,
+    ) {
+        let now = Instant::now();
+
+        while let Some(Reverse((scheduled, token))) = timers.peek().copied() {
+            if scheduled > now {
+                break;
+            }
+            timers.pop();
+
+            let state = match connections.get_mut(&token) {
+                Some(state) => state,
+                None => continue,
+            };
+            if state.scheduled == Some(scheduled) {
+                state.scheduled = None;
+            }
+
+            if state.deadline.is_none_or(|deadline| deadline > now) {
+                schedule_deadline(timers, token, state);
+                continue;
+            }
+
+            match &state.client_id {
+                Some(client_id) => self.log_file.info(&format!(
+                    "Client {} exceeded its keep alive time",
+                    String::from_utf8_lossy(client_id)
+                )),
+                None => self
+                    .log_file
+                    .error("Connect packet not received in time. Closing connection"),
+            }
+            if let Some(state) = connections.remove(&token) {
+                self.close_connection(&state);
             }
         }
     }
 
-    /// Establishes a new connection with a client by creating a new client and a new thread for it
-    pub fn connect_new_client(
+    /// Creates the state of a new connection, which has segs_to_connect to send its Connect packet,
+    /// registering its socket in the poll of the I/O loop.
+    /// The packets of the connection are encrypted with the keys of the server if it came from the main listener
+    fn new_connection_state(
         &self,
-        connect_packet: Connect,
         stream: TcpStream,
-    ) -> ServerResult<()> {
+        security: Security,
+        registry: &Registry,
+        token: Token,
+    ) -> Option<ConnectionState> {
+        let max_outgoing_bytes = self.config.get_max_outgoing_bytes();
+        let connection = match security {
+            Security::Encrypted => match self.keyring.read() {
+                Ok(keyring) => Connection::new(stream, max_outgoing_bytes, keyring.clone()),
+                Err(_) => Err(std::io::Error::other("The keyring lock was poisoned")),
+            },
+            Security::Plain => Connection::new(stream, max_outgoing_bytes, Keyring::default()),
+            Security::Tls(tls_config) => {
+                Connection::with_tls(stream, tls_config, max_outgoing_bytes)
+            }
+        };
+
+        let connection = match connection.and_then(|mut connection| {
+            connection.register(registry, token)?;
+            Ok(connection)
+        }) {
+            Ok(connection) => connection,
+            Err(err) => {
+                self.log_file
+                    .error(&format!("Error setting up connection: {:?}", err));
+                self.pending_connections.fetch_sub(1, Ordering::SeqCst);
+                return None;
+            }
+        };
+        connection.count_traffic(self.stats.clone());
+
+        let deadline = match self.config.get_segs_to_connect() {
+            0 => None,
+            segs => Some(Instant::now() + Duration::from_secs(segs as u64)),
+        };
+
+        Some(ConnectionState {
+            connection,
+            decoder: PacketDecoder::with_max_packet_size(self.config.get_max_packet_size()),
+            client_id: None,
+            authenticating: None,
+            timeout: None,
+            deadline,
+            scheduled: None,
+        })
+    }
+
+    /// Serves a ready connection: flushes what is queued for it, reads what it sent and handles every whole packet.
+    /// The packets that follow a Connect are not handled until the client is authenticated.
+    /// The connection is closed if it fails or if it sends an unexpected packet
+    fn poll_connection(
+        &self,
+        state: &mut ConnectionState,
+        token: Token,
+        authenticator: &Authenticator,
+    ) -> PollResult {
+        if state.connection.is_closed() {
+            return PollResult::Closed;
+        }
+
+        if let Err(err) = state.connection.flush() {
+            self.log_file.error(&format!("Connection Error: {:?}", err));
+            return PollResult::Closed;
+        }
+
+        let result = match state
+            .connection
+            .read_available(&mut state.decoder, MAX_READ_PER_POLL)
+        {
+            Ok(0) => PollResult::Idle,
+            Ok(read) => {
+                if let Some(timeout) = state.timeout {
+                    state.deadline = Some(Instant::now() + timeout);
+                }
+                match read >= MAX_READ_PER_POLL {
+                    true => PollResult::Pending,
+                    false => PollResult::Idle,
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                self.log_file.info("Connection closed by the client");
+                return PollResult::Closed;
+            }
+            Err(err) => {
+                self.log_file.error(&format!("Connection Error: {:?}", err));
+                return PollResult::Closed;
+            }
+        };
+
+        while state.authenticating.is_none() {
+            let packet = match state.decoder.decode(state.connection.keys()) {
+                Ok(Some(packet)) => packet,
+                Ok(None) => break,
+                Err(err) => {
+                    self.log_file
+                        .error(&format!("Error reading packet: {:?}", err));
+                    return PollResult::Closed;
+                }
+            };
+
+            let keep_open = match &state.client_id {
+                Some(client_id) => handle_packet(
+                    packet,
+                    client_id.clone(),
+                    self.client_actions_sender.clone(),
+                    self.log_file.clone(),
+                ),
+                None => {
+                    if let Some(key_id) = state.decoder.key_id() {
+                        state.connection.select_key(key_id);
+                    }
+                    if let Err(err) = state
+                        .connection
+                        .set_protocol_version(state.decoder.protocol_version())
+                    {
+                        self.log_file.error(&format!("Connection Error: {:?}", err));
+                        return PollResult::Closed;
+                    }
+                    self.handle_first_packet(packet, state, token, authenticator)
+                }
+            };
+
+            if !keep_open {
+                return PollResult::Closed;
+            }
+        }
+
+        result
+    }
+
+    /// Handles the first packet of a connection, which must be a Connect packet, handing it to the authenticator.
+    /// Returns false if the connection has to be closed
+    fn handle_first_packet(
+        &self,
+        packet: Packet,
+        state: &mut ConnectionState,
+        token: Token,
+        authenticator: &Authenticator,
+    ) -> bool {
+        let connect_packet = match packet {
+            Packet::Connect(connect_packet) => connect_packet,
+            _ => {
+                self.log_file.error(&format!(
+                    "Error handling new connection: {:?}",
+                    ServerError::UnsupportedPacket
+                ));
+                return false;
+            }
+        };
+
         let message = format!(
             "Received Connect Packet from client with ID: {}",
             connect_packet.client_id()
         );
         self.log_file.info(&message);
 
-        let client_manager = self.client_manager.read().map_err(|_| {
-            ServerError::ClientConnection("Failed to acquire read lock".to_string())
-        })?;
-
-        let stream_clone = stream.try_clone()?;
-
-        match client_manager.process_connect_packet(
+        state.authenticating = Some(connect_packet.keep_alive());
+        authenticator.authenticate(
+            token,
             connect_packet,
-            stream_clone,
-            self.config.get_key(),
-        ) {
-            Some(new_client) => {
-                self.log_file.info("Client connected successfully");
-
-                let client_id = new_client.id();
-                handle_connect(self.client_actions_sender.clone(), new_client)?;
-                self.create_new_client_thread(
-                    self.client_actions_sender.clone(),
-                    stream,
-                    client_id,
-                    self.log_file.clone(),
-                );
-            }
+            state.connection.clone(),
+            self.client_manager.clone(),
+        );
+        true
+    }
+
+    /// Establishes the connection of an authenticated client by sending it to the task handler, which answers
+    /// its Connect packet. Returns false if the client was refused and the connection has to be closed
+    fn connect_authenticated_client(
+        &self,
+        state: &mut ConnectionState,
+        client: Option<Client>,
+    ) -> bool {
+        let keep_alive = state.authenticating.take().unwrap_or(0);
+        let client = match client {
+            Some(client) => client,
             None => {
                 self.log_file.error("Error connecting client");
+                return false;
+            }
+        };
+
+        self.log_file.info("Client connected successfully");
+        self.pending_connections.fetch_sub(1, Ordering::SeqCst);
+        state.client_id = Some(client.id());
+        state.timeout = keep_alive_timeout(keep_alive, self.config.get_segs_to_disconnect());
+        state.deadline = state.timeout.map(|timeout| Instant::now() + timeout);
+
+        match handle_connect(self.client_actions_sender.clone(), client) {
+            Ok(keep_open) => keep_open,
+            Err(err) => {
+                self.log_file
+                    .error(&format!("Error handling new connection: {:?}", err));
+                false
             }
         }
-        Ok(())
     }
 
-    /// Creates a new thread for a client
-    pub fn create_new_client_thread(
-        &self,
-        sender_to_task_channel: std::sync::mpsc::Sender<Task>,
-        mut stream: TcpStream,
-        client_id: Vec<u8>,
-        log_file: Arc<Logger>,
-    ) {
-        let key = *self.config.get_key();
-
-        thread::spawn(move || {
-            loop {
-                let packet = Packet::from_bytes(&mut stream, &key);
-                match packet {
-                    Ok(packet) => {
-                        if !handle_packet(
-                            packet,
-                            client_id.clone(),
-                            sender_to_task_channel.clone(),
-                            log_file.clone(),
-                        ) {
-                            break;
-                        }
-                    }
-                    Err(err) => {
-                        log_file.error(&format!("Connection Error: {:?}", err));
-                        break;
-                    }
+    /// Closes a connection dropped by the I/O loop. A connected client goes through the disconnection task,
+    /// which ignores the connections taken over by a new connection of the same client
+    fn close_connection(&self, state: &ConnectionState) {
+        state.connection.close();
+
+        match &state.client_id {
+            Some(client_id) => {
+                self.log_file.info("Disconnecting client");
+                let task = Task::ConnectionClosed(client_id.clone(), state.connection.clone());
+                if self.client_actions_sender.send(task).is_err() {
+                    self.log_file.error("Error sending the disconnection task");
                 }
             }
-            log_file.info("Disconnecting client");
-            disconnect_client(sender_to_task_channel, client_id).unwrap_or(false);
-        });
+            None => {
+                self.pending_connections.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// Adds a timer for the deadline of a connection, unless one that checks it earlier is already set
+fn schedule_deadline(
+    timers: &mut BinaryHeap<Reverse<(Instant, Token)>>,
+    token: Token,
+    state: &mut ConnectionState,
+) {
+    if let Some(deadline) = state.deadline {
+        if state.scheduled.is_none_or(|scheduled| deadline < scheduled) {
+            timers.push(Reverse((deadline, token)));
+            state.scheduled = Some(deadline);
+        }
+    }
+}
+
+/// Returns how long the server waits for a packet of a client before disconnecting it.
+/// It is one and a half times the keep alive of the client, or segs_to_disconnect if the client did not set one.
+/// Returns None if neither of them is set, in which case the client is never disconnected for inactivity
+fn keep_alive_timeout(keep_alive: u16, segs_to_disconnect: u32) -> Option<Duration> {
+    let timeout = if keep_alive > 0 {
+        Duration::from_millis(keep_alive as u64 * 1500)
+    } else {
+        Duration::from_secs(segs_to_disconnect as u64)
+    };
+
+    if timeout.is_zero() {
+        None
+    } else {
+        Some(timeout)
     }
 }
 
@@ -219,18 +750,57 @@ pub fn handle_packet(
             handle_unsubscribe(unsubscribe_packet, sender_to_task_channel, client_id)
                 .unwrap_or(false)
         }
+        Packet::Puback(puback_packet) => {
+            log_message("Puback");
+            handle_puback(
+                puback_packet.packet_identifier(),
+                sender_to_task_channel,
+                client_id,
+            )
+            .unwrap_or(false)
+        }
+        Packet::Pubrec(pubrec_packet) => {
+            log_message("Pubrec");
+            handle_pubrec(
+                pubrec_packet.packet_identifier(),
+                sender_to_task_channel,
+                client_id,
+            )
+            .unwrap_or(false)
+        }
+        Packet::Pubrel(pubrel_packet) => {
+            log_message("Pubrel");
+            handle_pubrel(
+                pubrel_packet.packet_identifier(),
+                sender_to_task_channel,
+                client_id,
+            )
+            .unwrap_or(false)
+        }
+        Packet::Pubcomp(pubcomp_packet) => {
+            log_message("Pubcomp");
+            handle_pubcomp(
+                pubcomp_packet.packet_identifier(),
+                sender_to_task_channel,
+                client_id,
+            )
+            .unwrap_or(false)
+        }
         Packet::Pingreq(_) => {
             log_message("Pingreq");
             handle_pingreq(sender_to_task_channel, client_id).unwrap_or(false)
         }
-        Packet::Disconnect(_) => {
+        Packet::Disconnect(disconnect_packet) => {
             log_message("Disconnect");
-            disconnect_client(sender_to_task_channel, client_id).unwrap_or(false)
+            // With this reason code of MQTT 5.0 the will is published when the connection closes
+            if disconnect_packet.reason_code() == ReasonCode::DisconnectWithWillMessage {
+                return false;
+            }
+            discard_will(sender_to_task_channel, client_id).unwrap_or(false)
         }
+        // The client is disconnected when the I/O loop closes its connection
         _ => {
             log_file.error("Unsupported packet type");
-            log_file.info("Disconnecting client");
-            disconnect_client(sender_to_task_channel, client_id).unwrap_or(false);
             false
         }
     }
@@ -241,7 +811,7 @@ pub fn handle_connect(
     sender_to_topics_channel: std::sync::mpsc::Sender<Task>,
     client: Client,
 ) -> ServerResult<bool> {
-    sender_to_topics_channel.send(Task::ConnectClient(client))?;
+    sender_to_topics_channel.send(Task::ConnectClient(Box::new(client)))?;
     Ok(true)
 }
 
@@ -255,6 +825,46 @@ pub fn handle_publish(
     Ok(true)
 }
 
+/// Handles a PUBACK packet
+pub fn handle_puback(
+    packet_identifier: Option<u16>,
+    sender_to_task_channel: std::sync::mpsc::Sender<Task>,
+    client_id: Vec<u8>,
+) -> ServerResult<bool> {
+    sender_to_task_channel.send(Task::PublishAck(packet_identifier, client_id))?;
+    Ok(true)
+}
+
+/// Handles a PUBREC packet
+pub fn handle_pubrec(
+    packet_identifier: u16,
+    sender_to_task_channel: std::sync::mpsc::Sender<Task>,
+    client_id: Vec<u8>,
+) -> ServerResult<bool> {
+    sender_to_task_channel.send(Task::PublishReceived(packet_identifier, client_id))?;
+    Ok(true)
+}
+
+/// Handles a PUBREL packet
+pub fn handle_pubrel(
+    packet_identifier: u16,
+    sender_to_task_channel: std::sync::mpsc::Sender<Task>,
+    client_id: Vec<u8>,
+) -> ServerResult<bool> {
+    sender_to_task_channel.send(Task::PublishRelease(packet_identifier, client_id))?;
+    Ok(true)
+}
+
+/// Handles a PUBCOMP packet
+pub fn handle_pubcomp(
+    packet_identifier: u16,
+    sender_to_task_channel: std::sync::mpsc::Sender<Task>,
+    client_id: Vec<u8>,
+) -> ServerResult<bool> {
+    sender_to_task_channel.send(Task::PublishComplete(packet_identifier, client_id))?;
+    Ok(true)
+}
+
 /// Handles a SUBSCRIBE packet
 pub fn handle_subscribe(
     subscribe_packet: Subscribe,
@@ -287,10 +897,129 @@ pub fn handle_pingreq(
 }
 
 /// Handles a DISCONNECT packet
-pub fn disconnect_client(
+/// Sends a task to discard the will of a client that disconnected gracefully.
+/// The client is disconnected afterwards when the I/O loop closes its connection
+pub fn discard_will(
     sender_to_task_channel: std::sync::mpsc::Sender<Task>,
     client_id: Vec<u8>,
 ) -> ServerResult<bool> {
-    sender_to_task_channel.send(Task::DisconnectClient(client_id))?;
+    sender_to_task_channel.send(Task::DiscardWill(client_id))?;
     Ok(false)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write, path::Path};
+
+    use mqtt::{
+        model::{
+            components::{encoded_string::EncodedString, login::Login},
+            packets::{pingreq::Pingreq, pingresp::Pingresp},
+            return_codes::connect_return_code::ConnectReturnCode,
+        },
+        NO_ENCRYPTION,
+    };
+
+    use crate::credentials::testing::TempFile;
+
+    use super::*;
+
+    /// Starts the I/O loop of a server whose clients have one second to send their Connect packet.
+    /// Its files are written to the temporary directory and removed when the returned files are dropped
+    fn start_io_loop(name: &str) -> (ConnectionsSender, [TempFile; 3]) {
+        let files = [
+            TempFile::new(&format!("{}_Login.toml", name)),
+            TempFile::new(&format!("{}_Settings.toml", name)),
+            TempFile::new(&format!("{}_server.log", name)),
+        ];
+        fs::write(files[0].path(), "1 = drone = password\n").unwrap();
+        let settings = format!(
+            "key=\"{}\"\nlog_file=\"{}\"\nlogin_file=\"{}\"\nsegs_to_connect=1",
+            "k".repeat(32),
+            files[2].path(),
+            files[0].path()
+        );
+        fs::write(files[1].path(), settings).unwrap();
+
+        let server = Server::new(Config::from_file(Path::new(files[1].path())).unwrap()).unwrap();
+        (server.initialize_io_loop_thread().unwrap(), files)
+    }
+
+    /// Hands a new connection to the I/O loop, returning the socket of the client
+    fn open_connection(connections_sender: &ConnectionsSender) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        connections_sender.send(stream, Security::Plain).unwrap();
+        peer
+    }
+
+    #[test]
+    fn test_io_loop_answers_the_connect_of_a_client() {
+        let (connections_sender, _files) = start_io_loop("test_io_loop_connect");
+        let mut peer = open_connection(&connections_sender);
+
+        let login = Login::new(
+            EncodedString::from_string(&"drone".to_string()),
+            Some(EncodedString::from_string(&"password".to_string())),
+        );
+        let connect = Connect::new(
+            true,
+            0,
+            EncodedString::from_string(&"1".to_string()),
+            None,
+            Some(login),
+        );
+        // The Pingreq waits for the client to be authenticated
+        let mut bytes = Packet::Connect(connect).to_bytes(NO_ENCRYPTION);
+        bytes.extend(Packet::Pingreq(Pingreq::new()).to_bytes(NO_ENCRYPTION));
+        peer.write_all(&bytes).unwrap();
+
+        match Packet::from_bytes(&mut peer, NO_ENCRYPTION).unwrap() {
+            Packet::Connack(connack) => assert_eq!(
+                *connack.connect_return_code(),
+                ConnectReturnCode::ConnectionAccepted
+            ),
+            packet => panic!("Expected a Connack, received {:?}", packet),
+        }
+        assert!(matches!(
+            Packet::from_bytes(&mut peer, NO_ENCRYPTION).unwrap(),
+            Packet::Pingresp(_)
+        ));
+    }
+
+    #[test]
+    fn test_io_loop_closes_connections_that_do_not_connect_in_time() {
+        let (connections_sender, _files) = start_io_loop("test_io_loop_timeout");
+        let mut peer = open_connection(&connections_sender);
+
+        let started = Instant::now();
+        let mut buffer = [0; 1];
+        assert_eq!(std::io::Read::read(&mut peer, &mut buffer).unwrap(), 0);
+        assert!(started.elapsed() >= Duration::from_millis(900));
+    }
+
+    #[test]
+    fn test_unsupported_packet_closes_the_connection_without_a_task() {
+        let (sender, receiver) = mpsc::channel();
+        let log_file = TempFile::new("test_unsupported_packet.log");
+
+        let keep_open = handle_packet(
+            Packet::Pingresp(Pingresp::new()),
+            b"1".to_vec(),
+            sender,
+            Arc::new(Logger::new(log_file.path())),
+        );
+
+        assert!(!keep_open);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_keep_alive_timeout() {
+        assert_eq!(keep_alive_timeout(10, 30), Some(Duration::from_secs(15)));
+        assert_eq!(keep_alive_timeout(0, 30), Some(Duration::from_secs(30)));
+        assert_eq!(keep_alive_timeout(0, 0), None);
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Represents the traffic of the broker, counted by the connections as they read and write their sockets
+/// and by the task handler as it receives and delivers publishes
+#[derive(Debug, Default)]
+pub struct BrokerStats {
+    messages_received: AtomicU64,
+    messages_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+}
+
+/// Represents the traffic counted up to a moment, or the traffic per second between two moments
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Traffic {
+    pub messages_received: u64,
+    pub messages_sent: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+impl BrokerStats {
+    pub fn new() -> Self {
+        BrokerStats::default()
+    }
+
+    pub fn add_received_message(&self) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_sent_message(&self) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_received_bytes(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_sent_bytes(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the traffic counted since the server started
+    pub fn traffic(&self) -> Traffic {
+        Traffic {
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Traffic {
+    /// Returns the traffic per second between an earlier count and this one, rounded down
+    pub fn per_second(&self, earlier: &Traffic, elapsed: Duration) -> Traffic {
+        let millis = elapsed.as_millis().max(1) as u64;
+        let rate = |current: u64, earlier: u64| current.saturating_sub(earlier) * 1000 / millis;
+
+        Traffic {
+            messages_received: rate(self.messages_received, earlier.messages_received),
+            messages_sent: rate(self.messages_sent, earlier.messages_sent),
+            bytes_received: rate(self.bytes_received, earlier.bytes_received),
+            bytes_sent: rate(self.bytes_sent, earlier.bytes_sent),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traffic_per_second() {
+        let stats = BrokerStats::new();
+        let earlier = stats.traffic();
+
+        for _ in 0..10 {
+            stats.add_received_message();
+            stats.add_received_bytes(100);
+        }
+        stats.add_sent_message();
+        stats.add_sent_bytes(50);
+
+        let rates = stats.traffic().per_second(&earlier, Duration::from_secs(2));
+
+        assert_eq!(
+            rates,
+            Traffic {
+                messages_received: 5,
+                messages_sent: 0,
+                bytes_received: 500,
+                bytes_sent: 25,
+            }
+        );
+    }
+}
@@ -1,25 +1,44 @@
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
+    collections::{HashMap, HashSet},
     io::{Read, Write},
     sync::{mpsc, Arc, RwLock},
     thread,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
-    client::Client, client_manager::ClientManager, config::Config, error::ServerResult,
+    acl::{Acl, ADMIN_ID},
+    client::{Client, InflightMessage},
+    client_manager::ClientManager,
+    config::Config,
+    connection::Connection,
+    error::ServerResult,
     logfile::Logger,
+    offline_queue::{OfflineQueue, QueueOutcome, QueuePolicy, StoredMessage},
+    stats::{BrokerStats, Traffic},
 };
 
+use mqtt::keyring::{
+    append_to_file, bytes_to_hex, hex_to_bytes, parse_rotation_message, rotation_message, Keyring,
+    KEY_ROTATION_TOPIC,
+};
 use mqtt::model::packet::Packet;
 
 use mqtt::model::{
-    components::{qos::QoS, topic_filter::TopicFilter, topic_name::TopicName},
+    components::{
+        properties::{Properties, Property},
+        protocol_version::ProtocolVersion,
+        qos::QoS,
+        topic_filter::TopicFilter,
+        topic_name::TopicName,
+    },
     packets::{
-        connack::Connack, pingresp::Pingresp, puback::Puback, publish::Publish, suback::Suback,
-        subscribe::Subscribe, unsuback::Unsuback, unsubscribe::Unsubscribe,
+        connack::Connack, pingresp::Pingresp, puback::Puback, pubcomp::Pubcomp, publish::Publish,
+        pubrec::Pubrec, pubrel::Pubrel, suback::Suback, subscribe::Subscribe, unsuback::Unsuback,
+        unsubscribe::Unsubscribe,
     },
-    return_codes::{connect_return_code::ConnectReturnCode, suback_return_code::SubackReturnCode},
+    return_codes::{connect_return_code::ConnectReturnCode, reason_code::ReasonCode},
+    topic_tree::TopicTree,
 };
 
 use std::fs::File;
@@ -29,32 +48,76 @@ pub enum Task {
     SubscribeClient(Subscribe, Vec<u8>),
     UnsubscribeClient(Unsubscribe, Vec<u8>),
     Publish(Publish, Vec<u8>),
-    ConnectClient(Client),
-    DisconnectClient(Vec<u8>),
+    PublishAck(Option<u16>, Vec<u8>),
+    PublishReceived(u16, Vec<u8>),
+    PublishRelease(u16, Vec<u8>),
+    PublishComplete(u16, Vec<u8>),
+    ConnectClient(Box<Client>),
+    /// The I/O loop closed the connection of a client
+    ConnectionClosed(Vec<u8>, Connection),
+    DiscardWill(Vec<u8>),
     RespondPing(Vec<u8>),
 }
 
-const ADMIN_ID: &[u8] = b"admin";
 const CLIENT_REGISTER: &[u8] = b"$client-register";
 const SEPARATOR: u8 = b';';
+const WILL_PACKET_IDENTIFIER: u16 = 1;
 
 const RETAINED_MESSAGES_TAG: &str = "R";
 const OFFLINE_MESSAGES_TAG: &str = "O";
 const CLIENTS_TAG: &str = "C";
 
+/// Identifiers of the properties that are not forwarded to the subscribers
+const TOPIC_ALIAS: u8 = 0x23;
+const WILL_DELAY_INTERVAL: u8 = 0x18;
+const DEFAULT_MAX_INFLIGHT_MESSAGES: usize = 20;
+const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+/// Levels of the topics the statistics of the broker are published on
+const SYSTEM_TOPICS: &[u8] = b"$SYS";
+const BROKER_LEVEL: &[u8] = b"broker";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Seconds between the sweeps of the expired sessions and unacknowledged messages while no task arrives
+const SESSION_EXPIRY_SWEEP: Duration = Duration::from_secs(1);
+
 /// Represents the task handler that will handle all the tasks that the server needs to process
 #[derive(Debug)]
 pub struct TaskHandler {
     client_actions_receiver_channel: mpsc::Receiver<Task>,
     clients: RwLock<HashMap<Vec<u8>, Client>>,
     active_connections: HashSet<Vec<u8>>,
-    offline_messages: HashMap<Vec<u8>, VecDeque<Publish>>,
-    retained_messages: HashMap<TopicName, VecDeque<Publish>>,
+    /// Ids of the clients subscribed to each topic filter
+    subscriptions: TopicTree<Vec<u8>>,
+    /// Messages queued for the clients that are not connected
+    offline_messages: HashMap<Vec<u8>, OfflineQueue>,
+    /// Limits of the offline queues and rules of the messages of each topic
+    queue_policy: QueuePolicy,
+    /// Last retained message of each topic
+    retained_messages: HashMap<TopicName, StoredMessage>,
+    /// Topics that have a retained message, to find the ones matched by a new subscription
+    retained_topics: TopicTree<TopicName>,
     log_file: Arc<Logger>,
     client_manager: Arc<RwLock<ClientManager>>,
+    /// Rules of the topics each client may publish and subscribe to
+    acl: Acl,
+    /// Key the packets of the backup are encrypted with. Packets sent to clients use the key of their connection
     key: [u8; 32],
+    /// Current and previous keys of the encrypted listener, shared with the server
+    keyring: Arc<RwLock<Keyring>>,
+    /// File the rotated keys are added to, None if they are only kept in memory
+    keyring_file: Option<String>,
     backup_file: Option<String>,
     segs_to_backup: u32,
+    /// QoS 1 and 2 messages a client may have unacknowledged before the next ones wait for room
+    max_inflight_messages: usize,
+    /// Time after which an unacknowledged message is sent again, zero to only send it again on session resume
+    retry_interval: Duration,
+    /// Traffic of the broker, to which the connections add the bytes they read and write
+    stats: Arc<BrokerStats>,
+    /// Time between the publications of the statistics on the `$SYS` topics, zero to not publish them
+    stats_interval: Duration,
+    started_at: Instant,
+    /// Moment and traffic of the last publication of the statistics
+    last_stats: (Instant, Traffic),
 }
 
 impl TaskHandler {
@@ -71,21 +134,67 @@ impl TaskHandler {
             client_actions_receiver_channel: receiver_channel,
             clients: RwLock::new(HashMap::new()),
             active_connections: HashSet::new(),
+            subscriptions: TopicTree::new(),
             offline_messages: HashMap::new(),
+            queue_policy: QueuePolicy::unbounded(),
             retained_messages: HashMap::new(),
+            retained_topics: TopicTree::new(),
             log_file,
             client_manager,
+            acl: Acl::allow_all(),
             key,
+            keyring: Arc::new(RwLock::new(Keyring::new(0, &key))),
+            keyring_file: None,
             backup_file,
             segs_to_backup,
+            max_inflight_messages: DEFAULT_MAX_INFLIGHT_MESSAGES,
+            retry_interval: DEFAULT_RETRY_INTERVAL,
+            stats: Arc::new(BrokerStats::new()),
+            stats_interval: Duration::ZERO,
+            started_at: Instant::now(),
+            last_stats: (Instant::now(), Traffic::default()),
         }
     }
 
+    /// Creates a new task handler from the configuration of the server, restoring the backup if requested,
+    /// that enforces the specified ACL, limits the offline queues with the queue policy and rotates the keys
+    /// of the keyring shared with the server
     pub fn new(
         client_actions_receiver_channel: mpsc::Receiver<Task>,
         config: &Config,
         client_manager: Arc<RwLock<ClientManager>>,
         log_file: Arc<Logger>,
+        acl: Acl,
+        queue_policy: QueuePolicy,
+        keyring: Arc<RwLock<Keyring>>,
+    ) -> Self {
+        let mut task_handler = TaskHandler::from_config(
+            client_actions_receiver_channel,
+            config,
+            client_manager,
+            log_file,
+        );
+        task_handler.acl = acl;
+        task_handler.queue_policy = queue_policy;
+        task_handler.keyring = keyring;
+        task_handler.keyring_file = config.get_keyring_file();
+        task_handler.max_inflight_messages = config.get_max_inflight_messages();
+        task_handler.retry_interval = Duration::from_secs(config.get_segs_to_retry() as u64);
+        task_handler.stats_interval =
+            Duration::from_secs(config.get_segs_to_publish_stats() as u64);
+        task_handler
+    }
+
+    /// Returns the traffic of the broker, to which the connections add the bytes they read and write
+    pub fn stats(&self) -> Arc<BrokerStats> {
+        self.stats.clone()
+    }
+
+    fn from_config(
+        client_actions_receiver_channel: mpsc::Receiver<Task>,
+        config: &Config,
+        client_manager: Arc<RwLock<ClientManager>>,
+        log_file: Arc<Logger>,
     ) -> Self {
         let backup_file = config.get_backup_file();
         let initialize_with_backup = config.get_initialize_with_backup();
@@ -166,18 +275,35 @@ impl TaskHandler {
         let mut last_backup = std::time::Instant::now();
 
         loop {
-            match self.client_actions_receiver_channel.recv() {
+            match self
+                .client_actions_receiver_channel
+                .recv_timeout(SESSION_EXPIRY_SWEEP)
+            {
                 Ok(task) => {
                     if let Err(e) = self.handle_task(task) {
                         self.log_file.error(e.to_string().as_str());
                     }
                 }
-                Err(_) => {
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
                     std::thread::sleep(Duration::from_secs(1));
                     continue;
                 }
             }
 
+            if let Err(e) = self.expire_sessions() {
+                self.log_file.error(e.to_string().as_str());
+            }
+            if let Err(e) = self.retry_inflight() {
+                self.log_file.error(e.to_string().as_str());
+            }
+            if !self.stats_interval.is_zero() && self.last_stats.0.elapsed() >= self.stats_interval
+            {
+                if let Err(e) = self.publish_stats() {
+                    self.log_file.error(e.to_string().as_str());
+                }
+            }
+
             if self.backup_file.is_some() && last_backup.elapsed() >= backup_interval {
                 self.log_file.info("Backing up server data");
                 self.backup_data();
@@ -194,33 +320,79 @@ impl TaskHandler {
                 self.unsubscribe(unsubscribe, client_id)
             }
             Task::Publish(publish, client_id) => self.publish(&publish, client_id),
-            Task::ConnectClient(client) => self.handle_new_client_connection(client),
-            Task::DisconnectClient(client_id) => self.handle_client_disconnected(client_id),
+            Task::PublishAck(packet_identifier, client_id) => {
+                self.publish_ack(packet_identifier, client_id)
+            }
+            Task::PublishReceived(packet_identifier, client_id) => {
+                self.publish_received(packet_identifier, client_id)
+            }
+            Task::PublishRelease(packet_identifier, client_id) => {
+                self.publish_release(packet_identifier, client_id)
+            }
+            Task::PublishComplete(packet_identifier, client_id) => {
+                self.publish_complete(packet_identifier, client_id)
+            }
+            Task::ConnectClient(client) => self.handle_new_client_connection(*client),
+            Task::ConnectionClosed(client_id, connection) => {
+                self.handle_connection_closed(client_id, &connection)
+            }
+            Task::DiscardWill(client_id) => self.discard_will(client_id),
             Task::RespondPing(client_id) => self.respond_ping(client_id),
         }
     }
 
-    /// Subscribe a client_id into a set of topics given a Subscribe packet
-    pub fn subscribe(&self, subscribe_packet: Subscribe, client_id: Vec<u8>) -> ServerResult<()> {
+    /// Subscribe a client_id into a set of topics given a Subscribe packet, granting the requested QoS.
+    /// The retained messages of the topics are sent at the lower of their QoS and the granted one
+    pub fn subscribe(
+        &mut self,
+        subscribe_packet: Subscribe,
+        client_id: Vec<u8>,
+    ) -> ServerResult<()> {
         let mut clients = self.clients.write()?;
 
         if let Some(client) = clients.get_mut(&client_id) {
-            self.suback(subscribe_packet.packet_identifier(), client);
+            let mut topic_filters = vec![];
+            let mut reason_codes = vec![];
+
+            for (topic_filter, qos) in subscribe_packet.topics() {
+                if self
+                    .acl
+                    .can_subscribe(&client_id, &client.username, &topic_filter)
+                {
+                    reason_codes.push(ReasonCode::granted_qos(&qos));
+                    topic_filters.push((topic_filter, qos));
+                } else {
+                    let message = format!(
+                        "Client {} is not allowed to subscribe to {}",
+                        String::from_utf8_lossy(&client_id),
+                        topic_filter
+                    );
+                    self.log_file.info(message.as_str());
+                    reason_codes.push(ReasonCode::NotAuthorized);
+                }
+            }
+
+            self.suback(subscribe_packet.packet_identifier(), reason_codes, client);
 
             self.log_file
                 .log_successful_subscription(&client_id, &subscribe_packet);
 
-            for (topic_filter, _) in subscribe_packet.topics() {
-                client.add_subscription(topic_filter.clone());
-
-                // Send the retained message if it exists
-                for (topic_name, retained_messages) in &self.retained_messages {
-                    if topic_filter.match_topic_name(topic_name.clone()) {
-                        for message in retained_messages {
-                            client.send_message(message.clone(), &self.log_file, &self.key);
-                        }
+            for (topic_filter, qos) in topic_filters {
+                self.subscriptions.insert(&topic_filter, client_id.clone());
+
+                // Send the retained messages of the topics matched by the filter
+                for topic_name in self.retained_topics.matched_by(&topic_filter) {
+                    let retained_message = self
+                        .retained_messages
+                        .get(&topic_name)
+                        .and_then(StoredMessage::publish);
+                    if let Some(retained_message) = retained_message {
+                        let delivery_qos = retained_message.qos().clone().min(qos.clone());
+                        self.deliver(&retained_message, delivery_qos, client);
                     }
                 }
+
+                client.add_subscription(topic_filter, qos);
             }
         } else {
             self.log_file.log_client_does_not_exist(&client_id);
@@ -230,7 +402,7 @@ impl TaskHandler {
 
     /// Unsubscribe a client_id from a set of topics given an Unsubscribe packet
     pub fn unsubscribe(
-        &self,
+        &mut self,
         unsubscribe_packet: Unsubscribe,
         client_id: Vec<u8>,
     ) -> ServerResult<()> {
@@ -239,11 +411,16 @@ impl TaskHandler {
         if let Some(client) = clients.get_mut(&client_id) {
             for topic_filter in unsubscribe_packet.topics() {
                 client.remove_subscription(topic_filter);
+                self.subscriptions.remove(topic_filter, &client_id);
             }
 
             self.log_file
                 .log_successful_unsubscription(&client_id, &unsubscribe_packet);
-            self.unsuback(unsubscribe_packet.packet_identifier(), client);
+            self.unsuback(
+                unsubscribe_packet.packet_identifier(),
+                unsubscribe_packet.topics().len(),
+                client,
+            );
         } else {
             self.log_file.log_client_does_not_exist(&client_id);
         }
@@ -251,62 +428,487 @@ impl TaskHandler {
         Ok(())
     }
 
-    /// Publish a message to all clients subscribed to the topic of the Publish packet
+    /// Publish a message to all clients subscribed to the topic of the Publish packet.
+    /// The acknowledgement of an MQTT 5.0 client tells whether the publish was refused or had no subscribers
     pub fn publish(&mut self, publish_packet: &Publish, client_id: Vec<u8>) -> ServerResult<()> {
-        let topic_name = publish_packet.topic();
+        self.stats.add_received_message();
+        let allowed = self.can_publish(publish_packet, &client_id)?;
+        // The topics reserved to the server never keep a retained message
+        let allowed =
+            allowed && !(publish_packet.retain() && publish_packet.topic().server_reserved());
+        let reason_code = if !allowed {
+            ReasonCode::NotAuthorized
+        } else if !publish_packet.topic().server_reserved()
+            && self
+                .subscriptions
+                .matches(publish_packet.topic())
+                .is_empty()
+        {
+            ReasonCode::NoMatchingSubscribers
+        } else {
+            ReasonCode::Success
+        };
+
+        if !self.acknowledge_publish(publish_packet, &client_id, reason_code)? {
+            let message = format!(
+                "Discarded duplicated QoS 2 publish from client {}",
+                String::from_utf8_lossy(&client_id)
+            );
+            self.log_file.info(message.as_str());
+            return Ok(());
+        }
+
+        if !allowed {
+            let message = format!(
+                "Client {} is not allowed to publish to {}, discarding the publish",
+                String::from_utf8_lossy(&client_id),
+                publish_packet.topic()
+            );
+            self.log_file.info(message.as_str());
+            return Ok(());
+        }
 
-        if topic_name.server_reserved() {
+        if publish_packet.topic().server_reserved() {
             self.handle_server_reserved_topic(publish_packet, client_id);
             return Ok(());
         }
 
-        if publish_packet.retain() {
-            self.retained_messages
-                .entry(topic_name.clone())
-                .or_default()
-                .push_back(publish_packet.clone());
+        if !self.publish_to_subscribers(publish_packet, &client_id)? {
+            return Ok(());
         }
 
-        let mut clients = vec![];
+        let mut clients = self.clients.write()?;
+
+        let clients_retained_messages = self.offline_messages.get(&client_id);
+        let client = match clients.get_mut(&client_id) {
+            Some(client) => client,
+            None => {
+                self.log_file.log_client_does_not_exist(&client_id);
+                return Ok(());
+            }
+        };
 
-        for client in self.clients.read()?.values() {
-            if client.is_subscribed(topic_name) {
-                clients.push(client.id());
+        if let Some(clients_retained_messages) = clients_retained_messages {
+            self.handle_retained_messages(client, clients_retained_messages);
+            match self.offline_messages.get_mut(&client_id) {
+                Some(queue) => queue.clear(),
+                None => {
+                    self.log_file.error("Error clearing offline messages");
+                }
             }
         }
 
+        Ok(())
+    }
+
+    /// Returns whether the ACL allows a client to publish to the topic of a Publish packet
+    fn can_publish(&self, publish_packet: &Publish, client_id: &[u8]) -> ServerResult<bool> {
+        let clients = self.clients.read()?;
+        let username = match clients.get(client_id) {
+            Some(client) => client.username.as_slice(),
+            None => &[],
+        };
+
+        Ok(self
+            .acl
+            .can_publish(client_id, username, publish_packet.topic()))
+    }
+
+    /// Send a publish to every client subscribed to its topic, storing it as retained if requested.
+    /// Each subscriber receives it at the lower of its QoS and the highest QoS granted to the subscriber for the topic.
+    /// The subscribers receive it with the retain flag cleared, since they were already subscribed when it was published.
+    /// Subscribers that are not connected receive it when they reconnect, if it fits in their offline queue.
+    /// Returns false if there are no clients subscribed to the topic
+    fn publish_to_subscribers(
+        &mut self,
+        publish_packet: &Publish,
+        client_id: &[u8],
+    ) -> ServerResult<bool> {
+        let topic_name = publish_packet.topic();
+
+        if publish_packet.retain() {
+            self.retain_message(publish_packet);
+        }
+
+        let clients = self.subscriptions.matches(topic_name);
+
         if clients.is_empty() {
             let message = format!("No clients subscribed to topic: {}", topic_name);
             self.log_file.error(message.as_str());
-            return Ok(());
+            return Ok(false);
         }
 
         self.log_file
-            .log_successful_publish(&client_id, publish_packet);
+            .log_successful_publish(client_id, publish_packet);
 
+        let mut overflowed = vec![];
         for client_id in clients {
-            if let Some(client) = self.clients.read()?.get(&client_id) {
+            let mut offline_packet = None;
+            if let Some(client) = self.clients.write()?.get_mut(&client_id) {
+                let granted_qos = client.granted_qos(topic_name).unwrap_or(QoS::AtMost);
+                let forwarded_packet = forwarded_publish(
+                    publish_packet,
+                    publish_packet.qos().clone().min(granted_qos),
+                );
+
                 if self.active_connections.contains(&client_id) {
-                    client.send_message(publish_packet.clone(), &self.log_file, &self.key);
+                    let qos = forwarded_packet.qos().clone();
+                    self.deliver(&forwarded_packet, qos, client);
                 } else {
-                    self.offline_messages
-                        .entry(client_id.clone())
-                        .or_default()
-                        .push_back(publish_packet.clone());
+                    offline_packet = Some(forwarded_packet);
+                }
+            }
+
+            if let Some(forwarded_packet) = offline_packet {
+                if !self.queue_offline_message(&client_id, forwarded_packet) {
+                    overflowed.push(client_id);
                 }
             }
         }
 
+        for client_id in overflowed {
+            self.remove_session(&client_id)?;
+        }
+
+        Ok(true)
+    }
+
+    /// Queue a message for a client that is not connected, logging the messages dropped by the limits of the queue.
+    /// Returns false if the queue is full and its policy discards the session of the client
+    fn queue_offline_message(&mut self, client_id: &[u8], publish_packet: Publish) -> bool {
+        let queue = self.offline_messages.entry(client_id.to_vec()).or_default();
+        let outcome = queue.push(publish_packet, &self.queue_policy);
+        let client = String::from_utf8_lossy(client_id);
+
+        match outcome {
+            QueueOutcome::Queued(0) => true,
+            QueueOutcome::Queued(dropped) => {
+                let message = format!(
+                    "Dropped {} queued messages of client {}, {} messages ({} bytes) queued",
+                    dropped,
+                    client,
+                    queue.len(),
+                    queue.bytes()
+                );
+                self.log_file.info(message.as_str());
+                true
+            }
+            QueueOutcome::Dropped => {
+                let message = format!(
+                    "Offline queue of client {} is full with {} messages ({} bytes), dropping the new message",
+                    client,
+                    queue.len(),
+                    queue.bytes()
+                );
+                self.log_file.info(message.as_str());
+                true
+            }
+            QueueOutcome::Overflow => {
+                let message = format!(
+                    "Offline queue of client {} is full with {} messages ({} bytes), discarding its session",
+                    client,
+                    queue.len(),
+                    queue.bytes()
+                );
+                self.log_file.info(message.as_str());
+                false
+            }
+        }
+    }
+
+    /// Store a publish as the retained message of its topic, replacing the previous one.
+    /// A publish with an empty payload removes the retained message of the topic
+    fn retain_message(&mut self, publish_packet: &Publish) {
+        let topic_name = publish_packet.topic();
+
+        if publish_packet.message().is_empty() {
+            self.retained_topics
+                .remove(&TopicFilter::from(topic_name), topic_name);
+            if self.retained_messages.remove(topic_name).is_some() {
+                let message = format!("Removed retained message of topic: {}", topic_name);
+                self.log_file.info(message.as_str());
+            }
+            return;
+        }
+
+        self.retained_topics
+            .insert(&TopicFilter::from(topic_name), topic_name.clone());
+        self.retained_messages.insert(
+            topic_name.clone(),
+            StoredMessage::new(publish_packet.clone()),
+        );
+    }
+
+    /// Acknowledge a publish received from a client according to its QoS. A QoS 1 publish is answered
+    /// with a PUBACK and a QoS 2 publish with a PUBREC, carrying the reason code in MQTT 5.0.
+    /// Returns false if the publish is a QoS 2 message that was already received and not yet released,
+    /// so it must not be processed again. A refused QoS 2 message is not awaited to be released
+    fn acknowledge_publish(
+        &self,
+        publish_packet: &Publish,
+        client_id: &[u8],
+        reason_code: ReasonCode,
+    ) -> ServerResult<bool> {
         let mut clients = self.clients.write()?;
 
-        // If QoS is not AtMostOnce, send a Puback packet to the client that published the message
-        if &QoS::AtMost != publish_packet.qos() {
+        let client = match clients.get_mut(client_id) {
+            Some(client) => client,
+            None => {
+                self.log_file.log_client_does_not_exist(client_id);
+                return Ok(true);
+            }
+        };
+
+        match publish_packet.qos() {
+            QoS::AtMost => Ok(true),
+            QoS::AtLeast => {
+                self.puback(publish_packet.package_identifier(), reason_code, client);
+                Ok(true)
+            }
+            QoS::Exactly => {
+                let packet_identifier = match publish_packet.package_identifier() {
+                    Some(packet_identifier) => packet_identifier,
+                    None => {
+                        self.log_file
+                            .error("Received a QoS 2 publish without packet identifier");
+                        return Ok(false);
+                    }
+                };
+
+                let is_new =
+                    reason_code.is_error() || client.awaiting_pubrel.insert(packet_identifier);
+                self.pubrec(packet_identifier, reason_code, client);
+
+                Ok(is_new)
+            }
+        }
+    }
+
+    /// Send a publish to a connected client with the QoS of the delivery. Messages with QoS greater than 0
+    /// are sent with a packet identifier of the server and kept as inflight until the client acknowledges them.
+    /// While the inflight window of the client is full they wait for room, after the ones already waiting
+    fn deliver(&self, publish_packet: &Publish, qos: QoS, client: &mut Client) {
+        if qos != QoS::AtMost
+            && (client.inflight_len() >= self.max_inflight_messages || !client.pending.is_empty())
+        {
+            self.queue_pending_message(outgoing_publish(publish_packet, qos, None), client);
+            return;
+        }
+
+        let packet_identifier = match qos {
+            QoS::AtMost => None,
+            _ => Some(client.next_packet_identifier()),
+        };
+        let outgoing_packet = outgoing_publish(publish_packet, qos, packet_identifier);
+
+        if packet_identifier.is_some() {
+            client
+                .inflight
+                .push_back(InflightMessage::new(outgoing_packet.clone()));
+        }
+
+        client.send_message(outgoing_packet, &self.log_file);
+        self.stats.add_sent_message();
+    }
+
+    /// Queue a publish for a connected client whose inflight window is full, following the policy of the
+    /// offline queues. If the queue is full and its policy disconnects the client, its connection is closed
+    fn queue_pending_message(&self, publish_packet: Publish, client: &mut Client) {
+        let outcome = client.pending.push(publish_packet, &self.queue_policy);
+        let queue = &client.pending;
+        let client_id = String::from_utf8_lossy(&client.id);
+
+        match outcome {
+            QueueOutcome::Queued(0) => {}
+            QueueOutcome::Queued(dropped) => {
+                let message = format!(
+                    "Dropped {} pending messages of client {}, {} messages ({} bytes) pending",
+                    dropped,
+                    client_id,
+                    queue.len(),
+                    queue.bytes()
+                );
+                self.log_file.info(message.as_str());
+            }
+            QueueOutcome::Dropped => {
+                let message = format!(
+                    "Pending queue of client {} is full with {} messages ({} bytes), dropping the new message",
+                    client_id,
+                    queue.len(),
+                    queue.bytes()
+                );
+                self.log_file.info(message.as_str());
+            }
+            QueueOutcome::Overflow => {
+                let message = format!(
+                    "Pending queue of client {} is full with {} messages ({} bytes), closing its connection",
+                    client_id,
+                    queue.len(),
+                    queue.bytes()
+                );
+                self.log_file.info(message.as_str());
+                if let Some(connection) = &client.connection {
+                    connection.close();
+                }
+            }
+        }
+    }
+
+    /// Send the publishes waiting for room in the inflight window of a client while there is room.
+    /// The ones that expired while waiting are discarded
+    fn deliver_pending(&self, client: &mut Client) {
+        while client.inflight_len() < self.max_inflight_messages {
+            let pending = match client.pending.pop_front() {
+                Some(pending) => pending,
+                None => return,
+            };
+            if let Some(publish_packet) = pending.publish() {
+                let qos = publish_packet.qos().clone();
+                self.deliver(&publish_packet, qos, client);
+            }
+        }
+    }
+
+    /// Publish the statistics of the broker as retained messages on the `$SYS/broker` topics.
+    /// The traffic is the average per second since the last publication
+    fn publish_stats(&mut self) -> ServerResult<()> {
+        let now = Instant::now();
+        let traffic = self.stats.traffic();
+        let (last_published_at, last_traffic) = self.last_stats;
+        let rates = traffic.per_second(&last_traffic, now.duration_since(last_published_at));
+        self.last_stats = (now, traffic);
+
+        let (total_clients, subscriptions) = {
+            let clients = self.clients.read()?;
+            let subscriptions: usize = clients
+                .values()
+                .map(|client| client.subscriptions.len())
+                .sum();
+            (clients.len(), subscriptions)
+        };
+        let retained_messages = self
+            .retained_messages
+            .keys()
+            .filter(|topic_name| !topic_name.server_reserved())
+            .count();
+        let queued_messages: usize = self.offline_messages.values().map(OfflineQueue::len).sum();
+        let queued_bytes: usize = self
+            .offline_messages
+            .values()
+            .map(OfflineQueue::bytes)
+            .sum();
+
+        let stats = [
+            (
+                "clients/connected",
+                self.active_connections.len().to_string(),
+            ),
+            ("clients/total", total_clients.to_string()),
+            ("subscriptions/count", subscriptions.to_string()),
+            ("retained/count", retained_messages.to_string()),
+            ("messages/received", rates.messages_received.to_string()),
+            ("messages/sent", rates.messages_sent.to_string()),
+            ("bytes/received", rates.bytes_received.to_string()),
+            ("bytes/sent", rates.bytes_sent.to_string()),
+            ("queue/messages", queued_messages.to_string()),
+            ("queue/bytes", queued_bytes.to_string()),
+            ("uptime", self.started_at.elapsed().as_secs().to_string()),
+            ("version", VERSION.to_string()),
+        ];
+        for (topic, value) in stats {
+            self.publish_stat(topic, value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Retain a statistic on its `$SYS/broker` topic and send it to the connected clients subscribed to it.
+    /// The clients that are not connected get the retained value when they subscribe again
+    fn publish_stat(&mut self, topic: &str, value: String) -> ServerResult<()> {
+        let mut levels = vec![SYSTEM_TOPICS.to_vec(), BROKER_LEVEL.to_vec()];
+        levels.extend(topic.split('/').map(|level| level.as_bytes().to_vec()));
+        let publish_packet = Publish::new(
+            false,
+            QoS::AtMost,
+            true,
+            TopicName::new(levels, true),
+            None,
+            value.into_bytes(),
+        );
+        self.retain_message(&publish_packet);
+
+        let forwarded_packet = forwarded_publish(&publish_packet, QoS::AtMost);
+        let mut clients = self.clients.write()?;
+        for client_id in self.subscriptions.matches(publish_packet.topic()) {
+            if !self.active_connections.contains(&client_id) {
+                continue;
+            }
             if let Some(client) = clients.get_mut(&client_id) {
-                self.puback(publish_packet.package_identifier(), client);
+                self.deliver(&forwarded_packet, QoS::AtMost, client);
             }
         }
 
-        let clients_retained_messages = self.offline_messages.get(&client_id);
+        Ok(())
+    }
+
+    /// Send again with the DUP flag the inflight publishes of a client sent before the specified moment
+    fn redeliver_inflight(&self, client: &mut Client, sent_before: Instant) {
+        let mut redelivered = vec![];
+        for inflight in client.inflight.iter_mut() {
+            if inflight.sent_at > sent_before {
+                continue;
+            }
+            inflight.publish = duplicated_publish(&inflight.publish);
+            inflight.sent_at = Instant::now();
+            redelivered.push(inflight.publish.clone());
+        }
+
+        for publish_packet in redelivered {
+            let message = format!(
+                "Sending again message {:?} to client {}",
+                publish_packet.package_identifier(),
+                String::from_utf8_lossy(&client.id)
+            );
+            self.log_file.info(message.as_str());
+            client.send_message(publish_packet, &self.log_file);
+        }
+    }
+
+    /// Send again the publishes that the connected clients did not acknowledge within the retry interval
+    fn retry_inflight(&self) -> ServerResult<()> {
+        if self.retry_interval.is_zero() {
+            return Ok(());
+        }
+        let sent_before = match Instant::now().checked_sub(self.retry_interval) {
+            Some(sent_before) => sent_before,
+            None => return Ok(()),
+        };
+
+        let mut clients = self.clients.write()?;
+        for client_id in &self.active_connections {
+            if let Some(client) = clients.get_mut(client_id) {
+                self.redeliver_inflight(client, sent_before);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handle a PUBACK sent by a client for a QoS 1 message delivered by the server, which makes room
+    /// in its inflight window for the messages waiting
+    pub fn publish_ack(
+        &self,
+        packet_identifier: Option<u16>,
+        client_id: Vec<u8>,
+    ) -> ServerResult<()> {
+        let message = format!(
+            "Client {} acknowledged message {:?}",
+            String::from_utf8_lossy(&client_id),
+            packet_identifier
+        );
+        self.log_file.info(message.as_str());
+
+        let mut clients = self.clients.write()?;
         let client = match clients.get_mut(&client_id) {
             Some(client) => client,
             None => {
@@ -315,20 +917,69 @@ impl TaskHandler {
             }
         };
 
-        if let Some(clients_retained_messages) = clients_retained_messages {
-            self.handle_retained_messages(client, clients_retained_messages);
-            match self.offline_messages.get_mut(&client_id) {
-                Some(queue) => queue.clear(),
-                None => {
-                    self.log_file.error("Error clearing offline messages");
-                }
+        if let Some(packet_identifier) = packet_identifier {
+            client.acknowledge(packet_identifier, QoS::AtLeast);
+        }
+        self.deliver_pending(client);
+
+        Ok(())
+    }
+
+    /// Handle a PUBREC sent by a client for a QoS 2 message delivered by the server, answering with a PUBREL
+    pub fn publish_received(&self, packet_identifier: u16, client_id: Vec<u8>) -> ServerResult<()> {
+        let mut clients = self.clients.write()?;
+
+        let client = match clients.get_mut(&client_id) {
+            Some(client) => client,
+            None => {
+                self.log_file.log_client_does_not_exist(&client_id);
+                return Ok(());
+            }
+        };
+
+        client.acknowledge(packet_identifier, QoS::Exactly);
+        client.awaiting_pubcomp.insert(packet_identifier);
+        self.pubrel(packet_identifier, client);
+
+        Ok(())
+    }
+
+    /// Handle a PUBREL sent by a client for a QoS 2 message it published, answering with a PUBCOMP.
+    /// From now on, a publish with the same packet identifier is considered a new message
+    pub fn publish_release(&self, packet_identifier: u16, client_id: Vec<u8>) -> ServerResult<()> {
+        let mut clients = self.clients.write()?;
+
+        let client = match clients.get_mut(&client_id) {
+            Some(client) => client,
+            None => {
+                self.log_file.log_client_does_not_exist(&client_id);
+                return Ok(());
             }
+        };
+
+        client.awaiting_pubrel.remove(&packet_identifier);
+        self.pubcomp(packet_identifier, client);
+
+        Ok(())
+    }
+
+    /// Handle a PUBCOMP sent by a client, which ends the delivery of a QoS 2 message and makes room
+    /// in its inflight window for the messages waiting
+    pub fn publish_complete(&self, packet_identifier: u16, client_id: Vec<u8>) -> ServerResult<()> {
+        let mut clients = self.clients.write()?;
+
+        match clients.get_mut(&client_id) {
+            Some(client) => {
+                client.awaiting_pubcomp.remove(&packet_identifier);
+                self.deliver_pending(client);
+            }
+            None => self.log_file.log_client_does_not_exist(&client_id),
         }
 
         Ok(())
     }
 
-    /// Handle a server reserved topic (e.g. $client-register)
+    /// Handle a server reserved topic (e.g. $client-register or $key-rotation)
     pub fn handle_server_reserved_topic(&self, publish_packet: &Publish, client_id: Vec<u8>) {
         let topic_name = publish_packet.topic();
         let levels = topic_name.levels();
@@ -371,48 +1022,181 @@ impl TaskHandler {
                     self.log_file.error(e.to_string().as_str());
                 }
             }
+        } else if levels.len() == 1 && levels[0] == KEY_ROTATION_TOPIC.as_bytes() {
+            self.rotate_key(publish_packet);
         } else {
             self.log_file
                 .error("Invalid topic for server reserved topic");
         }
     }
 
-    /// Handle retained messages for a client
-    pub fn handle_retained_messages(
-        &self,
-        client: &mut Client,
-        retained_messages: &VecDeque<Publish>,
-    ) {
-        for message in retained_messages {
-            client.send_message(message.clone(), &self.log_file, &self.key);
-        }
-    }
-
-    /// Handle a new client connection
-    pub fn handle_new_client_connection(&mut self, client: Client) -> ServerResult<()> {
-        let connack_packet = Connack::new(true, ConnectReturnCode::ConnectionAccepted);
-        let connack_packet_vec = connack_packet.to_bytes(&self.key);
-        let connack_packet_bytes = connack_packet_vec.as_slice();
+    /// Makes the key of the message the current key of the keyring, adding it to the keyring file,
+    /// and pushes it to the clients connected with encryption that use the key of the server, not to the ones
+    /// that have a key of their own. The previous keys are still accepted,
+    /// so the clients that were not connected can use them until their configuration is updated
+    fn rotate_key(&self, publish_packet: &Publish) {
+        let (key_id, key) = match parse_rotation_message(publish_packet.message()) {
+            Some(rotation) => rotation,
+            None => {
+                self.log_file.error("Invalid message for key rotation");
+                return;
+            }
+        };
+
+        let mut keyring = match self.keyring.write() {
+            Ok(keyring) => keyring,
+            Err(_) => {
+                self.log_file.error("Error locking the keyring");
+                return;
+            }
+        };
+        if keyring.get(key_id).is_some() {
+            self.log_file
+                .error(&format!("Key {} is already in the keyring", key_id));
+            return;
+        }
+        if let Some(keyring_file) = &self.keyring_file {
+            if let Err(err) = append_to_file(keyring_file, key_id, &key) {
+                self.log_file
+                    .error(&format!("Error saving key {}: {:?}", key_id, err));
+                return;
+            }
+        }
+        keyring.rotate(key_id, &key);
+        drop(keyring);
+        self.log_file
+            .info(&format!("Key rotated to key {}", key_id));
+
+        let (clients, client_manager) = match (self.clients.read(), self.client_manager.read()) {
+            (Ok(clients), Ok(client_manager)) => (clients, client_manager),
+            _ => return,
+        };
+        let push = Packet::Publish(Publish::new(
+            false,
+            QoS::AtMost,
+            false,
+            publish_packet.topic().clone(),
+            None,
+            rotation_message(key_id, &key),
+        ));
+        for client in clients.values() {
+            let connection = match &client.connection {
+                Some(connection) if !connection.key().is_empty() => connection,
+                _ => continue,
+            };
+            if client_manager.has_own_key(&client.id) {
+                continue;
+            }
+
+            match connection.send(&push) {
+                Ok(_) => self
+                    .log_file
+                    .log_info_sent_packet("Key rotation", &client.id),
+                Err(_) => self
+                    .log_file
+                    .log_error_sending_packet("Key rotation", &client.id),
+            }
+        }
+    }
+
+    /// Send the queued messages to a client, skipping the expired ones
+    fn handle_retained_messages(&self, client: &mut Client, retained_messages: &OfflineQueue) {
+        let message = format!(
+            "Sending {} queued messages ({} bytes) to client {}",
+            retained_messages.len(),
+            retained_messages.bytes(),
+            String::from_utf8_lossy(&client.id)
+        );
+        self.log_file.info(message.as_str());
 
+        for message in retained_messages.publishes() {
+            let qos = message.qos().clone();
+            self.deliver(&message, qos, client);
+        }
+    }
+
+    /// Handle a new client connection.
+    /// A client connecting with a clean session starts without subscriptions nor queued messages,
+    /// otherwise the stored session is resumed: the messages it did not acknowledge are sent again with the DUP flag,
+    /// followed by the ones that waited for room and the ones queued while it was offline
+    pub fn handle_new_client_connection(&mut self, client: Client) -> ServerResult<()> {
+        self.expire_sessions()?;
         let client_id = client.id();
-        let mut clients = self.clients.write()?;
 
-        if clients.contains_key(&client_id) {
-            let message = format!("Client {} reconnected", String::from_utf8_lossy(&client_id));
+        if !self.can_publish_will(&client) {
+            let message = format!(
+                "Client {} is not allowed to publish its will, refusing the connection",
+                String::from_utf8_lossy(&client_id)
+            );
             self.log_file.info(message.as_str());
-            let old_client = match clients.get_mut(&client_id) {
-                Some(client) => client,
-                None => {
-                    self.log_file.error("Error retreiving the old client for reconnection. Connection will not be accepted.");
-                    return Ok(());
+            if let Some(connection) = &client.connection {
+                let connack = Connack::new(false, ConnectReturnCode::NotAuthorized);
+                if connection.send(&Packet::Connack(connack)).is_err() {
+                    self.log_file
+                        .log_error_sending_packet("Connack", &client_id);
                 }
-            };
-            old_client.stream = client.stream;
-        } else {
-            clients.entry(client_id.clone()).or_insert(client);
+                connection.close();
+            }
+            return Ok(());
         }
 
-        let client = match clients.get(&client_id) {
+        let mut clients = self.clients.write()?;
+
+        if self.active_connections.contains(&client_id) {
+            if let Some(connection) = clients
+                .get(&client_id)
+                .and_then(|old_client| old_client.connection.as_ref())
+            {
+                let message = format!(
+                    "Client {} connected again, closing its previous connection",
+                    String::from_utf8_lossy(&client_id)
+                );
+                self.log_file.info(message.as_str());
+                connection.take_over();
+            }
+        }
+
+        let session_present = match clients.get_mut(&client_id) {
+            Some(old_client) if !client.clean_session => {
+                let message = format!(
+                    "Client {} reconnected, resuming its session",
+                    String::from_utf8_lossy(&client_id)
+                );
+                self.log_file.info(message.as_str());
+                old_client.connection = client.connection;
+                old_client.username = client.username;
+                old_client.will = client.will;
+                old_client.clean_session = false;
+                old_client.session_expiry_interval = client.session_expiry_interval;
+                old_client.disconnected_at = None;
+                true
+            }
+            Some(_) => {
+                let message = format!(
+                    "Client {} reconnected with a clean session, discarding its previous session",
+                    String::from_utf8_lossy(&client_id)
+                );
+                self.log_file.info(message.as_str());
+                self.offline_messages.remove(&client_id);
+                if let Some(old_client) = clients.insert(client_id.clone(), client) {
+                    remove_subscriptions(&mut self.subscriptions, &old_client);
+                }
+                false
+            }
+            None => {
+                clients.insert(client_id.clone(), client);
+                false
+            }
+        };
+
+        // Only sent to the clients of MQTT 5.0, announcing the features the server does not support
+        let connack_properties = Properties::new()
+            .with(Property::SubscriptionIdentifierAvailable(0))
+            .with(Property::SharedSubscriptionAvailable(0));
+        let connack_packet = Connack::new(session_present, ConnectReturnCode::ConnectionAccepted)
+            .with_properties(connack_properties);
+
+        let client = match clients.get_mut(&client_id) {
             Some(client) => client,
             None => {
                 self.log_file.log_client_does_not_exist(&client_id);
@@ -420,8 +1204,8 @@ impl TaskHandler {
             }
         };
 
-        let mut stream = match &client.stream {
-            Some(stream) => stream,
+        let connection = match &client.connection {
+            Some(connection) => connection,
             None => {
                 self.log_file
                     .log_error_getting_stream(&client_id, "Connack");
@@ -429,7 +1213,7 @@ impl TaskHandler {
             }
         };
 
-        match stream.write_all(connack_packet_bytes) {
+        match connection.send_connack(connack_packet) {
             Ok(_) => {
                 self.active_connections.insert(client_id.clone());
                 let message = format!(
@@ -439,24 +1223,53 @@ impl TaskHandler {
                 self.log_file.info(message.as_str());
                 self.log_file.log_info_sent_packet("Connack", &client_id);
             }
-            Err(_) => self
-                .log_file
-                .log_error_sending_packet("Connack", &client_id),
+            Err(_) => {
+                self.log_file
+                    .log_error_sending_packet("Connack", &client_id);
+                return Ok(());
+            }
         };
+
+        if session_present {
+            self.redeliver_inflight(client, Instant::now());
+            for packet_identifier in client.awaiting_pubcomp.clone() {
+                self.pubrel(packet_identifier, client);
+            }
+            self.deliver_pending(client);
+        }
+
+        if let Some(offline_messages) = self.offline_messages.remove(&client_id) {
+            self.handle_retained_messages(client, &offline_messages);
+        }
+
         Ok(())
     }
 
+    /// Returns whether the ACL allows a client to publish its will, if it has one.
+    /// No client may leave a will on the topics that start with `$`, which are reserved to the server
+    fn can_publish_will(&self, client: &Client) -> bool {
+        match &client.will {
+            Some(will) => {
+                !will.topic().server_reserved()
+                    && self
+                        .acl
+                        .can_publish(&client.id, &client.username, will.topic())
+            }
+            None => true,
+        }
+    }
+
     /// Send a suback packet to a client
-    pub fn suback(&self, package_identifier: u16, client: &mut Client) {
-        let suback_packet = Suback::new(
-            package_identifier,
-            vec![SubackReturnCode::SuccessMaximumQoS0],
-        );
-        let suback_packet_vec = suback_packet.to_bytes(&self.key);
-        let suback_packet_bytes = suback_packet_vec.as_slice();
+    pub fn suback(
+        &self,
+        package_identifier: u16,
+        reason_codes: Vec<ReasonCode>,
+        client: &mut Client,
+    ) {
+        let suback_packet = Suback::with_reason_codes(package_identifier, reason_codes);
 
-        let mut stream = match &client.stream {
-            Some(stream) => stream,
+        let connection = match &client.connection {
+            Some(connection) => connection,
             None => {
                 self.log_file
                     .log_error_getting_stream(&client.id, "Connack");
@@ -464,7 +1277,7 @@ impl TaskHandler {
             }
         };
 
-        match stream.write_all(suback_packet_bytes) {
+        match connection.send(&Packet::Suback(suback_packet)) {
             Ok(_) => self.log_file.log_info_sent_packet("Suback", &client.id()),
             Err(_) => self
                 .log_file
@@ -473,13 +1286,16 @@ impl TaskHandler {
     }
 
     /// Send a puback packet to a client
-    pub fn puback(&self, package_identifier: Option<u16>, client: &mut Client) {
-        let puback_packet = Puback::new(package_identifier);
-        let puback_packet_vec = puback_packet.to_bytes(&self.key);
-        let puback_packet_bytes = puback_packet_vec.as_slice();
+    pub fn puback(
+        &self,
+        package_identifier: Option<u16>,
+        reason_code: ReasonCode,
+        client: &mut Client,
+    ) {
+        let puback_packet = Puback::new(package_identifier).with_reason_code(reason_code);
 
-        let mut stream = match &client.stream {
-            Some(stream) => stream,
+        let connection = match &client.connection {
+            Some(connection) => connection,
             None => {
                 self.log_file
                     .log_error_getting_stream(&client.id, "Connack");
@@ -487,7 +1303,7 @@ impl TaskHandler {
             }
         };
 
-        match stream.write_all(puback_packet_bytes) {
+        match connection.send(&Packet::Puback(puback_packet)) {
             Ok(_) => self.log_file.log_info_sent_packet("Puback", &client.id()),
             Err(_) => self
                 .log_file
@@ -495,14 +1311,61 @@ impl TaskHandler {
         };
     }
 
-    /// Send an unsuback packet to a client
-    pub fn unsuback(&self, package_identifier: u16, client: &mut Client) {
-        let unsuback_packet = Unsuback::new(package_identifier);
-        let unsuback_packet_vec = unsuback_packet.to_bytes(&self.key);
-        let unsuback_packet_bytes = unsuback_packet_vec.as_slice();
+    /// Send a pubrec packet to a client
+    pub fn pubrec(&self, packet_identifier: u16, reason_code: ReasonCode, client: &Client) {
+        self.send_packet(
+            Packet::Pubrec(Pubrec::new(packet_identifier).with_reason_code(reason_code)),
+            "Pubrec",
+            client,
+        );
+    }
+
+    /// Send a pubrel packet to a client
+    pub fn pubrel(&self, packet_identifier: u16, client: &Client) {
+        self.send_packet(
+            Packet::Pubrel(Pubrel::new(packet_identifier)),
+            "Pubrel",
+            client,
+        );
+    }
+
+    /// Send a pubcomp packet to a client
+    pub fn pubcomp(&self, packet_identifier: u16, client: &Client) {
+        self.send_packet(
+            Packet::Pubcomp(Pubcomp::new(packet_identifier)),
+            "Pubcomp",
+            client,
+        );
+    }
+
+    /// Send a packet to a client
+    fn send_packet(&self, packet: Packet, packet_type: &str, client: &Client) {
+        let connection = match &client.connection {
+            Some(connection) => connection,
+            None => {
+                self.log_file
+                    .log_error_getting_stream(&client.id, packet_type);
+                return;
+            }
+        };
+
+        match connection.send(&packet) {
+            Ok(_) => self.log_file.log_info_sent_packet(packet_type, &client.id),
+            Err(_) => self
+                .log_file
+                .log_error_sending_packet(packet_type, &client.id),
+        };
+    }
+
+    /// Send an unsuback packet to a client, with a reason code for each topic filter
+    pub fn unsuback(&self, package_identifier: u16, topic_filters: usize, client: &mut Client) {
+        let unsuback_packet = Unsuback::with_reason_codes(
+            package_identifier,
+            vec![ReasonCode::Success; topic_filters],
+        );
 
-        let mut stream = match &client.stream {
-            Some(stream) => stream,
+        let connection = match &client.connection {
+            Some(connection) => connection,
             None => {
                 self.log_file
                     .log_error_getting_stream(&client.id, "Connack");
@@ -510,7 +1373,7 @@ impl TaskHandler {
             }
         };
 
-        match stream.write_all(unsuback_packet_bytes) {
+        match connection.send(&Packet::Unsuback(unsuback_packet)) {
             Ok(_) => self.log_file.log_info_sent_packet("Unsuback", &client.id()),
             Err(_) => self
                 .log_file
@@ -530,11 +1393,9 @@ impl TaskHandler {
             }
         };
         let pingresp_packet = Pingresp::new();
-        let pingresp_packet_vec = pingresp_packet.to_bytes(&self.key);
-        let pingresp_packet_bytes = pingresp_packet_vec.as_slice();
 
-        let mut stream = match &client.stream {
-            Some(stream) => stream,
+        let connection = match &client.connection {
+            Some(connection) => connection,
             None => {
                 self.log_file
                     .log_error_getting_stream(&client.id, "Connack");
@@ -542,7 +1403,7 @@ impl TaskHandler {
             }
         };
 
-        match stream.write_all(pingresp_packet_bytes) {
+        match connection.send(&Packet::Pingresp(pingresp_packet)) {
             Ok(_) => {
                 self.log_file
                     .log_info_sent_packet("Ping response", &client_id);
@@ -555,12 +1416,124 @@ impl TaskHandler {
         Ok(())
     }
 
-    /// Handle a client disconnection
+    /// Handle a client disconnection. If the client did not send a Disconnect packet its will is published.
+    /// The session is discarded when its expiry interval is 0, otherwise it is kept until the interval elapses
     pub fn handle_client_disconnected(&mut self, client_id: Vec<u8>) -> ServerResult<()> {
         self.active_connections.remove(&client_id);
-        self.client_manager
-            .write()?
-            .disconnect_client(client_id.clone())?;
+
+        let will = match self.clients.write()?.get_mut(&client_id) {
+            Some(client) => client.will.take(),
+            None => None,
+        };
+
+        if let Some(will) = will {
+            let message = format!(
+                "Publishing will of client {} on topic {}",
+                String::from_utf8_lossy(&client_id),
+                will.topic()
+            );
+            self.log_file.info(message.as_str());
+
+            // The packet identifier is replaced by the one of each subscriber when the message is delivered
+            let packet_identifier = match will.qos() {
+                QoS::AtMost => None,
+                _ => Some(WILL_PACKET_IDENTIFIER),
+            };
+
+            let mut will_properties = will.properties().clone();
+            will_properties.remove(WILL_DELAY_INTERVAL);
+            let will_publish = Publish::new(
+                false,
+                will.qos().clone(),
+                will.retain(),
+                will.topic().clone(),
+                packet_identifier,
+                will.message().content().to_vec(),
+            )
+            .with_properties(will_properties);
+
+            self.publish_to_subscribers(&will_publish, &client_id)?;
+        }
+
+        let session_ended = match self.clients.write()?.get_mut(&client_id) {
+            Some(client) => {
+                client.disconnected_at = Some(Instant::now());
+                client.session_expiry_interval == 0
+            }
+            None => false,
+        };
+
+        if session_ended {
+            self.remove_session(&client_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Disconnect a client whose connection was closed, unless it is not the connection of its session,
+    /// either because a new connection of the client took over the session or because it was refused
+    pub fn handle_connection_closed(
+        &mut self,
+        client_id: Vec<u8>,
+        connection: &Connection,
+    ) -> ServerResult<()> {
+        let taken_over = match self.clients.read()?.get(&client_id) {
+            Some(client) => !client
+                .connection
+                .as_ref()
+                .is_some_and(|current| current.same_connection(connection)),
+            None => false,
+        };
+
+        if taken_over {
+            let message = format!(
+                "Previous connection of client {} closed, its session continues on the new one",
+                String::from_utf8_lossy(&client_id)
+            );
+            self.log_file.info(message.as_str());
+            return Ok(());
+        }
+
+        self.handle_client_disconnected(client_id)
+    }
+
+    /// Discard the sessions of the disconnected clients whose session expiry interval elapsed
+    fn expire_sessions(&mut self) -> ServerResult<()> {
+        let now = Instant::now();
+        let expired: Vec<Vec<u8>> = self
+            .clients
+            .read()?
+            .values()
+            .filter(|client| client.session_expired(now))
+            .map(Client::id)
+            .collect();
+
+        for client_id in expired {
+            let message = format!(
+                "Session of client {} expired",
+                String::from_utf8_lossy(&client_id)
+            );
+            self.log_file.info(message.as_str());
+            self.remove_session(&client_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Discard the session of a client, with its subscriptions and queued messages
+    fn remove_session(&mut self, client_id: &[u8]) -> ServerResult<()> {
+        if let Some(client) = self.clients.write()?.remove(client_id) {
+            remove_subscriptions(&mut self.subscriptions, &client);
+        }
+        self.offline_messages.remove(client_id);
+        Ok(())
+    }
+
+    /// Discard the will of a client that sent a Disconnect packet
+    pub fn discard_will(&mut self, client_id: Vec<u8>) -> ServerResult<()> {
+        if let Some(client) = self.clients.write()?.get_mut(&client_id) {
+            client.will = None;
+        }
         Ok(())
     }
 
@@ -569,27 +1542,47 @@ impl TaskHandler {
         let mut serialized_data = String::new();
 
         // Serialize offline_messages
+        // The messages are stored as MQTT 5.0 packets to keep their properties, followed by the moment they were
+        // stored in seconds since the Unix epoch and the time to live of their topic in seconds, 0 meaning none
         for (client, queue) in &self.offline_messages {
-            for publish in queue {
+            for message in queue.messages() {
+                let stored_at = SystemTime::now()
+                    .checked_sub(message.stored_for())
+                    .and_then(|stored_at| stored_at.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |stored_at| stored_at.as_secs());
+                let time_to_live = message
+                    .time_to_live()
+                    .map_or(0, |time_to_live| time_to_live.as_secs());
                 serialized_data.push_str(&format!(
-                    "{};{};{}\n",
+                    "{};{};{};{};{}\n",
                     OFFLINE_MESSAGES_TAG,
                     bytes_to_hex(client),
-                    bytes_to_hex(&publish.to_bytes(&self.key))
+                    bytes_to_hex(
+                        &message
+                            .publish
+                            .to_bytes_with_version(&self.key, ProtocolVersion::V5)
+                    ),
+                    stored_at,
+                    time_to_live
                 ));
             }
         }
 
-        // Serialize retained_messages
-        for (topic_name, messages) in &self.retained_messages {
-            for message in messages {
-                serialized_data.push_str(&format!(
-                    "{};{};{}\n",
-                    RETAINED_MESSAGES_TAG,
-                    bytes_to_hex(&topic_name.to_bytes()),
-                    bytes_to_hex(&message.to_bytes(&self.key))
-                ));
+        // Serialize retained_messages, except the statistics of the broker that are published again
+        for (topic_name, message) in &self.retained_messages {
+            if topic_name.server_reserved() {
+                continue;
             }
+            let message = match message.publish() {
+                Some(message) => message,
+                None => continue,
+            };
+            serialized_data.push_str(&format!(
+                "{};{};{}\n",
+                RETAINED_MESSAGES_TAG,
+                bytes_to_hex(&topic_name.to_bytes()),
+                bytes_to_hex(&message.to_bytes(&self.key))
+            ));
         }
 
         // Serialize clients
@@ -603,12 +1596,14 @@ impl TaskHandler {
         };
 
         for (id, client) in clients_read.iter() {
-            for sub in &client.subscriptions {
+            for (topic_filter, qos) in &client.subscriptions {
+                let mut subscription = topic_filter.to_bytes();
+                subscription.push(qos.to_byte());
                 serialized_data.push_str(&format!(
                     "{};{};{}\n",
                     CLIENTS_TAG,
                     bytes_to_hex(id),
-                    bytes_to_hex(&sub.to_bytes())
+                    bytes_to_hex(&subscription)
                 ));
             }
         }
@@ -631,7 +1626,7 @@ impl TaskHandler {
 
         for line in serialized_data.lines() {
             let parts: Vec<&str> = line.split(';').collect();
-            if parts.len() != 3 {
+            if parts.len() < 3 {
                 continue;
             }
 
@@ -649,14 +1644,25 @@ impl TaskHandler {
 
             match record_type {
                 OFFLINE_MESSAGES_TAG => {
-                    let publish = match Packet::from_bytes(&mut value_stream, &key) {
-                        Ok(Packet::Publish(publish)) => publish,
-                        _ => continue,
+                    // Backups without the moment the messages were stored keep them as MQTT 3.1.1 packets
+                    let message = match parts[3..] {
+                        [stored_at, time_to_live] => {
+                            restored_message(&mut value_stream, &key, stored_at, time_to_live)
+                        }
+                        _ => match Packet::from_bytes(&mut value_stream, &key) {
+                            Ok(Packet::Publish(publish)) => Some(StoredMessage::new(publish)),
+                            _ => None,
+                        },
+                    };
+                    let message = match message {
+                        Some(message) => message,
+                        None => continue,
                     };
+                    // The limits of the queues apply to the messages queued after the backup is restored
                     offline_messages
                         .entry(entry_key)
-                        .or_insert_with(VecDeque::new)
-                        .push_back(publish);
+                        .or_insert_with(OfflineQueue::new)
+                        .push_stored(message);
                 }
                 RETAINED_MESSAGES_TAG => {
                     let mut entry_stream = std::io::Cursor::new(entry_key);
@@ -673,10 +1679,7 @@ impl TaskHandler {
                         Ok(Packet::Publish(publish)) => publish,
                         _ => continue,
                     };
-                    retained_messages
-                        .entry(topic_name)
-                        .or_insert_with(VecDeque::new)
-                        .push_back(message);
+                    retained_messages.insert(topic_name, StoredMessage::new(message));
                 }
                 CLIENTS_TAG => {
                     let subscription = TopicFilter::from_bytes(&mut value_stream);
@@ -687,29 +1690,59 @@ impl TaskHandler {
                             continue;
                         }
                     };
+                    // Backups without the granted QoS keep the QoS 0 the subscriptions had
+                    let mut qos = [0];
+                    let qos = match value_stream.read(&mut qos) {
+                        Ok(1) => QoS::from_byte(qos[0]).unwrap_or(QoS::AtMost),
+                        _ => QoS::AtMost,
+                    };
                     clients
                         .entry(entry_key.clone())
                         .or_insert_with(|| Client::new_from_backup(entry_key.clone(), Vec::new()))
                         .subscriptions
-                        .push(subscription);
+                        .push((subscription, qos));
                 }
                 _ => {}
             }
         }
 
+        let mut subscriptions = TopicTree::new();
+        for (client_id, client) in &clients {
+            for (topic_filter, _) in &client.subscriptions {
+                subscriptions.insert(topic_filter, client_id.clone());
+            }
+        }
+
+        let mut retained_topics = TopicTree::new();
+        for topic_name in retained_messages.keys() {
+            retained_topics.insert(&TopicFilter::from(topic_name), topic_name.clone());
+        }
+
         let clients_lock = RwLock::new(clients);
 
         TaskHandler {
             client_actions_receiver_channel: receiver_channel,
             clients: clients_lock,
             active_connections: HashSet::new(),
+            subscriptions,
             offline_messages,
+            queue_policy: QueuePolicy::unbounded(),
             retained_messages,
+            retained_topics,
             log_file,
             client_manager,
+            acl: Acl::allow_all(),
             key,
+            keyring: Arc::new(RwLock::new(Keyring::new(0, &key))),
+            keyring_file: None,
             backup_file: config.get_backup_file(),
             segs_to_backup: config.get_segs_to_backup(),
+            max_inflight_messages: DEFAULT_MAX_INFLIGHT_MESSAGES,
+            retry_interval: DEFAULT_RETRY_INTERVAL,
+            stats: Arc::new(BrokerStats::new()),
+            stats_interval: Duration::ZERO,
+            started_at: Instant::now(),
+            last_stats: (Instant::now(), Traffic::default()),
         }
     }
 
@@ -736,19 +1769,1382 @@ impl TaskHandler {
     }
 }
 
-/// Convert a slice of bytes to a hexadecimal string
-fn bytes_to_hex(bytes: &[u8]) -> String {
-    bytes
-        .iter()
-        .map(|b| format!("{:02X}", b))
-        .collect::<Vec<_>>()
-        .join("")
+/// Reads a message of an offline queue from a backup, with the moment it was stored in seconds since the Unix epoch
+/// and the time to live of its topic in seconds, 0 meaning none
+fn restored_message(
+    stream: &mut dyn Read,
+    key: &[u8],
+    stored_at: &str,
+    time_to_live: &str,
+) -> Option<StoredMessage> {
+    let publish = match Packet::from_bytes_with_version(stream, key, ProtocolVersion::V5) {
+        Ok(Packet::Publish(publish)) => publish,
+        _ => return None,
+    };
+    let stored_at = UNIX_EPOCH + Duration::from_secs(stored_at.parse().ok()?);
+    let stored_for = SystemTime::now()
+        .duration_since(stored_at)
+        .unwrap_or(Duration::ZERO);
+    let time_to_live = match time_to_live.parse::<u64>().ok()? {
+        0 => None,
+        seconds => Some(Duration::from_secs(seconds)),
+    };
+
+    Some(StoredMessage::restored(publish, stored_for).with_time_to_live(time_to_live))
+}
+
+/// Remove the subscriptions of a client from the subscription tree
+fn remove_subscriptions(subscriptions: &mut TopicTree<Vec<u8>>, client: &Client) {
+    for (topic_filter, _) in &client.subscriptions {
+        subscriptions.remove(topic_filter, &client.id);
+    }
+}
+
+/// Returns a copy of a publish to forward to the clients already subscribed to its topic.
+/// Only the retained messages sent when a client subscribes keep the retain flag, so it is cleared.
+/// The topic alias belongs to the connection of the publisher, so it is not forwarded.
+/// The copy has the QoS of the delivery and gets the packet identifier of the subscriber when it is delivered
+fn forwarded_publish(publish: &Publish, qos: QoS) -> Publish {
+    let mut properties = publish.properties().clone();
+    properties.remove(TOPIC_ALIAS);
+
+    Publish::new(
+        false,
+        qos,
+        false,
+        publish.topic().clone(),
+        None,
+        publish.message().clone(),
+    )
+    .with_properties(properties)
+}
+
+/// Returns the copy of a publish sent to a client, with the QoS of the delivery and the packet identifier of the client
+fn outgoing_publish(publish: &Publish, qos: QoS, packet_identifier: Option<u16>) -> Publish {
+    Publish::new(
+        false,
+        qos,
+        publish.retain(),
+        publish.topic().clone(),
+        packet_identifier,
+        publish.message().clone(),
+    )
+    .with_properties(publish.properties().clone())
+}
+
+/// Returns a copy of a publish sent before with the DUP flag set, to send it again
+fn duplicated_publish(publish: &Publish) -> Publish {
+    Publish::new(
+        true,
+        publish.qos().clone(),
+        publish.retain(),
+        publish.topic().clone(),
+        publish.package_identifier(),
+        publish.message().clone(),
+    )
+    .with_properties(publish.properties().clone())
 }
 
-/// Convert a hexadecimal string to a vector of bytes
-fn hex_to_bytes(hex: &str) -> Result<Vec<u8>, String> {
-    (0..hex.len())
-        .step_by(2)
-        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|e| e.to_string()))
-        .collect()
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::Cursor,
+        net::{TcpListener, TcpStream},
+        path::Path,
+    };
+
+    use mqtt::{
+        codec::packet_decoder::PacketDecoder,
+        model::{
+            components::{encoded_string::EncodedString, will::Will},
+            return_codes::suback_return_code::SubackReturnCode,
+        },
+        Direction, NO_ENCRYPTION,
+    };
+
+    use crate::{credentials::testing::TempFile, offline_queue::OverflowPolicy};
+
+    use super::*;
+
+    const KEY: [u8; 32] = [0; 32];
+
+    /// Returns the decoder of a test client, which reads the packets the server sends to it
+    fn client_decoder() -> PacketDecoder {
+        let mut decoder = PacketDecoder::new();
+        decoder.set_direction(Direction::ToClient);
+        decoder
+    }
+
+    /// Represents the side of a test client that reads what the server sends to it
+    struct TestConnection {
+        stream: TcpStream,
+        key: &'static [u8],
+        decoder: PacketDecoder,
+    }
+
+    impl TestConnection {
+        /// Returns the packets the server sent since the last call
+        fn received(&mut self) -> Vec<Packet> {
+            while let Ok(read) = self.decoder.read_from(&mut self.stream) {
+                if read == 0 {
+                    break;
+                }
+            }
+
+            let mut packets = vec![];
+            while let Some(packet) = self.decoder.decode(self.key).unwrap() {
+                packets.push(packet);
+            }
+            packets
+        }
+
+        /// Returns the Connack the server sent first, leaving the packets that follow it to the next calls
+        fn connack(&mut self) -> Connack {
+            while let Ok(read) = self.decoder.read_from(&mut self.stream) {
+                if read == 0 {
+                    break;
+                }
+            }
+
+            match self.decoder.decode(self.key).unwrap() {
+                Some(Packet::Connack(connack)) => connack,
+                packet => panic!("Expected a Connack, received {:?}", packet),
+            }
+        }
+
+        /// Returns the publishes the server sent since the last call
+        fn received_publishes(&mut self) -> Vec<Publish> {
+            self.received()
+                .into_iter()
+                .filter_map(|packet| match packet {
+                    Packet::Publish(publish) => Some(publish),
+                    _ => None,
+                })
+                .collect()
+        }
+    }
+
+    /// Creates a task handler whose log and login files are removed when the returned files are dropped
+    fn setup_task_handler() -> (TaskHandler, [TempFile; 2]) {
+        let (_, receiver) = mpsc::channel();
+        let files = [
+            TempFile::new(&format!("test_log_file_{:?}.txt", thread::current().id())),
+            TempFile::new(&format!("test_login_file_{:?}.txt", thread::current().id())),
+        ];
+        let log_file = Arc::new(Logger::new(files[0].path()));
+        let client_manager = ClientManager::new(files[1].path());
+
+        let task_handler = TaskHandler::default(
+            receiver,
+            log_file,
+            Arc::new(RwLock::new(client_manager)),
+            KEY,
+            0,
+            None,
+        );
+        (task_handler, files)
+    }
+
+    fn connect_client(
+        task_handler: &mut TaskHandler,
+        client_id: &str,
+        clean_session: bool,
+        key: &'static [u8],
+    ) -> TestConnection {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let (server_stream, _) = listener.accept().unwrap();
+
+        let client = Client::new(
+            client_id.as_bytes().to_vec(),
+            client_id.as_bytes().to_vec(),
+            Some(Connection::new(server_stream, usize::MAX, keyring(key)).unwrap()),
+            clean_session,
+            0,
+            None,
+        );
+        task_handler.handle_new_client_connection(client).unwrap();
+
+        let mut connection = TestConnection {
+            stream,
+            key,
+            decoder: client_decoder(),
+        };
+        connection.connack();
+        connection
+    }
+
+    /// Connects a client of MQTT 5.0 with the session expiry interval
+    fn connect_v5(
+        task_handler: &mut TaskHandler,
+        client_id: &str,
+        session_expiry_interval: u32,
+    ) -> TestConnection {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let (server_stream, _) = listener.accept().unwrap();
+
+        let connection = Connection::new(server_stream, usize::MAX, keyring(&KEY)).unwrap();
+        connection
+            .set_protocol_version(ProtocolVersion::V5)
+            .unwrap();
+        let client = Client::new(
+            client_id.as_bytes().to_vec(),
+            client_id.as_bytes().to_vec(),
+            Some(connection),
+            false,
+            0,
+            None,
+        )
+        .with_session_expiry_interval(session_expiry_interval);
+        task_handler.handle_new_client_connection(client).unwrap();
+
+        let mut decoder = client_decoder();
+        decoder.set_protocol_version(ProtocolVersion::V5);
+        let mut connection = TestConnection {
+            stream,
+            key: &KEY,
+            decoder,
+        };
+        let connack = connection.connack();
+        assert_eq!(
+            connack.properties().get(0x2A),
+            Some(&Property::SharedSubscriptionAvailable(0))
+        );
+        connection
+    }
+
+    /// Returns the keyring of a connection encrypted with the key, empty if it is not encrypted
+    fn keyring(key: &[u8]) -> Keyring {
+        match key.is_empty() {
+            true => Keyring::default(),
+            false => Keyring::new(0, key),
+        }
+    }
+
+    fn connect(task_handler: &mut TaskHandler, client_id: &str) -> TestConnection {
+        connect_client(task_handler, client_id, true, &KEY)
+    }
+
+    fn topic_filter(topic_filter: &str) -> TopicFilter {
+        let bytes = EncodedString::new(topic_filter.as_bytes().to_vec()).to_bytes();
+        TopicFilter::from_bytes(&mut Cursor::new(bytes)).unwrap()
+    }
+
+    fn topic_name(topic_name: &str) -> TopicName {
+        let bytes = EncodedString::new(topic_name.as_bytes().to_vec()).to_bytes();
+        TopicName::from_bytes(&mut Cursor::new(bytes)).unwrap()
+    }
+
+    fn subscribe(task_handler: &mut TaskHandler, client_id: &str, topic: &str, qos: QoS) {
+        let subscribe = Subscribe::new(1, vec![(topic_filter(topic), qos)]);
+        task_handler
+            .subscribe(subscribe, client_id.as_bytes().to_vec())
+            .unwrap();
+    }
+
+    fn publish(
+        task_handler: &mut TaskHandler,
+        client_id: &str,
+        topic: &str,
+        message: &str,
+        qos: QoS,
+        retain: bool,
+    ) {
+        let packet_identifier = match qos {
+            QoS::AtMost => None,
+            _ => Some(1),
+        };
+        let publish = Publish::new(
+            false,
+            qos,
+            retain,
+            topic_name(topic),
+            packet_identifier,
+            message.as_bytes().to_vec(),
+        );
+        task_handler
+            .publish(&publish, client_id.as_bytes().to_vec())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_qos_2_publish_is_processed_once_until_released() {
+        let (mut task_handler, _files) = setup_task_handler();
+        let mut subscriber = connect(&mut task_handler, "subscriber");
+        let mut publisher = connect(&mut task_handler, "publisher");
+        subscribe(
+            &mut task_handler,
+            "subscriber",
+            "close-incident/1",
+            QoS::AtMost,
+        );
+        subscriber.received();
+
+        // The publisher sends the message again, as if the PUBREC was lost
+        for _ in 0..2 {
+            publish(
+                &mut task_handler,
+                "publisher",
+                "close-incident/1",
+                "closed",
+                QoS::Exactly,
+                false,
+            );
+            assert!(matches!(
+                publisher.received()[..],
+                [Packet::Pubrec(ref pubrec)] if pubrec.packet_identifier() == 1
+            ));
+        }
+        assert_eq!(subscriber.received_publishes().len(), 1);
+
+        task_handler
+            .publish_release(1, b"publisher".to_vec())
+            .unwrap();
+        assert!(matches!(
+            publisher.received()[..],
+            [Packet::Pubcomp(ref pubcomp)] if pubcomp.packet_identifier() == 1
+        ));
+
+        // Once released, the packet identifier is used for a new message
+        publish(
+            &mut task_handler,
+            "publisher",
+            "close-incident/1",
+            "closed",
+            QoS::Exactly,
+            false,
+        );
+        assert_eq!(subscriber.received_publishes().len(), 1);
+    }
+
+    #[test]
+    fn test_qos_2_delivery_to_a_subscriber() {
+        let (mut task_handler, _files) = setup_task_handler();
+        let mut subscriber = connect(&mut task_handler, "subscriber");
+        connect(&mut task_handler, "publisher");
+        subscribe(&mut task_handler, "subscriber", "topic", QoS::Exactly);
+        subscriber.received();
+
+        publish(
+            &mut task_handler,
+            "publisher",
+            "topic",
+            "message",
+            QoS::Exactly,
+            false,
+        );
+        let publishes = subscriber.received_publishes();
+        assert_eq!(publishes[0].qos(), &QoS::Exactly);
+        let packet_identifier = publishes[0].package_identifier().unwrap();
+
+        task_handler
+            .publish_received(packet_identifier, b"subscriber".to_vec())
+            .unwrap();
+        assert!(matches!(
+            subscriber.received()[..],
+            [Packet::Pubrel(ref pubrel)] if pubrel.packet_identifier() == packet_identifier
+        ));
+        task_handler
+            .publish_complete(packet_identifier, b"subscriber".to_vec())
+            .unwrap();
+
+        let clients = task_handler.clients.read().unwrap();
+        let client = clients.get(b"subscriber".as_slice()).unwrap();
+        assert!(client.inflight.is_empty());
+        assert!(client.awaiting_pubcomp.is_empty());
+    }
+
+    #[test]
+    fn test_publishes_are_delivered_at_the_granted_qos() {
+        let (mut task_handler, _files) = setup_task_handler();
+        let mut drone = connect(&mut task_handler, "drone");
+        let mut camera = connect(&mut task_handler, "camera");
+        connect(&mut task_handler, "monitor");
+        publish(
+            &mut task_handler,
+            "monitor",
+            "new-incident",
+            "retained",
+            QoS::AtLeast,
+            true,
+        );
+
+        subscribe(&mut task_handler, "drone", "new-incident", QoS::AtLeast);
+        subscribe(&mut task_handler, "camera", "new-incident", QoS::AtMost);
+        match &drone.received()[..] {
+            [Packet::Suback(suback), Packet::Publish(retained)] => {
+                assert_eq!(
+                    suback.suback_return_codes(),
+                    &vec![SubackReturnCode::SuccessMaximumQoS1]
+                );
+                assert_eq!(retained.qos(), &QoS::AtLeast);
+            }
+            packets => panic!("Expected a Suback and a Publish, received {:?}", packets),
+        }
+        assert_eq!(camera.received_publishes()[0].qos(), &QoS::AtMost);
+
+        publish(
+            &mut task_handler,
+            "monitor",
+            "new-incident",
+            "exactly",
+            QoS::Exactly,
+            false,
+        );
+        publish(
+            &mut task_handler,
+            "monitor",
+            "new-incident",
+            "at most",
+            QoS::AtMost,
+            false,
+        );
+
+        let publishes = drone.received_publishes();
+        assert_eq!(publishes[0].qos(), &QoS::AtLeast);
+        assert_eq!(publishes[0].package_identifier(), Some(2));
+        assert_eq!(publishes[1].qos(), &QoS::AtMost);
+        let publishes = camera.received_publishes();
+        assert_eq!(publishes[0].qos(), &QoS::AtMost);
+        assert_eq!(publishes[0].package_identifier(), None);
+        // Only the QoS 1 deliveries to the drone wait for their acknowledgement
+        let clients = task_handler.clients.read().unwrap();
+        let inflight = &clients.get(b"drone".as_slice()).unwrap().inflight;
+        assert_eq!(inflight.len(), 2);
+        assert!(inflight
+            .iter()
+            .all(|inflight| inflight.publish.qos() == &QoS::AtLeast));
+        assert!(clients
+            .get(b"camera".as_slice())
+            .unwrap()
+            .inflight
+            .is_empty());
+    }
+
+    #[test]
+    fn test_inflight_window_holds_the_messages_until_they_are_acknowledged() {
+        let (mut task_handler, _files) = setup_task_handler();
+        task_handler.max_inflight_messages = 2;
+        let mut drone = connect(&mut task_handler, "drone");
+        connect(&mut task_handler, "monitor");
+        subscribe(&mut task_handler, "drone", "new-incident", QoS::AtLeast);
+        drone.received();
+
+        for message in ["1", "2", "3"] {
+            publish(
+                &mut task_handler,
+                "monitor",
+                "new-incident",
+                message,
+                QoS::AtLeast,
+                false,
+            );
+        }
+        let publishes = drone.received_publishes();
+        assert_eq!(publishes.len(), 2);
+
+        task_handler
+            .publish_ack(publishes[0].package_identifier(), b"drone".to_vec())
+            .unwrap();
+        match &drone.received_publishes()[..] {
+            [publish] => assert_eq!(publish.message(), b"3"),
+            publishes => panic!("Expected the third message, received {:?}", publishes),
+        }
+    }
+
+    #[test]
+    fn test_messages_waiting_for_the_inflight_window_follow_the_queue_policy() {
+        let (mut task_handler, _files) = setup_task_handler();
+        task_handler.max_inflight_messages = 1;
+        task_handler.queue_policy = QueuePolicy::new(1, 0, OverflowPolicy::DropOldest);
+        let mut drone = connect(&mut task_handler, "drone");
+        connect(&mut task_handler, "monitor");
+        subscribe(&mut task_handler, "drone", "new-incident", QoS::AtLeast);
+        drone.received();
+
+        for message in ["1", "2", "3"] {
+            publish(
+                &mut task_handler,
+                "monitor",
+                "new-incident",
+                message,
+                QoS::AtLeast,
+                false,
+            );
+        }
+        let publishes = drone.received_publishes();
+        assert_eq!(publishes.len(), 1);
+
+        task_handler
+            .publish_ack(publishes[0].package_identifier(), b"drone".to_vec())
+            .unwrap();
+        match &drone.received_publishes()[..] {
+            [publish] => assert_eq!(publish.message(), b"3"),
+            publishes => panic!("Expected the third message, received {:?}", publishes),
+        }
+    }
+
+    #[test]
+    fn test_full_pending_queue_with_disconnect_policy_closes_the_connection() {
+        let (mut task_handler, _files) = setup_task_handler();
+        task_handler.max_inflight_messages = 1;
+        task_handler.queue_policy = QueuePolicy::new(1, 0, OverflowPolicy::Disconnect);
+        connect(&mut task_handler, "drone");
+        connect(&mut task_handler, "monitor");
+        subscribe(&mut task_handler, "drone", "new-incident", QoS::AtLeast);
+
+        for message in ["1", "2", "3"] {
+            publish(
+                &mut task_handler,
+                "monitor",
+                "new-incident",
+                message,
+                QoS::AtLeast,
+                false,
+            );
+        }
+
+        assert!(client_connection(&task_handler, "drone").is_closed());
+    }
+
+    #[test]
+    fn test_unacknowledged_messages_are_sent_again_with_the_dup_flag() {
+        let (mut task_handler, _files) = setup_task_handler();
+        task_handler.retry_interval = Duration::from_millis(1);
+        let mut drone = connect_client(&mut task_handler, "drone", false, &KEY);
+        connect(&mut task_handler, "monitor");
+        subscribe(&mut task_handler, "drone", "new-incident", QoS::AtLeast);
+        drone.received();
+        publish(
+            &mut task_handler,
+            "monitor",
+            "new-incident",
+            "incident",
+            QoS::AtLeast,
+            false,
+        );
+        let first = drone.received_publishes().remove(0);
+        assert!(!first.dup());
+
+        thread::sleep(Duration::from_millis(5));
+        task_handler.retry_inflight().unwrap();
+        let retried = drone.received_publishes().remove(0);
+        assert!(retried.dup());
+        assert_eq!(retried.package_identifier(), first.package_identifier());
+
+        task_handler
+            .handle_client_disconnected(b"drone".to_vec())
+            .unwrap();
+        let mut drone = connect_client(&mut task_handler, "drone", false, &KEY);
+        match &drone.received_publishes()[..] {
+            [resumed] => {
+                assert!(resumed.dup());
+                assert_eq!(resumed.message(), b"incident");
+            }
+            publishes => panic!("Expected the message again, received {:?}", publishes),
+        }
+
+        task_handler
+            .publish_ack(first.package_identifier(), b"drone".to_vec())
+            .unwrap();
+        thread::sleep(Duration::from_millis(5));
+        task_handler.retry_inflight().unwrap();
+        assert!(drone.received_publishes().is_empty());
+    }
+
+    #[test]
+    fn test_clean_session_discards_the_queued_messages() {
+        let (mut task_handler, _files) = setup_task_handler();
+        connect_client(&mut task_handler, "subscriber", false, &KEY);
+        connect(&mut task_handler, "publisher");
+        subscribe(&mut task_handler, "subscriber", "topic", QoS::AtLeast);
+        task_handler
+            .handle_client_disconnected(b"subscriber".to_vec())
+            .unwrap();
+        publish(
+            &mut task_handler,
+            "publisher",
+            "topic",
+            "message",
+            QoS::AtLeast,
+            false,
+        );
+
+        let mut subscriber = connect(&mut task_handler, "subscriber");
+
+        assert!(subscriber.received().is_empty());
+        assert!(!task_handler
+            .offline_messages
+            .contains_key(b"subscriber".as_slice()));
+    }
+
+    #[test]
+    fn test_live_publish_clears_the_retain_flag() {
+        let (mut task_handler, _files) = setup_task_handler();
+        let mut subscriber = connect(&mut task_handler, "subscriber");
+        connect(&mut task_handler, "publisher");
+        subscribe(&mut task_handler, "subscriber", "topic", QoS::AtMost);
+        subscriber.received();
+
+        publish(
+            &mut task_handler,
+            "publisher",
+            "topic",
+            "message",
+            QoS::AtMost,
+            true,
+        );
+
+        let publishes = subscriber.received_publishes();
+        assert_eq!(publishes.len(), 1);
+        assert!(!publishes[0].retain());
+        assert!(task_handler
+            .retained_messages
+            .get(&topic_name("topic"))
+            .is_some_and(|message| message.publish.retain()));
+    }
+
+    #[test]
+    fn test_retained_message_keeps_the_retain_flag_on_subscribe() {
+        let (mut task_handler, _files) = setup_task_handler();
+        let mut subscriber = connect(&mut task_handler, "subscriber");
+        connect(&mut task_handler, "publisher");
+        publish(
+            &mut task_handler,
+            "publisher",
+            "topic",
+            "message",
+            QoS::AtMost,
+            true,
+        );
+
+        subscribe(&mut task_handler, "subscriber", "topic", QoS::AtMost);
+
+        let publishes = subscriber.received_publishes();
+        assert_eq!(publishes.len(), 1);
+        assert!(publishes[0].retain());
+        assert_eq!(publishes[0].message(), b"message");
+    }
+
+    #[test]
+    fn test_empty_retained_message_removes_the_retained_message_of_the_topic() {
+        let (mut task_handler, _files) = setup_task_handler();
+        connect(&mut task_handler, "publisher");
+        publish(
+            &mut task_handler,
+            "publisher",
+            "topic",
+            "message",
+            QoS::AtMost,
+            true,
+        );
+        assert!(task_handler
+            .retained_messages
+            .contains_key(&topic_name("topic")));
+
+        publish(
+            &mut task_handler,
+            "publisher",
+            "topic",
+            "",
+            QoS::AtMost,
+            true,
+        );
+
+        assert!(!task_handler
+            .retained_messages
+            .contains_key(&topic_name("topic")));
+    }
+
+    #[test]
+    fn test_wildcard_subscription_receives_retained_and_live_messages() {
+        let (mut task_handler, _files) = setup_task_handler();
+        let mut subscriber = connect(&mut task_handler, "subscriber");
+        connect(&mut task_handler, "publisher");
+        publish(
+            &mut task_handler,
+            "publisher",
+            "drone-data/1",
+            "retained",
+            QoS::AtMost,
+            true,
+        );
+
+        subscribe(&mut task_handler, "subscriber", "drone-data/+", QoS::AtMost);
+        let publishes = subscriber.received_publishes();
+        assert_eq!(publishes.len(), 1);
+        assert_eq!(publishes[0].message(), b"retained");
+
+        publish(
+            &mut task_handler,
+            "publisher",
+            "drone-data/2",
+            "live",
+            QoS::AtMost,
+            false,
+        );
+        assert_eq!(subscriber.received_publishes()[0].message(), b"live");
+
+        let unsubscribe = Unsubscribe::new(2, vec![topic_filter("drone-data/+")]);
+        task_handler
+            .unsubscribe(unsubscribe, b"subscriber".to_vec())
+            .unwrap();
+        subscriber.received();
+        publish(
+            &mut task_handler,
+            "publisher",
+            "drone-data/2",
+            "live",
+            QoS::AtMost,
+            false,
+        );
+        assert!(subscriber.received_publishes().is_empty());
+    }
+
+    #[test]
+    fn test_publishes_are_sent_in_the_format_of_each_connection() {
+        let (mut task_handler, _files) = setup_task_handler();
+        let mut encrypted = connect(&mut task_handler, "encrypted");
+        let mut plain = connect_client(&mut task_handler, "plain", true, NO_ENCRYPTION);
+        subscribe(&mut task_handler, "encrypted", "sensor-data", QoS::AtMost);
+        subscribe(&mut task_handler, "plain", "sensor-data", QoS::AtMost);
+        encrypted.received();
+        plain.received();
+
+        publish(
+            &mut task_handler,
+            "plain",
+            "sensor-data",
+            "42",
+            QoS::AtMost,
+            false,
+        );
+
+        assert_eq!(encrypted.received_publishes()[0].message(), b"42");
+        assert_eq!(plain.received_publishes()[0].message(), b"42");
+    }
+
+    #[test]
+    fn test_acl_denies_subscriptions_and_publishes() {
+        let (mut task_handler, _files) = setup_task_handler();
+        task_handler.acl = Acl::from_content(
+            "admin = readwrite = #
+            * = read = new-incident
+            * = write = drone-data/%c",
+        )
+        .unwrap();
+        let mut admin = connect(&mut task_handler, "admin");
+        let mut drone = connect(&mut task_handler, "1");
+        subscribe(&mut task_handler, "admin", "drone-data/+", QoS::AtMost);
+        admin.received();
+
+        let subscribe_packet = Subscribe::new(
+            2,
+            vec![
+                (topic_filter("new-incident"), QoS::AtMost),
+                (topic_filter("drone-data/+"), QoS::AtMost),
+            ],
+        );
+        task_handler
+            .subscribe(subscribe_packet, b"1".to_vec())
+            .unwrap();
+        match &drone.received()[..] {
+            [Packet::Suback(suback)] => assert_eq!(
+                suback.suback_return_codes(),
+                &vec![
+                    SubackReturnCode::SuccessMaximumQoS0,
+                    SubackReturnCode::Failure
+                ]
+            ),
+            packets => panic!("Expected a Suback, received {:?}", packets),
+        }
+
+        publish(
+            &mut task_handler,
+            "1",
+            "drone-data/2",
+            "spoofed",
+            QoS::AtMost,
+            false,
+        );
+        publish(
+            &mut task_handler,
+            "1",
+            "new-incident",
+            "spoofed",
+            QoS::AtMost,
+            true,
+        );
+        publish(
+            &mut task_handler,
+            "1",
+            "drone-data/1",
+            "battery",
+            QoS::AtMost,
+            false,
+        );
+
+        let publishes = admin.received_publishes();
+        assert_eq!(publishes.len(), 1);
+        assert_eq!(publishes[0].message(), b"battery");
+        assert!(task_handler.retained_messages.is_empty());
+    }
+
+    /// Connects a client with a will on the topic, returning the Connack the server answered with
+    fn connect_with_will(task_handler: &mut TaskHandler, client_id: &str, topic: &str) -> Connack {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let (server_stream, _) = listener.accept().unwrap();
+
+        let will = Will::new(
+            QoS::AtMost,
+            true,
+            topic_name(topic),
+            EncodedString::from_string(&"offline".to_string()),
+        );
+        let client = Client::new(
+            client_id.as_bytes().to_vec(),
+            client_id.as_bytes().to_vec(),
+            Some(Connection::new(server_stream, usize::MAX, keyring(&KEY)).unwrap()),
+            true,
+            0,
+            Some(will),
+        );
+        task_handler.handle_new_client_connection(client).unwrap();
+
+        let mut connection = TestConnection {
+            stream,
+            key: &KEY,
+            decoder: client_decoder(),
+        };
+        connection.connack()
+    }
+
+    #[test]
+    fn test_will_must_be_allowed_by_the_acl() {
+        let (mut task_handler, _files) = setup_task_handler();
+        task_handler.acl = Acl::from_content("* = write = drone-data/%c").unwrap();
+
+        let connack = connect_with_will(&mut task_handler, "1", "new-incident");
+        assert_eq!(
+            *connack.connect_return_code(),
+            ConnectReturnCode::NotAuthorized
+        );
+        assert!(!task_handler
+            .clients
+            .read()
+            .unwrap()
+            .contains_key(b"1".as_slice()));
+
+        let connack = connect_with_will(&mut task_handler, "1", "drone-data/1");
+        assert_eq!(
+            *connack.connect_return_code(),
+            ConnectReturnCode::ConnectionAccepted
+        );
+    }
+
+    #[test]
+    fn test_clients_may_not_use_the_topics_reserved_to_the_server() {
+        let (mut task_handler, _files) = setup_task_handler();
+
+        let connack = connect_with_will(&mut task_handler, "1", "$SYS/broker/uptime");
+        assert_eq!(
+            *connack.connect_return_code(),
+            ConnectReturnCode::NotAuthorized
+        );
+
+        connect(&mut task_handler, "2");
+        publish(
+            &mut task_handler,
+            "2",
+            "$SYS/broker/uptime",
+            "spoofed",
+            QoS::AtMost,
+            true,
+        );
+        assert!(task_handler.retained_messages.is_empty());
+    }
+
+    #[test]
+    fn test_admin_rotates_the_key_and_pushes_it_to_the_encrypted_clients() {
+        let (mut task_handler, _files) = setup_task_handler();
+        let mut drone = connect(&mut task_handler, "1");
+        let mut sensor = connect_client(&mut task_handler, "sensor", true, NO_ENCRYPTION);
+        let message = format!("2;{}", "k".repeat(32));
+
+        publish(
+            &mut task_handler,
+            "1",
+            "$key-rotation",
+            &message,
+            QoS::AtMost,
+            false,
+        );
+        assert_eq!(task_handler.keyring.read().unwrap().ids(), vec![0]);
+
+        publish(
+            &mut task_handler,
+            "admin",
+            "$key-rotation",
+            &message,
+            QoS::AtLeast,
+            false,
+        );
+        let replayed = format!("2;{}", "r".repeat(32));
+        publish(
+            &mut task_handler,
+            "admin",
+            "$key-rotation",
+            &replayed,
+            QoS::AtMost,
+            false,
+        );
+
+        let keyring = task_handler.keyring.read().unwrap().clone();
+        assert_eq!(keyring.ids(), vec![0, 2]);
+        assert_eq!(keyring.current(), Some((2, "k".repeat(32).as_bytes())));
+        match &drone.received_publishes()[..] {
+            [push] => {
+                assert_eq!(*push.topic(), topic_name("$key-rotation"));
+                assert_eq!(push.qos(), &QoS::AtMost);
+                assert_eq!(push.message(), message.as_bytes());
+            }
+            publishes => panic!("Expected the new key, received {:?}", publishes),
+        }
+        assert!(sensor.received_publishes().is_empty());
+    }
+
+    #[test]
+    fn test_key_is_not_pushed_to_the_clients_with_a_key_of_their_own() {
+        let (mut task_handler, files) = setup_task_handler();
+        let client_manager = ClientManager::new(files[1].path())
+            .with_client_keys(HashMap::from([(b"camera".to_vec(), vec![7; 32])]));
+        task_handler.client_manager = Arc::new(RwLock::new(client_manager));
+        let mut drone = connect(&mut task_handler, "1");
+        let mut camera = connect(&mut task_handler, "camera");
+
+        publish(
+            &mut task_handler,
+            "admin",
+            "$key-rotation",
+            &format!("2;{}", "k".repeat(32)),
+            QoS::AtMost,
+            false,
+        );
+
+        assert_eq!(drone.received_publishes().len(), 1);
+        assert!(camera.received_publishes().is_empty());
+    }
+
+    #[test]
+    fn test_mqtt_5_puback_tells_why_a_publish_was_not_delivered() {
+        let (mut task_handler, _files) = setup_task_handler();
+        task_handler.acl = Acl::from_content("* = write = drone-data/%c").unwrap();
+        let mut drone = connect_v5(&mut task_handler, "1", 0);
+        let mut drone_v311 = connect(&mut task_handler, "2");
+
+        publish(
+            &mut task_handler,
+            "1",
+            "drone-data/1",
+            "battery",
+            QoS::AtLeast,
+            false,
+        );
+        publish(
+            &mut task_handler,
+            "1",
+            "drone-data/2",
+            "spoofed",
+            QoS::AtLeast,
+            false,
+        );
+        publish(
+            &mut task_handler,
+            "2",
+            "drone-data/2",
+            "battery",
+            QoS::AtLeast,
+            false,
+        );
+
+        let reason_codes: Vec<ReasonCode> = drone
+            .received()
+            .into_iter()
+            .map(|packet| match packet {
+                Packet::Puback(puback) => puback.reason_code(),
+                packet => panic!("Expected a Puback, received {:?}", packet),
+            })
+            .collect();
+        assert_eq!(
+            reason_codes,
+            vec![ReasonCode::NoMatchingSubscribers, ReasonCode::NotAuthorized]
+        );
+        match &drone_v311.received()[..] {
+            [Packet::Puback(puback)] => assert_eq!(puback.reason_code(), ReasonCode::Success),
+            packets => panic!("Expected a Puback, received {:?}", packets),
+        }
+    }
+
+    #[test]
+    fn test_expired_messages_are_not_delivered() {
+        let (mut task_handler, _files) = setup_task_handler();
+        connect_v5(&mut task_handler, "subscriber", 60);
+        connect(&mut task_handler, "publisher");
+        subscribe(&mut task_handler, "subscriber", "topic", QoS::AtMost);
+        task_handler
+            .handle_client_disconnected(b"subscriber".to_vec())
+            .unwrap();
+
+        for (message, message_expiry_interval) in [("expired", 0), ("fresh", 60)] {
+            let publish = Publish::new(
+                false,
+                QoS::AtMost,
+                false,
+                topic_name("topic"),
+                None,
+                message.as_bytes().to_vec(),
+            )
+            .with_properties(
+                Properties::new().with(Property::MessageExpiryInterval(message_expiry_interval)),
+            );
+            task_handler
+                .publish(&publish, b"publisher".to_vec())
+                .unwrap();
+        }
+
+        let mut subscriber = connect_v5(&mut task_handler, "subscriber", 60);
+
+        match &subscriber.received_publishes()[..] {
+            [publish] => {
+                assert_eq!(publish.message(), b"fresh");
+                assert!(publish
+                    .properties()
+                    .message_expiry_interval()
+                    .is_some_and(|interval| interval <= 60));
+            }
+            publishes => panic!("Expected the fresh message, received {:?}", publishes),
+        }
+    }
+
+    #[test]
+    fn test_restored_offline_messages_keep_their_expiry_and_the_time_they_were_stored() {
+        let (mut task_handler, files) = setup_task_handler();
+        connect_v5(&mut task_handler, "subscriber", 600);
+        connect(&mut task_handler, "publisher");
+        subscribe(&mut task_handler, "subscriber", "topic", QoS::AtMost);
+        task_handler
+            .handle_client_disconnected(b"subscriber".to_vec())
+            .unwrap();
+
+        for (message, message_expiry_interval) in [("expiring", 60), ("kept", 600)] {
+            let publish = Publish::new(
+                false,
+                QoS::AtMost,
+                false,
+                topic_name("topic"),
+                None,
+                message.as_bytes().to_vec(),
+            )
+            .with_properties(
+                Properties::new().with(Property::MessageExpiryInterval(message_expiry_interval)),
+            );
+            task_handler
+                .publish(&publish, b"publisher".to_vec())
+                .unwrap();
+        }
+
+        // The messages of the backup were stored two minutes before it is restored
+        let backup: String = task_handler
+            .serialize()
+            .lines()
+            .map(|line| {
+                let mut parts: Vec<String> = line.split(';').map(String::from).collect();
+                if parts[0] == OFFLINE_MESSAGES_TAG {
+                    let stored_at = parts[3].parse::<u64>().unwrap() - 120;
+                    parts[3] = stored_at.to_string();
+                }
+                parts.join(";") + "\n"
+            })
+            .collect();
+        let settings = TempFile::new(&format!("test_settings_{:?}.txt", thread::current().id()));
+        fs::write(settings.path(), [b"key=\"", KEY.as_slice(), b"\""].concat()).unwrap();
+        let config = Config::from_file(Path::new(settings.path())).unwrap();
+        let restored = TaskHandler::deserialize(
+            &backup,
+            task_handler.client_manager.clone(),
+            Arc::new(Logger::new(files[0].path())),
+            &config,
+            mpsc::channel().1,
+        );
+
+        match &restored.offline_messages[b"subscriber".as_slice()]
+            .publishes()
+            .collect::<Vec<_>>()[..]
+        {
+            [publish] => {
+                assert_eq!(publish.message(), b"kept");
+                assert!(publish
+                    .properties()
+                    .message_expiry_interval()
+                    .is_some_and(|interval| interval <= 480));
+            }
+            publishes => panic!("Expected the kept message, received {:?}", publishes),
+        }
+    }
+
+    #[test]
+    fn test_session_is_kept_until_its_expiry_interval_elapses() {
+        let (mut task_handler, _files) = setup_task_handler();
+        connect_v5(&mut task_handler, "ephemeral", 0);
+        connect_v5(&mut task_handler, "persistent", 60);
+        subscribe(&mut task_handler, "ephemeral", "topic", QoS::AtMost);
+        subscribe(&mut task_handler, "persistent", "topic", QoS::AtMost);
+
+        task_handler
+            .handle_client_disconnected(b"ephemeral".to_vec())
+            .unwrap();
+        task_handler
+            .handle_client_disconnected(b"persistent".to_vec())
+            .unwrap();
+
+        assert!(!task_handler
+            .clients
+            .read()
+            .unwrap()
+            .contains_key(b"ephemeral".as_slice()));
+        assert_eq!(
+            task_handler.subscriptions.matches(&topic_name("topic")),
+            HashSet::from([b"persistent".to_vec()])
+        );
+
+        if let Some(client) = task_handler
+            .clients
+            .write()
+            .unwrap()
+            .get_mut(b"persistent".as_slice())
+        {
+            client.disconnected_at = Instant::now().checked_sub(Duration::from_secs(61));
+        }
+        task_handler.expire_sessions().unwrap();
+
+        assert!(task_handler.clients.read().unwrap().is_empty());
+        assert!(task_handler
+            .subscriptions
+            .matches(&topic_name("topic"))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_latest_value_topics_only_queue_the_last_message() {
+        let (mut task_handler, _files) = setup_task_handler();
+        task_handler.queue_policy = QueuePolicy::unbounded()
+            .with_rules("telemetry/+ = 0 = latest")
+            .unwrap();
+        connect_client(&mut task_handler, "subscriber", false, &KEY);
+        connect(&mut task_handler, "publisher");
+        subscribe(&mut task_handler, "subscriber", "#", QoS::AtMost);
+        task_handler
+            .handle_client_disconnected(b"subscriber".to_vec())
+            .unwrap();
+
+        for (topic, message) in [
+            ("telemetry/1", "old"),
+            ("events", "first"),
+            ("telemetry/1", "new"),
+            ("events", "second"),
+        ] {
+            publish(
+                &mut task_handler,
+                "publisher",
+                topic,
+                message,
+                QoS::AtMost,
+                false,
+            );
+        }
+
+        let mut subscriber = connect_client(&mut task_handler, "subscriber", false, &KEY);
+        let messages: Vec<Vec<u8>> = subscriber
+            .received_publishes()
+            .iter()
+            .map(|publish| publish.message().clone())
+            .collect();
+
+        assert_eq!(
+            messages,
+            vec![b"first".to_vec(), b"new".to_vec(), b"second".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_full_queue_with_disconnect_policy_discards_the_session() {
+        let (mut task_handler, _files) = setup_task_handler();
+        task_handler.queue_policy = QueuePolicy::new(1, 0, OverflowPolicy::Disconnect);
+        connect_client(&mut task_handler, "subscriber", false, &KEY);
+        connect(&mut task_handler, "publisher");
+        subscribe(&mut task_handler, "subscriber", "topic", QoS::AtMost);
+        task_handler
+            .handle_client_disconnected(b"subscriber".to_vec())
+            .unwrap();
+
+        for message in ["first", "second"] {
+            publish(
+                &mut task_handler,
+                "publisher",
+                "topic",
+                message,
+                QoS::AtMost,
+                false,
+            );
+        }
+
+        assert!(!task_handler
+            .clients
+            .read()
+            .unwrap()
+            .contains_key(b"subscriber".as_slice()));
+        assert!(!task_handler
+            .offline_messages
+            .contains_key(b"subscriber".as_slice()));
+        assert!(task_handler
+            .subscriptions
+            .matches(&topic_name("topic"))
+            .is_empty());
+    }
+
+    /// Returns the connection the server keeps for the client
+    fn client_connection(task_handler: &TaskHandler, client_id: &str) -> Connection {
+        task_handler
+            .clients
+            .read()
+            .unwrap()
+            .get(client_id.as_bytes())
+            .and_then(|client| client.connection.clone())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_reconnecting_client_takes_over_its_session() {
+        let (mut task_handler, _files) = setup_task_handler();
+        connect_client(&mut task_handler, "drone", false, &KEY);
+        connect(&mut task_handler, "publisher");
+        subscribe(&mut task_handler, "drone", "topic", QoS::AtMost);
+        let old_connection = client_connection(&task_handler, "drone");
+
+        let mut drone = connect_client(&mut task_handler, "drone", false, &KEY);
+        assert!(old_connection.is_closed());
+
+        // The I/O loop reports the old connection closed after the new one took over
+        task_handler
+            .handle_connection_closed(b"drone".to_vec(), &old_connection)
+            .unwrap();
+        publish(
+            &mut task_handler,
+            "publisher",
+            "topic",
+            "message",
+            QoS::AtMost,
+            false,
+        );
+
+        assert!(task_handler
+            .active_connections
+            .contains(b"drone".as_slice()));
+        match &drone.received_publishes()[..] {
+            [publish] => assert_eq!(publish.message(), b"message"),
+            publishes => panic!("Expected the message, received {:?}", publishes),
+        }
+    }
+
+    #[test]
+    fn test_clean_session_takeover_discards_the_previous_session() {
+        let (mut task_handler, _files) = setup_task_handler();
+        connect_client(&mut task_handler, "drone", false, &KEY);
+        subscribe(&mut task_handler, "drone", "topic", QoS::AtMost);
+        let old_connection = client_connection(&task_handler, "drone");
+
+        connect(&mut task_handler, "drone");
+
+        assert!(old_connection.is_closed());
+        assert!(task_handler
+            .subscriptions
+            .matches(&topic_name("topic"))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_mqtt_5_client_is_told_its_session_was_taken_over() {
+        let (mut task_handler, _files) = setup_task_handler();
+        let mut old_drone = connect_v5(&mut task_handler, "drone", 60);
+
+        connect_v5(&mut task_handler, "drone", 60);
+
+        match &old_drone.received()[..] {
+            [Packet::Disconnect(disconnect)] => {
+                assert_eq!(disconnect.reason_code(), ReasonCode::SessionTakenOver)
+            }
+            packets => panic!("Expected a Disconnect, received {:?}", packets),
+        }
+    }
+
+    #[test]
+    fn test_broker_stats_are_published_to_the_admin() {
+        let (mut task_handler, _files) = setup_task_handler();
+        let mut admin = connect(&mut task_handler, "admin");
+        let mut drone = connect(&mut task_handler, "drone");
+        subscribe(&mut task_handler, "admin", "$SYS/#", QoS::AtMost);
+        subscribe(&mut task_handler, "drone", "$SYS/#", QoS::AtMost);
+        subscribe(&mut task_handler, "drone", "topic", QoS::AtMost);
+        publish(
+            &mut task_handler,
+            "admin",
+            "topic",
+            "message",
+            QoS::AtMost,
+            true,
+        );
+        admin.received();
+        drone.received();
+
+        task_handler.publish_stats().unwrap();
+
+        let stats: HashMap<String, String> = admin
+            .received_publishes()
+            .iter()
+            .map(|publish| {
+                (
+                    publish.topic().to_string(),
+                    String::from_utf8_lossy(publish.message()).into_owned(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            task_handler
+                .retained_messages
+                .keys()
+                .filter(|topic_name| topic_name.server_reserved())
+                .count(),
+            stats.len()
+        );
+        assert_eq!(stats["$SYS/broker/clients/connected"], "2");
+        assert_eq!(stats["$SYS/broker/subscriptions/count"], "2");
+        assert_eq!(stats["$SYS/broker/retained/count"], "1");
+        assert_eq!(stats["$SYS/broker/queue/messages"], "0");
+        assert_eq!(stats["$SYS/broker/version"], VERSION);
+        assert!(stats.contains_key("$SYS/broker/messages/received"));
+        assert!(stats.contains_key("$SYS/broker/uptime"));
+        assert!(drone.received_publishes().is_empty());
+    }
 }
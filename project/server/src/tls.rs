@@ -0,0 +1,164 @@
+use std::sync::Arc;
+
+use mqtt::tls::{crypto_provider, load_certificates, load_private_key, load_root_store};
+use rustls::{pki_types::CertificateDer, server::WebPkiClientVerifier, ServerConfig};
+use x509_parser::{certificate::X509Certificate, prelude::FromDer};
+
+use crate::error::{ServerError, ServerResult};
+
+/// Creates the TLS settings of the server, which presents the certificate of a PEM file signed with
+/// the private key of another one. If a file of certificate authorities is given, the clients must
+/// present a certificate signed by one of them
+pub fn server_config(
+    certificate_file: &str,
+    private_key_file: &str,
+    client_ca_file: Option<&str>,
+) -> ServerResult<Arc<ServerConfig>> {
+    let certificates = load_certificates(certificate_file)?;
+    let private_key = load_private_key(private_key_file)?;
+
+    let builder = ServerConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|err| ServerError::Tls(err.to_string()))?;
+
+    let builder = match client_ca_file {
+        Some(client_ca_file) => {
+            let root_store = Arc::new(load_root_store(client_ca_file)?);
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(root_store, crypto_provider())
+                    .build()
+                    .map_err(|err| ServerError::Tls(err.to_string()))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let config = builder
+        .with_single_cert(certificates, private_key)
+        .map_err(|err| ServerError::Tls(err.to_string()))?;
+
+    Ok(Arc::new(config))
+}
+
+/// Returns the common name of the subject of a certificate, None if it has none
+pub fn certificate_name(certificate: &CertificateDer) -> Option<String> {
+    let (_, certificate) = X509Certificate::from_der(certificate).ok()?;
+    let name = certificate.subject().iter_common_name().next()?;
+
+    name.as_str().ok().map(str::to_string)
+}
+
+/// Certificates generated for the tests of the TLS connections
+#[cfg(test)]
+pub mod testing {
+    use std::{fs, sync::Arc};
+
+    use rcgen::{BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa, KeyPair};
+    use rustls::{ClientConfig, RootCertStore, ServerConfig};
+
+    use super::*;
+
+    /// Creates a certificate authority and a certificate for localhost signed by it, and returns the
+    /// TLS settings of a server that presents it and of a client that trusts the authority.
+    /// If a client name is given, the server requires client certificates and the client presents
+    /// one issued to that name. The files are written with the prefix and removed afterwards
+    pub fn tls_configs(
+        prefix: &str,
+        client_name: Option<&str>,
+    ) -> (Arc<ServerConfig>, Arc<ClientConfig>) {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let server_key = KeyPair::generate().unwrap();
+        let server_certificate = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&server_key, &ca, &ca_key)
+            .unwrap();
+
+        let ca_file = format!("{}_ca.pem", prefix);
+        let certificate_file = format!("{}_cert.pem", prefix);
+        let private_key_file = format!("{}_key.pem", prefix);
+        fs::write(&ca_file, ca.pem()).unwrap();
+        fs::write(&certificate_file, server_certificate.pem()).unwrap();
+        fs::write(&private_key_file, server_key.serialize_pem()).unwrap();
+
+        let client_ca_file = client_name.map(|_| ca_file.as_str());
+        let server_config = server_config(&certificate_file, &private_key_file, client_ca_file);
+        for file in [&ca_file, &certificate_file, &private_key_file] {
+            fs::remove_file(file).unwrap();
+        }
+
+        let mut root_store = RootCertStore::empty();
+        root_store.add(ca.der().clone()).unwrap();
+        let builder = ClientConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(root_store);
+
+        let client_config = match client_name {
+            Some(client_name) => {
+                let client_key = KeyPair::generate().unwrap();
+                let mut client_params = CertificateParams::new(Vec::new()).unwrap();
+                client_params.distinguished_name = DistinguishedName::new();
+                client_params
+                    .distinguished_name
+                    .push(DnType::CommonName, client_name);
+                let client_certificate =
+                    client_params.signed_by(&client_key, &ca, &ca_key).unwrap();
+
+                builder
+                    .with_client_auth_cert(
+                        vec![client_certificate.der().clone()],
+                        client_key.serialize_der().try_into().unwrap(),
+                    )
+                    .unwrap()
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        (server_config.unwrap(), Arc::new(client_config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair};
+
+    use super::*;
+
+    #[test]
+    fn test_certificate_name_is_the_common_name_of_the_subject() {
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "drone-7");
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, "Sauron");
+        let certificate = params.self_signed(&KeyPair::generate().unwrap()).unwrap();
+
+        assert_eq!(
+            certificate_name(certificate.der()),
+            Some("drone-7".to_string())
+        );
+    }
+
+    #[test]
+    fn test_certificate_without_common_name_has_no_name() {
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params.distinguished_name = DistinguishedName::new();
+        let certificate = params.self_signed(&KeyPair::generate().unwrap()).unwrap();
+
+        assert_eq!(certificate_name(certificate.der()), None);
+    }
+
+    #[test]
+    fn test_server_config_fails_without_certificate_files() {
+        let result = server_config("test_missing_cert.pem", "test_missing_key.pem", None);
+
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use std::{
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+    net::Shutdown,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use mio::{net::TcpStream, Interest, Registry, Token};
+use rustls::{ServerConfig, ServerConnection};
+
+use crate::tls::certificate_name;
+
+/// Represents the socket of a connection, over TCP or TLS. Reads and writes never block:
+/// they fail with WouldBlock when the socket is not ready, and the poll it is registered in tells when it is.
+/// With TLS the handshake goes on as the connection is read, and the records that could not be
+/// written right away wait in the session until they are flushed
+pub enum Transport {
+    Tcp(TcpStream),
+    Tls(TcpStream, Box<Mutex<ServerConnection>>),
+}
+
+impl Transport {
+    /// Uses the socket as it is, setting it as non blocking
+    pub fn tcp(stream: std::net::TcpStream) -> io::Result<Self> {
+        Ok(Transport::Tcp(nonblocking(stream)?))
+    }
+
+    /// Starts a TLS session over the socket, setting it as non blocking
+    pub fn tls(stream: std::net::TcpStream, config: Arc<ServerConfig>) -> io::Result<Self> {
+        let session = ServerConnection::new(config).map_err(io::Error::other)?;
+
+        Ok(Transport::Tls(
+            nonblocking(stream)?,
+            Box::new(Mutex::new(session)),
+        ))
+    }
+
+    /// Registers the socket in the poll, which tells when it can be read or written
+    pub fn register(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        let stream = match self {
+            Transport::Tcp(stream) | Transport::Tls(stream, _) => stream,
+        };
+        registry.register(stream, token, Interest::READABLE | Interest::WRITABLE)
+    }
+
+    /// Writes the TLS records waiting to be sent. Returns true if something was written
+    pub fn write_pending(&self) -> io::Result<bool> {
+        match self {
+            Transport::Tcp(_) => Ok(false),
+            Transport::Tls(stream, session) => write_records(&mut *lock(session)?, stream),
+        }
+    }
+
+    /// Returns the subject name of the certificate the client presented, None if it did not present one
+    pub fn peer_name(&self) -> Option<String> {
+        match self {
+            Transport::Tcp(_) => None,
+            Transport::Tls(_, session) => {
+                let session = lock(session).ok()?;
+                let certificate = session.peer_certificates()?.first()?;
+                certificate_name(certificate)
+            }
+        }
+    }
+
+    /// Closes the socket, telling the client first if the connection is over TLS
+    pub fn shutdown(&self) {
+        if let Transport::Tls(stream, session) = self {
+            if let Ok(mut session) = lock(session) {
+                session.send_close_notify();
+                let _ = write_records(&mut session, stream);
+            }
+        }
+        let _ = self.stream().shutdown(Shutdown::Both);
+    }
+
+    fn stream(&self) -> &TcpStream {
+        match self {
+            Transport::Tcp(stream) | Transport::Tls(stream, _) => stream,
+        }
+    }
+}
+
+impl Read for &Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (mut stream, session) = match self {
+            Transport::Tcp(stream) => return (&*stream).read(buf),
+            Transport::Tls(stream, session) => (stream, session),
+        };
+
+        let mut session = lock(session)?;
+        loop {
+            match session.reader().read(buf) {
+                Ok(read) => return Ok(read),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+
+            if session.read_tls(&mut stream)? == 0 {
+                return Ok(0);
+            }
+
+            // The answers of the handshake and the alerts are sent even if the records are invalid
+            let processed = session.process_new_packets();
+            write_records(&mut session, stream)?;
+            processed.map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+        }
+    }
+}
+
+impl Write for &Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (stream, session) = match self {
+            Transport::Tcp(stream) => return (&*stream).write(buf),
+            Transport::Tls(stream, session) => (stream, session),
+        };
+
+        let mut session = lock(session)?;
+        write_records(&mut session, stream)?;
+        if session.wants_write() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+
+        let written = session.writer().write(buf)?;
+        if written == 0 && !buf.is_empty() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        write_records(&mut session, stream)?;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Tcp(stream) => f.debug_tuple("Tcp").field(stream).finish(),
+            Transport::Tls(stream, _) => f.debug_tuple("Tls").field(stream).finish(),
+        }
+    }
+}
+
+fn nonblocking(stream: std::net::TcpStream) -> io::Result<TcpStream> {
+    stream.set_nonblocking(true)?;
+    Ok(TcpStream::from_std(stream))
+}
+
+/// Writes the TLS records of the session until the socket stops accepting them.
+/// Returns true if something was written
+fn write_records(session: &mut ServerConnection, mut stream: &TcpStream) -> io::Result<bool> {
+    let mut written = false;
+
+    while session.wants_write() {
+        match session.write_tls(&mut stream) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(_) => written = true,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(written)
+}
+
+fn lock(session: &Mutex<ServerConnection>) -> io::Result<MutexGuard<'_, ServerConnection>> {
+    session
+        .lock()
+        .map_err(|_| io::Error::other("The TLS session lock was poisoned"))
+}